@@ -1,13 +1,19 @@
 // Agent module
 // エージェント実行ループ（最小）
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use futures_util::future::BoxFuture;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
-use crate::llm::{LlmClient, LlmRequest, LlmResponse, LlmStream};
+use crate::llm::{
+    estimate_tokens, keep_last_tokens, truncate_to_tokens, LlmClient, LlmRequest, LlmResponse,
+    LlmRetryHandler, LlmStream, LlmTool, LlmToolCall, LlmTurn, LlmWarningHandler, ModelInfo,
+    SchemaMismatch,
+};
+use crate::mcp::{McpManager, McpTool, SamplingRequest};
 use crate::tools::{
     ApprovalOverride, ToolApprovalDecision, ToolApprovalRequest, ToolApprovalRequired,
     ToolExecutor, ToolInput, ToolPolicy, ToolResult,
@@ -32,10 +38,43 @@ impl Agent {
 }
 
 pub struct AgentRunner {
-    client: LlmClient,
-    model_name: String,
+    active: Mutex<ActiveModel>,
+    routes: HashMap<ModelRole, ActiveModel>,
+    client_factory: Option<LlmClientFactory>,
     tool_policy: ToolPolicy,
     approval_handler: Mutex<Option<ApprovalHandler>>,
+    mcp: Option<Arc<McpManager>>,
+    system_prompt: Option<String>,
+    json_schema: Option<serde_json::Value>,
+}
+
+/// Builds a client for a `[providers.*]` name and a model, for `/model` switches.
+pub type LlmClientFactory = Arc<dyn Fn(&str, &str) -> Result<LlmClient> + Send + Sync>;
+
+/// Housekeeping calls of a turn that `[routing]` can send to another model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ModelRole {
+    Plan,
+    ToolSelect,
+    Compact,
+}
+
+impl ModelRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Plan => "plan",
+            Self::ToolSelect => "tool_select",
+            Self::Compact => "compact",
+        }
+    }
+}
+
+/// Each request takes a snapshot, so a switch never changes a request already sent.
+#[derive(Clone)]
+struct ActiveModel {
+    client: Arc<LlmClient>,
+    model_name: String,
+    provider_name: String,
 }
 
 pub struct AgentOutput {
@@ -66,18 +105,201 @@ enum ToolCall {
         pattern: String,
         root: Option<String>,
     },
+    Mcp {
+        server: String,
+        name: String,
+        #[serde(default)]
+        arguments: serde_json::Value,
+    },
 }
 
 impl AgentRunner {
     pub fn new(client: LlmClient, model_name: String, tool_policy: ToolPolicy) -> Self {
+        let provider_name = client.provider().as_str().to_string();
         Self {
-            client,
-            model_name,
+            active: Mutex::new(ActiveModel {
+                client: Arc::new(client),
+                model_name,
+                provider_name,
+            }),
+            routes: HashMap::new(),
+            client_factory: None,
             tool_policy,
             approval_handler: Mutex::new(None),
+            mcp: None,
+            system_prompt: None,
+            json_schema: None,
         }
     }
 
+    /// Sent with every request of the runner (TENGU.md, `--system-prompt`, the agent prompt).
+    pub fn with_system_prompt(mut self, system_prompt: Option<String>) -> Self {
+        self.system_prompt = system_prompt.filter(|prompt| !prompt.trim().is_empty());
+        self
+    }
+
+    /// Answers (not plans or tool selection) must be JSON matching this schema.
+    pub fn with_json_schema(mut self, schema: Option<serde_json::Value>) -> Self {
+        self.json_schema = schema;
+        self
+    }
+
+    pub fn with_mcp_manager(mut self, manager: Arc<McpManager>) -> Self {
+        if !manager.is_empty() {
+            self.mcp = Some(manager);
+        }
+        self
+    }
+
+    /// `provider_name` is the `[providers.*]` name the current client was built from.
+    pub fn with_client_factory(mut self, provider_name: &str, factory: LlmClientFactory) -> Self {
+        if let Ok(active) = self.active.get_mut() {
+            active.provider_name = provider_name.to_string();
+        }
+        self.client_factory = Some(factory);
+        self
+    }
+
+    pub fn with_route(
+        mut self,
+        role: ModelRole,
+        provider_name: &str,
+        client: LlmClient,
+        model_name: String,
+    ) -> Self {
+        self.routes.insert(
+            role,
+            ActiveModel {
+                client: Arc::new(client),
+                model_name,
+                provider_name: provider_name.to_string(),
+            },
+        );
+        self
+    }
+
+    /// The routed model for `role`, or the main one.
+    fn route(&self, role: ModelRole) -> ActiveModel {
+        match self.routes.get(&role) {
+            Some(route) => route.clone(),
+            None => self.active(),
+        }
+    }
+
+    fn active(&self) -> ActiveModel {
+        match self.active.lock() {
+            Ok(active) => active.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    pub fn model_name(&self) -> String {
+        self.active().model_name
+    }
+
+    pub fn provider_name(&self) -> String {
+        self.active().provider_name
+    }
+
+    /// Catalog entry of the active model, if the catalog knows it.
+    pub fn model_info(&self) -> Option<ModelInfo> {
+        let active = self.active();
+        active.client.model_info(&active.model_name)
+    }
+
+    pub async fn list_models(&self) -> Result<Vec<String>> {
+        self.active().client.list_models().await
+    }
+
+    pub fn model_summary(&self, model: &str) -> Option<String> {
+        self.active()
+            .client
+            .model_info(model)
+            .map(|info| info.summary())
+    }
+
+    pub fn catalog_models(&self) -> Vec<String> {
+        self.active().client.catalog_models()
+    }
+
+    /// Builds a client for `model` on `provider` (the current one when `None`) without
+    /// switching to it, so the caller can check the model first.
+    pub fn build_client(&self, provider: Option<&str>, model: &str) -> Result<(String, LlmClient)> {
+        let factory = self
+            .client_factory
+            .as_ref()
+            .ok_or_else(|| anyhow!("model switching is not available"))?;
+        let active = self.active();
+        let provider = provider.map(str::to_string).unwrap_or(active.provider_name);
+        let client = factory(&provider, model)?;
+        client.inherit_handlers(&active.client);
+        Ok((provider, client))
+    }
+
+    /// Later requests go to the new client. The conversation is kept by the caller and sent
+    /// as context, so it carries over.
+    pub fn switch_client(&self, provider_name: String, client: LlmClient, model_name: String) {
+        let active = ActiveModel {
+            client: Arc::new(client),
+            model_name,
+            provider_name,
+        };
+        match self.active.lock() {
+            Ok(mut guard) => *guard = active,
+            Err(poisoned) => *poisoned.into_inner() = active,
+        }
+    }
+
+    pub fn mcp_manager(&self) -> Option<&Arc<McpManager>> {
+        self.mcp.as_ref()
+    }
+
+    /// Serves an MCP `sampling/createMessage` request with the active model.
+    /// Answers with the first configured model (main or routed) that matches one of the
+    /// server's model hints, else the main model.
+    pub async fn sample(&self, request: &SamplingRequest) -> Result<serde_json::Value> {
+        let mut models = vec![self.active()];
+        models.extend(self.routes.values().cloned());
+        let active = request
+            .model_hints
+            .iter()
+            .find_map(|hint| {
+                models
+                    .iter()
+                    .find(|model| model.model_name.contains(hint.as_str()))
+            })
+            .unwrap_or(&models[0])
+            .clone();
+        let response = active
+            .client
+            .generate(&active.model_name, &request.to_llm_request())
+            .await?;
+        Ok(SamplingRequest::result(
+            response.content.trim(),
+            &active.model_name,
+        ))
+    }
+
+    /// Called after the running turn was aborted; approvals granted for it must not leak
+    /// into the next one.
+    pub fn reset_interrupted_turn(&self) {
+        self.tool_policy.clear_allow_once();
+    }
+
+    pub fn set_retry_handler(&self, handler: LlmRetryHandler) {
+        for route in self.routes.values() {
+            route.client.set_retry_handler(handler.clone());
+        }
+        self.active().client.set_retry_handler(handler);
+    }
+
+    pub fn set_warning_handler(&self, handler: LlmWarningHandler) {
+        for route in self.routes.values() {
+            route.client.set_warning_handler(handler.clone());
+        }
+        self.active().client.set_warning_handler(handler);
+    }
+
     pub fn set_approval_handler(&self, handler: ApprovalHandler) {
         if let Ok(mut guard) = self.approval_handler.lock() {
             *guard = Some(handler);
@@ -98,28 +320,29 @@ impl AgentRunner {
                 .handle_prompt_with_context(&request.prompt, context)
                 .await;
         }
-        let final_prompt = if context.trim().is_empty() {
-            request.prompt
-        } else {
-            format!(
-                "Conversation context:\n{}\n\nUser request:\n{}",
-                context, request.prompt
-            )
-        };
-        let final_response = self
+        let active = self.active();
+        let final_response = active
             .client
             .generate(
-                &self.model_name,
-                &LlmRequest {
-                    prompt: final_prompt,
-                    images: request.images,
-                },
+                &active.model_name,
+                &self
+                    .fit(
+                        &active,
+                        LlmRequest {
+                            images: request.images,
+                            json_schema: self.json_schema.clone(),
+                            ..self.request(request.prompt, context)
+                        },
+                        context,
+                    )
+                    .await,
             )
             .await?;
         Ok(AgentOutput {
             response: LlmResponse {
                 content: final_response.content.trim().to_string(),
                 usage: final_response.usage,
+                ..Default::default()
             },
             tool_result: None,
         })
@@ -133,13 +356,20 @@ impl AgentRunner {
         let (_plan, final_prompt, tool_result) = self
             .resolve_final_prompt_with_context(input, context)
             .await?;
-        let final_response = self
+        let active = self.active();
+        let final_response = active
             .client
-            .generate(&self.model_name, &LlmRequest::text(final_prompt))
+            .generate(
+                &active.model_name,
+                &self
+                    .final_request(&active, final_prompt, context, &[], tool_result.as_ref())
+                    .await,
+            )
             .await?;
         let response = LlmResponse {
             content: final_response.content.trim().to_string(),
             usage: final_response.usage,
+            ..Default::default()
         };
         Ok(AgentOutput {
             response,
@@ -166,25 +396,25 @@ impl AgentRunner {
     ) -> Result<(LlmStream, Option<ToolResult>)> {
         if request.images.is_empty() {
             return self
-                .handle_prompt_stream_with_tool_context(&request.prompt, context)
+                .prompt_stream(&request.prompt, context, &request.history)
                 .await;
         }
-        let final_prompt = if context.trim().is_empty() {
-            request.prompt
-        } else {
-            format!(
-                "Conversation context:\n{}\n\nUser request:\n{}",
-                context, request.prompt
-            )
-        };
-        let stream = self
+        let active = self.active();
+        let stream = active
             .client
             .generate_stream(
-                &self.model_name,
-                &LlmRequest {
-                    prompt: final_prompt,
-                    images: request.images,
-                },
+                &active.model_name,
+                &self
+                    .fit(
+                        &active,
+                        LlmRequest {
+                            images: request.images,
+                            history: request.history,
+                            ..self.request(request.prompt, context)
+                        },
+                        context,
+                    )
+                    .await,
             )
             .await?;
         Ok((stream, None))
@@ -194,18 +424,39 @@ impl AgentRunner {
         &self,
         input: &str,
         context: &str,
+    ) -> Result<(LlmStream, Option<ToolResult>)> {
+        self.prompt_stream(input, context, &[]).await
+    }
+
+    async fn prompt_stream(
+        &self,
+        input: &str,
+        context: &str,
+        history: &[LlmTurn],
     ) -> Result<(LlmStream, Option<ToolResult>)> {
         let (_plan, final_prompt, tool_result) = self
             .resolve_final_prompt_with_context(input, context)
             .await?;
-        let stream = self
+        let active = self.active();
+        let stream = active
             .client
-            .generate_stream(&self.model_name, &LlmRequest::text(final_prompt))
+            .generate_stream(
+                &active.model_name,
+                &self
+                    .final_request(
+                        &active,
+                        final_prompt,
+                        context,
+                        history,
+                        tool_result.as_ref(),
+                    )
+                    .await,
+            )
             .await?;
         Ok((stream, tool_result))
     }
 
-    fn execute_tool_call(&self, call: ToolCall) -> Result<ToolResult> {
+    async fn execute_tool_call(&self, call: ToolCall) -> Result<ToolResult> {
         let executor = ToolExecutor::with_policy(self.tool_policy.clone());
         match call {
             ToolCall::Read { path } => executor.execute(ToolInput::Read {
@@ -215,7 +466,9 @@ impl AgentRunner {
                 executor.preview_write(PathBuf::from(path), content)
             }
             ToolCall::Shell { command, args } => {
-                executor.execute(ToolInput::Shell { command, args })
+                executor
+                    .execute_async(ToolInput::Shell { command, args })
+                    .await
             }
             ToolCall::Grep { pattern, paths } => executor.execute(ToolInput::Grep {
                 pattern,
@@ -225,8 +478,48 @@ impl AgentRunner {
                 pattern,
                 root: root.map(PathBuf::from),
             }),
+            ToolCall::Mcp {
+                server,
+                name,
+                arguments,
+            } => self.execute_mcp_call(server, name, arguments).await,
         }
     }
+
+    async fn execute_mcp_call(
+        &self,
+        server: String,
+        name: String,
+        arguments: serde_json::Value,
+    ) -> Result<ToolResult> {
+        let arguments = if arguments.is_null() {
+            serde_json::json!({})
+        } else {
+            arguments
+        };
+        self.tool_policy.check(&ToolInput::Mcp {
+            server: server.clone(),
+            tool: name.clone(),
+            arguments: arguments.clone(),
+        })?;
+        let manager = self
+            .mcp
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no mcp servers configured"))?;
+        let result = manager.call_tool(&server, &name, arguments).await?;
+        if result.is_error {
+            return Err(anyhow::anyhow!(
+                "mcp tool {}/{} failed: {}",
+                server,
+                name,
+                result.text()
+            ));
+        }
+        Ok(ToolResult::Content {
+            text: result.text(),
+            images: result.images(),
+        })
+    }
 }
 
 impl AgentRunner {
@@ -240,13 +533,100 @@ impl AgentRunner {
 
     async fn generate_plan_with_context(&self, input: &str, context: &str) -> Result<String> {
         let prompt = build_plan_prompt_with_context(input, context);
-        let response = self
+        let active = self.route(ModelRole::Plan);
+        let response = active
             .client
-            .generate(&self.model_name, &LlmRequest::text(prompt))
+            .generate(
+                &active.model_name,
+                &self
+                    .fit(&active, self.request(prompt, context), context)
+                    .await,
+            )
             .await?;
         Ok(response.content)
     }
 
+    /// Summarizes turns dropped from the conversation, with the `compact` route.
+    pub async fn summarize_history(&self, history: &str, focus: Option<&str>) -> Result<String> {
+        let active = self.route(ModelRole::Compact);
+        let mut request = LlmRequest {
+            prompt: build_compact_prompt(history, focus),
+            system: self.system_prompt.clone(),
+            ..Default::default()
+        };
+        let over = active.client.overflow(&active.model_name, &request).await;
+        if over > 0 {
+            let budget = estimate_tokens(history).saturating_sub(over);
+            request.prompt = build_compact_prompt(&truncate_to_tokens(history, budget), focus);
+        }
+        let response = active.client.generate(&active.model_name, &request).await?;
+        let summary = response.content.trim();
+        if summary.is_empty() {
+            return Err(anyhow!("{} returned an empty summary", active.model_name));
+        }
+        Ok(summary.to_string())
+    }
+
+    /// The system prompt and the conversation go into their own fields so that they form a
+    /// prefix shared by the plan, tool selection and answer requests of a turn.
+    fn request(&self, prompt: String, context: &str) -> LlmRequest {
+        LlmRequest {
+            prompt,
+            system: self.system_prompt.clone(),
+            context: context_field(context),
+            ..Default::default()
+        }
+    }
+
+    /// Drops the oldest conversation lines from a request that would not fit the window.
+    async fn fit(
+        &self,
+        active: &ActiveModel,
+        mut request: LlmRequest,
+        context: &str,
+    ) -> LlmRequest {
+        let over = active.client.overflow(&active.model_name, &request).await;
+        if over == 0 || request.context.is_none() {
+            return request;
+        }
+        let kept = keep_last_tokens(context, estimate_tokens(context).saturating_sub(over));
+        request.context = context_field(&kept);
+        let mut dropped = 0;
+        while dropped < over && !request.history.is_empty() {
+            dropped += estimate_tokens(&request.history.remove(0).content);
+        }
+        request
+    }
+
+    /// Images returned by a tool (e.g. an MCP screenshot) are attached to the answer request.
+    async fn final_request(
+        &self,
+        active: &ActiveModel,
+        prompt: String,
+        context: &str,
+        history: &[LlmTurn],
+        tool_result: Option<&ToolResult>,
+    ) -> LlmRequest {
+        let request = LlmRequest {
+            images: tool_result
+                .map(|result| result.images().to_vec())
+                .unwrap_or_default(),
+            history: history.to_vec(),
+            json_schema: self.json_schema.clone(),
+            ..self.request(prompt, context)
+        };
+        self.fit(active, request, context).await
+    }
+
+    /// A tool result as it goes into the answer prompt, cut to the model's tool budget.
+    fn tool_result_text(&self, result: &ToolResult) -> String {
+        let budget = self
+            .model_info()
+            .and_then(|info| info.tool_result_budget())
+            .unwrap_or(DEFAULT_TOOL_RESULT_TOKENS);
+        truncate_to_tokens(&format_tool_result(result), budget).into_owned()
+    }
+
     async fn resolve_final_prompt_with_context(
         &self,
         input: &str,
@@ -260,23 +640,20 @@ impl AgentRunner {
         for attempt in 0..=MAX_TOOL_RETRIES {
             if let Some(path) = detect_direct_read_path(input) {
                 let call = ToolCall::Read { path };
-                match self.execute_tool_call(call.clone()) {
+                match self.execute_tool_call(call.clone()).await {
                     Ok(result) => {
                         let follow_prompt = build_followup_prompt_with_context(
                             input,
                             context,
                             &plan,
-                            &format_tool_result(&result),
+                            &self.tool_result_text(&result),
                         );
                         tool_result = Some(result);
                         return Ok((plan, follow_prompt, tool_result));
                     }
                     Err(err) => {
                         if let Some(required) = err.downcast_ref::<ToolApprovalRequired>() {
-                            let request = ToolApprovalRequest {
-                                tool: required.tool,
-                                paths: required.paths.clone(),
-                            };
+                            let request = required.to_request();
                             match self.request_approval(request).await {
                                 Ok(decision) => match decision {
                                     ToolApprovalDecision::AllowOnce => {
@@ -338,23 +715,20 @@ impl AgentRunner {
                 return Ok((plan, execute_prompt, tool_result));
             };
 
-            match self.execute_tool_call(call.clone()) {
+            match self.execute_tool_call(call.clone()).await {
                 Ok(result) => {
                     let follow_prompt = build_followup_prompt_with_context(
                         input,
                         context,
                         &plan,
-                        &format_tool_result(&result),
+                        &self.tool_result_text(&result),
                     );
                     tool_result = Some(result);
                     return Ok((plan, follow_prompt, tool_result));
                 }
                 Err(err) => {
                     if let Some(required) = err.downcast_ref::<ToolApprovalRequired>() {
-                        let request = ToolApprovalRequest {
-                            tool: required.tool,
-                            paths: required.paths.clone(),
-                        };
+                        let request = required.to_request();
                         match self.request_approval(request).await {
                             Ok(decision) => match decision {
                                 ToolApprovalDecision::AllowOnce => {
@@ -427,12 +801,35 @@ impl AgentRunner {
         last_error: Option<&str>,
         last_call: Option<&ToolCall>,
     ) -> Result<Option<ToolCall>> {
-        let prompt =
-            build_tool_select_prompt_with_context(input, context, plan, last_error, last_call);
-        let response = self
-            .client
-            .generate(&self.model_name, &LlmRequest::text(prompt))
-            .await?;
+        let mcp_tools = match &self.mcp {
+            Some(manager) => manager.tool_catalog().await,
+            None => Vec::new(),
+        };
+        let prompt = build_tool_select_prompt_with_context(
+            input, context, plan, last_error, last_call, &mcp_tools,
+        );
+        let mut request = self.request(prompt, context);
+        request.json_output = true;
+        let active = self.route(ModelRole::ToolSelect);
+        if active.client.supports_tools() {
+            request.tools = native_tool_specs(&mcp_tools);
+        } else {
+            request.json_schema = Some(tool_select_schema());
+        }
+        let request = self.fit(&active, request, context).await;
+        let response = match active.client.generate(&active.model_name, &request).await {
+            Ok(response) => response,
+            // A selection that never matched the schema is treated as "no tool".
+            Err(err) if err.downcast_ref::<SchemaMismatch>().is_some() => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if let Some(call) = response
+            .tool_calls
+            .iter()
+            .find_map(|call| tool_call_from_native(call, &mcp_tools))
+        {
+            return Ok(Some(call));
+        }
         Ok(parse_tool_call_loose(&response.content))
     }
 }
@@ -441,6 +838,12 @@ type ApprovalHandler =
     Arc<dyn Fn(ToolApprovalRequest) -> BoxFuture<'static, ToolApprovalDecision> + Send + Sync>;
 
 const MAX_TOOL_RETRIES: usize = 2;
+/// Tool result budget for models the catalog does not know.
+const DEFAULT_TOOL_RESULT_TOKENS: u64 = 8000;
+
+fn context_field(context: &str) -> Option<String> {
+    (!context.trim().is_empty()).then(|| format!("過去の会話:\n{}", context))
+}
 
 fn build_plan_prompt(input: &str) -> String {
     format!(
@@ -454,8 +857,21 @@ fn build_plan_prompt_with_context(input: &str, context: &str) -> String {
         return build_plan_prompt(input);
     }
     format!(
-        "次の過去の会話を踏まえて、指示に対する最小の計画を1-3項目で日本語の箇条書きで作成してください。\n\n過去の会話:\n{}\n\n指示:\n{}",
-        context, input
+        "過去の会話を踏まえて、指示に対する最小の計画を1-3項目で日本語の箇条書きで作成してください。\n\n指示:\n{}",
+        input
+    )
+}
+
+fn build_compact_prompt(history: &str, focus: Option<&str>) -> String {
+    let focus = focus
+        .filter(|focus| !focus.trim().is_empty())
+        .map(|focus| format!("\n特に次の点は詳しく残してください: {}\n", focus.trim()))
+        .unwrap_or_default();
+    format!(
+        "次の会話履歴を、作業を続けるための要約にまとめてください。\n\
+         「目標」「決定事項」「変更・参照したファイル」「未完了のタスク」の見出しごとに日本語の箇条書きで書き、\
+         ファイルパス、コマンド、識別子はそのまま残してください。\n{}\n会話履歴:\n{}",
+        focus, history
     )
 }
 
@@ -471,8 +887,8 @@ fn build_execute_prompt_with_context(input: &str, context: &str, plan: &str) ->
         return build_execute_prompt(input, plan);
     }
     format!(
-        "次の過去の会話と計画に従って実行してください。\n\n過去の会話:\n{}\n\n計画:\n{}\n\n指示:\n{}",
-        context, plan, input
+        "過去の会話と次の計画に従って実行してください。\n\n計画:\n{}\n\n指示:\n{}",
+        plan, input
     )
 }
 
@@ -482,8 +898,15 @@ fn build_tool_select_prompt_with_context(
     plan: &str,
     last_error: Option<&str>,
     last_call: Option<&ToolCall>,
+    mcp_tools: &[(String, McpTool)],
 ) -> String {
     let mut extra = String::new();
+    if !mcp_tools.is_empty() {
+        extra.push_str(
+            "\nMCPツールは {\"tool\":\"mcp\",\"server\":\"<server>\",\"name\":\"<tool>\",\"arguments\":{...}} の形式で呼び出せます。\n利用可能なMCPツール:\n",
+        );
+        extra.push_str(&format_mcp_catalog(mcp_tools));
+    }
     if let Some(error) = last_error {
         extra.push_str("\n前回の失敗理由:\n");
         extra.push_str(error);
@@ -497,10 +920,17 @@ fn build_tool_select_prompt_with_context(
         }
     }
     format!(
-        "次の過去の会話と計画を進めるために必要なツールがあれば、JSONのみで出力してください。\n\
+        "{}次の計画を進めるために必要なツールがあれば、JSONのみで出力してください。\n\
 ツールが不要なら {{\"tool\":\"none\"}} とだけ出力してください。{}\n\n\
-過去の会話:\n{}\n\n計画:\n{}\n\n指示:\n{}",
-        extra, context, plan, input
+計画:\n{}\n\n指示:\n{}",
+        if context.trim().is_empty() {
+            ""
+        } else {
+            "過去の会話を踏まえ、"
+        },
+        extra,
+        plan,
+        input
     )
 }
 
@@ -521,8 +951,8 @@ fn build_followup_prompt_with_context(
         return build_followup_prompt(input, plan, tool_result);
     }
     format!(
-        "実行結果を踏まえて最終回答を簡潔に出力してください。\n\n過去の会話:\n{}\n\n指示:\n{}\n\n計画:\n{}\n\nツール結果:\n{}",
-        context, input, plan, tool_result
+        "過去の会話と実行結果を踏まえて最終回答を簡潔に出力してください。\n\n指示:\n{}\n\n計画:\n{}\n\nツール結果:\n{}",
+        input, plan, tool_result
     )
 }
 
@@ -548,8 +978,8 @@ fn build_failed_followup_prompt_with_context(
         return build_failed_followup_prompt(input, plan, error);
     }
     let mut prompt = format!(
-        "ツール実行に失敗したため、失敗理由を踏まえて最終回答を簡潔に出力してください。\n\n過去の会話:\n{}\n\n指示:\n{}\n\n計画:\n{}",
-        context, input, plan
+        "ツール実行に失敗したため、過去の会話と失敗理由を踏まえて最終回答を簡潔に出力してください。\n\n指示:\n{}\n\n計画:\n{}",
+        input, plan
     );
     if let Some(error) = error {
         prompt.push_str("\n\n失敗理由:\n");
@@ -557,6 +987,23 @@ fn build_failed_followup_prompt_with_context(
     }
     prompt
 }
+fn format_mcp_catalog(tools: &[(String, McpTool)]) -> String {
+    let mut out = String::new();
+    for (server, tool) in tools {
+        out.push_str(&format!("- {}/{}", server, tool.name));
+        if let Some(description) = tool.description.as_deref().or(tool.title.as_deref()) {
+            out.push_str(": ");
+            out.push_str(description.lines().next().unwrap_or_default());
+        }
+        if let Some(schema) = &tool.input_schema {
+            out.push_str(" 引数スキーマ: ");
+            out.push_str(&schema.to_string());
+        }
+        out.push('\n');
+    }
+    out
+}
+
 fn format_tool_result(result: &ToolResult) -> String {
     match result {
         ToolResult::Text(text) => text.clone(),
@@ -568,7 +1015,127 @@ fn format_tool_result(result: &ToolResult) -> String {
             .join("\n"),
         ToolResult::Status(code) => format!("status: {}", code),
         ToolResult::PreviewWrite { diff, .. } => diff.clone(),
+        ToolResult::Content { text, .. } => text.clone(),
+    }
+}
+
+/// The same tools the selection prompt describes, for backends with native tool calling.
+/// MCP tools are exposed one by one as `mcp__<server>__<tool>`.
+fn native_tool_specs(mcp_tools: &[(String, McpTool)]) -> Vec<LlmTool> {
+    let string = serde_json::json!({ "type": "string" });
+    let strings = serde_json::json!({ "type": "array", "items": { "type": "string" } });
+    let object = |properties: serde_json::Value, required: &[&str]| serde_json::json!({ "type": "object", "properties": properties, "required": required });
+    let mut tools = vec![
+        LlmTool {
+            name: "read".to_string(),
+            description: "Read a file".to_string(),
+            parameters: object(serde_json::json!({ "path": string }), &["path"]),
+        },
+        LlmTool {
+            name: "write".to_string(),
+            description: "Write a file (shown to the user as a preview first)".to_string(),
+            parameters: object(
+                serde_json::json!({ "path": string, "content": string }),
+                &["path", "content"],
+            ),
+        },
+        LlmTool {
+            name: "shell".to_string(),
+            description: "Run a command".to_string(),
+            parameters: object(
+                serde_json::json!({ "command": string, "args": strings }),
+                &["command"],
+            ),
+        },
+        LlmTool {
+            name: "grep".to_string(),
+            description: "Search files for a pattern".to_string(),
+            parameters: object(
+                serde_json::json!({ "pattern": string, "paths": strings }),
+                &["pattern", "paths"],
+            ),
+        },
+        LlmTool {
+            name: "glob".to_string(),
+            description: "List files matching a glob pattern".to_string(),
+            parameters: object(
+                serde_json::json!({ "pattern": string, "root": string }),
+                &["pattern"],
+            ),
+        },
+    ];
+    for (server, tool) in mcp_tools {
+        tools.push(LlmTool {
+            name: format!("mcp__{}__{}", server, tool.name),
+            description: tool
+                .description
+                .clone()
+                .or_else(|| tool.title.clone())
+                .unwrap_or_default(),
+            parameters: tool
+                .input_schema
+                .clone()
+                .unwrap_or_else(|| serde_json::json!({ "type": "object" })),
+        });
+    }
+    tools
+}
+
+/// MCP names are matched against the catalog, since servers and tools may contain `__`.
+fn tool_call_from_native(call: &LlmToolCall, mcp_tools: &[(String, McpTool)]) -> Option<ToolCall> {
+    if call.name.starts_with("mcp__") {
+        return mcp_tools
+            .iter()
+            .find(|(server, tool)| call.name == format!("mcp__{}__{}", server, tool.name))
+            .map(|(server, tool)| ToolCall::Mcp {
+                server: server.clone(),
+                name: tool.name.clone(),
+                arguments: call.arguments.clone(),
+            });
     }
+    let mut value = match &call.arguments {
+        serde_json::Value::Object(map) => map.clone(),
+        _ => serde_json::Map::new(),
+    };
+    value.insert(
+        "tool".to_string(),
+        serde_json::Value::from(call.name.clone()),
+    );
+    serde_json::from_value(serde_json::Value::Object(value)).ok()
+}
+
+/// Keeps text tool selection parseable: one branch per tool with its arguments, or `none`.
+fn tool_select_schema() -> serde_json::Value {
+    let branch = |tool: &str, mut parameters: serde_json::Value| {
+        parameters["properties"]["tool"] = serde_json::json!({ "type": "string", "enum": [tool] });
+        let mut required = vec![serde_json::Value::from("tool")];
+        if let Some(names) = parameters["required"].as_array() {
+            required.extend(names.iter().cloned());
+        }
+        parameters["required"] = serde_json::Value::Array(required);
+        parameters
+    };
+    let mut branches: Vec<_> = native_tool_specs(&[])
+        .into_iter()
+        .map(|tool| branch(&tool.name, tool.parameters))
+        .collect();
+    branches.push(branch(
+        "mcp",
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "server": { "type": "string" },
+                "name": { "type": "string" },
+                "arguments": { "type": "object" },
+            },
+            "required": ["server", "name"],
+        }),
+    ));
+    branches.push(branch(
+        "none",
+        serde_json::json!({ "type": "object", "properties": {} }),
+    ));
+    serde_json::json!({ "anyOf": branches })
 }
 
 fn parse_tool_call_loose(content: &str) -> Option<ToolCall> {
@@ -582,7 +1149,8 @@ fn parse_tool_call_loose(content: &str) -> Option<ToolCall> {
         | ToolCall::Write { .. }
         | ToolCall::Shell { .. }
         | ToolCall::Grep { .. }
-        | ToolCall::Glob { .. } => Some(call),
+        | ToolCall::Glob { .. }
+        | ToolCall::Mcp { .. } => Some(call),
     }
 }
 
@@ -637,3 +1205,88 @@ fn pick_longer(current: String, candidate: String) -> String {
         current
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{parse_structured, MockBackend};
+
+    fn runner(turns: serde_json::Value) -> (AgentRunner, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("tengu-select-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, serde_json::json!({ "turns": turns }).to_string()).unwrap();
+        let client = LlmClient::new(Box::new(MockBackend::new(&path).unwrap()));
+        let runner = AgentRunner::new(client, "m".to_string(), ToolPolicy::default());
+        (runner, path)
+    }
+
+    #[test]
+    fn tool_select_schema_keeps_the_arguments() {
+        let schema = tool_select_schema();
+        let answer = r#"{"tool": "grep", "pattern": "fn main", "paths": ["src"]}"#;
+        let value = parse_structured(answer, &schema).unwrap();
+        assert!(matches!(
+            parse_tool_call_loose(&value.to_string()),
+            Some(ToolCall::Grep { pattern, paths }) if pattern == "fn main" && paths == ["src"]
+        ));
+        assert!(parse_structured(r#"{"tool": "read"}"#, &schema).is_err());
+        assert!(parse_structured(r#"{"tool": "none"}"#, &schema).is_ok());
+    }
+
+    #[test]
+    fn resolves_native_mcp_calls_against_the_catalog() {
+        let tool = |name: &str| McpTool {
+            name: name.to_string(),
+            title: None,
+            description: None,
+            input_schema: None,
+            output_schema: None,
+        };
+        let catalog = vec![
+            ("my".to_string(), tool("srv__list")),
+            ("my__srv".to_string(), tool("list")),
+        ];
+        let call = |name: &str| LlmToolCall {
+            name: name.to_string(),
+            arguments: serde_json::json!({ "q": 1 }),
+        };
+        assert!(matches!(
+            tool_call_from_native(&call("mcp__my__srv__list"), &catalog[1..]),
+            Some(ToolCall::Mcp { server, name, .. }) if server == "my__srv" && name == "list"
+        ));
+        assert!(matches!(
+            tool_call_from_native(&call("mcp__my__srv__list"), &catalog[..1]),
+            Some(ToolCall::Mcp { server, name, .. }) if server == "my" && name == "srv__list"
+        ));
+        assert!(tool_call_from_native(&call("mcp__other__list"), &catalog).is_none());
+        let read = LlmToolCall {
+            name: "read".to_string(),
+            arguments: serde_json::json!({ "path": "src/main.rs" }),
+        };
+        assert!(matches!(
+            tool_call_from_native(&read, &catalog),
+            Some(ToolCall::Read { path }) if path == "src/main.rs"
+        ));
+    }
+
+    #[tokio::test]
+    async fn selects_constrained_tool_calls_and_gives_up_without_failing_the_turn() {
+        let (runner, path) = runner(serde_json::json!([
+            { "text": "{\"tool\": \"read\"}" },
+            { "expect": "JSONスキーマ", "text": "{\"tool\": \"read\", \"path\": \"src/main.rs\"}" },
+            { "text": "no" },
+            { "text": "still no" },
+            { "text": "no tool" },
+        ]));
+        let call = runner
+            .select_tool_with_context("read main", "", "", None, None)
+            .await
+            .unwrap();
+        assert!(matches!(call, Some(ToolCall::Read { path }) if path == "src/main.rs"));
+        let call = runner
+            .select_tool_with_context("hello", "", "", None, None)
+            .await
+            .unwrap();
+        assert!(call.is_none());
+        std::fs::remove_file(&path).ok();
+    }
+}
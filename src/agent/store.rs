@@ -4,11 +4,15 @@ use std::path::PathBuf;
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::llm::SamplingParams;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StoredAgent {
     pub name: String,
     pub description: String,
     pub prompt: String,
+    #[serde(default, skip_serializing_if = "SamplingParams::is_empty")]
+    pub sampling: SamplingParams,
 }
 
 impl StoredAgent {
@@ -20,6 +24,7 @@ impl StoredAgent {
                 "You are the `{}` agent. Help with this task while staying concise and practical.",
                 name
             ),
+            sampling: SamplingParams::default(),
         }
     }
 }
@@ -1,10 +1,18 @@
-use crate::agent::{AgentOutput, AgentRunner, AgentStore, StoredAgent};
-use crate::config::Config;
+use crate::agent::{
+    AgentOutput, AgentRunner, AgentStore, LlmClientFactory, ModelRole, StoredAgent,
+};
+use crate::config::{Config, ProviderConfig};
 use crate::llm::{
-    AnthropicBackend, GoogleBackend, LlmBackend, LlmClient, LlmImage, LlmProvider, LlmRequest,
-    LlmStreamEvent, LlmUsage, OllamaBackend, OpenAiBackend,
+    load_json_schema, mask_api_key, AnthropicBackend, AwsCredentials, BedrockBackend,
+    CredentialRef, CredentialStore, GoogleBackend, LlmBackend, LlmClient, LlmImage, LlmProvider,
+    LlmRequest, LlmRetryNotice, LlmStreamEvent, LlmUsage, MockBackend, ModelCatalog, OllamaBackend,
+    OpenAiBackend, ProviderSettings, ReasoningEffort, RecordingBackend, ReplayBackend, RetryPolicy,
+    SamplingParams, ThinkingParams, DEFAULT_CASSETTE_DIR, DEFAULT_MOCK_SCRIPT, DEFAULT_PROFILE,
+};
+use crate::mcp::{
+    headless_request_handler, McpClient, McpManager, McpScope, McpServerConfig, McpStore,
+    McpTransportKind,
 };
-use crate::mcp::{list_tools_http, list_tools_stdio, McpServerConfig, McpStore};
 use crate::review::{build_review_prompt, ReviewOptions};
 use crate::session::{Session, SessionStore};
 use crate::tools::{ToolExecutor, ToolInput, ToolPolicy, ToolResult};
@@ -14,12 +22,12 @@ use base64::Engine;
 use chrono::Utc;
 use clap::{Parser, Subcommand};
 use futures_util::StreamExt;
-use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(
     name = "tengu",
     version,
@@ -39,6 +47,34 @@ pub struct Cli {
     #[arg(long, value_delimiter = ',')]
     pub image: Vec<PathBuf>,
 
+    /// サンプリング温度
+    #[arg(long)]
+    pub temperature: Option<f32>,
+
+    /// top_p（nucleus sampling）
+    #[arg(long)]
+    pub top_p: Option<f32>,
+
+    /// top_k
+    #[arg(long)]
+    pub top_k: Option<u32>,
+
+    /// 停止シーケンス（複数指定可）
+    #[arg(long)]
+    pub stop: Vec<String>,
+
+    /// 乱数シード
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// 思考（extended thinking）のトークン予算
+    #[arg(long)]
+    pub thinking_budget: Option<u32>,
+
+    /// 推論の強さ (low/medium/high)
+    #[arg(long, value_parser = ["low", "medium", "high"])]
+    pub reasoning_effort: Option<String>,
+
     /// OllamaベースURL（例: http://localhost:11434）
     #[arg(long)]
     pub ollama_base_url: Option<String>,
@@ -67,6 +103,10 @@ pub struct Cli {
     #[arg(long, default_value = "text")]
     pub output_format: String,
 
+    /// 回答をJSONスキーマ（ファイル）に合うJSONに限定
+    #[arg(long, value_name = "FILE")]
+    pub json_schema: Option<PathBuf>,
+
     /// カスタムエージェント
     #[arg(long)]
     pub agent: Option<String>,
@@ -79,6 +119,14 @@ pub struct Cli {
     #[arg(long)]
     pub add_dir: Vec<PathBuf>,
 
+    /// LLMの応答をカセットとして記録するディレクトリ
+    #[arg(long, value_name = "DIR", conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+
+    /// 記録したカセットから応答を再生（ネットワーク不要）
+    #[arg(long, value_name = "DIR")]
+    pub replay: Option<PathBuf>,
+
     /// 詳細ログ
     #[arg(short, long)]
     pub verbose: bool,
@@ -88,7 +136,7 @@ pub struct Cli {
     pub command: Option<Commands>,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 pub enum Commands {
     /// MCPサーバー管理
     Mcp {
@@ -132,6 +180,12 @@ pub enum Commands {
         preset: Option<String>,
     },
 
+    /// モデル一覧・取得・削除
+    Models {
+        #[command(subcommand)]
+        command: Option<ModelCommands>,
+    },
+
     /// 認証管理
     Auth {
         #[command(subcommand)]
@@ -148,25 +202,82 @@ pub enum Commands {
     Tui,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 pub enum McpCommands {
     /// MCPサーバー追加
     Add {
         /// サーバー名
         name: String,
 
-        /// コマンド（-- の後に指定）
-        #[arg(last = true)]
+        /// トランスポート（stdio/http/sse、省略時はURLかどうかで判定）
+        #[arg(short = 't', long)]
+        transport: Option<String>,
+
+        /// 保存先スコープ（user/project/local）
+        #[arg(short = 's', long, default_value = "project")]
+        scope: String,
+
+        /// HTTPヘッダー（"Key: Value"、複数指定可）
+        #[arg(short = 'H', long = "header")]
+        headers: Vec<String>,
+
+        /// 環境変数（KEY=VALUE、複数指定可）
+        #[arg(short = 'e', long = "env")]
+        env: Vec<String>,
+
+        /// タイムアウト秒数
+        #[arg(long)]
+        timeout: Option<u64>,
+
+        /// Bearerトークンを読む環境変数名
+        #[arg(long)]
+        bearer_token_env_var: Option<String>,
+
+        /// コマンドと引数、またはURL（-- の後にも指定可）
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         command: Vec<String>,
     },
 
     /// MCPサーバー一覧
     List,
 
+    /// MCPサーバー詳細
+    Get {
+        /// サーバー名
+        name: String,
+    },
+
     /// MCPサーバー削除
     Remove {
         /// サーバー名
         name: String,
+
+        /// 削除対象スコープ（省略時は定義されているスコープ）
+        #[arg(short = 's', long)]
+        scope: Option<String>,
+    },
+
+    /// MCPサーバー有効化
+    Enable {
+        /// サーバー名
+        name: String,
+    },
+
+    /// MCPサーバー無効化
+    Disable {
+        /// サーバー名
+        name: String,
+    },
+
+    /// .mcp.json からMCPサーバーを取り込み
+    Import {
+        /// 取り込むJSONファイル
+        #[arg(default_value = ".mcp.json")]
+        path: PathBuf,
+
+        /// 保存先スコープ（user/project/local）
+        #[arg(short = 's', long, default_value = "project")]
+        scope: String,
     },
 
     /// MCPサーバーのツール一覧
@@ -174,9 +285,15 @@ pub enum McpCommands {
         /// サーバー名
         name: String,
     },
+
+    /// MCPサーバーの接続診断（ハンドシェイク、プロトコル、機能、ツール数）
+    Doctor {
+        /// サーバー名（省略時はすべて）
+        name: Option<String>,
+    },
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 pub enum AgentCommands {
     /// エージェント一覧
     List,
@@ -197,7 +314,7 @@ pub enum AgentCommands {
     Generate,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 pub enum SessionCommands {
     /// セッション一覧
     List,
@@ -212,19 +329,54 @@ pub enum SessionCommands {
     Clear,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
+pub enum ModelCommands {
+    /// 利用可能なモデル一覧（サブコマンド省略時も同じ）
+    List {
+        /// プロバイダー名（省略時は設定済みのすべて）
+        provider: Option<String>,
+    },
+
+    /// Ollamaモデルを取得
+    Pull {
+        /// モデル名
+        model: String,
+    },
+
+    /// Ollamaモデルを削除
+    Rm {
+        /// モデル名
+        model: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
 pub enum AuthCommands {
-    /// ログイン
-    Login,
+    /// APIキーを入力して認証情報ファイルに保存
+    Login {
+        /// プロバイダー名（省略時は設定中のプロバイダー）
+        #[arg(long)]
+        provider: Option<String>,
+        /// プロファイル名
+        #[arg(long)]
+        profile: Option<String>,
+    },
 
-    /// ログアウト
-    Logout,
+    /// 保存したAPIキーを削除
+    Logout {
+        /// プロバイダー名（省略時は設定中のプロバイダー）
+        #[arg(long)]
+        provider: Option<String>,
+        /// プロファイル名
+        #[arg(long)]
+        profile: Option<String>,
+    },
 
-    /// ステータス確認
+    /// 各プロバイダーのAPIキーの取得元を表示
     Status,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 pub enum ToolCommands {
     /// ファイル読み込み
     Read {
@@ -307,92 +459,148 @@ impl Cli {
                 println!("new session: {}", session.id);
                 Ok(())
             }
+            Commands::Models { command } => self.execute_models_command(command.as_ref()).await,
             Commands::Auth { command } => self.execute_auth_command(command).await,
         }
     }
 
     async fn execute_mcp_command(&self, command: &McpCommands) -> Result<()> {
-        let path = McpStore::default_path();
-        let mut config = McpStore::load(&path)?;
         match command {
-            McpCommands::Add { name, command } => {
-                if command.is_empty() {
-                    return Err(anyhow!("mcp add requires a command"));
-                }
-                let mut iter = command.iter();
-                let cmd = iter.next().cloned().unwrap_or_default();
-                let args: Vec<String> = iter.cloned().collect();
-                let entry = McpServerConfig {
-                    command: Some(cmd),
-                    args: if args.is_empty() { None } else { Some(args) },
-                    env: None,
-                    url: None,
-                    bearer_token_env_var: None,
-                    http_headers: None,
-                    timeout_sec: None,
-                };
+            McpCommands::Add {
+                name,
+                transport,
+                scope,
+                headers,
+                env,
+                timeout,
+                bearer_token_env_var,
+                command,
+            } => {
+                let scope = McpScope::from_str(scope)?;
+                let entry = build_mcp_server_config(
+                    transport.as_deref(),
+                    command,
+                    headers,
+                    env,
+                    *timeout,
+                    bearer_token_env_var.clone(),
+                )?;
+                let mut config = McpStore::load_scope(scope)?;
                 config.mcp_servers.insert(name.clone(), entry);
-                McpStore::save(&path, &config)?;
-                println!("mcp server added: {}", name);
+                McpStore::save_scope(scope, &config)?;
+                println!("mcp server added: {} ({})", name, scope.as_str());
                 Ok(())
             }
             McpCommands::List => {
-                if config.mcp_servers.is_empty() {
+                let servers = McpStore::load_with_scopes()?;
+                if servers.is_empty() {
                     println!("no mcp servers");
                     return Ok(());
                 }
-                for (name, server) in config.mcp_servers.iter() {
-                    let summary = if let Some(url) = &server.url {
-                        format!("http {}", url)
-                    } else if let Some(cmd) = &server.command {
-                        let args = server
-                            .args
-                            .as_ref()
-                            .map(|a| a.join(" "))
-                            .unwrap_or_default();
-                        if args.is_empty() {
-                            format!("stdio {}", cmd)
-                        } else {
-                            format!("stdio {} {}", cmd, args)
-                        }
-                    } else {
-                        "unknown".to_string()
-                    };
-                    println!("{} {}", name, summary.trim());
+                for (name, (scope, server)) in servers.iter() {
+                    println!("{} [{}] {}", name, scope.as_str(), server.summary());
                 }
                 Ok(())
             }
-            McpCommands::Remove { name } => {
+            McpCommands::Get { name } => {
+                let servers = McpStore::load_with_scopes()?;
+                let Some((scope, server)) = servers.get(name) else {
+                    println!("mcp server not found: {}", name);
+                    return Ok(());
+                };
+                println!("{}", format_mcp_server_details(name, *scope, server));
+                Ok(())
+            }
+            McpCommands::Remove { name, scope } => {
+                let scope = match scope {
+                    Some(scope) => Some(McpScope::from_str(scope)?),
+                    None => McpStore::find_scope(name)?,
+                };
+                let Some(scope) = scope else {
+                    println!("mcp server not found: {}", name);
+                    return Ok(());
+                };
+                let mut config = McpStore::load_scope(scope)?;
                 if config.mcp_servers.remove(name).is_some() {
-                    McpStore::save(&path, &config)?;
-                    println!("mcp server removed: {}", name);
+                    McpStore::save_scope(scope, &config)?;
+                    println!("mcp server removed: {} ({})", name, scope.as_str());
                 } else {
                     println!("mcp server not found: {}", name);
                 }
                 Ok(())
             }
+            McpCommands::Enable { name } | McpCommands::Disable { name } => {
+                let enabled = matches!(command, McpCommands::Enable { .. });
+                let Some(scope) = McpStore::find_scope(name)? else {
+                    println!("mcp server not found: {}", name);
+                    return Ok(());
+                };
+                let mut config = McpStore::load_scope(scope)?;
+                if let Some(server) = config.mcp_servers.get_mut(name) {
+                    server.enabled = if enabled { None } else { Some(false) };
+                }
+                McpStore::save_scope(scope, &config)?;
+                println!(
+                    "mcp server {}: {}",
+                    if enabled { "enabled" } else { "disabled" },
+                    name
+                );
+                Ok(())
+            }
+            McpCommands::Import { path, scope } => {
+                let scope = McpScope::from_str(scope)?;
+                let content = fs::read_to_string(path)
+                    .map_err(|err| anyhow!("failed to read {}: {}", path.display(), err))?;
+                let imported = McpStore::import_json(&content)?;
+                if imported.is_empty() {
+                    println!("no mcp servers in {}", path.display());
+                    return Ok(());
+                }
+                let mut config = McpStore::load_scope(scope)?;
+                for (name, server) in imported {
+                    println!("mcp server imported: {} {}", name, server.summary());
+                    config.mcp_servers.insert(name, server);
+                }
+                McpStore::save_scope(scope, &config)?;
+                Ok(())
+            }
             McpCommands::Tools { name } => {
+                let config = McpStore::load_merged()?;
                 let Some(server) = config.mcp_servers.get(name) else {
                     println!("mcp server not found: {}", name);
                     return Ok(());
                 };
-                if server.url.is_some() {
-                    let tools = list_tools_http(server).await?;
-                    for tool in tools {
-                        println!("@{}/{}", name, tool.name);
-                    }
-                    return Ok(());
+                if !server.is_enabled() {
+                    return Err(anyhow!("mcp server is disabled: {}", name));
                 }
-                let tools = tokio::task::spawn_blocking({
-                    let server = server.clone();
-                    move || list_tools_stdio(&server)
-                })
-                .await??;
+                let client = McpClient::connect(name, server, Default::default()).await?;
+                let tools = client.list_tools().await?;
                 for tool in tools {
                     println!("@{}/{}", name, tool.name);
                 }
                 Ok(())
             }
+            McpCommands::Doctor { name } => {
+                let mut servers = McpStore::load_merged()?.mcp_servers;
+                if let Some(name) = name {
+                    servers.retain(|key, _| key == name);
+                    if servers.is_empty() {
+                        println!("mcp server not found: {}", name);
+                        return Ok(());
+                    }
+                }
+                if servers.is_empty() {
+                    println!("no mcp servers");
+                    return Ok(());
+                }
+                for (name, server) in servers.iter().filter(|(_, s)| !s.is_enabled()) {
+                    println!("{}: disabled ({})", name, server.summary());
+                }
+                for status in McpManager::new(servers).status().await {
+                    println!("{}", status.render());
+                }
+                Ok(())
+            }
         }
     }
 
@@ -455,56 +663,205 @@ impl Cli {
         }
     }
 
-    async fn execute_auth_command(&self, command: &AuthCommands) -> Result<()> {
+    async fn execute_models_command(&self, command: Option<&ModelCommands>) -> Result<()> {
         let config = load_config().unwrap_or_default();
-        let provider_name = if !config.model.provider.trim().is_empty() {
-            config.model.provider.as_str()
+        match command {
+            None => self.list_models(&config, None).await,
+            Some(ModelCommands::List { provider }) => {
+                self.list_models(&config, provider.as_deref()).await
+            }
+            Some(ModelCommands::Pull { model }) => {
+                let backend = self.ollama_backend(&config, "pull")?;
+                backend
+                    .pull(model, |status| eprintln!("{}", status))
+                    .await?;
+                println!("pulled: {}", model);
+                Ok(())
+            }
+            Some(ModelCommands::Rm { model }) => {
+                self.ollama_backend(&config, "rm")?.delete(model).await?;
+                println!("removed: {}", model);
+                Ok(())
+            }
+        }
+    }
+
+    /// Every `[providers.*]` table plus the configured provider; a failing provider is
+    /// reported and the others are still listed.
+    async fn list_models(&self, config: &Config, only: Option<&str>) -> Result<()> {
+        let mut names: Vec<String> = match only {
+            Some(name) => vec![name.to_string()],
+            None => config.providers.keys().cloned().collect(),
+        };
+        let configured = configured_provider_name(config);
+        if only.is_none() && !names.iter().any(|name| name == configured) {
+            names.insert(0, configured.to_string());
+        }
+        let catalog = ModelCatalog::load();
+        let mut listed = Vec::new();
+        for name in names {
+            let (kind, result) = match provider_kind(config, &name) {
+                Ok(kind) => {
+                    let settings = provider_settings(
+                        config,
+                        &kind,
+                        &name,
+                        config.providers.get(&name),
+                        self.ollama_base_url.clone(),
+                    );
+                    let result = match build_backend(&kind, &name, settings) {
+                        Ok(backend) => backend.list_models().await,
+                        Err(err) => Err(err),
+                    };
+                    (Some(kind), result)
+                }
+                Err(err) => (None, Err(err)),
+            };
+            let info = |id: &str| kind.and_then(|kind| catalog.find(kind.as_str(), id));
+            if self.output_format == "json" {
+                listed.push(match result {
+                    Ok(models) => json!({
+                        "provider": name,
+                        "models": models
+                            .iter()
+                            .map(|id| json!({ "id": id, "info": info(id) }))
+                            .collect::<Vec<_>>(),
+                    }),
+                    Err(err) => json!({ "provider": name, "error": err.to_string() }),
+                });
+                continue;
+            }
+            println!("{}:", name);
+            match result {
+                Ok(models) if models.is_empty() => println!("  no models"),
+                Ok(models) => {
+                    for id in models {
+                        match info(&id) {
+                            Some(info) => println!("  {}  {}", id, info.summary()),
+                            None => println!("  {}", id),
+                        }
+                    }
+                }
+                Err(err) => println!("  error: {}", err),
+            }
+        }
+        if self.output_format == "json" {
+            println!("{}", serde_json::to_string_pretty(&listed)?);
+        }
+        Ok(())
+    }
+
+    /// The configured provider when it is Ollama, otherwise `[providers.ollama]` or the default.
+    fn ollama_backend(&self, config: &Config, action: &str) -> Result<OllamaBackend> {
+        let configured = configured_provider_name(config);
+        let name = if matches!(provider_kind(config, configured), Ok(LlmProvider::Local)) {
+            configured
         } else {
-            "anthropic"
+            "ollama"
         };
-        let required_env = auth_env_var_for_provider(provider_name);
+        if !matches!(provider_kind(config, name), Ok(LlmProvider::Local)) {
+            return Err(anyhow!("{} is only supported for ollama", action));
+        }
+        OllamaBackend::new(provider_settings(
+            config,
+            &LlmProvider::Local,
+            name,
+            config.providers.get(name),
+            self.ollama_base_url.clone(),
+        ))
+    }
+
+    async fn execute_auth_command(&self, command: &AuthCommands) -> Result<()> {
+        let config = load_config().unwrap_or_default();
+        let store = CredentialStore::new(CredentialStore::default_path()?);
         match command {
-            AuthCommands::Login => {
-                let Some(env_name) = required_env else {
-                    return Err(anyhow!(
-                        "auth login is unsupported for provider: {}",
-                        provider_name
-                    ));
-                };
-                if std::env::var(env_name).is_err() {
-                    return Err(anyhow!("{} is not set", env_name));
+            AuthCommands::Login { provider, profile } => {
+                let name = provider
+                    .as_deref()
+                    .unwrap_or(configured_provider_name(&config));
+                let kind = provider_kind(&config, name)?;
+                let profile = auth_profile(&config, name, profile.as_deref());
+                let api_key = read_api_key(&format!("API key for {} ({}): ", name, profile))?;
+                store.set(name, &profile, &api_key)?;
+                println!(
+                    "saved {} key (profile {}) to {}",
+                    name,
+                    profile,
+                    store.path().display()
+                );
+                let env = config
+                    .providers
+                    .get(name)
+                    .and_then(|table| table.api_key_env.as_deref())
+                    .or(auth_env_var_for_provider(kind.as_str()));
+                if let Some(env) = env.filter(|env| std::env::var(env).is_ok()) {
+                    println!("note: {} is set and takes precedence", env);
                 }
-                save_auth_session(provider_name, env_name)?;
-                println!("auth ready: provider={} via {}", provider_name, env_name);
                 Ok(())
             }
-            AuthCommands::Logout => {
-                clear_auth_session()?;
-                println!("auth session cleared");
+            AuthCommands::Logout { provider, profile } => {
+                let name = provider
+                    .as_deref()
+                    .unwrap_or(configured_provider_name(&config));
+                let profile = auth_profile(&config, name, profile.as_deref());
+                if store.remove(name, &profile)? {
+                    println!("removed {} key (profile {})", name, profile);
+                } else {
+                    println!("no stored key for {} (profile {})", name, profile);
+                }
                 Ok(())
             }
             AuthCommands::Status => {
-                let env_status = required_env
-                    .map(|env_name| {
-                        if std::env::var(env_name).is_ok() {
-                            format!("{}=set", env_name)
-                        } else {
-                            format!("{}=missing", env_name)
+                let stored = store.entries()?;
+                let mut names = vec![configured_provider_name(&config).to_string()];
+                names.extend(config.providers.keys().cloned());
+                names.extend(stored.iter().map(|(provider, _)| provider.clone()));
+                let mut seen = std::collections::HashSet::new();
+                names.retain(|name| seen.insert(name.clone()));
+                for name in names {
+                    let kind = match provider_kind(&config, &name) {
+                        Ok(kind) => kind,
+                        Err(err) => {
+                            println!("{}: {}", name, err);
+                            continue;
                         }
-                    })
-                    .unwrap_or_else(|| "env=unsupported".to_string());
-                let session_status = load_auth_session()
-                    .map(|session| {
-                        format!(
-                            "session=ready provider={} updated_at={}",
-                            session.provider, session.updated_at
-                        )
-                    })
-                    .unwrap_or_else(|| "session=none".to_string());
-                println!(
-                    "provider: {}\n{}\n{}",
-                    provider_name, env_status, session_status
-                );
+                    };
+                    let settings =
+                        provider_settings(&config, &kind, &name, config.providers.get(&name), None);
+                    let profile = settings
+                        .credentials
+                        .as_ref()
+                        .map(|credentials| credentials.profile.clone())
+                        .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+                    let default_env = match kind {
+                        LlmProvider::OpenAiCompatible if settings.has_api_key_source() => {
+                            Some("OPENAI_API_KEY")
+                        }
+                        _ => auth_env_var_for_provider(kind.as_str()),
+                    };
+                    let status = match default_env {
+                        _ if kind == LlmProvider::Bedrock => match AwsCredentials::from_env() {
+                            Ok(aws) => format!(
+                                "{} from AWS env credentials",
+                                mask_api_key(&aws.access_key_id)
+                            ),
+                            Err(err) => format!("missing: {}", err),
+                        },
+                        _ if settings.token_command.is_some() => {
+                            "bearer token from token_command".to_string()
+                        }
+                        None => "no key required".to_string(),
+                        Some(env) => match settings.resolve_api_key(env).await {
+                            Ok((key, source)) => format!("{} from {}", mask_api_key(&key), source),
+                            Err(err) => format!("missing: {}", err),
+                        },
+                    };
+                    println!("{} (profile {}): {}", name, profile, status);
+                }
+                println!("credentials: {}", store.path().display());
+                for (provider, profile) in stored {
+                    println!("  {}.{}", provider, profile);
+                }
                 Ok(())
             }
         }
@@ -559,7 +916,7 @@ impl Cli {
         let config = load_config().unwrap_or_default();
         let (client, model_name) = self.resolve_llm_with_config(&config)?;
         let policy = ToolPolicy::from_config(&config);
-        let runner = AgentRunner::new(client, model_name, policy);
+        let runner = self.with_routes(&config, AgentRunner::new(client, model_name, policy))?;
 
         if self.output_format == "stream-json" {
             let (mut stream, _tool_result) = runner
@@ -574,6 +931,12 @@ impl Cli {
                             json!({ "type": "chunk", "mode": "review", "delta": text })
                         );
                     }
+                    Ok(LlmStreamEvent::Thinking { text, signature }) => {
+                        println!(
+                            "{}",
+                            json!({ "type": "thinking", "mode": "review", "delta": text, "signature": signature })
+                        );
+                    }
                     Ok(LlmStreamEvent::Usage(usage)) => {
                         println!(
                             "{}",
@@ -605,7 +968,25 @@ impl Cli {
         let (client, model_name) = self.resolve_llm_with_config(&config)?;
         let policy = ToolPolicy::from_config(&config);
         let status_model = model_name.clone();
-        let runner = std::sync::Arc::new(AgentRunner::new(client, model_name, policy));
+        let (system_prompt, _) = self.resolve_system_prompt()?;
+        let provider_name = self
+            .main_model_selection(&config)
+            .0
+            .unwrap_or(configured_provider_name(&config))
+            .to_string();
+        // `/model` rebuilds the client from the config as it is now, with these CLI flags.
+        let cli = self.clone();
+        let factory: LlmClientFactory = std::sync::Arc::new(move |provider, model| {
+            let config = load_config().unwrap_or_default();
+            cli.build_llm(&config, Some(provider), Some(model))
+                .map(|(client, _)| client)
+        });
+        let runner = std::sync::Arc::new(
+            self.with_routes(&config, AgentRunner::new(client, model_name, policy))?
+                .with_mcp_manager(load_mcp_manager())
+                .with_system_prompt(system_prompt)
+                .with_client_factory(&provider_name, factory),
+        );
         let handle = tokio::runtime::Handle::current();
         let status_build = option_env!("BUILD_TIMESTAMP")
             .unwrap_or("unknown")
@@ -627,9 +1008,15 @@ impl Cli {
     async fn execute_headless(&self) -> Result<()> {
         let (system_prompt, sources) = self.resolve_system_prompt()?;
         self.log_system_prompt_sources(&sources, system_prompt.as_deref());
+        let json_schema = self
+            .json_schema
+            .as_deref()
+            .map(load_json_schema)
+            .transpose()?;
         if let Some(prompt) = self.prompt.as_deref() {
             let request = build_headless_request(prompt, system_prompt.as_deref(), &self.image)?;
-            if self.output_format == "stream-json" {
+            // A schema answer is checked as a whole, so it is printed once it is complete.
+            if self.output_format == "stream-json" && json_schema.is_none() {
                 let config = load_config().unwrap_or_default();
                 let (client, model_name) = self.resolve_llm_with_config(&config)?;
                 if !request.images.is_empty() {
@@ -643,6 +1030,12 @@ impl Cli {
                                     json!({ "type": "chunk", "mode": "llm", "delta": text })
                                 );
                             }
+                            Ok(LlmStreamEvent::Thinking { text, signature }) => {
+                                println!(
+                                    "{}",
+                                    json!({ "type": "thinking", "mode": "llm", "delta": text, "signature": signature })
+                                );
+                            }
                             Ok(LlmStreamEvent::Usage(usage)) => {
                                 println!(
                                     "{}",
@@ -663,7 +1056,10 @@ impl Cli {
                     return Ok(());
                 }
                 let policy = ToolPolicy::from_config(&config);
-                let runner = AgentRunner::new(client, model_name, policy);
+                let runner = self
+                    .with_routes(&config, AgentRunner::new(client, model_name, policy))?
+                    .with_mcp_manager(load_mcp_manager())
+                    .with_system_prompt(request.system.clone());
                 let (mut stream, tool_result) = runner
                     .handle_prompt_stream_with_tool_context(&request.prompt, "")
                     .await?;
@@ -677,6 +1073,12 @@ impl Cli {
                                 json!({ "type": "chunk", "mode": "llm", "delta": text })
                             );
                         }
+                        Ok(LlmStreamEvent::Thinking { text, signature }) => {
+                            println!(
+                                "{}",
+                                json!({ "type": "thinking", "mode": "llm", "delta": text, "signature": signature })
+                            );
+                        }
                         Ok(LlmStreamEvent::Usage(usage)) => {
                             println!(
                                 "{}",
@@ -718,15 +1120,30 @@ impl Cli {
                 return Ok(());
             }
         }
-        let message = format!("Headless mode with prompt: {:?}", self.prompt);
-        self.print_output("headless", &message, self.prompt.as_deref());
+        // With a schema, stdout is only the answer so scripts can parse it.
+        if json_schema.is_none() {
+            let message = format!("Headless mode with prompt: {:?}", self.prompt);
+            self.print_output("headless", &message, self.prompt.as_deref());
+        }
         if let Some(prompt) = self.prompt.as_deref() {
-            let request = build_headless_request(prompt, system_prompt.as_deref(), &self.image)?;
+            let mut request =
+                build_headless_request(prompt, system_prompt.as_deref(), &self.image)?;
+            request.json_schema = json_schema.clone();
             let config = load_config().unwrap_or_default();
             let (client, model_name) = self.resolve_llm_with_config(&config)?;
             if !request.images.is_empty() {
                 let output = client.generate(&model_name, &request).await?;
                 if self.output_format == "json" {
+                    if let Some(thinking) = output.thinking.as_ref() {
+                        println!(
+                            "{}",
+                            json!({
+                                "type": "thinking",
+                                "text": thinking.text,
+                                "signature": thinking.signature
+                            })
+                        );
+                    }
                     if let Some(usage) = output.usage.as_ref() {
                         println!(
                             "{}",
@@ -738,7 +1155,11 @@ impl Cli {
                 return Ok(());
             }
             let policy = ToolPolicy::from_config(&config);
-            let runner = AgentRunner::new(client, model_name, policy);
+            let runner = self
+                .with_routes(&config, AgentRunner::new(client, model_name, policy))?
+                .with_mcp_manager(load_mcp_manager())
+                .with_system_prompt(request.system.clone())
+                .with_json_schema(json_schema);
             let output = runner.handle_prompt(&request.prompt).await?;
             if self.output_format == "json" {
                 if let Some(usage) = output.response.usage.as_ref() {
@@ -854,41 +1275,185 @@ impl Cli {
         }
     }
 
-    fn resolve_llm_with_config(&self, config: &Config) -> Result<(LlmClient, String)> {
-        let configured_provider_name = if !config.model.provider.trim().is_empty() {
-            config.model.provider.as_str()
+    /// `[model]` < `--agent` < CLI flags.
+    fn resolve_sampling(&self, config: &Config) -> SamplingParams {
+        let mut sampling = config.model.sampling.clone();
+        if let Some(agent) = self
+            .agent
+            .as_deref()
+            .and_then(|name| AgentStore::new().load(name).ok())
+        {
+            sampling = sampling.merged(&agent.sampling);
+        }
+        sampling.merged(&SamplingParams {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            stop: self.stop.clone(),
+            seed: self.seed,
+        })
+    }
+
+    /// A budget or effort given on the command line replaces both config values.
+    fn resolve_thinking(&self, config: &Config) -> Result<ThinkingParams> {
+        let (budget, effort) = if self.thinking_budget.is_some() || self.reasoning_effort.is_some()
+        {
+            (self.thinking_budget, self.reasoning_effort.as_deref())
         } else {
-            config
-                .model
-                .backend
-                .as_deref()
-                .filter(|value| !value.trim().is_empty())
-                .unwrap_or("anthropic")
+            (
+                config.model.thinking_budget,
+                config.model.reasoning_effort.as_deref(),
+            )
         };
-        let configured_provider = LlmProvider::from_str(configured_provider_name)?;
-        let model_name = self
+        Ok(ThinkingParams {
+            budget_tokens: budget,
+            effort: effort.map(ReasoningEffort::from_str).transpose()?,
+        })
+    }
+
+    fn resolve_llm_with_config(&self, config: &Config) -> Result<(LlmClient, String)> {
+        let (provider, model) = self.main_model_selection(config);
+        self.build_llm(config, provider, model)
+    }
+
+    /// `--model` names a provider, a model or `provider:model`; without it, `[routing] answer`.
+    fn main_model_selection<'a>(
+        &'a self,
+        config: &'a Config,
+    ) -> (Option<&'a str>, Option<&'a str>) {
+        let value = self
             .model
             .as_deref()
-            .filter(|value| LlmProvider::from_str(value).is_err())
+            .or(config.routing.answer.as_deref())
+            .map(str::trim)
+            .filter(|value| !value.is_empty());
+        match value {
+            Some(value) if is_provider_name(config, value) => (Some(value), None),
+            Some(value) => {
+                let (provider, model) = config.split_model_spec(value);
+                (provider, Some(model))
+            }
+            None => (None, None),
+        }
+    }
+
+    /// Adds a client for each `[routing]` role that is set. A route without a provider
+    /// stays on the main one.
+    fn with_routes(&self, config: &Config, mut runner: AgentRunner) -> Result<AgentRunner> {
+        let main_provider = self
+            .main_model_selection(config)
+            .0
+            .unwrap_or(configured_provider_name(config));
+        let routing = &config.routing;
+        for (role, spec) in [
+            (ModelRole::Plan, &routing.plan),
+            (ModelRole::ToolSelect, &routing.tool_select),
+            (ModelRole::Compact, &routing.compact),
+        ] {
+            let Some(spec) = spec.as_deref().map(str::trim).filter(|s| !s.is_empty()) else {
+                continue;
+            };
+            let (provider, model) = config.split_model_spec(spec);
+            let provider = provider.unwrap_or(main_provider);
+            let (client, _) = self
+                .build_llm(config, Some(provider), Some(model))
+                .map_err(|err| anyhow!("routing.{}: {}", role.as_str(), err))?;
+            runner = runner.with_route(role, provider, client, model.to_string());
+        }
+        Ok(runner)
+    }
+
+    /// Builds the client with the `[model] fallback` chain behind it. A fallback without a
+    /// provider uses the provider of the client it backs up.
+    fn build_llm(
+        &self,
+        config: &Config,
+        cli_provider: Option<&str>,
+        cli_model: Option<&str>,
+    ) -> Result<(LlmClient, String)> {
+        let (mut client, model_name) = self.build_llm_client(config, cli_provider, cli_model)?;
+        let provider_name = cli_provider.unwrap_or(configured_provider_name(config));
+        for spec in &config.model.fallback {
+            let (provider, model) = config.split_model_spec(spec.trim());
+            let provider = provider.unwrap_or(provider_name);
+            if model.is_empty() || (provider == provider_name && model == model_name) {
+                continue;
+            }
+            let (fallback, _) = self
+                .build_llm_client(config, Some(provider), Some(model))
+                .map_err(|err| anyhow!("model.fallback {}: {}", spec, err))?;
+            client = client.with_fallback(provider, fallback, model.to_string());
+        }
+        Ok((client, model_name))
+    }
+
+    fn build_llm_client(
+        &self,
+        config: &Config,
+        cli_provider: Option<&str>,
+        cli_model: Option<&str>,
+    ) -> Result<(LlmClient, String)> {
+        let provider_name = cli_provider.unwrap_or(configured_provider_name(config));
+        let provider_config = config.providers.get(provider_name);
+        let provider = provider_kind(config, provider_name)?;
+        let provider_model = provider_config
+            .and_then(|p| p.model.as_deref())
+            .filter(|value| !value.trim().is_empty())
+            .map(|value| value.to_string());
+        let config_model = config
+            .model
+            .name
+            .as_deref()
+            .filter(|value| !value.trim().is_empty())
+            .map(|value| value.to_string());
+        // Switching provider makes that table's model beat `model.name`.
+        let model_name = cli_model
             .map(|value| value.to_string())
             .or_else(|| {
-                config
-                    .model
-                    .name
-                    .as_deref()
-                    .filter(|value| !value.trim().is_empty())
-                    .map(|value| value.to_string())
+                if cli_provider.is_some() {
+                    provider_model.clone().or(config_model.clone())
+                } else {
+                    config_model.clone().or(provider_model.clone())
+                }
             })
             .or_else(|| {
                 (!config.model.default.trim().is_empty()).then(|| config.model.default.clone())
             })
             .ok_or_else(|| anyhow!("model name is not set in config.toml"))?;
-        let provider = match self.model.as_deref() {
-            Some(value) => LlmProvider::from_str(value).unwrap_or(configured_provider),
-            None => configured_provider,
+        let settings = provider_settings(
+            config,
+            &provider,
+            provider_name,
+            provider_config,
+            self.ollama_base_url.clone(),
+        );
+        let mut backend = match self.replay.as_deref() {
+            Some(dir) => Box::new(ReplayBackend::new(dir, provider_name)?),
+            None => build_backend(&provider, provider_name, settings)?,
         };
-        let backend = build_backend(&provider, config, self.ollama_base_url.clone());
-        Ok((LlmClient::new(backend), model_name))
+        if let Some(dir) = self.record.as_deref() {
+            backend = Box::new(RecordingBackend::new(backend, provider_name, dir)?);
+        }
+        let mut retry = RetryPolicy::default();
+        if let Some(max_retries) = config.model.max_retries {
+            retry.max_retries = max_retries;
+        }
+        let client = LlmClient::new(backend)
+            .with_catalog(std::sync::Arc::new(ModelCatalog::load()))
+            .with_retry_policy(retry)
+            .with_sampling(self.resolve_sampling(config))
+            .with_thinking(self.resolve_thinking(config)?);
+        // The TUI replaces this with a status line message.
+        client.set_retry_handler(std::sync::Arc::new(|notice: &LlmRetryNotice| {
+            eprintln!(
+                "{}; retrying in {:.1}s ({}/{})",
+                notice.error,
+                notice.delay.as_secs_f64(),
+                notice.attempt,
+                notice.max_retries
+            );
+        }));
+        Ok((client, model_name))
     }
 }
 
@@ -910,44 +1475,150 @@ fn load_config() -> Option<Config> {
     config
 }
 
-fn build_backend(
-    provider: &LlmProvider,
+fn configured_provider_name(config: &Config) -> &str {
+    if !config.model.provider.trim().is_empty() {
+        config.model.provider.as_str()
+    } else {
+        config
+            .model
+            .backend
+            .as_deref()
+            .filter(|value| !value.trim().is_empty())
+            .unwrap_or("anthropic")
+    }
+}
+
+/// A table's `type` wins over its name (`[providers.work]` with `type = "openai"`).
+fn provider_kind(config: &Config, name: &str) -> Result<LlmProvider> {
+    match config.providers.get(name).and_then(|p| p.kind.as_deref()) {
+        Some(kind) => LlmProvider::from_str(kind),
+        None => LlmProvider::from_str(name),
+    }
+}
+
+fn is_provider_name(config: &Config, value: &str) -> bool {
+    config.providers.contains_key(value) || LlmProvider::from_str(value).is_ok()
+}
+
+/// `[providers.<name>]` wins; the legacy `model.backend_url` only applies without a table.
+fn provider_settings(
     config: &Config,
+    provider: &LlmProvider,
+    name: &str,
+    provider_config: Option<&ProviderConfig>,
     cli_base_url: Option<String>,
-) -> Box<dyn LlmBackend + Send + Sync> {
-    match provider {
-        LlmProvider::Local => {
-            let base_url = cli_base_url
-                .or_else(|| std::env::var("OLLAMA_BASE_URL").ok())
-                .or_else(|| config.model.backend_url.clone())
-                .unwrap_or_else(|| "http://localhost:11434".to_string());
-            Box::new(OllamaBackend::new(base_url))
-        }
-        LlmProvider::Anthropic => Box::new(AnthropicBackend::new(
-            config.model.backend_url.clone(),
-            config.model.max_tokens,
-        )),
-        LlmProvider::OpenAI => Box::new(OpenAiBackend::new(
-            config.model.backend_url.clone(),
-            config.model.max_tokens,
-        )),
-        LlmProvider::Google => Box::new(GoogleBackend::new(config.model.backend_url.clone())),
-    }
+) -> ProviderSettings {
+    let table = provider_config.cloned().unwrap_or_default();
+    let fallback_url = if provider_config.is_some() {
+        None
+    } else {
+        config.model.backend_url.clone()
+    };
+    let mut settings = ProviderSettings::default();
+    settings.base_url = match provider {
+        LlmProvider::Local => cli_base_url
+            .or_else(|| std::env::var("OLLAMA_BASE_URL").ok())
+            .or(table.base_url)
+            .or(fallback_url),
+        LlmProvider::Azure => table
+            .base_url
+            .or(fallback_url)
+            .or_else(|| std::env::var("AZURE_OPENAI_ENDPOINT").ok()),
+        _ => table.base_url.or(fallback_url),
+    };
+    settings.api_key_env = table.api_key_env;
+    settings.api_key_command = table.api_key_command;
+    settings.api_key_command_ttl = table.api_key_command_ttl_sec.map(Duration::from_secs);
+    settings.credentials = CredentialStore::default_path()
+        .ok()
+        .map(|path| CredentialRef {
+            path,
+            provider: name.to_string(),
+            profile: credential_profile(table.profile.as_deref()),
+        });
+    settings.headers = table.headers.unwrap_or_default().into_iter().collect();
+    settings.read_timeout = table.timeout_sec.map(Duration::from_secs);
+    settings.connect_timeout = table.connect_timeout_sec.map(Duration::from_secs);
+    settings.proxy = table.proxy;
+    settings.max_tokens = table.max_tokens.or(config.model.max_tokens);
+    settings.stream_options = table.stream_options;
+    settings.keep_alive = table.keep_alive;
+    settings.options = table.options.unwrap_or_default().into_iter().collect();
+    settings.prompt_cache = table.prompt_cache.or(config.model.prompt_cache);
+    settings.prompt_cache_ttl = table
+        .prompt_cache_ttl
+        .or(config.model.prompt_cache_ttl.clone());
+    settings.cassettes = table.cassettes.map(PathBuf::from);
+    settings.api_version = table.api_version;
+    settings.token_command = table.token_command;
+    settings.region = table.region;
+    settings.script = std::env::var("TENGU_MOCK_SCRIPT")
+        .ok()
+        .or(table.script)
+        .map(PathBuf::from);
+    settings
+}
+
+/// `TENGU_PROFILE` wins over the table's `profile`.
+fn credential_profile(table_profile: Option<&str>) -> String {
+    std::env::var("TENGU_PROFILE")
+        .ok()
+        .or(table_profile.map(str::to_string))
+        .filter(|profile| !profile.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_PROFILE.to_string())
+}
+
+fn build_backend(
+    provider: &LlmProvider,
+    name: &str,
+    settings: ProviderSettings,
+) -> Result<Box<dyn LlmBackend + Send + Sync>> {
+    Ok(match provider {
+        LlmProvider::Local => Box::new(OllamaBackend::new(settings)?),
+        LlmProvider::Anthropic => Box::new(AnthropicBackend::new(settings)?),
+        LlmProvider::OpenAI => Box::new(OpenAiBackend::new(settings)?),
+        LlmProvider::Google => Box::new(GoogleBackend::new(settings)?),
+        LlmProvider::OpenAiCompatible => Box::new(OpenAiBackend::compatible(name, settings)?),
+        LlmProvider::Azure => Box::new(OpenAiBackend::azure(name, settings)?),
+        LlmProvider::Bedrock => Box::new(BedrockBackend::new(settings)?),
+        LlmProvider::Replay => Box::new(ReplayBackend::new(
+            &settings
+                .cassettes
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_CASSETTE_DIR)),
+            name,
+        )?),
+        LlmProvider::Mock => Box::new(MockBackend::new(
+            &settings
+                .script
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_MOCK_SCRIPT)),
+        )?),
+    })
 }
 
 impl Cli {
     async fn generate_agent_with_llm(&self, store: &AgentStore) -> Result<()> {
         let config = load_config().unwrap_or_default();
         let (client, model_name) = self.resolve_llm_with_config(&config)?;
-        let request = LlmRequest::text(
-            "Create a practical coding assistant agent configuration.\n\
-             Return JSON only with keys: name, description, prompt.\n\
-             Requirements:\n\
-             - name must be lowercase kebab-case\n\
-             - description must be one short sentence\n\
-             - prompt must instruct concise, pragmatic coding assistance\n\
-             - do not include markdown fences or extra commentary",
-        );
+        let request = LlmRequest {
+            json_schema: Some(json!({
+                "type": "object",
+                "properties": {
+                    "name": { "type": "string" },
+                    "description": { "type": "string" },
+                    "prompt": { "type": "string" },
+                },
+                "required": ["name", "description", "prompt"],
+            })),
+            ..LlmRequest::text(
+                "Create a practical coding assistant agent configuration.\n\
+                 Return JSON only with keys: name, description, prompt.\n\
+                 Requirements:\n\
+                 - name must be lowercase kebab-case\n\
+                 - description must be one short sentence\n\
+                 - prompt must instruct concise, pragmatic coding assistance\n\
+                 - do not include markdown fences or extra commentary",
+            )
+        };
         let response = client.generate(&model_name, &request).await?;
         let agent =
             parse_generated_agent(&response.content).unwrap_or_else(|_| fallback_generated_agent());
@@ -984,6 +1655,7 @@ fn format_tool_result(result: &ToolResult) -> String {
             .join("\n"),
         ToolResult::Status(code) => format!("status: {}", code),
         ToolResult::PreviewWrite { diff, .. } => diff.clone(),
+        ToolResult::Content { text, .. } => text.clone(),
     }
 }
 
@@ -1005,11 +1677,62 @@ fn apply_preview_write_with_config(result: &ToolResult) -> Result<Option<ToolRes
     apply_preview_write(&executor, result)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct AuthSession {
-    provider: String,
-    env_var: String,
-    updated_at: String,
+/// `--profile`, else the profile the provider's backend would use.
+fn auth_profile(config: &Config, name: &str, profile: Option<&str>) -> String {
+    profile.map(str::to_string).unwrap_or_else(|| {
+        credential_profile(
+            config
+                .providers
+                .get(name)
+                .and_then(|t| t.profile.as_deref()),
+        )
+    })
+}
+
+/// Reads a key without echoing it on a terminal, or one line from piped stdin.
+fn read_api_key(prompt: &str) -> Result<String> {
+    use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+    use std::io::{BufRead, IsTerminal, Write};
+
+    let mut key = String::new();
+    if std::io::stdin().is_terminal() {
+        eprint!("{}", prompt);
+        std::io::stderr().flush()?;
+        crossterm::terminal::enable_raw_mode()?;
+        let read = loop {
+            let event = match event::read() {
+                Ok(event) => event,
+                Err(err) => break Err(err.into()),
+            };
+            let Event::Key(key_event) = event else {
+                continue;
+            };
+            if key_event.kind == KeyEventKind::Release {
+                continue;
+            }
+            match key_event.code {
+                KeyCode::Enter => break Ok(()),
+                KeyCode::Char('c') if key_event.modifiers.contains(KeyModifiers::CONTROL) => {
+                    break Err(anyhow!("login cancelled"));
+                }
+                KeyCode::Char(c) => key.push(c),
+                KeyCode::Backspace => {
+                    key.pop();
+                }
+                _ => {}
+            }
+        };
+        crossterm::terminal::disable_raw_mode()?;
+        eprintln!();
+        read?;
+    } else {
+        std::io::stdin().lock().read_line(&mut key)?;
+    }
+    let key = key.trim().to_string();
+    if key.is_empty() {
+        return Err(anyhow!("no api key entered"));
+    }
+    Ok(key)
 }
 
 fn auth_env_var_for_provider(provider: &str) -> Option<&'static str> {
@@ -1017,72 +1740,147 @@ fn auth_env_var_for_provider(provider: &str) -> Option<&'static str> {
         "anthropic" => Some("ANTHROPIC_API_KEY"),
         "openai" => Some("OPENAI_API_KEY"),
         "google" | "gemini" => Some("GOOGLE_API_KEY"),
+        "azure" | "azure-openai" => Some("AZURE_OPENAI_API_KEY"),
         _ => None,
     }
 }
 
-fn auth_session_path() -> Result<PathBuf> {
-    let home = std::env::var("HOME").map_err(|_| anyhow!("HOME not set"))?;
-    Ok(auth_session_path_from_home(Path::new(&home)))
-}
-
-fn save_auth_session(provider: &str, env_var: &str) -> Result<()> {
-    let path = auth_session_path()?;
-    save_auth_session_at(&path, provider, env_var)
-}
-
-fn auth_session_path_from_home(home: &Path) -> PathBuf {
-    home.join(".tengu").join("auth").join("session.json")
-}
-
-fn save_auth_session_at(path: &Path, provider: &str, env_var: &str) -> Result<()> {
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?;
-    }
-    let session = AuthSession {
-        provider: provider.to_string(),
-        env_var: env_var.to_string(),
-        updated_at: Utc::now().to_rfc3339(),
-    };
-    fs::write(path, serde_json::to_string_pretty(&session)?)?;
-    Ok(())
-}
-
-fn load_auth_session() -> Option<AuthSession> {
-    let path = auth_session_path().ok()?;
-    load_auth_session_from_path(&path)
-}
-
-fn load_auth_session_from_path(path: &Path) -> Option<AuthSession> {
-    let data = fs::read_to_string(path).ok()?;
-    serde_json::from_str(&data).ok()
-}
-
-fn clear_auth_session() -> Result<()> {
-    let path = auth_session_path()?;
-    clear_auth_session_at(&path)
-}
-
-fn clear_auth_session_at(path: &Path) -> Result<()> {
-    if path.exists() {
-        fs::remove_file(path)?;
-    }
-    Ok(())
-}
-
 fn usage_to_json(usage: &LlmUsage) -> serde_json::Value {
     json!({
         "provider": &usage.provider,
+        "model": &usage.model,
         "input_tokens": usage.input_tokens,
         "output_tokens": usage.output_tokens,
         "total_tokens": usage.total_tokens,
         "cache_creation_input_tokens": usage.cache_creation_input_tokens,
         "cache_read_input_tokens": usage.cache_read_input_tokens,
         "reasoning_tokens": usage.reasoning_tokens,
+        "cost_usd": usage.cost_usd,
         "raw": usage.raw.as_ref(),
     })
 }
 
+fn load_mcp_manager() -> std::sync::Arc<McpManager> {
+    let manager = McpManager::from_store().unwrap_or_else(|err| {
+        eprintln!("warning: failed to load mcp servers: {}", err);
+        McpManager::new(Default::default())
+    });
+    // The TUI replaces this with interactive handlers before any server is started.
+    manager.set_request_handler(headless_request_handler());
+    std::sync::Arc::new(manager)
+}
+
+fn build_mcp_server_config(
+    transport: Option<&str>,
+    command: &[String],
+    headers: &[String],
+    env: &[String],
+    timeout: Option<u64>,
+    bearer_token_env_var: Option<String>,
+) -> Result<McpServerConfig> {
+    let Some((first, rest)) = command.split_first() else {
+        return Err(anyhow!("mcp add requires a command or url"));
+    };
+    let kind = match transport {
+        Some(transport) => McpTransportKind::from_str(transport)?,
+        None if first.starts_with("http://") || first.starts_with("https://") => {
+            McpTransportKind::Http
+        }
+        None => McpTransportKind::Stdio,
+    };
+    let mut entry = McpServerConfig {
+        transport: transport.map(|_| kind.as_str().to_string()),
+        timeout_sec: timeout,
+        ..Default::default()
+    };
+    match kind {
+        McpTransportKind::Stdio => {
+            if bearer_token_env_var.is_some() || !headers.is_empty() {
+                return Err(anyhow!(
+                    "--header and --bearer-token-env-var require http or sse transport"
+                ));
+            }
+            entry.command = Some(first.clone());
+            entry.args = (!rest.is_empty()).then(|| rest.to_vec());
+            if !env.is_empty() {
+                entry.env = Some(parse_key_values(env, '=')?);
+            }
+        }
+        McpTransportKind::Http | McpTransportKind::Sse => {
+            if !rest.is_empty() {
+                return Err(anyhow!(
+                    "unexpected arguments after url: {}",
+                    rest.join(" ")
+                ));
+            }
+            if !env.is_empty() {
+                return Err(anyhow!("--env requires stdio transport"));
+            }
+            entry.url = Some(first.clone());
+            entry.bearer_token_env_var = bearer_token_env_var;
+            if !headers.is_empty() {
+                entry.http_headers = Some(parse_key_values(headers, ':')?);
+            }
+        }
+    }
+    Ok(entry)
+}
+
+fn parse_key_values(
+    entries: &[String],
+    separator: char,
+) -> Result<std::collections::BTreeMap<String, String>> {
+    let mut map = std::collections::BTreeMap::new();
+    for entry in entries {
+        let (key, value) = entry
+            .split_once(separator)
+            .ok_or_else(|| anyhow!("expected KEY{}VALUE: {}", separator, entry))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(anyhow!("empty key: {}", entry));
+        }
+        map.insert(key.to_string(), value.trim().to_string());
+    }
+    Ok(map)
+}
+
+fn format_mcp_server_details(name: &str, scope: McpScope, server: &McpServerConfig) -> String {
+    let mut lines = vec![
+        format!("name: {}", name),
+        format!("scope: {}", scope.as_str()),
+        format!(
+            "transport: {}",
+            server
+                .transport_kind()
+                .map(|kind| kind.as_str())
+                .unwrap_or("unknown")
+        ),
+        format!("enabled: {}", server.is_enabled()),
+    ];
+    if let Some(command) = &server.command {
+        lines.push(format!("command: {}", command));
+    }
+    if let Some(args) = server.args.as_ref().filter(|args| !args.is_empty()) {
+        lines.push(format!("args: {}", args.join(" ")));
+    }
+    if let Some(url) = &server.url {
+        lines.push(format!("url: {}", url));
+    }
+    if let Some(var) = &server.bearer_token_env_var {
+        lines.push(format!("bearer_token_env_var: {}", var));
+    }
+    for (key, value) in server.env.iter().flatten() {
+        lines.push(format!("env: {}={}", key, value));
+    }
+    for (key, value) in server.http_headers.iter().flatten() {
+        lines.push(format!("header: {}: {}", key, value));
+    }
+    if let Some(timeout) = server.timeout_sec {
+        lines.push(format!("timeout_sec: {}", timeout));
+    }
+    lines.join("\n")
+}
+
 fn parse_generated_agent(raw: &str) -> Result<StoredAgent> {
     let trimmed = raw.trim();
     let candidate = if let Some(stripped) = trimmed.strip_prefix("```") {
@@ -1129,6 +1927,7 @@ fn fallback_generated_agent() -> StoredAgent {
             "You are the `{name}` agent. Provide concise, pragmatic coding help, \
              prioritize correctness, explain tradeoffs briefly, and propose concrete next steps."
         ),
+        sampling: SamplingParams::default(),
     }
 }
 
@@ -1137,20 +1936,19 @@ fn build_headless_request(
     system_prompt: Option<&str>,
     image_paths: &[PathBuf],
 ) -> Result<LlmRequest> {
-    let prompt = match system_prompt {
-        Some(system_prompt) if !system_prompt.trim().is_empty() => format!(
-            "System instructions:\n{}\n\nUser request:\n{}",
-            system_prompt, prompt
-        ),
-        _ => prompt.to_string(),
-    };
-
     let images = image_paths
         .iter()
         .map(|path| load_llm_image(path))
         .collect::<Result<Vec<_>>>()?;
 
-    Ok(LlmRequest { prompt, images })
+    Ok(LlmRequest {
+        prompt: prompt.to_string(),
+        system: system_prompt
+            .filter(|system_prompt| !system_prompt.trim().is_empty())
+            .map(str::to_string),
+        images,
+        ..Default::default()
+    })
 }
 
 fn load_llm_image(path: &Path) -> Result<LlmImage> {
@@ -1210,9 +2008,119 @@ mod tests {
         );
         assert_eq!(auth_env_var_for_provider("openai"), Some("OPENAI_API_KEY"));
         assert_eq!(auth_env_var_for_provider("gemini"), Some("GOOGLE_API_KEY"));
+        assert_eq!(
+            auth_env_var_for_provider("azure"),
+            Some("AZURE_OPENAI_API_KEY")
+        );
         assert_eq!(auth_env_var_for_provider("local"), None);
     }
 
+    #[test]
+    fn resolves_provider_tables_from_config() {
+        let config: Config = toml::from_str(
+            r#"
+[model]
+provider = "gateway"
+backend_url = "http://legacy.invalid"
+max_tokens = 4096
+
+[providers.gateway]
+type = "anthropic"
+base_url = "https://llm-gateway.internal"
+api_key_command = "cat ~/.gateway-token"
+headers = { "x-team" = "platform" }
+timeout_sec = 120
+model = "claude-sonnet-4-20250514"
+
+[providers.openai]
+api_key_env = "WORK_OPENAI_KEY"
+model = "gpt-4.1"
+max_tokens = 2048
+"#,
+        )
+        .unwrap();
+        assert!(is_provider_name(&config, "gateway"));
+        assert!(!is_provider_name(&config, "gpt-4.1"));
+
+        let gateway = provider_settings(
+            &config,
+            &LlmProvider::Anthropic,
+            "gateway",
+            config.providers.get("gateway"),
+            None,
+        );
+        assert_eq!(
+            gateway.base_url.as_deref(),
+            Some("https://llm-gateway.internal")
+        );
+        assert_eq!(
+            gateway.headers,
+            vec![("x-team".to_string(), "platform".to_string())]
+        );
+        assert_eq!(gateway.read_timeout, Some(Duration::from_secs(120)));
+        assert_eq!(gateway.max_tokens, Some(4096));
+
+        let openai = provider_settings(
+            &config,
+            &LlmProvider::OpenAI,
+            "openai",
+            config.providers.get("openai"),
+            None,
+        );
+        assert_eq!(openai.base_url, None);
+        assert_eq!(openai.api_key_env.as_deref(), Some("WORK_OPENAI_KEY"));
+        assert_eq!(openai.max_tokens, Some(2048));
+
+        let google = provider_settings(&config, &LlmProvider::Google, "google", None, None);
+        assert_eq!(google.base_url.as_deref(), Some("http://legacy.invalid"));
+    }
+
+    #[test]
+    fn routes_roles_and_answer_from_config() {
+        let config: Config = toml::from_str(
+            r#"
+[model]
+provider = "anthropic"
+name = "claude-sonnet-4"
+
+[routing]
+answer = "openai:gpt-4.1"
+plan = "ollama:llama3.2:3b"
+tool_select = "gpt-4.1-mini"
+
+[providers.broken]
+type = "nope"
+"#,
+        )
+        .unwrap();
+        let cli = Cli::try_parse_from(["tengu"]).unwrap();
+        assert_eq!(
+            cli.main_model_selection(&config),
+            (Some("openai"), Some("gpt-4.1"))
+        );
+        let (client, model) = cli.resolve_llm_with_config(&config).unwrap();
+        assert_eq!(model, "gpt-4.1");
+        let policy = ToolPolicy::from_config(&config);
+        assert!(cli
+            .with_routes(&config, AgentRunner::new(client, model, policy))
+            .is_ok());
+
+        let cli = Cli::try_parse_from(["tengu", "--model", "anthropic:claude-opus-4"]).unwrap();
+        assert_eq!(
+            cli.main_model_selection(&config),
+            (Some("anthropic"), Some("claude-opus-4"))
+        );
+
+        let mut broken = config;
+        broken.routing.plan = Some("broken:m".to_string());
+        let (client, model) = cli.resolve_llm_with_config(&broken).unwrap();
+        let runner = AgentRunner::new(client, model, ToolPolicy::from_config(&broken));
+        assert_eq!(
+            cli.with_routes(&broken, runner).err().unwrap().to_string(),
+            "routing.plan: unsupported provider: nope"
+        );
+    }
+
     #[test]
     fn detects_supported_image_media_types() {
         assert_eq!(image_media_type(Path::new("a.png")), Some("image/png"));
@@ -1224,24 +2132,12 @@ mod tests {
 
     #[test]
     fn builds_headless_request_with_system_prompt() {
-        let request = build_headless_request("hello", Some("system"), &[]).unwrap();
+        let mut request = build_headless_request("hello", Some("system"), &[]).unwrap();
+        assert_eq!(request.system.as_deref(), Some("system"));
+        assert!(request.images.is_empty());
+        request.fold_prompt();
         assert!(request.prompt.contains("System instructions:"));
         assert!(request.prompt.contains("User request:"));
-        assert!(request.images.is_empty());
-    }
-
-    #[test]
-    fn saves_loads_and_clears_auth_session_file() {
-        let root = unique_temp_dir("auth-session");
-        let path = auth_session_path_from_home(&root);
-
-        save_auth_session_at(&path, "anthropic", "ANTHROPIC_API_KEY").unwrap();
-        let session = load_auth_session_from_path(&path).unwrap();
-        assert_eq!(session.provider, "anthropic");
-        assert_eq!(session.env_var, "ANTHROPIC_API_KEY");
-
-        clear_auth_session_at(&path).unwrap();
-        assert!(load_auth_session_from_path(&path).is_none());
     }
 
     #[test]
@@ -1259,21 +2155,25 @@ mod tests {
     fn serializes_usage_json_payload() {
         let value = usage_to_json(&LlmUsage {
             provider: "openai".to_string(),
+            model: Some("gpt-4o".to_string()),
             input_tokens: Some(12),
             output_tokens: Some(5),
             total_tokens: Some(17),
             cache_creation_input_tokens: None,
             cache_read_input_tokens: Some(3),
             reasoning_tokens: Some(2),
+            cost_usd: Some(0.5),
             raw: Some(serde_json::json!({"prompt_tokens": 12})),
         });
 
         assert_eq!(value["provider"], "openai");
+        assert_eq!(value["model"], "gpt-4o");
         assert_eq!(value["input_tokens"], 12);
         assert_eq!(value["output_tokens"], 5);
         assert_eq!(value["total_tokens"], 17);
         assert_eq!(value["cache_read_input_tokens"], 3);
         assert_eq!(value["reasoning_tokens"], 2);
+        assert_eq!(value["cost_usd"], 0.5);
         assert_eq!(value["raw"]["prompt_tokens"], 12);
     }
 
@@ -1293,4 +2193,75 @@ mod tests {
         assert!(agent.name.starts_with("generated-"));
         assert!(!agent.prompt.is_empty());
     }
+
+    #[test]
+    fn parses_mcp_add_with_url_and_headers() {
+        let cli = Cli::try_parse_from([
+            "tengu",
+            "mcp",
+            "add",
+            "--transport",
+            "http",
+            "-H",
+            "X-Api-Key: secret",
+            "--scope",
+            "user",
+            "github",
+            "https://example.com/mcp",
+        ])
+        .unwrap();
+        let Some(Commands::Mcp {
+            command:
+                McpCommands::Add {
+                    name,
+                    transport,
+                    scope,
+                    headers,
+                    command,
+                    ..
+                },
+        }) = cli.command
+        else {
+            panic!("expected mcp add");
+        };
+        assert_eq!(name, "github");
+        assert_eq!(scope, "user");
+        let entry =
+            build_mcp_server_config(transport.as_deref(), &command, &headers, &[], None, None)
+                .unwrap();
+        assert_eq!(entry.url.as_deref(), Some("https://example.com/mcp"));
+        assert_eq!(entry.http_headers.unwrap()["X-Api-Key"], "secret");
+    }
+
+    #[test]
+    fn parses_mcp_add_with_command_after_separator() {
+        let cli = Cli::try_parse_from([
+            "tengu", "mcp", "add", "-e", "DEBUG=1", "fs", "--", "npx", "-y", "server",
+        ])
+        .unwrap();
+        let Some(Commands::Mcp {
+            command: McpCommands::Add { env, command, .. },
+        }) = cli.command
+        else {
+            panic!("expected mcp add");
+        };
+        let entry = build_mcp_server_config(None, &command, &[], &env, Some(30), None).unwrap();
+        assert_eq!(entry.command.as_deref(), Some("npx"));
+        assert_eq!(entry.args.unwrap(), vec!["-y", "server"]);
+        assert_eq!(entry.env.unwrap()["DEBUG"], "1");
+        assert_eq!(entry.timeout_sec, Some(30));
+        assert!(entry.transport.is_none());
+    }
+
+    #[test]
+    fn rejects_mismatched_mcp_add_options() {
+        let url = vec!["https://example.com/mcp".to_string()];
+        let env = vec!["DEBUG=1".to_string()];
+        assert!(build_mcp_server_config(None, &url, &[], &env, None, None).is_err());
+
+        let cmd = vec!["npx".to_string()];
+        let headers = vec!["Authorization: Bearer x".to_string()];
+        assert!(build_mcp_server_config(None, &cmd, &headers, &[], None, None).is_err());
+        assert!(parse_key_values(&["NOVALUE".to_string()], '=').is_err());
+    }
 }
@@ -1,7 +1,9 @@
 // Config module
 // 設定ファイル管理
 
+use crate::llm::{LlmProvider, SamplingParams};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -12,6 +14,12 @@ pub struct Config {
     pub permissions: Option<PermissionsConfig>,
     #[serde(default)]
     pub sandbox: Option<SandboxConfig>,
+    #[serde(default)]
+    pub providers: BTreeMap<String, ProviderConfig>,
+    #[serde(default)]
+    pub routing: RoutingConfig,
+    #[serde(default)]
+    pub compact: CompactConfig,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -24,6 +32,68 @@ pub struct ModelConfig {
     pub backend: Option<String>,
     pub name: Option<String>,
     pub backend_url: Option<String>,
+    pub max_retries: Option<u32>,
+    #[serde(flatten)]
+    pub sampling: SamplingParams,
+    pub thinking_budget: Option<u32>,
+    pub reasoning_effort: Option<String>,
+    pub prompt_cache: Option<bool>,
+    pub prompt_cache_ttl: Option<String>,
+    /// `[provider:]model` entries tried in order when the model fails with a retryable error.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback: Vec<String>,
+}
+
+/// `[providers.<name>]`: connection settings for one backend. `type` is only needed when
+/// the table name is not a built-in provider (e.g. `[providers.gateway] type = "anthropic"`).
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct ProviderConfig {
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub base_url: Option<String>,
+    pub api_key_env: Option<String>,
+    pub api_key_command: Option<String>,
+    /// Seconds an `api_key_command` result is reused; unset keeps it for the session.
+    pub api_key_command_ttl_sec: Option<u64>,
+    /// Which `tengu auth login` key to use; `TENGU_PROFILE` wins.
+    pub profile: Option<String>,
+    pub headers: Option<BTreeMap<String, String>>,
+    pub timeout_sec: Option<u64>,
+    pub connect_timeout_sec: Option<u64>,
+    pub proxy: Option<String>,
+    pub model: Option<String>,
+    pub max_tokens: Option<u32>,
+    pub stream_options: Option<bool>,
+    pub keep_alive: Option<String>,
+    pub options: Option<BTreeMap<String, serde_json::Value>>,
+    pub prompt_cache: Option<bool>,
+    pub prompt_cache_ttl: Option<String>,
+    pub cassettes: Option<String>,
+    pub script: Option<String>,
+    /// Azure: `api-version`, and a command printing an Entra ID token (used instead of a key).
+    pub api_version: Option<String>,
+    pub token_command: Option<String>,
+    /// Bedrock: AWS region; `AWS_REGION` / `AWS_DEFAULT_REGION` when unset.
+    pub region: Option<String>,
+}
+
+/// `[routing]`: `[provider:]model` for each kind of call of a turn. Unset roles use the
+/// main model; `answer` is the main model unless `--model` is given.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct RoutingConfig {
+    pub plan: Option<String>,
+    pub tool_select: Option<String>,
+    pub answer: Option<String>,
+    pub compact: Option<String>,
+}
+
+/// `[compact]`: when the TUI summarizes older turns on its own, and how many it keeps.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct CompactConfig {
+    pub auto: Option<bool>,
+    /// Share of the model's context budget the conversation may fill before compacting.
+    pub threshold: Option<f64>,
+    pub keep_turns: Option<usize>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -49,6 +119,13 @@ impl Default for ModelConfig {
             backend: None,
             name: None,
             backend_url: None,
+            max_retries: None,
+            sampling: SamplingParams::default(),
+            thinking_budget: None,
+            reasoning_effort: None,
+            prompt_cache: None,
+            prompt_cache_ttl: None,
+            fallback: Vec::new(),
         }
     }
 }
@@ -61,6 +138,20 @@ impl Config {
         Ok(config)
     }
 
+    /// `openai:gpt-4o` names a provider; `llama3.2:3b` is an Ollama tag on the current one.
+    pub fn split_model_spec<'a>(&self, spec: &'a str) -> (Option<&'a str>, &'a str) {
+        match spec.split_once(':') {
+            Some((provider, model))
+                if !model.is_empty()
+                    && (self.providers.contains_key(provider)
+                        || LlmProvider::from_str(provider).is_ok()) =>
+            {
+                (Some(provider), model)
+            }
+            _ => (None, spec),
+        }
+    }
+
     fn expand_env_vars(&mut self) {
         self.model.provider = expand_env_vars_in_string(&self.model.provider);
         self.model.default = expand_env_vars_in_string(&self.model.default);
@@ -73,6 +164,40 @@ impl Config {
         if let Some(backend_url) = &self.model.backend_url {
             self.model.backend_url = Some(expand_env_vars_in_string(backend_url));
         }
+        for fallback in self.model.fallback.iter_mut() {
+            *fallback = expand_env_vars_in_string(fallback);
+        }
+        for route in [
+            &mut self.routing.plan,
+            &mut self.routing.tool_select,
+            &mut self.routing.answer,
+            &mut self.routing.compact,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *route = expand_env_vars_in_string(route);
+        }
+        for provider in self.providers.values_mut() {
+            for value in [
+                &mut provider.kind,
+                &mut provider.base_url,
+                &mut provider.api_key_env,
+                &mut provider.proxy,
+                &mut provider.model,
+                &mut provider.keep_alive,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                *value = expand_env_vars_in_string(value);
+            }
+            if let Some(headers) = &mut provider.headers {
+                for value in headers.values_mut() {
+                    *value = expand_env_vars_in_string(value);
+                }
+            }
+        }
         if let Some(permissions) = &mut self.permissions {
             if let Some(approval_policy) = &permissions.approval_policy {
                 permissions.approval_policy = Some(expand_env_vars_in_string(approval_policy));
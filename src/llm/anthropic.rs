@@ -3,27 +3,56 @@ use bytes::Bytes;
 use futures_util::stream::{self, BoxStream, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::HashSet;
 
 use crate::llm::{
-    LlmBackend, LlmProvider, LlmRequest, LlmResponse, LlmStream, LlmStreamEvent, LlmUsage,
+    model_ids, LlmBackend, LlmError, LlmErrorKind, LlmProvider, LlmRequest, LlmResponse, LlmRole,
+    LlmStream, LlmStreamEvent, LlmThinking, LlmToolCall, LlmUsage, ProviderSettings,
 };
 
 const DEFAULT_ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com";
 const DEFAULT_ANTHROPIC_VERSION: &str = "2023-06-01";
+const BEDROCK_ANTHROPIC_VERSION: &str = "bedrock-2023-05-31";
 const DEFAULT_MAX_TOKENS: u32 = 8192;
+/// Tool whose forced call carries a `json_schema` answer as its input.
+pub(crate) const RESPOND_TOOL: &str = "respond";
 
 #[derive(Debug, Clone)]
 pub struct AnthropicBackend {
     pub base_url: String,
     pub max_tokens: u32,
+    /// `None` when prompt caching is turned off.
+    cache_control: Option<Value>,
+    settings: ProviderSettings,
+    client: reqwest::Client,
 }
 
+/// Indexes of the streamed content blocks that are `respond` tool calls.
+#[derive(Debug, Default)]
+pub(crate) struct AnswerBlocks(HashSet<u64>);
+
 #[derive(Debug, Serialize)]
 struct MessageRequest {
     model: String,
     max_tokens: u32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    system: Vec<MessageContentBlock>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ToolDefinition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<Value>,
     messages: Vec<MessageInput>,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<Value>,
 }
 
 #[derive(Debug, Serialize)]
@@ -35,8 +64,27 @@ struct MessageInput {
 #[derive(Debug, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum MessageContentBlock {
-    Text { text: String },
-    Image { source: ImageSource },
+    Text {
+        text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<Value>,
+    },
+    Image {
+        source: ImageSource,
+    },
+    Thinking {
+        thinking: String,
+        signature: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct ToolDefinition {
+    name: String,
+    description: String,
+    input_schema: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_control: Option<Value>,
 }
 
 #[derive(Debug, Serialize)]
@@ -71,14 +119,39 @@ struct ContentBlock {
     #[serde(rename = "type")]
     kind: String,
     text: Option<String>,
+    #[serde(default)]
+    thinking: Option<String>,
+    #[serde(default)]
+    signature: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    input: Option<Value>,
 }
 
 impl AnthropicBackend {
-    pub fn new(base_url: Option<String>, max_tokens: Option<u32>) -> Self {
-        Self {
-            base_url: base_url.unwrap_or_else(|| DEFAULT_ANTHROPIC_BASE_URL.to_string()),
-            max_tokens: max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
-        }
+    pub fn new(settings: ProviderSettings) -> Result<Self> {
+        let cache_control = match (settings.prompt_cache, settings.prompt_cache_ttl.as_deref()) {
+            (Some(false), _) => None,
+            (_, None | Some("5m")) => Some(serde_json::json!({ "type": "ephemeral" })),
+            (_, Some("1h")) => Some(serde_json::json!({ "type": "ephemeral", "ttl": "1h" })),
+            (_, Some(ttl)) => {
+                return Err(anyhow!(
+                    "prompt_cache_ttl must be \"5m\" or \"1h\", got {:?}",
+                    ttl
+                ))
+            }
+        };
+        Ok(Self {
+            base_url: settings
+                .base_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_ANTHROPIC_BASE_URL.to_string()),
+            max_tokens: settings.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            cache_control,
+            client: settings.http_client()?,
+            settings,
+        })
     }
 
     fn messages_url(&self) -> String {
@@ -90,14 +163,122 @@ impl AnthropicBackend {
         }
     }
 
-    fn api_key(&self) -> Result<String> {
-        std::env::var("ANTHROPIC_API_KEY").map_err(|_| anyhow!("ANTHROPIC_API_KEY is not set"))
+    fn count_tokens_url(&self) -> String {
+        format!("{}/count_tokens", self.messages_url())
+    }
+
+    /// The message request without the fields `count_tokens` rejects (max_tokens, stream,
+    /// sampling).
+    fn count_tokens_body(&self, model: &str, request: &LlmRequest) -> Result<Value> {
+        let mut body = serde_json::to_value(self.request_body(model, request, false))?;
+        if let Some(fields) = body.as_object_mut() {
+            fields.retain(|key, _| {
+                matches!(
+                    key.as_str(),
+                    "model" | "system" | "tools" | "messages" | "thinking"
+                )
+            });
+        }
+        Ok(body)
+    }
+
+    /// The message request as Bedrock's InvokeModel takes it: the model goes in the URL and
+    /// the version in the body.
+    pub(crate) fn bedrock_body(&self, request: &LlmRequest) -> Result<Value> {
+        let mut body = serde_json::to_value(self.request_body("", request, false))?;
+        if let Some(fields) = body.as_object_mut() {
+            fields.remove("model");
+            fields.remove("stream");
+            fields.insert(
+                "anthropic_version".to_string(),
+                BEDROCK_ANTHROPIC_VERSION.into(),
+            );
+        }
+        Ok(body)
+    }
+
+    fn models_url(&self) -> String {
+        let base = self.base_url.trim_end_matches('/');
+        if base.ends_with("/v1") {
+            format!("{}/models?limit=1000", base)
+        } else {
+            format!("{}/v1/models?limit=1000", base)
+        }
+    }
+
+    async fn api_key(&self) -> Result<String> {
+        self.settings.api_key("ANTHROPIC_API_KEY").await
+    }
+
+    /// A text block that ends a cacheable prefix. Breakpoints go after the tools, the system
+    /// prompt and the conversation context; the API allows four.
+    fn cached_text(&self, text: &str) -> MessageContentBlock {
+        MessageContentBlock::Text {
+            text: text.to_string(),
+            cache_control: self.cache_control.clone(),
+        }
+    }
+
+    /// Earlier turns as messages. With thinking on, each answer is preceded by its signed
+    /// thinking block, which the API needs to continue from it. The conversation has to open
+    /// with a user turn.
+    fn history_messages(&self, request: &LlmRequest) -> Vec<MessageInput> {
+        let thinking = request.thinking.is_enabled();
+        let mut messages: Vec<MessageInput> = request
+            .history
+            .iter()
+            .skip_while(|turn| turn.role != LlmRole::User)
+            .filter(|turn| !turn.content.trim().is_empty())
+            .map(|turn| {
+                let mut content = Vec::new();
+                let signed = turn
+                    .thinking
+                    .as_ref()
+                    .filter(|_| thinking && turn.role == LlmRole::Assistant)
+                    .and_then(|t| Some((t.text.clone(), t.signature.clone()?)));
+                if let Some((thinking, signature)) = signed {
+                    content.push(MessageContentBlock::Thinking {
+                        thinking,
+                        signature,
+                    });
+                }
+                content.push(MessageContentBlock::Text {
+                    text: turn.content.clone(),
+                    cache_control: None,
+                });
+                let role = match turn.role {
+                    LlmRole::User => "user",
+                    LlmRole::Assistant => "assistant",
+                };
+                MessageInput {
+                    role: role.to_string(),
+                    content,
+                }
+            })
+            .collect();
+        if let Some(MessageContentBlock::Text { cache_control, .. }) = messages
+            .last_mut()
+            .and_then(|message| message.content.last_mut())
+        {
+            *cache_control = self.cache_control.clone();
+        }
+        messages
     }
 
     fn request_body(&self, model: &str, request: &LlmRequest, stream: bool) -> MessageRequest {
-        let mut content = vec![MessageContentBlock::Text {
+        let mut messages = self.history_messages(request);
+        let mut content = Vec::new();
+        if let Some(context) = request
+            .context
+            .as_deref()
+            .filter(|c| !c.trim().is_empty() && messages.is_empty())
+        {
+            content.push(self.cached_text(context));
+        }
+        content.push(MessageContentBlock::Text {
             text: request.prompt.clone(),
-        }];
+            cache_control: None,
+        });
         for image in &request.images {
             content.push(MessageContentBlock::Image {
                 source: ImageSource {
@@ -107,26 +288,125 @@ impl AnthropicBackend {
                 },
             });
         }
+        messages.push(MessageInput {
+            role: "user".to_string(),
+            content,
+        });
+        let sampling = &request.sampling;
+        let thinking = request.thinking.is_enabled();
+        // The budget must stay below max_tokens, and the API allows at least 1024.
+        let budget = request.thinking.budget().max(1024);
+        let system = request
+            .system
+            .as_deref()
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| vec![self.cached_text(s)])
+            .unwrap_or_default();
+        let mut tools = request
+            .tools
+            .iter()
+            .map(|tool| ToolDefinition {
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                input_schema: tool.parameters.clone(),
+                cache_control: None,
+            })
+            .collect::<Vec<_>>();
+        // The API has no JSON mode: the answer goes into a tool call whose input schema is
+        // the requested one. Thinking only allows `auto`, so the call is not forced then.
+        let schema = request.json_schema.as_ref().filter(|_| tools.is_empty());
+        if let Some(schema) = schema {
+            tools.push(ToolDefinition {
+                name: RESPOND_TOOL.to_string(),
+                description: "Give the answer as the input of this tool.".to_string(),
+                input_schema: schema.clone(),
+                cache_control: None,
+            });
+        }
+        let tool_choice = schema
+            .filter(|_| !thinking)
+            .map(|_| serde_json::json!({ "type": "tool", "name": RESPOND_TOOL }));
+        if let Some(last) = tools.last_mut() {
+            last.cache_control = self.cache_control.clone();
+        }
         MessageRequest {
             model: model.to_string(),
-            max_tokens: self.max_tokens,
-            messages: vec![MessageInput {
-                role: "user".to_string(),
-                content,
-            }],
+            max_tokens: {
+                let max_tokens = request.max_tokens.unwrap_or(self.max_tokens);
+                if thinking {
+                    max_tokens.max(budget + 1024)
+                } else {
+                    max_tokens
+                }
+            },
+            system,
+            tools,
+            tool_choice,
+            messages,
             stream,
+            temperature: sampling.temperature.filter(|_| !thinking),
+            top_p: sampling.top_p.filter(|p| !thinking || *p >= 0.95),
+            top_k: sampling.top_k.filter(|_| !thinking),
+            stop_sequences: sampling.stop.clone(),
+            thinking: thinking
+                .then(|| serde_json::json!({ "type": "enabled", "budget_tokens": budget })),
         }
     }
 
-    fn collect_text(blocks: Vec<ContentBlock>) -> String {
+    fn collect_text(blocks: &[ContentBlock]) -> String {
         blocks
-            .into_iter()
+            .iter()
             .filter(|block| block.kind == "text")
-            .filter_map(|block| block.text)
+            .filter_map(|block| block.text.as_deref())
             .collect::<Vec<_>>()
             .join("")
     }
 
+    fn collect_thinking(blocks: &[ContentBlock]) -> Option<LlmThinking> {
+        let mut thinking = LlmThinking::default();
+        for block in blocks.iter().filter(|block| block.kind == "thinking") {
+            thinking.push(
+                block.thinking.as_deref().unwrap_or_default(),
+                block.signature.as_deref(),
+            );
+        }
+        (!thinking.is_empty()).then_some(thinking)
+    }
+
+    fn collect_tool_calls(blocks: &[ContentBlock]) -> Vec<LlmToolCall> {
+        blocks
+            .iter()
+            .filter(|block| block.kind == "tool_use")
+            .filter_map(|block| {
+                Some(LlmToolCall {
+                    name: block.name.clone()?,
+                    arguments: block.input.clone().unwrap_or(Value::Null),
+                })
+            })
+            .collect()
+    }
+
+    /// `message_start` carries the input and cache counts, `message_delta` the output.
+    pub(crate) fn merge_usage(prev: Option<LlmUsage>, next: LlmUsage) -> LlmUsage {
+        let Some(prev) = prev else {
+            return next;
+        };
+        let input_tokens = next.input_tokens.or(prev.input_tokens);
+        let output_tokens = next.output_tokens.or(prev.output_tokens);
+        LlmUsage {
+            input_tokens,
+            output_tokens,
+            total_tokens: input_tokens.zip(output_tokens).map(|(i, o)| i + o),
+            cache_creation_input_tokens: next
+                .cache_creation_input_tokens
+                .or(prev.cache_creation_input_tokens),
+            cache_read_input_tokens: next
+                .cache_read_input_tokens
+                .or(prev.cache_read_input_tokens),
+            ..next
+        }
+    }
+
     fn normalize_usage(usage: MessageUsage, raw: Option<Value>) -> LlmUsage {
         let total_tokens = match (usage.input_tokens, usage.output_tokens) {
             (Some(input), Some(output)) => Some(input + output),
@@ -134,12 +414,14 @@ impl AnthropicBackend {
         };
         LlmUsage {
             provider: "anthropic".to_string(),
+            model: None,
             input_tokens: usage.input_tokens,
             output_tokens: usage.output_tokens,
             total_tokens,
             cache_creation_input_tokens: usage.cache_creation_input_tokens,
             cache_read_input_tokens: usage.cache_read_input_tokens,
             reasoning_tokens: None,
+            cost_usd: None,
             raw,
         }
     }
@@ -155,25 +437,35 @@ impl AnthropicBackend {
         Some(Self::normalize_usage(usage, Some(usage_value)))
     }
 
-    fn parse_stream_event(data: &str) -> Result<Option<LlmStreamEvent>> {
+    pub(crate) fn parse_stream_event(
+        data: &str,
+        answer_blocks: &mut AnswerBlocks,
+    ) -> Result<Option<LlmStreamEvent>> {
         let payload = data.trim();
         if payload.is_empty() || payload == "[DONE]" {
             return Ok(None);
         }
 
         let value: Value = serde_json::from_str(payload)?;
-        if let Some(error) = value
-            .get("error")
-            .and_then(|err| err.get("message"))
-            .and_then(Value::as_str)
-        {
-            return Err(anyhow!("anthropic stream error: {}", error));
+        if let Some(error) = value.get("error").filter(|err| !err.is_null()) {
+            return Err(LlmError::from_stream_error("anthropic", error).into());
         }
 
         if let Some(usage) = Self::extract_usage_value(&value) {
             return Ok(Some(LlmStreamEvent::Usage(usage)));
         }
 
+        if let Some(delta) = value.get("delta") {
+            let thinking = delta.get("thinking").and_then(Value::as_str);
+            let signature = delta.get("signature").and_then(Value::as_str);
+            if thinking.is_some() || signature.is_some() {
+                return Ok(Some(LlmStreamEvent::Thinking {
+                    text: thinking.unwrap_or_default().to_string(),
+                    signature: signature.map(str::to_string),
+                }));
+            }
+        }
+
         if let Some(text) = value
             .get("delta")
             .and_then(|delta| delta.get("text"))
@@ -182,6 +474,29 @@ impl AnthropicBackend {
             return Ok(Some(LlmStreamEvent::Text(text.to_string())));
         }
 
+        let index = value.get("index").and_then(Value::as_u64);
+        if let Some(block) = value
+            .get("content_block")
+            .filter(|block| block.get("type").and_then(Value::as_str) == Some("tool_use"))
+        {
+            if block.get("name").and_then(Value::as_str) == Some(RESPOND_TOOL) {
+                answer_blocks.0.extend(index);
+            }
+            return Ok(None);
+        }
+
+        // Only the `respond` tool's input is the answer; native tool calls are not shown.
+        if let Some(json) = value
+            .get("delta")
+            .and_then(|delta| delta.get("partial_json"))
+            .and_then(Value::as_str)
+        {
+            if index.is_some_and(|index| answer_blocks.0.contains(&index)) {
+                return Ok(Some(LlmStreamEvent::Text(json.to_string())));
+            }
+            return Ok(None);
+        }
+
         if let Some(text) = value
             .get("content_block")
             .and_then(|block| block.get("text"))
@@ -202,47 +517,130 @@ impl LlmBackend for AnthropicBackend {
         LlmProvider::Anthropic
     }
 
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
+    fn supports_prompt_cache(&self) -> bool {
+        true
+    }
+
+    fn supports_roles(&self) -> bool {
+        true
+    }
+
+    fn supports_json_schema(&self) -> bool {
+        true
+    }
+
+    fn unsupported_sampling(&self, request: &LlmRequest) -> Vec<&'static str> {
+        let sampling = &request.sampling;
+        let mut names = Vec::new();
+        if sampling.seed.is_some() {
+            names.push("seed");
+        }
+        if request.thinking.is_enabled() {
+            if sampling.temperature.is_some() {
+                names.push("temperature with thinking");
+            }
+            if sampling.top_k.is_some() {
+                names.push("top_k with thinking");
+            }
+            if sampling.top_p.is_some_and(|p| p < 0.95) {
+                names.push("top_p below 0.95 with thinking");
+            }
+        }
+        names
+    }
+
+    async fn count_tokens(&self, model: &str, request: &LlmRequest) -> Result<Option<u64>> {
+        let api_key = self.api_key().await?;
+        let response = self
+            .client
+            .post(self.count_tokens_url())
+            .header("x-api-key", api_key)
+            .header("anthropic-version", DEFAULT_ANTHROPIC_VERSION)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .json(&self.count_tokens_body(model, request)?)
+            .send()
+            .await
+            .map_err(|err| LlmError::network("anthropic", err))?;
+
+        if !response.status().is_success() {
+            return Err(LlmError::from_response("anthropic", response).await.into());
+        }
+
+        let body: Value = response.json().await?;
+        Ok(body["input_tokens"].as_u64())
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let api_key = self.api_key().await?;
+        let response = self
+            .client
+            .get(self.models_url())
+            .header("x-api-key", api_key)
+            .header("anthropic-version", DEFAULT_ANTHROPIC_VERSION)
+            .send()
+            .await
+            .map_err(|err| LlmError::network("anthropic", err))?;
+
+        if !response.status().is_success() {
+            return Err(LlmError::from_response("anthropic", response).await.into());
+        }
+
+        let body: Value = response.json().await?;
+        Ok(model_ids(&body["data"], "id"))
+    }
+
     async fn generate(&self, model: &str, request: &LlmRequest) -> Result<LlmResponse> {
-        let api_key = self.api_key()?;
-        let client = reqwest::Client::new();
-        let response = client
+        let api_key = self.api_key().await?;
+        let response = self
+            .client
             .post(self.messages_url())
             .header("x-api-key", api_key)
             .header("anthropic-version", DEFAULT_ANTHROPIC_VERSION)
             .header(reqwest::header::CONTENT_TYPE, "application/json")
             .json(&self.request_body(model, request, false))
             .send()
-            .await?;
+            .await
+            .map_err(|err| LlmError::network("anthropic", err))?;
 
         if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!("anthropic error: {} {}", status, body.trim()));
+            return Err(LlmError::from_response("anthropic", response).await.into());
         }
 
         let body: MessageResponse = response.json().await?;
+        let mut tool_calls = Self::collect_tool_calls(&body.content);
+        let mut content = Self::collect_text(&body.content);
+        if request.json_schema.is_some() {
+            if let Some(index) = tool_calls.iter().position(|call| call.name == RESPOND_TOOL) {
+                content = tool_calls.remove(index).arguments.to_string();
+            }
+        }
         Ok(LlmResponse {
-            content: Self::collect_text(body.content),
+            content,
+            thinking: Self::collect_thinking(&body.content),
+            tool_calls,
             usage: body.usage.map(|usage| Self::normalize_usage(usage, None)),
         })
     }
 
     async fn generate_stream(&self, model: &str, request: &LlmRequest) -> Result<LlmStream> {
-        let api_key = self.api_key()?;
-        let client = reqwest::Client::new();
-        let response = client
+        let api_key = self.api_key().await?;
+        let response = self
+            .client
             .post(self.messages_url())
             .header("x-api-key", api_key)
             .header("anthropic-version", DEFAULT_ANTHROPIC_VERSION)
             .header(reqwest::header::CONTENT_TYPE, "application/json")
             .json(&self.request_body(model, request, true))
             .send()
-            .await?;
+            .await
+            .map_err(|err| LlmError::network("anthropic", err))?;
 
         if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!("anthropic error: {} {}", status, body.trim()));
+            return Err(LlmError::from_response("anthropic", response).await.into());
         }
 
         struct StreamState {
@@ -250,6 +648,9 @@ impl LlmBackend for AnthropicBackend {
             buffer: String,
             pending_event: Option<String>,
             pending_data: Vec<String>,
+            usage: Option<LlmUsage>,
+            answer_blocks: AnswerBlocks,
+            ended: bool,
             finished: bool,
         }
 
@@ -266,7 +667,10 @@ impl LlmBackend for AnthropicBackend {
                         let data = state.pending_data.join("\n");
                         state.pending_event = None;
                         state.pending_data.clear();
-                        return AnthropicBackend::parse_stream_event(&data);
+                        return AnthropicBackend::parse_stream_event(
+                            &data,
+                            &mut state.answer_blocks,
+                        );
                     }
                     continue;
                 }
@@ -289,6 +693,9 @@ impl LlmBackend for AnthropicBackend {
             buffer: String::new(),
             pending_event: None,
             pending_data: Vec::new(),
+            usage: None,
+            answer_blocks: AnswerBlocks::default(),
+            ended: false,
             finished: false,
         };
 
@@ -299,13 +706,23 @@ impl LlmBackend for AnthropicBackend {
 
             loop {
                 match take_message(&mut state) {
-                    Ok(Some(text)) => return Some((Ok(text), state)),
+                    Ok(Some(LlmStreamEvent::Usage(usage))) => {
+                        state.usage =
+                            Some(AnthropicBackend::merge_usage(state.usage.take(), usage));
+                        continue;
+                    }
+                    Ok(Some(event)) => return Some((Ok(event), state)),
                     Ok(None) => {}
                     Err(err) => {
                         state.finished = true;
                         return Some((Err(err), state));
                     }
                 }
+                if state.ended {
+                    state.finished = true;
+                    let usage = state.usage.take()?;
+                    return Some((Ok(LlmStreamEvent::Usage(usage)), state));
+                }
 
                 match state.stream.next().await {
                     Some(Ok(chunk)) => {
@@ -313,19 +730,17 @@ impl LlmBackend for AnthropicBackend {
                     }
                     Some(Err(err)) => {
                         state.finished = true;
-                        return Some((Err(anyhow::Error::new(err)), state));
+                        let err = LlmError::new(
+                            LlmErrorKind::StreamInterrupted,
+                            "anthropic",
+                            err.to_string(),
+                        );
+                        return Some((Err(err.into()), state));
                     }
                     None => {
-                        state.finished = true;
-                        if !state.pending_data.is_empty() {
-                            let data = state.pending_data.join("\n");
-                            match AnthropicBackend::parse_stream_event(&data) {
-                                Ok(Some(text)) => return Some((Ok(text), state)),
-                                Ok(None) => return None,
-                                Err(err) => return Some((Err(err), state)),
-                            }
-                        }
-                        return None;
+                        // Flushes an event that was not followed by a blank line.
+                        state.ended = true;
+                        state.buffer.push_str("\n\n");
                     }
                 }
             }
@@ -341,25 +756,45 @@ mod tests {
 
     #[test]
     fn builds_messages_url_from_default_base() {
-        let backend = AnthropicBackend::new(None, None);
+        let backend = AnthropicBackend::new(ProviderSettings::default()).unwrap();
         assert_eq!(
             backend.messages_url(),
             "https://api.anthropic.com/v1/messages"
         );
     }
 
+    #[test]
+    fn counts_tokens_without_generation_fields() {
+        let backend = AnthropicBackend::new(ProviderSettings::default()).unwrap();
+        assert_eq!(
+            backend.count_tokens_url(),
+            "https://api.anthropic.com/v1/messages/count_tokens"
+        );
+        let mut request = LlmRequest::text("hi");
+        request.system = Some("be brief".to_string());
+        request.sampling.temperature = Some(0.2);
+        let body = backend
+            .count_tokens_body("claude-sonnet-4", &request)
+            .unwrap();
+        let mut keys: Vec<_> = body.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["messages", "model", "system"]);
+    }
+
     #[test]
     fn parses_text_delta_from_stream_payload() {
         let payload =
             r#"{"type":"content_block_delta","delta":{"type":"text_delta","text":"hello"}}"#;
-        let parsed = AnthropicBackend::parse_stream_event(payload).unwrap();
+        let parsed =
+            AnthropicBackend::parse_stream_event(payload, &mut AnswerBlocks::default()).unwrap();
         assert!(matches!(parsed, Some(LlmStreamEvent::Text(text)) if text == "hello"));
     }
 
     #[test]
     fn parses_usage_from_stream_payload() {
         let payload = r#"{"type":"message_delta","usage":{"input_tokens":10,"output_tokens":4,"cache_creation_input_tokens":2,"cache_read_input_tokens":1}}"#;
-        let parsed = AnthropicBackend::parse_stream_event(payload).unwrap();
+        let parsed =
+            AnthropicBackend::parse_stream_event(payload, &mut AnswerBlocks::default()).unwrap();
         assert!(matches!(
             parsed,
             Some(LlmStreamEvent::Usage(usage))
@@ -371,4 +806,191 @@ mod tests {
                     && usage.cache_read_input_tokens == Some(1)
         ));
     }
+
+    #[test]
+    fn replays_signed_thinking_before_earlier_answers() {
+        let backend = AnthropicBackend::new(ProviderSettings::default()).unwrap();
+        let turn = |role, content: &str, thinking: Option<LlmThinking>| crate::llm::LlmTurn {
+            role,
+            content: content.to_string(),
+            thinking,
+        };
+        let mut request = LlmRequest {
+            context: Some("過去の会話:\nユーザー: hi\nアシスタント: hello".to_string()),
+            history: vec![
+                turn(LlmRole::Assistant, "dangling", None),
+                turn(LlmRole::User, "hi", None),
+                turn(
+                    LlmRole::Assistant,
+                    "hello",
+                    Some(LlmThinking {
+                        text: "greet back".to_string(),
+                        signature: Some("EqQB".to_string()),
+                    }),
+                ),
+            ],
+            ..LlmRequest::text("next")
+        };
+        request.thinking.budget_tokens = Some(2048);
+        let body = serde_json::to_value(backend.request_body("m", &request, false)).unwrap();
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0]["content"][0]["text"], "hi");
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(
+            messages[1]["content"][0],
+            serde_json::json!({ "type": "thinking", "thinking": "greet back", "signature": "EqQB" })
+        );
+        assert_eq!(messages[1]["content"][1]["text"], "hello");
+        assert!(messages[1]["content"][1].get("cache_control").is_some());
+        assert_eq!(
+            messages[2]["content"],
+            serde_json::json!([{ "type": "text", "text": "next" }])
+        );
+
+        request.thinking.budget_tokens = None;
+        let body = serde_json::to_value(backend.request_body("m", &request, false)).unwrap();
+        assert_eq!(body["messages"][1]["content"][0]["type"], "text");
+    }
+
+    #[test]
+    fn requests_and_parses_thinking() {
+        let backend = AnthropicBackend::new(ProviderSettings::default()).unwrap();
+        let mut request = LlmRequest::text("hi");
+        request.thinking.budget_tokens = Some(10000);
+        request.sampling.temperature = Some(0.2);
+        let body = serde_json::to_value(backend.request_body("m", &request, true)).unwrap();
+        assert_eq!(body["thinking"]["budget_tokens"], 10000);
+        assert_eq!(body["max_tokens"], 11024);
+        assert!(body.get("temperature").is_none());
+
+        let payload = r#"{"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"Let me see"}}"#;
+        let parsed =
+            AnthropicBackend::parse_stream_event(payload, &mut AnswerBlocks::default()).unwrap();
+        assert!(matches!(
+            parsed,
+            Some(LlmStreamEvent::Thinking { text, signature: None }) if text == "Let me see"
+        ));
+        let payload = r#"{"type":"content_block_delta","index":0,"delta":{"type":"signature_delta","signature":"EqQB"}}"#;
+        let parsed =
+            AnthropicBackend::parse_stream_event(payload, &mut AnswerBlocks::default()).unwrap();
+        assert!(matches!(
+            parsed,
+            Some(LlmStreamEvent::Thinking { text, signature: Some(sig) })
+                if text.is_empty() && sig == "EqQB"
+        ));
+    }
+
+    #[test]
+    fn forces_a_respond_tool_for_json_schema() {
+        let backend = AnthropicBackend::new(ProviderSettings::default()).unwrap();
+        let schema = serde_json::json!({ "type": "object", "required": ["ok"] });
+        let mut request = LlmRequest {
+            json_schema: Some(schema.clone()),
+            ..LlmRequest::text("check")
+        };
+        let body = serde_json::to_value(backend.request_body("m", &request, false)).unwrap();
+        assert_eq!(body["tools"][0]["name"], RESPOND_TOOL);
+        assert_eq!(body["tools"][0]["input_schema"], schema);
+        assert_eq!(
+            body["tool_choice"],
+            serde_json::json!({ "type": "tool", "name": RESPOND_TOOL })
+        );
+        request.thinking.budget_tokens = Some(2048);
+        let body = serde_json::to_value(backend.request_body("m", &request, false)).unwrap();
+        assert_eq!(body["tools"][0]["name"], RESPOND_TOOL);
+        assert!(body.get("tool_choice").is_none());
+
+        let mut blocks = AnswerBlocks::default();
+        let start = |index: u32, name: &str| {
+            serde_json::json!({
+                "type": "content_block_start",
+                "index": index,
+                "content_block": { "type": "tool_use", "id": "t", "name": name, "input": {} },
+            })
+            .to_string()
+        };
+        let delta = |index: u32| {
+            format!(
+                r#"{{"type":"content_block_delta","index":{},"delta":{{"type":"input_json_delta","partial_json":"{{\"ok\":"}}}}"#,
+                index
+            )
+        };
+        let mut parse =
+            |payload: &str| AnthropicBackend::parse_stream_event(payload, &mut blocks).unwrap();
+        assert!(parse(&start(0, "read")).is_none());
+        assert!(parse(&delta(0)).is_none());
+        assert!(parse(&start(1, RESPOND_TOOL)).is_none());
+        assert!(matches!(
+            parse(&delta(1)),
+            Some(LlmStreamEvent::Text(text)) if text == "{\"ok\":"
+        ));
+    }
+
+    #[test]
+    fn marks_cache_breakpoints_and_reads_tool_use() {
+        let mut settings = ProviderSettings::default();
+        settings.prompt_cache_ttl = Some("1h".to_string());
+        let backend = AnthropicBackend::new(settings).unwrap();
+        let request = LlmRequest {
+            system: Some("Project rules".to_string()),
+            context: Some("過去の会話:\nユーザー: hi".to_string()),
+            tools: vec![crate::llm::LlmTool {
+                name: "read".to_string(),
+                description: "Read a file".to_string(),
+                parameters: serde_json::json!({ "type": "object" }),
+            }],
+            ..LlmRequest::text("next")
+        };
+        let body = serde_json::to_value(backend.request_body("m", &request, false)).unwrap();
+        let ttl = serde_json::json!({ "type": "ephemeral", "ttl": "1h" });
+        assert_eq!(body["system"][0]["cache_control"], ttl);
+        assert_eq!(body["tools"][0]["cache_control"], ttl);
+        assert_eq!(body["tools"][0]["input_schema"]["type"], "object");
+        let content = &body["messages"][0]["content"];
+        assert_eq!(content[0]["cache_control"], ttl);
+        assert_eq!(content[1]["text"], "next");
+        assert!(content[1].get("cache_control").is_none());
+
+        let mut settings = ProviderSettings::default();
+        settings.prompt_cache = Some(false);
+        let off = AnthropicBackend::new(settings).unwrap();
+        let body = serde_json::to_value(off.request_body("m", &request, false)).unwrap();
+        assert!(body["system"][0].get("cache_control").is_none());
+        let mut settings = ProviderSettings::default();
+        settings.prompt_cache_ttl = Some("10m".to_string());
+        assert!(AnthropicBackend::new(settings).is_err());
+
+        let body: MessageResponse = serde_json::from_str(
+            r#"{"content":[{"type":"tool_use","id":"t1","name":"read","input":{"path":"a.rs"}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            AnthropicBackend::collect_tool_calls(&body.content),
+            vec![LlmToolCall {
+                name: "read".to_string(),
+                arguments: serde_json::json!({ "path": "a.rs" }),
+            }]
+        );
+    }
+
+    #[test]
+    fn merges_stream_usage_from_start_and_delta() {
+        let start = r#"{"type":"message_start","message":{"usage":{"input_tokens":5,"output_tokens":1,"cache_read_input_tokens":900}}}"#;
+        let delta = r#"{"type":"message_delta","usage":{"output_tokens":40}}"#;
+        let usage = |payload: &str| match AnthropicBackend::parse_stream_event(
+            payload,
+            &mut AnswerBlocks::default(),
+        )
+        .unwrap()
+        {
+            Some(LlmStreamEvent::Usage(usage)) => usage,
+            other => panic!("unexpected event: {:?}", other),
+        };
+        let merged = AnthropicBackend::merge_usage(Some(usage(start)), usage(delta));
+        assert_eq!(merged.input_tokens, Some(5));
+        assert_eq!(merged.output_tokens, Some(40));
+        assert_eq!(merged.total_tokens, Some(45));
+        assert_eq!(merged.cache_read_input_tokens, Some(900));
+    }
 }
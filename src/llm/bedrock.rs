@@ -0,0 +1,680 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use bytes::Bytes;
+use chrono::Utc;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::llm::anthropic::{AnswerBlocks, RESPOND_TOOL};
+use crate::llm::{
+    aws_region_from_env, aws_uri_encode, model_ids, sign_request, AnthropicBackend, AwsCredentials,
+    LlmBackend, LlmError, LlmErrorKind, LlmProvider, LlmRequest, LlmResponse, LlmStream,
+    LlmStreamEvent, LlmThinking, LlmToolCall, LlmUsage, ProviderSettings,
+};
+
+const DEFAULT_BEDROCK_REGION: &str = "us-east-1";
+const DEFAULT_MAX_TOKENS: u32 = 8192;
+
+/// Anthropic models on AWS Bedrock: Converse for whole answers, InvokeModelWithResponseStream
+/// (the Anthropic message format) for streams. Requests are signed with the standard AWS env
+/// credentials.
+#[derive(Debug, Clone)]
+pub struct BedrockBackend {
+    pub base_url: String,
+    pub region: String,
+    pub max_tokens: u32,
+    /// The control-plane endpoint, which lists models. `base_url` only moves the runtime
+    /// endpoint.
+    control_url: String,
+    credentials: Option<AwsCredentials>,
+    /// Builds InvokeModel bodies and reads their stream events.
+    anthropic: AnthropicBackend,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConverseResponse {
+    output: ConverseOutput,
+    #[serde(default)]
+    usage: Option<ConverseUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConverseOutput {
+    #[serde(default)]
+    message: Option<ConverseMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConverseMessage {
+    #[serde(default)]
+    content: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ConverseUsage {
+    #[serde(default)]
+    input_tokens: Option<u64>,
+    #[serde(default)]
+    output_tokens: Option<u64>,
+    #[serde(default)]
+    total_tokens: Option<u64>,
+    #[serde(default)]
+    cache_read_input_tokens: Option<u64>,
+    #[serde(default)]
+    cache_write_input_tokens: Option<u64>,
+}
+
+/// One message of the `application/vnd.amazon.eventstream` framing.
+struct EventMessage {
+    headers: Vec<(String, String)>,
+    payload: Vec<u8>,
+}
+
+impl BedrockBackend {
+    pub fn new(settings: ProviderSettings) -> Result<Self> {
+        let region = settings
+            .region
+            .clone()
+            .or_else(aws_region_from_env)
+            .unwrap_or_else(|| DEFAULT_BEDROCK_REGION.to_string());
+        let base_url = settings
+            .base_url
+            .clone()
+            .unwrap_or_else(|| format!("https://bedrock-runtime.{}.amazonaws.com", region));
+        let control_url = format!("https://bedrock.{}.amazonaws.com", region);
+        // Not every Bedrock model takes cache breakpoints, so they are opt-in here.
+        let mut anthropic = settings.clone();
+        anthropic.prompt_cache = Some(settings.prompt_cache.unwrap_or(false));
+        let anthropic = AnthropicBackend::new(anthropic)?;
+        Ok(Self {
+            base_url,
+            region,
+            max_tokens: settings.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            control_url,
+            credentials: settings.aws_credentials.clone(),
+            anthropic,
+            client: settings.http_client()?,
+        })
+    }
+
+    /// Model ids such as `anthropic.claude-3-5-sonnet-20240620-v1:0` are encoded in the path.
+    fn model_url(&self, model: &str, action: &str) -> String {
+        format!(
+            "{}/model/{}/{}",
+            self.base_url.trim_end_matches('/'),
+            aws_uri_encode(model),
+            action
+        )
+    }
+
+    fn models_url(&self) -> String {
+        format!(
+            "{}/foundation-models?byProvider=anthropic",
+            self.control_url.trim_end_matches('/')
+        )
+    }
+
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        url: &str,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response> {
+        let url = reqwest::Url::parse(url)?;
+        let credentials = match &self.credentials {
+            Some(credentials) => credentials.clone(),
+            None => AwsCredentials::from_env()?,
+        };
+        let headers = sign_request(
+            &credentials,
+            &self.region,
+            "bedrock",
+            method.as_str(),
+            &url,
+            &body,
+            Utc::now(),
+        );
+        let mut builder = self.client.request(method, url);
+        for (name, value) in headers {
+            builder = builder.header(name, value);
+        }
+        if !body.is_empty() {
+            builder = builder
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body);
+        }
+        let response = builder
+            .send()
+            .await
+            .map_err(|err| LlmError::network("bedrock", err))?;
+        if !response.status().is_success() {
+            return Err(LlmError::from_response("bedrock", response).await.into());
+        }
+        Ok(response)
+    }
+
+    fn converse_body(&self, request: &LlmRequest) -> Value {
+        let mut content = Vec::new();
+        if let Some(context) = request.context.as_deref().filter(|c| !c.trim().is_empty()) {
+            content.push(json!({ "text": context }));
+        }
+        content.push(json!({ "text": request.prompt }));
+        for image in &request.images {
+            let format = match image.media_type.trim_start_matches("image/") {
+                "jpg" => "jpeg",
+                other => other,
+            };
+            content.push(json!({
+                "image": { "format": format, "source": { "bytes": image.data_base64 } }
+            }));
+        }
+        let mut body = json!({ "messages": [{ "role": "user", "content": content }] });
+        if let Some(system) = request.system.as_deref().filter(|s| !s.trim().is_empty()) {
+            body["system"] = json!([{ "text": system }]);
+        }
+
+        let sampling = &request.sampling;
+        let thinking = request.thinking.is_enabled();
+        let budget = request.thinking.budget().max(1024);
+        let max_tokens = request.max_tokens.unwrap_or(self.max_tokens);
+        let mut inference = json!({
+            "maxTokens": if thinking { max_tokens.max(budget + 1024) } else { max_tokens },
+        });
+        if let Some(temperature) = sampling.temperature.filter(|_| !thinking) {
+            inference["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = sampling.top_p.filter(|p| !thinking || *p >= 0.95) {
+            inference["topP"] = json!(top_p);
+        }
+        if !sampling.stop.is_empty() {
+            inference["stopSequences"] = json!(sampling.stop);
+        }
+        body["inferenceConfig"] = inference;
+
+        let mut extra = serde_json::Map::new();
+        if let Some(top_k) = sampling.top_k.filter(|_| !thinking) {
+            extra.insert("top_k".to_string(), json!(top_k));
+        }
+        if thinking {
+            extra.insert(
+                "thinking".to_string(),
+                json!({ "type": "enabled", "budget_tokens": budget }),
+            );
+        }
+        if !extra.is_empty() {
+            body["additionalModelRequestFields"] = Value::Object(extra);
+        }
+
+        let mut tools = request
+            .tools
+            .iter()
+            .map(|tool| {
+                json!({ "toolSpec": {
+                    "name": tool.name,
+                    "description": tool.description,
+                    "inputSchema": { "json": tool.parameters },
+                } })
+            })
+            .collect::<Vec<_>>();
+        // Like the Anthropic API: the answer is the input of a forced `respond` call.
+        let schema = request.json_schema.as_ref().filter(|_| tools.is_empty());
+        if let Some(schema) = schema {
+            tools.push(json!({ "toolSpec": {
+                "name": RESPOND_TOOL,
+                "description": "Give the answer as the input of this tool.",
+                "inputSchema": { "json": schema },
+            } }));
+        }
+        if !tools.is_empty() {
+            body["toolConfig"] = json!({ "tools": tools });
+            if schema.is_some() && !thinking {
+                body["toolConfig"]["toolChoice"] = json!({ "tool": { "name": RESPOND_TOOL } });
+            }
+        }
+        body
+    }
+
+    fn parse_converse(request: &LlmRequest, body: ConverseResponse) -> LlmResponse {
+        let blocks = body
+            .output
+            .message
+            .map(|message| message.content)
+            .unwrap_or_default();
+        let mut content = String::new();
+        let mut thinking = LlmThinking::default();
+        let mut tool_calls = Vec::new();
+        for block in &blocks {
+            if let Some(text) = block.get("text").and_then(Value::as_str) {
+                content.push_str(text);
+            } else if let Some(tool_use) = block.get("toolUse") {
+                if let Some(name) = tool_use.get("name").and_then(Value::as_str) {
+                    tool_calls.push(LlmToolCall {
+                        name: name.to_string(),
+                        arguments: tool_use.get("input").cloned().unwrap_or(Value::Null),
+                    });
+                }
+            } else if let Some(reasoning) = block
+                .get("reasoningContent")
+                .and_then(|reasoning| reasoning.get("reasoningText"))
+            {
+                thinking.push(
+                    reasoning
+                        .get("text")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default(),
+                    reasoning.get("signature").and_then(Value::as_str),
+                );
+            }
+        }
+        if request.json_schema.is_some() {
+            if let Some(index) = tool_calls.iter().position(|call| call.name == RESPOND_TOOL) {
+                content = tool_calls.remove(index).arguments.to_string();
+            }
+        }
+        LlmResponse {
+            content,
+            thinking: (!thinking.is_empty()).then_some(thinking),
+            tool_calls,
+            usage: body.usage.map(|usage| LlmUsage {
+                provider: "bedrock".to_string(),
+                model: None,
+                input_tokens: usage.input_tokens,
+                output_tokens: usage.output_tokens,
+                total_tokens: usage.total_tokens.or_else(|| {
+                    usage
+                        .input_tokens
+                        .zip(usage.output_tokens)
+                        .map(|(input, output)| input + output)
+                }),
+                cache_creation_input_tokens: usage.cache_write_input_tokens,
+                cache_read_input_tokens: usage.cache_read_input_tokens,
+                reasoning_tokens: None,
+                cost_usd: None,
+                raw: None,
+            }),
+        }
+    }
+
+    /// Frames are `total length | headers length | prelude crc | headers | payload | crc`,
+    /// lengths big-endian. The CRCs are not checked; TLS already guards the bytes.
+    fn take_event_message(buffer: &mut Vec<u8>) -> Result<Option<EventMessage>> {
+        if buffer.len() < 12 {
+            return Ok(None);
+        }
+        let total = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+        let headers_len = u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]) as usize;
+        if total < 16 + headers_len {
+            return Err(anyhow!("malformed bedrock event stream frame"));
+        }
+        if buffer.len() < total {
+            return Ok(None);
+        }
+        let frame: Vec<u8> = buffer.drain(..total).collect();
+        Ok(Some(EventMessage {
+            headers: Self::parse_event_headers(&frame[12..12 + headers_len])?,
+            payload: frame[12 + headers_len..total - 4].to_vec(),
+        }))
+    }
+
+    /// Keeps the string headers (`:event-type`, `:message-type`, ...) and skips the rest.
+    fn parse_event_headers(mut bytes: &[u8]) -> Result<Vec<(String, String)>> {
+        let malformed = || anyhow!("malformed bedrock event stream headers");
+        let mut headers = Vec::new();
+        while let Some((&name_len, rest)) = bytes.split_first() {
+            let name_len = name_len as usize;
+            let name = rest.get(..name_len).ok_or_else(malformed)?;
+            let (&kind, rest) = rest[name_len..].split_first().ok_or_else(malformed)?;
+            let size = match kind {
+                0 | 1 => 0,
+                2 => 1,
+                3 => 2,
+                4 => 4,
+                5 | 8 => 8,
+                9 => 16,
+                6 | 7 => {
+                    let len = rest.get(..2).ok_or_else(malformed)?;
+                    2 + u16::from_be_bytes([len[0], len[1]]) as usize
+                }
+                _ => return Err(malformed()),
+            };
+            let value = rest.get(..size).ok_or_else(malformed)?;
+            if kind == 7 {
+                headers.push((
+                    String::from_utf8_lossy(name).to_string(),
+                    String::from_utf8_lossy(&value[2..]).to_string(),
+                ));
+            }
+            bytes = &rest[size..];
+        }
+        Ok(headers)
+    }
+
+    /// A `chunk` carries one Anthropic stream event, base64-encoded.
+    fn parse_event(
+        message: &EventMessage,
+        answer_blocks: &mut AnswerBlocks,
+    ) -> Result<Option<LlmStreamEvent>> {
+        let header = |name: &str| {
+            message
+                .headers
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        let payload: Value = serde_json::from_slice(&message.payload).unwrap_or(Value::Null);
+        if matches!(header(":message-type"), Some("exception" | "error")) {
+            let kind = header(":exception-type")
+                .or(header(":error-code"))
+                .unwrap_or("exception");
+            let text = payload
+                .get("message")
+                .and_then(Value::as_str)
+                .or(header(":error-message"))
+                .unwrap_or(kind);
+            let error = json!({ "type": kind, "message": text });
+            return Err(LlmError::from_stream_error("bedrock", &error).into());
+        }
+        if header(":event-type") != Some("chunk") {
+            return Ok(None);
+        }
+        let bytes = payload
+            .get("bytes")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("bedrock chunk without bytes"))?;
+        let data = base64::engine::general_purpose::STANDARD.decode(bytes)?;
+        let mut event =
+            AnthropicBackend::parse_stream_event(&String::from_utf8_lossy(&data), answer_blocks)?;
+        if let Some(LlmStreamEvent::Usage(usage)) = event.as_mut() {
+            usage.provider = "bedrock".to_string();
+        }
+        Ok(event)
+    }
+}
+
+#[async_trait::async_trait]
+impl LlmBackend for BedrockBackend {
+    fn provider(&self) -> LlmProvider {
+        LlmProvider::Bedrock
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
+    fn supports_json_schema(&self) -> bool {
+        true
+    }
+
+    /// `foundation-models` leaves out inference profiles such as `us.anthropic.…`.
+    fn lists_served_models(&self) -> bool {
+        false
+    }
+
+    fn unsupported_sampling(&self, request: &LlmRequest) -> Vec<&'static str> {
+        self.anthropic.unsupported_sampling(request)
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let response = self
+            .send(reqwest::Method::GET, &self.models_url(), Vec::new())
+            .await?;
+        let body: Value = response.json().await?;
+        Ok(model_ids(&body["modelSummaries"], "modelId"))
+    }
+
+    async fn generate(&self, model: &str, request: &LlmRequest) -> Result<LlmResponse> {
+        let body = serde_json::to_vec(&self.converse_body(request))?;
+        let response = self
+            .send(
+                reqwest::Method::POST,
+                &self.model_url(model, "converse"),
+                body,
+            )
+            .await?;
+        let body: ConverseResponse = response.json().await?;
+        Ok(Self::parse_converse(request, body))
+    }
+
+    async fn generate_stream(&self, model: &str, request: &LlmRequest) -> Result<LlmStream> {
+        let body = serde_json::to_vec(&self.anthropic.bedrock_body(request)?)?;
+        let url = self.model_url(model, "invoke-with-response-stream");
+        let response = self.send(reqwest::Method::POST, &url, body).await?;
+
+        struct StreamState {
+            stream: BoxStream<'static, Result<Bytes, reqwest::Error>>,
+            buffer: Vec<u8>,
+            usage: Option<LlmUsage>,
+            answer_blocks: AnswerBlocks,
+            finished: bool,
+        }
+
+        let state = StreamState {
+            stream: Box::pin(response.bytes_stream()),
+            buffer: Vec::new(),
+            usage: None,
+            answer_blocks: AnswerBlocks::default(),
+            finished: false,
+        };
+
+        let output = stream::unfold(state, |mut state| async move {
+            if state.finished {
+                return None;
+            }
+
+            loop {
+                let event =
+                    BedrockBackend::take_event_message(&mut state.buffer).and_then(|message| {
+                        match message {
+                            Some(message) => {
+                                BedrockBackend::parse_event(&message, &mut state.answer_blocks)
+                                    .map(Some)
+                            }
+                            None => Ok(None),
+                        }
+                    });
+                match event {
+                    Ok(Some(Some(LlmStreamEvent::Usage(usage)))) => {
+                        state.usage =
+                            Some(AnthropicBackend::merge_usage(state.usage.take(), usage));
+                        continue;
+                    }
+                    Ok(Some(Some(event))) => return Some((Ok(event), state)),
+                    Ok(Some(None)) => continue,
+                    Ok(None) => {}
+                    Err(err) => {
+                        state.finished = true;
+                        return Some((Err(err), state));
+                    }
+                }
+
+                match state.stream.next().await {
+                    Some(Ok(chunk)) => state.buffer.extend_from_slice(&chunk),
+                    Some(Err(err)) => {
+                        state.finished = true;
+                        let err = LlmError::new(
+                            LlmErrorKind::StreamInterrupted,
+                            "bedrock",
+                            err.to_string(),
+                        );
+                        return Some((Err(err.into()), state));
+                    }
+                    None => {
+                        state.finished = true;
+                        let usage = state.usage.take()?;
+                        return Some((Ok(LlmStreamEvent::Usage(usage)), state));
+                    }
+                }
+            }
+        });
+
+        Ok(Box::pin(output) as BoxStream<'static, Result<LlmStreamEvent>>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::stub::serve_once;
+
+    fn frame(string_headers: &[(&str, &str)], payload: &Value) -> Vec<u8> {
+        let mut headers = Vec::new();
+        for (name, value) in string_headers {
+            headers.push(name.len() as u8);
+            headers.extend_from_slice(name.as_bytes());
+            headers.push(7);
+            headers.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            headers.extend_from_slice(value.as_bytes());
+        }
+        let payload = payload.to_string().into_bytes();
+        let total = 16 + headers.len() + payload.len();
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&(total as u32).to_be_bytes());
+        frame.extend_from_slice(&(headers.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&[0; 4]);
+        frame.extend_from_slice(&headers);
+        frame.extend_from_slice(&payload);
+        frame.extend_from_slice(&[0; 4]);
+        frame
+    }
+
+    fn chunk(event: Value) -> Vec<u8> {
+        let bytes = base64::engine::general_purpose::STANDARD.encode(event.to_string());
+        frame(
+            &[(":event-type", "chunk"), (":message-type", "event")],
+            &json!({ "bytes": bytes }),
+        )
+    }
+
+    fn backend(base_url: &str) -> BedrockBackend {
+        let mut settings = ProviderSettings::default();
+        settings.aws_credentials = Some(AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "secret".to_string(),
+            session_token: None,
+        });
+        settings.base_url = Some(base_url.to_string());
+        settings.region = Some("us-west-2".to_string());
+        settings.max_tokens = Some(512);
+        BedrockBackend::new(settings).unwrap()
+    }
+
+    #[tokio::test]
+    async fn converses_with_a_signed_request() {
+        let answer = json!({
+            "output": { "message": { "role": "assistant", "content": [
+                { "reasoningContent": { "reasoningText": { "text": "hmm", "signature": "sig" } } },
+                { "toolUse": { "toolUseId": "t1", "name": RESPOND_TOOL, "input": { "ok": true } } },
+            ] } },
+            "usage": { "inputTokens": 10, "outputTokens": 3, "totalTokens": 13 },
+            "stopReason": "tool_use",
+        });
+        let (base_url, server) =
+            serve_once("application/json", answer.to_string().into_bytes()).await;
+        let request = LlmRequest {
+            system: Some("be brief".to_string()),
+            json_schema: Some(json!({ "type": "object" })),
+            ..LlmRequest::text("check")
+        };
+        let backend = backend(&base_url);
+        assert_eq!(
+            backend.models_url(),
+            "https://bedrock.us-west-2.amazonaws.com/foundation-models?byProvider=anthropic"
+        );
+        let response = backend
+            .generate("anthropic.claude-3-5-sonnet-20240620-v1:0", &request)
+            .await
+            .unwrap();
+        assert_eq!(response.content, r#"{"ok":true}"#);
+        assert_eq!(response.thinking.unwrap().text, "hmm");
+        let usage = response.usage.unwrap();
+        assert_eq!(
+            (usage.provider.as_str(), usage.total_tokens),
+            ("bedrock", Some(13))
+        );
+
+        let raw = server.await.unwrap();
+        assert!(raw.starts_with(
+            "POST /model/anthropic.claude-3-5-sonnet-20240620-v1%3A0/converse HTTP/1.1"
+        ));
+        assert!(raw.contains("authorization: AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"));
+        assert!(raw.contains("/us-west-2/bedrock/aws4_request"));
+        let body: Value = serde_json::from_str(raw.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["system"][0]["text"], "be brief");
+        assert_eq!(body["messages"][0]["content"][0]["text"], "check");
+        assert_eq!(body["inferenceConfig"]["maxTokens"], 512);
+        assert_eq!(
+            body["toolConfig"]["toolChoice"]["tool"]["name"],
+            RESPOND_TOOL
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_anthropic_events_from_the_event_stream() {
+        let mut stream_body = Vec::new();
+        stream_body.extend(chunk(json!({
+            "type": "message_start",
+            "message": { "usage": { "input_tokens": 7, "output_tokens": 1 } },
+        })));
+        stream_body.extend(chunk(json!({
+            "type": "content_block_delta",
+            "delta": { "type": "text_delta", "text": "Hel" },
+        })));
+        stream_body.extend(chunk(json!({
+            "type": "content_block_delta",
+            "delta": { "type": "text_delta", "text": "lo" },
+        })));
+        stream_body.extend(chunk(json!({
+            "type": "message_delta",
+            "usage": { "output_tokens": 2 },
+        })));
+        let (base_url, server) =
+            serve_once("application/vnd.amazon.eventstream", stream_body).await;
+        let mut stream = backend(&base_url)
+            .generate_stream(
+                "anthropic.claude-3-haiku-20240307-v1:0",
+                &LlmRequest::text("hi"),
+            )
+            .await
+            .unwrap();
+        let mut text = String::new();
+        let mut usage = None;
+        while let Some(event) = stream.next().await {
+            match event.unwrap() {
+                LlmStreamEvent::Text(delta) => text.push_str(&delta),
+                LlmStreamEvent::Usage(total) => usage = Some(total),
+                _ => {}
+            }
+        }
+        assert_eq!(text, "Hello");
+        let usage = usage.unwrap();
+        assert_eq!(usage.provider, "bedrock");
+        assert_eq!(
+            (usage.input_tokens, usage.output_tokens),
+            (Some(7), Some(2))
+        );
+
+        let raw = server.await.unwrap();
+        assert!(raw.contains("/invoke-with-response-stream HTTP/1.1"));
+        let body: Value = serde_json::from_str(raw.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["anthropic_version"], "bedrock-2023-05-31");
+        assert!(body.get("model").is_none() && body.get("stream").is_none());
+
+        let mut throttled = frame(
+            &[
+                (":exception-type", "throttlingException"),
+                (":message-type", "exception"),
+            ],
+            &json!({ "message": "Too many requests" }),
+        );
+        let message = BedrockBackend::take_event_message(&mut throttled)
+            .unwrap()
+            .unwrap();
+        let err = BedrockBackend::parse_event(&message, &mut AnswerBlocks::default()).unwrap_err();
+        let err = err.downcast::<LlmError>().unwrap();
+        assert_eq!(err.kind, LlmErrorKind::RateLimited);
+        assert!(throttled.is_empty());
+    }
+}
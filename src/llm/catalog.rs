@@ -0,0 +1,258 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::llm::{format_tokens, LlmUsage};
+
+/// What Tengu knows about one model. Prices are USD per million tokens.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct ModelInfo {
+    pub provider: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    pub context_window: Option<u64>,
+    pub max_output: Option<u32>,
+    #[serde(default)]
+    pub vision: bool,
+    #[serde(default)]
+    pub tools: bool,
+    #[serde(default)]
+    pub thinking: bool,
+    pub input_price: Option<f64>,
+    pub output_price: Option<f64>,
+    pub cache_read_price: Option<f64>,
+    pub cache_write_price: Option<f64>,
+}
+
+impl ModelInfo {
+    /// Tokens the conversation context may take: half of what is left after the answer,
+    /// so the system prompt and tool results still fit.
+    pub fn context_budget(&self) -> Option<u64> {
+        let window = self.context_window?;
+        Some(window.saturating_sub(self.max_output.unwrap_or(0) as u64) / 2)
+    }
+
+    /// Tokens one tool result may take in the answer request.
+    pub fn tool_result_budget(&self) -> Option<u64> {
+        self.context_budget().map(|budget| budget / 2)
+    }
+
+    /// Input tokens a request may use: the window less room for the answer, which takes at
+    /// most a quarter of it.
+    pub fn input_limit(&self) -> Option<u64> {
+        let window = self.context_window?;
+        let reserve = (self.max_output.unwrap_or(0) as u64).min(window / 4);
+        Some(window - reserve)
+    }
+
+    /// Best-effort price of one request. Cache prices fall back to the input price.
+    pub fn cost_usd(&self, usage: &LlmUsage) -> Option<f64> {
+        let input_price = self.input_price?;
+        let output_price = self.output_price?;
+        if usage.input_tokens.is_none() && usage.output_tokens.is_none() {
+            return None;
+        }
+        let input = usage.input_tokens.unwrap_or(0);
+        let cache_read = usage.cache_read_input_tokens.unwrap_or(0);
+        let cache_write = usage.cache_creation_input_tokens.unwrap_or(0);
+        // Anthropic reports cached tokens apart from `input_tokens`; the others include them.
+        let uncached = if usage.provider == "anthropic" {
+            input
+        } else {
+            input.saturating_sub(cache_read).saturating_sub(cache_write)
+        };
+        let mut output = usage.output_tokens.unwrap_or(0);
+        // Gemini bills thoughts as output but leaves them out of `candidatesTokenCount`.
+        if usage.provider == "google" {
+            output += usage.reasoning_tokens.unwrap_or(0);
+        }
+        let per_token = |tokens: u64, price: f64| tokens as f64 * price / 1_000_000.0;
+        Some(
+            per_token(uncached, input_price)
+                + per_token(cache_read, self.cache_read_price.unwrap_or(input_price))
+                + per_token(cache_write, self.cache_write_price.unwrap_or(input_price))
+                + per_token(output, output_price),
+        )
+    }
+
+    /// One line for model pickers: "200k ctx, 64k out, vision, tools, thinking, $3/$15 per Mtok".
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(window) = self.context_window {
+            parts.push(format!("{} ctx", format_tokens(window)));
+        }
+        if let Some(max_output) = self.max_output {
+            parts.push(format!("{} out", format_tokens(max_output as u64)));
+        }
+        for (enabled, name) in [
+            (self.vision, "vision"),
+            (self.tools, "tools"),
+            (self.thinking, "thinking"),
+        ] {
+            if enabled {
+                parts.push(name.to_string());
+            }
+        }
+        if let (Some(input), Some(output)) = (self.input_price, self.output_price) {
+            parts.push(format!("${}/${} per Mtok", input, output));
+        }
+        parts.join(", ")
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.name.as_str()).chain(self.aliases.iter().map(String::as_str))
+    }
+}
+
+/// The built-in `models.toml`, extended by `~/.tengu/models.toml` and `./.tengu/models.toml`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ModelCatalog {
+    #[serde(default, rename = "model")]
+    pub models: Vec<ModelInfo>,
+}
+
+impl ModelCatalog {
+    pub fn builtin() -> Self {
+        toml::from_str(include_str!("models.toml")).unwrap_or_default()
+    }
+
+    /// Unreadable or invalid user files are skipped, like `config.toml`.
+    pub fn load() -> Self {
+        let mut catalog = Self::builtin();
+        let mut paths = Vec::new();
+        if let Some(home) = std::env::var_os("HOME") {
+            paths.push(PathBuf::from(home).join(".tengu").join("models.toml"));
+        }
+        paths.push(PathBuf::from(".").join(".tengu").join("models.toml"));
+        for path in paths {
+            if let Some(extra) = Self::read(&path) {
+                catalog.extend(extra);
+            }
+        }
+        catalog
+    }
+
+    fn read(path: &Path) -> Option<Self> {
+        let content = std::fs::read_to_string(path).ok()?;
+        toml::from_str(&content).ok()
+    }
+
+    /// Entries with the same provider and name replace existing ones.
+    pub fn extend(&mut self, other: ModelCatalog) {
+        for model in other.models {
+            match self
+                .models
+                .iter_mut()
+                .find(|m| m.provider == model.provider && m.name == model.name)
+            {
+                Some(existing) => *existing = model,
+                None => self.models.push(model),
+            }
+        }
+    }
+
+    pub fn has_provider(&self, provider: &str) -> bool {
+        self.models.iter().any(|m| m.provider == provider)
+    }
+
+    /// Exact name or alias first, then the longest name that prefixes a dated or tagged
+    /// variant (`gpt-4o-2024-08-06`, `llama3.2:3b`).
+    pub fn find(&self, provider: &str, model: &str) -> Option<&ModelInfo> {
+        let candidates = || self.models.iter().filter(|m| m.provider == provider);
+        if let Some(exact) = candidates().find(|m| m.names().any(|name| name == model)) {
+            return Some(exact);
+        }
+        candidates()
+            .filter_map(|m| {
+                m.names()
+                    .filter(|name| {
+                        model.strip_prefix(name).is_some_and(|rest| {
+                            rest.starts_with('-') || rest.starts_with(':') || rest.starts_with('@')
+                        })
+                    })
+                    .map(str::len)
+                    .max()
+                    .map(|len| (len, m))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, m)| m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_models_by_alias_and_dated_name() {
+        let catalog = ModelCatalog::builtin();
+        assert!(catalog.models.len() > 10);
+        let sonnet = catalog.find("anthropic", "claude-sonnet-4").unwrap();
+        assert_eq!(sonnet.name, "claude-sonnet-4-20250514");
+        assert_eq!(
+            catalog
+                .find("openai", "gpt-4o-mini-2024-07-18")
+                .unwrap()
+                .name,
+            "gpt-4o-mini"
+        );
+        assert_eq!(catalog.find("openai", "o3-mini").unwrap().name, "o3-mini");
+        assert!(catalog.find("openai", "gpt-4oo").is_none());
+        assert!(catalog.find("google", "claude-sonnet-4").is_none());
+        assert_eq!(
+            sonnet.summary(),
+            "200k ctx, 64k out, vision, tools, thinking, $3/$15 per Mtok"
+        );
+    }
+
+    #[test]
+    fn user_entries_replace_builtin_ones() {
+        let mut catalog = ModelCatalog::builtin();
+        let extra: ModelCatalog = toml::from_str(
+            r#"
+            [[model]]
+            provider = "openai"
+            name = "gpt-4o"
+            context_window = 1000
+            [[model]]
+            provider = "ollama"
+            name = "qwen2.5-coder"
+            context_window = 32768
+            tools = true
+            "#,
+        )
+        .unwrap();
+        catalog.extend(extra);
+        assert_eq!(
+            catalog.find("openai", "gpt-4o").unwrap().context_window,
+            Some(1000)
+        );
+        assert!(catalog.find("ollama", "qwen2.5-coder:7b").unwrap().tools);
+    }
+
+    #[test]
+    fn estimates_cost_per_provider() {
+        let catalog = ModelCatalog::builtin();
+        let usage = LlmUsage {
+            provider: "anthropic".to_string(),
+            input_tokens: Some(1_000),
+            output_tokens: Some(1_000),
+            cache_read_input_tokens: Some(10_000),
+            ..Default::default()
+        };
+        let sonnet = catalog.find("anthropic", "claude-sonnet-4").unwrap();
+        let cost = sonnet.cost_usd(&usage).unwrap();
+        assert!((cost - (0.003 + 0.015 + 0.003)).abs() < 1e-9);
+
+        let usage = LlmUsage {
+            provider: "openai".to_string(),
+            input_tokens: Some(2_000_000),
+            output_tokens: Some(0),
+            cache_read_input_tokens: Some(1_000_000),
+            ..Default::default()
+        };
+        let gpt = catalog.find("openai", "gpt-4o").unwrap();
+        assert!((gpt.cost_usd(&usage).unwrap() - 3.75).abs() < 1e-9);
+        assert!(gpt.cost_usd(&LlmUsage::default()).is_none());
+    }
+}
@@ -1,6 +1,16 @@
 use anyhow::{anyhow, Result};
-use futures_util::stream::BoxStream;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::llm::{
+    estimate_request_tokens, parse_structured, schema_instruction, schema_retry_prompt, LlmError,
+    LlmErrorKind, ModelCatalog, ModelInfo, SchemaMismatch,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LlmProvider {
@@ -8,6 +18,11 @@ pub enum LlmProvider {
     OpenAI,
     Google,
     Local,
+    OpenAiCompatible,
+    Azure,
+    Bedrock,
+    Replay,
+    Mock,
 }
 
 impl LlmProvider {
@@ -16,66 +31,481 @@ impl LlmProvider {
             "anthropic" => Ok(Self::Anthropic),
             "openai" => Ok(Self::OpenAI),
             "google" | "gemini" => Ok(Self::Google),
-            "local" | "ollama" | "lm-studio" | "lmstudio" => Ok(Self::Local),
+            "local" | "ollama" => Ok(Self::Local),
+            "openai-compatible" | "openai_compatible" | "lm-studio" | "lmstudio" | "vllm"
+            | "llama.cpp" | "llamacpp" | "llama-cpp" => Ok(Self::OpenAiCompatible),
+            "azure" | "azure-openai" | "azure_openai" => Ok(Self::Azure),
+            "bedrock" | "aws-bedrock" | "aws_bedrock" => Ok(Self::Bedrock),
+            "replay" => Ok(Self::Replay),
+            "mock" => Ok(Self::Mock),
             other => Err(anyhow!("unsupported provider: {}", other)),
         }
     }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Anthropic => "anthropic",
+            Self::OpenAI => "openai",
+            Self::Google => "google",
+            Self::Local => "ollama",
+            Self::OpenAiCompatible => "openai-compatible",
+            Self::Azure => "azure",
+            Self::Bedrock => "bedrock",
+            Self::Replay => "replay",
+            Self::Mock => "mock",
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct LlmResponse {
     pub content: String,
     pub usage: Option<LlmUsage>,
+    pub thinking: Option<LlmThinking>,
+    /// Native tool calls, only filled by backends whose `supports_tools` is true.
+    pub tool_calls: Vec<LlmToolCall>,
 }
 
+/// A function the model may call natively; `parameters` is a JSON Schema object.
 #[derive(Debug, Clone)]
+pub struct LlmTool {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LlmToolCall {
+    pub name: String,
+    pub arguments: Value,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct LlmUsage {
     pub provider: String,
+    /// The model that served the request, set by `LlmClient` (a fallback may have answered).
+    pub model: Option<String>,
     pub input_tokens: Option<u64>,
     pub output_tokens: Option<u64>,
     pub total_tokens: Option<u64>,
     pub cache_creation_input_tokens: Option<u64>,
     pub cache_read_input_tokens: Option<u64>,
     pub reasoning_tokens: Option<u64>,
+    /// Estimated from the model catalog; `None` for models without prices.
+    pub cost_usd: Option<f64>,
     pub raw: Option<Value>,
 }
 
 #[derive(Debug, Clone)]
 pub enum LlmStreamEvent {
     Text(String),
+    /// A reasoning delta. The signature arrives separately, with empty text, at block end.
+    Thinking {
+        text: String,
+        signature: Option<String>,
+    },
     Usage(LlmUsage),
 }
 
+/// Reasoning returned with an answer. Anthropic needs `signature` to accept the block
+/// again in a later turn.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LlmThinking {
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl LlmThinking {
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty() && self.signature.is_none()
+    }
+
+    pub fn push(&mut self, text: &str, signature: Option<&str>) {
+        self.text.push_str(text);
+        if let Some(signature) = signature {
+            self.signature = Some(signature.to_string());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LlmRole {
+    User,
+    Assistant,
+}
+
+/// One earlier turn of the conversation, with the thinking that came with an answer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlmTurn {
+    pub role: LlmRole,
+    pub content: String,
+    pub thinking: Option<LlmThinking>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+impl ReasoningEffort {
+    pub fn from_str(input: &str) -> Result<Self> {
+        match input.trim().to_ascii_lowercase().as_str() {
+            "low" => Ok(Self::Low),
+            "medium" => Ok(Self::Medium),
+            "high" => Ok(Self::High),
+            other => Err(anyhow!("unsupported reasoning effort: {}", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::High => "high",
+        }
+    }
+}
+
+/// Either a token budget (Anthropic, Gemini) or an effort level (OpenAI); each backend
+/// converts whichever is set into its own form.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThinkingParams {
+    pub budget_tokens: Option<u32>,
+    pub effort: Option<ReasoningEffort>,
+}
+
+impl ThinkingParams {
+    pub fn is_enabled(&self) -> bool {
+        self.budget_tokens.is_some() || self.effort.is_some()
+    }
+
+    pub fn budget(&self) -> u32 {
+        if let Some(budget) = self.budget_tokens {
+            return budget;
+        }
+        match self.effort.unwrap_or(ReasoningEffort::Medium) {
+            ReasoningEffort::Low => 2048,
+            ReasoningEffort::Medium => 8192,
+            ReasoningEffort::High => 24576,
+        }
+    }
+
+    pub fn effort(&self) -> ReasoningEffort {
+        if let Some(effort) = self.effort {
+            return effort;
+        }
+        match self.budget() {
+            0..=4095 => ReasoningEffort::Low,
+            4096..=16383 => ReasoningEffort::Medium,
+            _ => ReasoningEffort::High,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct LlmImage {
     pub media_type: String,
     pub data_base64: String,
 }
 
-#[derive(Debug, Clone)]
+/// Temperature, nucleus/top-k sampling, stop sequences and seed. `None` leaves the
+/// provider default in place.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SamplingParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+impl SamplingParams {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Fields set in `over` win.
+    pub fn merged(&self, over: &SamplingParams) -> SamplingParams {
+        SamplingParams {
+            temperature: over.temperature.or(self.temperature),
+            top_p: over.top_p.or(self.top_p),
+            top_k: over.top_k.or(self.top_k),
+            stop: if over.stop.is_empty() {
+                self.stop.clone()
+            } else {
+                over.stop.clone()
+            },
+            seed: over.seed.or(self.seed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct LlmRequest {
     pub prompt: String,
+    pub system: Option<String>,
+    /// Conversation so far, sent ahead of `prompt`. It stays the same across the calls of a
+    /// turn, so backends with prompt caching mark it as a cache breakpoint.
+    pub context: Option<String>,
+    /// The same conversation as `context`, turn by turn. Backends that replay thinking
+    /// (Anthropic) send these messages in place of `context`.
+    pub history: Vec<LlmTurn>,
     pub images: Vec<LlmImage>,
+    /// Output limit for this request, in place of the provider's `max_tokens`.
+    pub max_tokens: Option<u32>,
+    pub sampling: SamplingParams,
+    pub thinking: ThinkingParams,
+    pub tools: Vec<LlmTool>,
+    /// Asks for a bare JSON answer where the backend has a JSON mode (Ollama `format`).
+    pub json_output: bool,
+    /// Constrains the answer to JSON matching this schema. `LlmClient::generate` checks it
+    /// locally and asks again when the answer does not match.
+    pub json_schema: Option<Value>,
 }
 
 impl LlmRequest {
     pub fn text(prompt: impl Into<String>) -> Self {
         Self {
             prompt: prompt.into(),
-            images: Vec::new(),
+            ..Default::default()
+        }
+    }
+
+    /// Puts `context` and `system` back into the prompt for backends that take a single text.
+    pub fn fold_prompt(&mut self) {
+        self.history.clear();
+        if let Some(context) = self.context.take().filter(|c| !c.trim().is_empty()) {
+            self.prompt = format!("{}\n\n{}", context, self.prompt);
+        }
+        if let Some(system) = self.system.take().filter(|s| !s.trim().is_empty()) {
+            self.prompt = format!(
+                "System instructions:\n{}\n\nUser request:\n{}",
+                system, self.prompt
+            );
         }
     }
 }
 
 pub type LlmStream = BoxStream<'static, Result<LlmStreamEvent>>;
 
+/// Share of the input limit past which the local estimate is checked with the provider.
+const COUNT_THRESHOLD: f64 = 0.8;
+
+/// Server-provided waits longer than this are not worth blocking the turn for.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Extra attempts when an answer does not match `LlmRequest::json_schema`.
+const MAX_SCHEMA_RETRIES: usize = 2;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 4,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(32),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with jitter in `[delay/2, delay]`; `Retry-After` wins when present.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(wait) = retry_after {
+            return wait;
+        }
+        let exp = self
+            .base_delay
+            .saturating_mul(1u32 << attempt.min(16))
+            .min(self.max_delay);
+        let jitter = (uuid::Uuid::new_v4().as_u128() % 1000) as f64 / 1000.0;
+        exp.mul_f64(0.5 + jitter / 2.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LlmRetryNotice {
+    pub attempt: u32,
+    pub max_retries: u32,
+    pub delay: Duration,
+    pub error: LlmError,
+}
+
+pub type LlmRetryHandler = Arc<dyn Fn(&LlmRetryNotice) + Send + Sync>;
+pub type LlmWarningHandler = Arc<dyn Fn(&str) + Send + Sync>;
+
 pub struct LlmClient {
     backend: Box<dyn LlmBackend + Send + Sync>,
+    retry: RetryPolicy,
+    retry_handler: Mutex<Option<LlmRetryHandler>>,
+    sampling: SamplingParams,
+    thinking: ThinkingParams,
+    warning_handler: Mutex<Option<LlmWarningHandler>>,
+    warned: Mutex<HashSet<&'static str>>,
+    catalog: Arc<ModelCatalog>,
+    fallbacks: Vec<LlmFallback>,
+}
+
+struct LlmFallback {
+    /// `[providers.*]` name, for the failover warning.
+    provider_name: String,
+    client: LlmClient,
+    model: String,
 }
 
 impl LlmClient {
     pub fn new(backend: Box<dyn LlmBackend + Send + Sync>) -> Self {
-        Self { backend }
+        Self {
+            backend,
+            retry: RetryPolicy::default(),
+            retry_handler: Mutex::new(None),
+            sampling: SamplingParams::default(),
+            thinking: ThinkingParams::default(),
+            warning_handler: Mutex::new(None),
+            warned: Mutex::new(HashSet::new()),
+            catalog: Arc::new(ModelCatalog::default()),
+            fallbacks: Vec::new(),
+        }
+    }
+
+    /// Tried in order, with `model`, when this client gives up on a retryable error.
+    pub fn with_fallback(mut self, provider_name: &str, client: LlmClient, model: String) -> Self {
+        self.fallbacks.push(LlmFallback {
+            provider_name: provider_name.to_string(),
+            client,
+            model,
+        });
+        self
+    }
+
+    pub fn with_catalog(mut self, catalog: Arc<ModelCatalog>) -> Self {
+        self.catalog = catalog;
+        self
+    }
+
+    pub fn model_info(&self, model: &str) -> Option<ModelInfo> {
+        self.catalog
+            .find(self.backend.provider().as_str(), model)
+            .cloned()
+    }
+
+    pub async fn list_models(&self) -> Result<Vec<String>> {
+        self.backend.list_models().await
+    }
+
+    /// Catalog names for the backend's provider, for when the server cannot be asked.
+    pub fn catalog_models(&self) -> Vec<String> {
+        let provider = self.backend.provider().as_str();
+        self.catalog
+            .models
+            .iter()
+            .filter(|m| m.provider == provider)
+            .map(|m| m.name.clone())
+            .collect()
+    }
+
+    /// Accepts a model the catalog knows or the server lists. When the server cannot be
+    /// asked, only catalog-covered providers reject unknown names.
+    pub async fn check_model(&self, model: &str) -> Result<()> {
+        let provider = self.backend.provider().as_str();
+        if self.catalog.find(provider, model).is_some() {
+            return Ok(());
+        }
+        if !self.backend.lists_served_models() {
+            return self.validate_model(model);
+        }
+        match self.backend.list_models().await {
+            Ok(models) if !models.is_empty() => {
+                let tagged = format!("{}:latest", model);
+                if models.iter().any(|id| id == model || *id == tagged) {
+                    Ok(())
+                } else {
+                    Err(anyhow!("{} does not serve model {}", provider, model))
+                }
+            }
+            _ => self.validate_model(model),
+        }
+    }
+
+    fn validate_model(&self, model: &str) -> Result<()> {
+        let provider = self.backend.provider().as_str();
+        if !self.catalog.has_provider(provider) || self.catalog.find(provider, model).is_some() {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "unknown {} model: {} (add it to ~/.tengu/models.toml to use it)",
+            provider,
+            model
+        ))
+    }
+
+    fn check_request(&self, model: &str, request: &LlmRequest) -> Result<Option<ModelInfo>> {
+        let info = self.model_info(model);
+        if !request.images.is_empty() && info.as_ref().is_some_and(|info| !info.vision) {
+            return Err(anyhow!("model {} does not support images", model));
+        }
+        Ok(info)
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Defaults for every request; values already set on a request win.
+    pub fn with_sampling(mut self, sampling: SamplingParams) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    /// Default thinking budget or effort for requests that do not set one.
+    pub fn with_thinking(mut self, thinking: ThinkingParams) -> Self {
+        self.thinking = thinking;
+        self
+    }
+
+    pub fn set_warning_handler(&self, handler: LlmWarningHandler) {
+        for fallback in &self.fallbacks {
+            fallback.client.set_warning_handler(handler.clone());
+        }
+        if let Ok(mut guard) = self.warning_handler.lock() {
+            *guard = Some(handler);
+        }
+    }
+
+    /// Copies the retry and warning handlers of the client this one replaces.
+    pub fn inherit_handlers(&self, other: &LlmClient) {
+        if let Some(handler) = other.retry_handler.lock().ok().and_then(|h| h.clone()) {
+            self.set_retry_handler(handler);
+        }
+        if let Some(handler) = other.warning_handler.lock().ok().and_then(|h| h.clone()) {
+            self.set_warning_handler(handler);
+        }
+    }
+
+    pub fn set_retry_handler(&self, handler: LlmRetryHandler) {
+        for fallback in &self.fallbacks {
+            fallback.client.set_retry_handler(handler.clone());
+        }
+        if let Ok(mut guard) = self.retry_handler.lock() {
+            *guard = Some(handler);
+        }
     }
 
     #[allow(dead_code)]
@@ -83,12 +513,284 @@ impl LlmClient {
         self.backend.provider()
     }
 
+    pub fn supports_tools(&self) -> bool {
+        self.backend.supports_tools()
+    }
+
     pub async fn generate(&self, model: &str, request: &LlmRequest) -> Result<LlmResponse> {
-        self.backend.generate(model, request).await
+        let Some(schema) = request.json_schema.as_ref() else {
+            return self.generate_with_fallbacks(model, request).await;
+        };
+        let mut attempt = request.clone();
+        let mut retries = 0;
+        loop {
+            let mut response = self.generate_with_fallbacks(model, &attempt).await?;
+            match parse_structured(&response.content, schema) {
+                Ok(value) => {
+                    response.content = value.to_string();
+                    return Ok(response);
+                }
+                Err(reason) if retries < MAX_SCHEMA_RETRIES => {
+                    retries += 1;
+                    self.warn(&format!(
+                        "answer does not match the json schema ({}); asking again",
+                        reason
+                    ));
+                    attempt.prompt = schema_retry_prompt(&request.prompt, &reason);
+                }
+                Err(reason) => {
+                    return Err(SchemaMismatch {
+                        attempts: retries + 1,
+                        reason,
+                    }
+                    .into())
+                }
+            }
+        }
     }
 
+    async fn generate_with_fallbacks(
+        &self,
+        model: &str,
+        request: &LlmRequest,
+    ) -> Result<LlmResponse> {
+        let mut result = self.generate_once(model, request).await;
+        for fallback in &self.fallbacks {
+            match &result {
+                Err(err) if fails_over(err) => self.warn_failover(err, fallback),
+                _ => break,
+            }
+            result = fallback
+                .client
+                .generate_once(&fallback.model, request)
+                .await;
+        }
+        result
+    }
+
+    async fn generate_once(&self, model: &str, request: &LlmRequest) -> Result<LlmResponse> {
+        let info = self.check_request(model, request)?;
+        let request = self.prepare(request);
+        self.check_window(model, &request, info.as_ref()).await?;
+        let mut attempt = 0;
+        loop {
+            match self.backend.generate(model, &request).await {
+                Ok(mut response) => {
+                    if let Some(usage) = response.usage.as_mut() {
+                        usage.model = Some(model.to_string());
+                        usage.cost_usd = info.as_ref().and_then(|info| info.cost_usd(usage));
+                    }
+                    return Ok(response);
+                }
+                Err(err) => self.backoff(err, &mut attempt).await?,
+            }
+        }
+    }
+
+    /// Retries, and fails over, only before the first event, so output is never duplicated.
     pub async fn generate_stream(&self, model: &str, request: &LlmRequest) -> Result<LlmStream> {
-        self.backend.generate_stream(model, request).await
+        let mut result = self.generate_stream_once(model, request).await;
+        for fallback in &self.fallbacks {
+            match &result {
+                Err(err) if fails_over(err) => self.warn_failover(err, fallback),
+                _ => break,
+            }
+            result = fallback
+                .client
+                .generate_stream_once(&fallback.model, request)
+                .await;
+        }
+        result
+    }
+
+    async fn generate_stream_once(&self, model: &str, request: &LlmRequest) -> Result<LlmStream> {
+        let info = self.check_request(model, request)?;
+        let stream = self.open_stream(model, request, info.as_ref()).await?;
+        let model = model.to_string();
+        Ok(Box::pin(stream.map(move |event| match event {
+            Ok(LlmStreamEvent::Usage(mut usage)) => {
+                usage.model = Some(model.clone());
+                usage.cost_usd = info.as_ref().and_then(|info| info.cost_usd(&usage));
+                Ok(LlmStreamEvent::Usage(usage))
+            }
+            other => other,
+        })))
+    }
+
+    fn warn_failover(&self, err: &anyhow::Error, fallback: &LlmFallback) {
+        self.warn(&format!(
+            "{}; falling back to {}:{}",
+            err, fallback.provider_name, fallback.model
+        ));
+    }
+
+    async fn open_stream(
+        &self,
+        model: &str,
+        request: &LlmRequest,
+        info: Option<&ModelInfo>,
+    ) -> Result<LlmStream> {
+        let request = self.prepare(request);
+        self.check_window(model, &request, info).await?;
+        let mut attempt = 0;
+        loop {
+            let mut stream = match self.backend.generate_stream(model, &request).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    self.backoff(err, &mut attempt).await?;
+                    continue;
+                }
+            };
+            match stream.next().await {
+                Some(Err(err)) => self.backoff(err, &mut attempt).await?,
+                Some(Ok(first)) => {
+                    return Ok(Box::pin(
+                        stream::once(async move { Ok(first) }).chain(stream),
+                    ));
+                }
+                None => return Ok(stream),
+            }
+        }
+    }
+
+    /// Tokens by which `request` exceeds what the model accepts; 0 when it fits or the
+    /// window is unknown.
+    pub async fn overflow(&self, model: &str, request: &LlmRequest) -> u64 {
+        let request = self.prepare(request);
+        let info = self.model_info(model);
+        match self.measure(model, &request, info.as_ref()).await {
+            Some((count, accepted)) => count.saturating_sub(accepted),
+            None => 0,
+        }
+    }
+
+    /// Refuses a request that cannot fit the model's window instead of sending it.
+    async fn check_window(
+        &self,
+        model: &str,
+        request: &LlmRequest,
+        info: Option<&ModelInfo>,
+    ) -> Result<()> {
+        match self.measure(model, request, info).await {
+            Some((count, accepted)) if count > accepted => Err(LlmError::new(
+                LlmErrorKind::ContextTooLong,
+                self.backend.provider().as_str(),
+                format!(
+                    "request needs about {} tokens, {} accepts {}",
+                    count, model, accepted
+                ),
+            )
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Input tokens and the most the model accepts, for requests near the limit. Those are
+    /// counted by the provider where it can; the local estimate alone is only held against
+    /// the whole window.
+    async fn measure(
+        &self,
+        model: &str,
+        request: &LlmRequest,
+        info: Option<&ModelInfo>,
+    ) -> Option<(u64, u64)> {
+        let limit = info.and_then(ModelInfo::input_limit)?;
+        let window = info.and_then(|info| info.context_window)?;
+        let estimate = estimate_request_tokens(request);
+        if (estimate as f64) < limit as f64 * COUNT_THRESHOLD {
+            return None;
+        }
+        match self.backend.count_tokens(model, request).await {
+            Ok(Some(count)) => Some((count, limit)),
+            _ => Some((estimate, window)),
+        }
+    }
+
+    /// Applies the default sampling parameters and warns, once per parameter, about the
+    /// ones this backend drops.
+    fn prepare<'a>(&self, request: &'a LlmRequest) -> Cow<'a, LlmRequest> {
+        let fill_thinking = self.thinking.is_enabled() && !request.thinking.is_enabled();
+        let fold = !self.backend.supports_roles()
+            && (request.system.is_some() || request.context.is_some());
+        let describe_schema = request
+            .json_schema
+            .as_ref()
+            .filter(|_| !self.backend.supports_json_schema());
+        let request =
+            if self.sampling.is_empty() && !fill_thinking && !fold && describe_schema.is_none() {
+                Cow::Borrowed(request)
+            } else {
+                let mut owned = request.clone();
+                owned.sampling = self.sampling.merged(&request.sampling);
+                if fill_thinking {
+                    owned.thinking = self.thinking;
+                }
+                if fold {
+                    owned.fold_prompt();
+                }
+                if let Some(schema) = describe_schema {
+                    owned.prompt = format!("{}\n\n{}", owned.prompt, schema_instruction(schema));
+                }
+                Cow::Owned(owned)
+            };
+        for name in self.backend.unsupported_sampling(&request) {
+            let first = self
+                .warned
+                .lock()
+                .map(|mut warned| warned.insert(name))
+                .unwrap_or(false);
+            if !first {
+                continue;
+            }
+            self.warn(&format!(
+                "{} does not support {}; ignoring it",
+                self.backend.provider().as_str(),
+                name
+            ));
+        }
+        request
+    }
+
+    fn warn(&self, message: &str) {
+        let handler = self
+            .warning_handler
+            .lock()
+            .ok()
+            .and_then(|guard| guard.clone());
+        match handler {
+            Some(handler) => handler(message),
+            None => eprintln!("warning: {}", message),
+        }
+    }
+
+    /// Sleeps before the next attempt, or hands the error back when it should not be retried.
+    async fn backoff(&self, err: anyhow::Error, attempt: &mut u32) -> Result<()> {
+        let Some(llm_err) = err.downcast_ref::<LlmError>() else {
+            return Err(err);
+        };
+        if !llm_err.is_retryable() || *attempt >= self.retry.max_retries {
+            return Err(err);
+        }
+        let delay = self.retry.delay(*attempt, llm_err.retry_after);
+        if delay > MAX_RETRY_AFTER {
+            return Err(err);
+        }
+        *attempt += 1;
+        let handler = self
+            .retry_handler
+            .lock()
+            .ok()
+            .and_then(|guard| guard.clone());
+        if let Some(handler) = handler {
+            handler(&LlmRetryNotice {
+                attempt: *attempt,
+                max_retries: self.retry.max_retries,
+                delay,
+                error: llm_err.clone(),
+            });
+        }
+        tokio::time::sleep(delay).await;
+        Ok(())
     }
 }
 
@@ -96,6 +798,311 @@ impl LlmClient {
 pub trait LlmBackend {
     #[allow(dead_code)]
     fn provider(&self) -> LlmProvider;
+    fn supports_tools(&self) -> bool {
+        false
+    }
+    /// Whether requests carry cache breakpoints.
+    fn supports_prompt_cache(&self) -> bool {
+        false
+    }
+    /// Whether `system` and `context` are sent as separate parts (roles); otherwise they are
+    /// folded into the prompt before the request reaches the backend.
+    fn supports_roles(&self) -> bool {
+        false
+    }
+    /// Whether `LlmRequest::json_schema` constrains the answer; otherwise the schema is
+    /// described in the prompt.
+    fn supports_json_schema(&self) -> bool {
+        false
+    }
+    /// Whether `list_models` names every model `generate` accepts. Azure deployments and
+    /// Bedrock inference profiles are not listed, so names are not checked against it.
+    fn lists_served_models(&self) -> bool {
+        true
+    }
+    /// Set parameters the backend leaves out of the request.
+    fn unsupported_sampling(&self, _request: &LlmRequest) -> Vec<&'static str> {
+        Vec::new()
+    }
+    /// Input tokens as the provider counts them; `None` where it has no endpoint for it.
+    async fn count_tokens(&self, _model: &str, _request: &LlmRequest) -> Result<Option<u64>> {
+        Ok(None)
+    }
+    /// Model ids the server offers, in the server's order.
+    async fn list_models(&self) -> Result<Vec<String>> {
+        Err(anyhow!("{} cannot list models", self.provider().as_str()))
+    }
     async fn generate(&self, model: &str, request: &LlmRequest) -> Result<LlmResponse>;
     async fn generate_stream(&self, model: &str, request: &LlmRequest) -> Result<LlmStream>;
 }
+
+/// Overloaded, rate limited (after retries) and network failures move on to the next backend;
+/// auth and invalid requests would fail there too, or hide a configuration mistake.
+fn fails_over(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<LlmError>()
+        .is_some_and(LlmError::is_retryable)
+}
+
+/// Reads `key` from each object of a model-list array, skipping entries without it.
+pub fn model_ids(list: &Value, key: &str) -> Vec<String> {
+    list.as_array()
+        .map(|items| {
+            items
+                .iter()
+                .filter_map(|item| item.get(key).and_then(Value::as_str))
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    struct FlakyBackend {
+        calls: Arc<AtomicU32>,
+        failures: u32,
+        kind: LlmErrorKind,
+    }
+
+    impl FlakyBackend {
+        fn error(&self) -> anyhow::Error {
+            let mut err = LlmError::new(self.kind, "test", "boom");
+            err.retry_after = Some(Duration::ZERO);
+            err.into()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl LlmBackend for FlakyBackend {
+        fn provider(&self) -> LlmProvider {
+            LlmProvider::Local
+        }
+
+        async fn list_models(&self) -> Result<Vec<String>> {
+            Ok(vec!["llama3.2:latest".to_string(), "qwen3:8b".to_string()])
+        }
+
+        async fn generate(&self, _model: &str, _request: &LlmRequest) -> Result<LlmResponse> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err(self.error());
+            }
+            Ok(LlmResponse {
+                content: "ok".to_string(),
+                ..Default::default()
+            })
+        }
+
+        async fn generate_stream(&self, _model: &str, _request: &LlmRequest) -> Result<LlmStream> {
+            let failed = self.calls.fetch_add(1, Ordering::SeqCst) < self.failures;
+            let first = if failed {
+                Err(self.error())
+            } else {
+                Ok(LlmStreamEvent::Text("ok".to_string()))
+            };
+            Ok(Box::pin(stream::iter(vec![first])))
+        }
+    }
+
+    fn client(failures: u32, kind: LlmErrorKind) -> (LlmClient, Arc<AtomicU32>) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let backend = FlakyBackend {
+            calls: Arc::clone(&calls),
+            failures,
+            kind,
+        };
+        (LlmClient::new(Box::new(backend)), calls)
+    }
+
+    #[test]
+    fn applies_default_sampling_and_warns_once_about_dropped_params() {
+        let backend = crate::llm::AnthropicBackend::new(Default::default()).unwrap();
+        let client = LlmClient::new(Box::new(backend)).with_sampling(SamplingParams {
+            temperature: Some(0.2),
+            seed: Some(7),
+            stop: vec!["END".to_string()],
+            ..Default::default()
+        });
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&warnings);
+        client.set_warning_handler(Arc::new(move |message: &str| {
+            seen.lock().unwrap().push(message.to_string());
+        }));
+        let mut request = LlmRequest::text("hi");
+        request.sampling.temperature = Some(0.9);
+        let prepared = client.prepare(&request);
+        assert_eq!(prepared.sampling.temperature, Some(0.9));
+        assert_eq!(prepared.sampling.seed, Some(7));
+        assert_eq!(prepared.sampling.stop, vec!["END".to_string()]);
+        client.prepare(&request);
+        assert_eq!(
+            *warnings.lock().unwrap(),
+            vec!["anthropic does not support seed; ignoring it".to_string()]
+        );
+    }
+
+    #[test]
+    fn folds_system_and_context_for_backends_without_prompt_cache() {
+        let (client, _) = client(0, LlmErrorKind::Overloaded);
+        let request = LlmRequest {
+            system: Some("Be terse.".to_string()),
+            context: Some("過去の会話:\nユーザー: hi".to_string()),
+            ..LlmRequest::text("next")
+        };
+        let prepared = client.prepare(&request);
+        assert_eq!(
+            prepared.prompt,
+            "System instructions:\nBe terse.\n\nUser request:\n過去の会話:\nユーザー: hi\n\nnext"
+        );
+        assert!(prepared.system.is_none() && prepared.context.is_none());
+    }
+
+    #[tokio::test]
+    async fn checks_catalog_for_images_and_unknown_models() {
+        let (client, calls) = client(0, LlmErrorKind::Overloaded);
+        let catalog: ModelCatalog = toml::from_str(
+            "[[model]]\nprovider = \"ollama\"\nname = \"coder\"\ninput_price = 1.0\noutput_price = 2.0",
+        )
+        .unwrap();
+        let client = client.with_catalog(Arc::new(catalog));
+        let mut request = LlmRequest::text("hi");
+        request.images.push(crate::llm::LlmImage {
+            media_type: "image/png".to_string(),
+            data_base64: String::new(),
+        });
+        let err = client.generate("coder:7b", &request).await.unwrap_err();
+        assert_eq!(err.to_string(), "model coder:7b does not support images");
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        assert!(client.validate_model("coder").is_ok());
+        assert!(client.validate_model("other").is_err());
+        assert!(client.check_model("coder:7b").await.is_ok());
+        assert!(client.check_model("llama3.2").await.is_ok());
+        assert!(client.check_model("qwen3:8b").await.is_ok());
+        assert_eq!(
+            client.check_model("qwen3").await.unwrap_err().to_string(),
+            "ollama does not serve model qwen3"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_past_the_window_before_sending() {
+        let (client, calls) = client(0, LlmErrorKind::Overloaded);
+        let catalog: ModelCatalog = toml::from_str(
+            "[[model]]\nprovider = \"ollama\"\nname = \"tiny\"\ncontext_window = 1000\nmax_output = 200",
+        )
+        .unwrap();
+        let client = client.with_catalog(Arc::new(catalog));
+        let near = LlmRequest::text("x".repeat(3300));
+        assert_eq!(client.overflow("tiny", &near).await, 0);
+        let over = LlmRequest::text("x".repeat(4400));
+        assert_eq!(client.overflow("tiny", &over).await, 116);
+        let err = client.generate("tiny", &over).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LlmError>().map(|err| err.kind),
+            Some(LlmErrorKind::ContextTooLong)
+        );
+        let err = client.generate_stream("tiny", &over).await.err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("request needs about 1116 tokens, tiny accepts 1000"));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        assert!(client.generate("tiny", &near).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn retries_overloaded_and_reports_each_wait() {
+        let (client, calls) = client(2, LlmErrorKind::Overloaded);
+        let notices = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&notices);
+        client.set_retry_handler(Arc::new(move |notice: &LlmRetryNotice| {
+            seen.lock().unwrap().push(notice.attempt);
+        }));
+        let response = client.generate("m", &LlmRequest::text("hi")).await.unwrap();
+        assert_eq!(response.content, "ok");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(*notices.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_backend_after_retries() {
+        let (primary, primary_calls) = client(u32::MAX, LlmErrorKind::Overloaded);
+        let (fallback, fallback_calls) = client(0, LlmErrorKind::Overloaded);
+        let chain = primary
+            .with_retry_policy(RetryPolicy {
+                max_retries: 1,
+                ..Default::default()
+            })
+            .with_fallback("local", fallback, "backup".to_string());
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&warnings);
+        chain.set_warning_handler(Arc::new(move |message: &str| {
+            seen.lock().unwrap().push(message.to_string());
+        }));
+        let response = chain.generate("m", &LlmRequest::text("hi")).await.unwrap();
+        assert_eq!(response.content, "ok");
+        assert_eq!(primary_calls.load(Ordering::SeqCst), 2);
+        assert_eq!(fallback_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            *warnings.lock().unwrap(),
+            vec!["test overloaded: boom; falling back to local:backup".to_string()]
+        );
+
+        let mut stream = chain
+            .generate_stream("m", &LlmRequest::text("hi"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            stream.next().await,
+            Some(Ok(LlmStreamEvent::Text(text))) if text == "ok"
+        ));
+        assert_eq!(fallback_calls.load(Ordering::SeqCst), 2);
+
+        let (primary, _) = client(1, LlmErrorKind::Auth);
+        let (fallback, fallback_calls) = client(0, LlmErrorKind::Auth);
+        let chain = primary.with_fallback("local", fallback, "backup".to_string());
+        assert!(chain.generate("m", &LlmRequest::text("hi")).await.is_err());
+        assert_eq!(fallback_calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_auth_errors() {
+        let (client, calls) = client(1, LlmErrorKind::Auth);
+        let err = client
+            .generate("m", &LlmRequest::text("hi"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LlmError>().map(|e| e.kind),
+            Some(LlmErrorKind::Auth)
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn reopens_stream_that_fails_before_first_event() {
+        let (client, calls) = client(1, LlmErrorKind::RateLimited);
+        let mut stream = client
+            .generate_stream("m", &LlmRequest::text("hi"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            stream.next().await,
+            Some(Ok(LlmStreamEvent::Text(text))) if text == "ok"
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn backoff_grows_and_honors_retry_after() {
+        let policy = RetryPolicy::default();
+        let first = policy.delay(0, None);
+        assert!(first >= Duration::from_millis(500) && first <= Duration::from_secs(1));
+        assert!(policy.delay(10, None) <= policy.max_delay);
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_secs(9))),
+            Duration::from_secs(9)
+        );
+    }
+}
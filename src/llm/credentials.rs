@@ -0,0 +1,171 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Where a backend looks up a key saved by `tengu auth login`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CredentialRef {
+    pub path: PathBuf,
+    /// The `[providers.*]` name, or the built-in provider name.
+    pub provider: String,
+    pub profile: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredCredential {
+    pub api_key: String,
+    pub updated_at: String,
+}
+
+/// `~/.tengu/credentials.toml`: one `[<provider>.<profile>]` table per key, readable only by
+/// the owner.
+pub struct CredentialStore {
+    path: PathBuf,
+}
+
+type Credentials = BTreeMap<String, BTreeMap<String, StoredCredential>>;
+
+impl CredentialStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn default_path() -> Result<PathBuf> {
+        let home = std::env::var("HOME").map_err(|_| anyhow!("HOME not set"))?;
+        Ok(PathBuf::from(home).join(".tengu").join("credentials.toml"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, provider: &str, profile: &str) -> Result<Option<StoredCredential>> {
+        Ok(self
+            .read()?
+            .get(provider)
+            .and_then(|profiles| profiles.get(profile))
+            .cloned())
+    }
+
+    pub fn set(&self, provider: &str, profile: &str, api_key: &str) -> Result<()> {
+        let mut credentials = self.read()?;
+        credentials.entry(provider.to_string()).or_default().insert(
+            profile.to_string(),
+            StoredCredential {
+                api_key: api_key.to_string(),
+                updated_at: Utc::now().to_rfc3339(),
+            },
+        );
+        self.write(&credentials)
+    }
+
+    /// Returns whether there was a key to remove.
+    pub fn remove(&self, provider: &str, profile: &str) -> Result<bool> {
+        let mut credentials = self.read()?;
+        let Some(profiles) = credentials.get_mut(provider) else {
+            return Ok(false);
+        };
+        if profiles.remove(profile).is_none() {
+            return Ok(false);
+        }
+        if profiles.is_empty() {
+            credentials.remove(provider);
+        }
+        self.write(&credentials)?;
+        Ok(true)
+    }
+
+    /// `(provider, profile)` of every saved key.
+    pub fn entries(&self) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .read()?
+            .into_iter()
+            .flat_map(|(provider, profiles)| {
+                profiles
+                    .into_keys()
+                    .map(move |profile| (provider.clone(), profile))
+            })
+            .collect())
+    }
+
+    fn read(&self) -> Result<Credentials> {
+        if !self.path.exists() {
+            return Ok(Credentials::new());
+        }
+        let content = fs::read_to_string(&self.path)?;
+        toml::from_str(&content).map_err(|err| anyhow!("{}: {}", self.path.display(), err))
+    }
+
+    fn write(&self, credentials: &Credentials) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let content = toml::to_string(credentials)?;
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // `mode` only applies to new files; tighten one created some other way.
+            if self.path.exists() {
+                fs::set_permissions(&self.path, fs::Permissions::from_mode(0o600))?;
+            }
+        }
+        std::io::Write::write_all(&mut options.open(&self.path)?, content.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// "sk-a…wxyz": enough to tell keys apart in `auth status`.
+pub fn mask_api_key(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    if chars.len() <= 8 {
+        return "*".repeat(chars.len());
+    }
+    let head: String = chars[..4].iter().collect();
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("{}…{}", head, tail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_keys_per_provider_and_profile_owner_only() {
+        let root = std::env::temp_dir().join(format!("tengu-creds-{}", uuid::Uuid::new_v4()));
+        let store = CredentialStore::new(root.join("credentials.toml"));
+        assert!(store.get("anthropic", DEFAULT_PROFILE).unwrap().is_none());
+
+        store.set("anthropic", DEFAULT_PROFILE, "sk-ant-1").unwrap();
+        store.set("anthropic", "work", "sk-ant-2").unwrap();
+        store.set("gateway", DEFAULT_PROFILE, "gw").unwrap();
+        assert_eq!(
+            store.get("anthropic", "work").unwrap().unwrap().api_key,
+            "sk-ant-2"
+        );
+        assert_eq!(store.entries().unwrap().len(), 3);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(store.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        assert!(store.remove("gateway", DEFAULT_PROFILE).unwrap());
+        assert!(!store.remove("gateway", DEFAULT_PROFILE).unwrap());
+        let content = fs::read_to_string(store.path()).unwrap();
+        assert!(content.contains("[anthropic.work]"));
+        assert!(!content.contains("gateway"));
+
+        assert_eq!(mask_api_key("sk-ant-api03-abcdwxyz"), "sk-a…wxyz");
+        assert_eq!(mask_api_key("short"), "*****");
+        fs::remove_dir_all(&root).ok();
+    }
+}
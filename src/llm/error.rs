@@ -0,0 +1,254 @@
+use reqwest::header::HeaderMap;
+use reqwest::{Response, StatusCode};
+use serde_json::Value;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LlmErrorKind {
+    Auth,
+    RateLimited,
+    Overloaded,
+    ContextTooLong,
+    InvalidRequest,
+    Network,
+    StreamInterrupted,
+}
+
+impl LlmErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Auth => "auth",
+            Self::RateLimited => "rate_limited",
+            Self::Overloaded => "overloaded",
+            Self::ContextTooLong => "context_too_long",
+            Self::InvalidRequest => "invalid_request",
+            Self::Network => "network",
+            Self::StreamInterrupted => "stream_interrupted",
+        }
+    }
+}
+
+/// A backend failure classified so callers can decide whether to retry, compact or give up.
+#[derive(Debug, Clone)]
+pub struct LlmError {
+    pub kind: LlmErrorKind,
+    pub provider: String,
+    pub status: Option<u16>,
+    pub message: String,
+    pub retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for LlmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            Some(status) => write!(
+                f,
+                "{} error: {} {}",
+                self.provider,
+                StatusCode::from_u16(status)
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|_| status.to_string()),
+                self.message
+            ),
+            None => write!(
+                f,
+                "{} {}: {}",
+                self.provider,
+                self.kind.as_str(),
+                self.message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+impl LlmError {
+    pub fn new(kind: LlmErrorKind, provider: &str, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            provider: provider.to_string(),
+            status: None,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// Reads the body of a non-2xx response and classifies it.
+    pub async fn from_response(provider: &str, response: Response) -> Self {
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.text().await.unwrap_or_default();
+        Self::from_status(provider, status, &headers, body.trim())
+    }
+
+    pub fn from_status(
+        provider: &str,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &str,
+    ) -> Self {
+        let kind = match status.as_u16() {
+            401 | 403 => LlmErrorKind::Auth,
+            429 => LlmErrorKind::RateLimited,
+            408 => LlmErrorKind::Network,
+            413 => LlmErrorKind::ContextTooLong,
+            400 | 422 if mentions_context_limit(body) => LlmErrorKind::ContextTooLong,
+            _ if status.is_server_error() || status.as_u16() == 529 => LlmErrorKind::Overloaded,
+            _ => LlmErrorKind::InvalidRequest,
+        };
+        Self {
+            kind,
+            provider: provider.to_string(),
+            status: Some(status.as_u16()),
+            message: body.to_string(),
+            retry_after: parse_retry_after(headers),
+        }
+    }
+
+    pub fn network(provider: &str, err: reqwest::Error) -> Self {
+        Self::new(LlmErrorKind::Network, provider, err.to_string())
+    }
+
+    /// Classifies an `error` object sent inside a stream (e.g. Anthropic's `overloaded_error`).
+    pub fn from_stream_error(provider: &str, error: &Value) -> Self {
+        let message = error
+            .get("message")
+            .and_then(Value::as_str)
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| error.to_string());
+        let code = ["type", "code", "status"]
+            .iter()
+            .filter_map(|key| error.get(*key))
+            .map(|value| match value {
+                Value::String(s) => s.to_ascii_lowercase(),
+                other => other.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        let kind = if code.contains("overloaded")
+            || code.contains("unavailable")
+            || code.contains("529")
+        {
+            LlmErrorKind::Overloaded
+        } else if code.contains("rate_limit")
+            || code.contains("throttl")
+            || code.contains("resource_exhausted")
+            || code.contains("429")
+        {
+            LlmErrorKind::RateLimited
+        } else if mentions_context_limit(&message) || mentions_context_limit(&code) {
+            LlmErrorKind::ContextTooLong
+        } else if code.contains("authentication") || code.contains("permission") {
+            LlmErrorKind::Auth
+        } else {
+            LlmErrorKind::StreamInterrupted
+        };
+        Self::new(kind, provider, format!("stream error: {}", message))
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind,
+            LlmErrorKind::RateLimited
+                | LlmErrorKind::Overloaded
+                | LlmErrorKind::Network
+                | LlmErrorKind::StreamInterrupted
+        )
+    }
+}
+
+fn mentions_context_limit(text: &str) -> bool {
+    let lower = text.to_ascii_lowercase();
+    [
+        "context_length_exceeded",
+        "context length",
+        "context window",
+        "prompt is too long",
+        "too many tokens",
+        "maximum context",
+    ]
+    .iter()
+    .any(|needle| lower.contains(needle))
+}
+
+/// Supports `retry-after-ms`, `retry-after` in seconds and `retry-after` as an HTTP date.
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+    };
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.parse::<f64>().ok()) {
+        return Some(Duration::from_secs_f64(ms.max(0.0) / 1000.0));
+    }
+    let value = header("retry-after")?;
+    if let Ok(secs) = value.parse::<f64>() {
+        return Some(Duration::from_secs_f64(secs.max(0.0)));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let wait = date.with_timezone(&chrono::Utc) - chrono::Utc::now();
+    Some(wait.to_std().unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn classifies_status_codes() {
+        let empty = HeaderMap::new();
+        let kind = |status: u16, body: &str| {
+            LlmError::from_status("test", StatusCode::from_u16(status).unwrap(), &empty, body).kind
+        };
+        assert_eq!(kind(401, ""), LlmErrorKind::Auth);
+        assert_eq!(kind(429, ""), LlmErrorKind::RateLimited);
+        assert_eq!(kind(529, ""), LlmErrorKind::Overloaded);
+        assert_eq!(kind(503, ""), LlmErrorKind::Overloaded);
+        assert_eq!(
+            kind(
+                400,
+                r#"{"error":{"message":"prompt is too long: 210000 tokens"}}"#
+            ),
+            LlmErrorKind::ContextTooLong
+        );
+        assert_eq!(kind(400, "bad field"), LlmErrorKind::InvalidRequest);
+    }
+
+    #[test]
+    fn reads_retry_after_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("7"));
+        let err = LlmError::from_status(
+            "anthropic",
+            StatusCode::TOO_MANY_REQUESTS,
+            &headers,
+            "slow down",
+        );
+        assert_eq!(err.retry_after, Some(Duration::from_secs(7)));
+        assert_eq!(
+            err.to_string(),
+            "anthropic error: 429 Too Many Requests slow down"
+        );
+        headers.insert("retry-after-ms", HeaderValue::from_static("1500"));
+        assert_eq!(
+            parse_retry_after(&headers),
+            Some(Duration::from_millis(1500))
+        );
+    }
+
+    #[test]
+    fn classifies_stream_errors() {
+        let overloaded = LlmError::from_stream_error(
+            "anthropic",
+            &serde_json::json!({ "type": "overloaded_error", "message": "Overloaded" }),
+        );
+        assert_eq!(overloaded.kind, LlmErrorKind::Overloaded);
+        assert_eq!(
+            overloaded.to_string(),
+            "anthropic overloaded: stream error: Overloaded"
+        );
+    }
+}
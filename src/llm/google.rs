@@ -1,11 +1,13 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use bytes::Bytes;
 use futures_util::stream::{self, BoxStream, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::VecDeque;
 
 use crate::llm::{
-    LlmBackend, LlmProvider, LlmRequest, LlmResponse, LlmStream, LlmStreamEvent, LlmUsage,
+    model_ids, LlmBackend, LlmError, LlmErrorKind, LlmProvider, LlmRequest, LlmResponse, LlmStream,
+    LlmStreamEvent, LlmThinking, LlmUsage, ProviderSettings,
 };
 
 const DEFAULT_GOOGLE_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
@@ -13,11 +15,45 @@ const DEFAULT_GOOGLE_BASE_URL: &str = "https://generativelanguage.googleapis.com
 #[derive(Debug, Clone)]
 pub struct GoogleBackend {
     pub base_url: String,
+    settings: ProviderSettings,
+    client: reqwest::Client,
 }
 
 #[derive(Debug, Serialize)]
 struct GenerateContentRequest {
     contents: Vec<GoogleContent>,
+    #[serde(rename = "generationConfig", skip_serializing_if = "Option::is_none")]
+    generation_config: Option<GoogleGenerationConfig>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GoogleGenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking_config: Option<GoogleThinkingConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_schema: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GoogleThinkingConfig {
+    thinking_budget: u32,
+    include_thoughts: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -72,19 +108,29 @@ struct GoogleContentResponse {
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct GooglePartResponse {
     text: Option<String>,
+    #[serde(default)]
+    thought: bool,
+    #[serde(default)]
+    thought_signature: Option<String>,
 }
 
 impl GoogleBackend {
-    pub fn new(base_url: Option<String>) -> Self {
-        Self {
-            base_url: base_url.unwrap_or_else(|| DEFAULT_GOOGLE_BASE_URL.to_string()),
-        }
+    pub fn new(settings: ProviderSettings) -> Result<Self> {
+        Ok(Self {
+            base_url: settings
+                .base_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_GOOGLE_BASE_URL.to_string()),
+            client: settings.http_client()?,
+            settings,
+        })
     }
 
-    fn api_key(&self) -> Result<String> {
-        std::env::var("GOOGLE_API_KEY").map_err(|_| anyhow!("GOOGLE_API_KEY is not set"))
+    async fn api_key(&self) -> Result<String> {
+        self.settings.api_key("GOOGLE_API_KEY").await
     }
 
     fn request_body(&self, request: &LlmRequest) -> GenerateContentRequest {
@@ -101,8 +147,27 @@ impl GoogleBackend {
                 }),
             });
         }
+        let sampling = &request.sampling;
         GenerateContentRequest {
             contents: vec![GoogleContent { parts }],
+            generation_config: (!sampling.is_empty()
+                || request.max_tokens.is_some()
+                || request.thinking.is_enabled()
+                || request.json_schema.is_some())
+            .then(|| GoogleGenerationConfig {
+                temperature: sampling.temperature,
+                top_p: sampling.top_p,
+                top_k: sampling.top_k,
+                stop_sequences: sampling.stop.clone(),
+                seed: sampling.seed,
+                max_output_tokens: request.max_tokens,
+                thinking_config: request.thinking.is_enabled().then(|| GoogleThinkingConfig {
+                    thinking_budget: request.thinking.budget(),
+                    include_thoughts: true,
+                }),
+                response_mime_type: request.json_schema.as_ref().map(|_| "application/json"),
+                response_schema: request.json_schema.as_ref().map(gemini_schema),
+            }),
         }
     }
 
@@ -125,25 +190,63 @@ impl GoogleBackend {
         url
     }
 
-    fn extract_text(value: &Value) -> String {
-        value
-            .get("candidates")
-            .and_then(Value::as_array)
+    fn count_tokens_url(&self, model: &str, api_key: &str) -> String {
+        format!(
+            "{}/models/{}:countTokens?key={}",
+            self.base_url.trim_end_matches('/'),
+            model,
+            api_key
+        )
+    }
+
+    /// Keeps the models that can answer `generateContent`, without the `models/` prefix.
+    fn chat_models(body: &Value) -> Vec<String> {
+        let Some(models) = body["models"].as_array() else {
+            return Vec::new();
+        };
+        let generates = |model: &Value| {
+            model["supportedGenerationMethods"]
+                .as_array()
+                .is_none_or(|methods| methods.iter().any(|m| m == "generateContent"))
+        };
+        let chat: Vec<Value> = models.iter().filter(|m| generates(m)).cloned().collect();
+        model_ids(&Value::Array(chat), "name")
             .into_iter()
-            .flatten()
-            .filter_map(|candidate| candidate.get("content"))
-            .filter_map(|content| content.get("parts"))
-            .filter_map(Value::as_array)
-            .flat_map(|parts| parts.iter())
-            .filter_map(|part| part.get("text"))
-            .filter_map(Value::as_str)
-            .collect::<Vec<_>>()
-            .join("")
+            .map(|name| name.trim_start_matches("models/").to_string())
+            .collect()
+    }
+
+    /// Splits the parts of the first candidate into answer text and thought summary.
+    fn split_parts(parts: Vec<GooglePartResponse>) -> (String, LlmThinking) {
+        let mut text = String::new();
+        let mut thinking = LlmThinking::default();
+        for part in parts {
+            let part_text = part.text.unwrap_or_default();
+            if part.thought {
+                thinking.push(&part_text, part.thought_signature.as_deref());
+            } else {
+                text.push_str(&part_text);
+                if let Some(signature) = part.thought_signature {
+                    thinking.signature = Some(signature);
+                }
+            }
+        }
+        (text, thinking)
+    }
+
+    fn candidate_parts(candidates: Option<Vec<GoogleCandidate>>) -> Vec<GooglePartResponse> {
+        candidates
+            .unwrap_or_default()
+            .into_iter()
+            .find_map(|candidate| candidate.content)
+            .and_then(|content| content.parts)
+            .unwrap_or_default()
     }
 
     fn normalize_usage(usage: GoogleUsageMetadata, raw: Option<Value>) -> LlmUsage {
         LlmUsage {
             provider: "google".to_string(),
+            model: None,
             input_tokens: usage.prompt_token_count,
             output_tokens: usage.candidates_token_count,
             total_tokens: usage.total_token_count.or_else(|| {
@@ -155,106 +258,215 @@ impl GoogleBackend {
             cache_creation_input_tokens: None,
             cache_read_input_tokens: usage.cached_content_token_count,
             reasoning_tokens: usage.thoughts_token_count,
+            cost_usd: None,
             raw,
         }
     }
 
-    fn parse_stream_data(data: &str) -> Result<Option<LlmStreamEvent>> {
+    /// Gemini repeats `usageMetadata` in every chunk, so callers keep only the last one.
+    fn parse_stream_data(data: &str) -> Result<Vec<LlmStreamEvent>> {
         let payload = data.trim();
         if payload.is_empty() || payload == "[DONE]" {
-            return Ok(None);
+            return Ok(Vec::new());
         }
         let value: Value = serde_json::from_str(payload)?;
-        if let Some(error) = value
-            .get("error")
-            .and_then(|err| err.get("message"))
-            .and_then(Value::as_str)
-        {
-            return Err(anyhow!("google stream error: {}", error));
+        if let Some(error) = value.get("error").filter(|err| !err.is_null()) {
+            return Err(LlmError::from_stream_error("google", error).into());
         }
-        if let Some(usage_value) = value.get("usageMetadata").cloned() {
-            if let Ok(usage) = serde_json::from_value::<GoogleUsageMetadata>(usage_value.clone()) {
-                return Ok(Some(LlmStreamEvent::Usage(Self::normalize_usage(
-                    usage,
-                    Some(usage_value),
-                ))));
-            }
+        let body: GenerateContentResponse = serde_json::from_value(value.clone())?;
+        let (text, thinking) = Self::split_parts(Self::candidate_parts(body.candidates));
+        let mut events = Vec::new();
+        if !thinking.is_empty() {
+            events.push(LlmStreamEvent::Thinking {
+                text: thinking.text,
+                signature: thinking.signature,
+            });
         }
-        let text = Self::extract_text(&value);
-        if text.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(LlmStreamEvent::Text(text)))
+        if !text.is_empty() {
+            events.push(LlmStreamEvent::Text(text));
+        }
+        if let Some(usage) = body.usage_metadata {
+            events.push(LlmStreamEvent::Usage(Self::normalize_usage(
+                usage,
+                value.get("usageMetadata").cloned(),
+            )));
         }
+        Ok(events)
     }
 }
 
+/// `responseSchema` takes an OpenAPI subset: keywords outside it are dropped, and a
+/// `["string", "null"]` type becomes a nullable string.
+fn gemini_schema(schema: &Value) -> Value {
+    const KEYWORDS: &[&str] = &[
+        "type",
+        "format",
+        "description",
+        "nullable",
+        "enum",
+        "properties",
+        "required",
+        "items",
+        "minItems",
+        "maxItems",
+        "minimum",
+        "maximum",
+        "anyOf",
+        "propertyOrdering",
+    ];
+    let Some(object) = schema.as_object() else {
+        return schema.clone();
+    };
+    let mut out = serde_json::Map::new();
+    for (key, value) in object {
+        if !KEYWORDS.contains(&key.as_str()) {
+            continue;
+        }
+        let value = match (key.as_str(), value) {
+            ("type", Value::Array(types)) => {
+                if types.iter().any(|t| t == "null") {
+                    out.insert("nullable".to_string(), Value::Bool(true));
+                }
+                types
+                    .iter()
+                    .find(|t| *t != "null")
+                    .cloned()
+                    .unwrap_or(Value::from("string"))
+            }
+            ("properties", Value::Object(properties)) => Value::Object(
+                properties
+                    .iter()
+                    .map(|(name, property)| (name.clone(), gemini_schema(property)))
+                    .collect(),
+            ),
+            ("items", items) => gemini_schema(items),
+            ("anyOf", Value::Array(variants)) => {
+                Value::Array(variants.iter().map(gemini_schema).collect())
+            }
+            _ => value.clone(),
+        };
+        out.insert(key.clone(), value);
+    }
+    Value::Object(out)
+}
+
 #[async_trait::async_trait]
 impl LlmBackend for GoogleBackend {
     fn provider(&self) -> LlmProvider {
         LlmProvider::Google
     }
 
+    fn supports_json_schema(&self) -> bool {
+        true
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let api_key = self.api_key().await?;
+        let url = format!(
+            "{}/models?pageSize=1000&key={}",
+            self.base_url.trim_end_matches('/'),
+            api_key
+        );
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(|err| LlmError::network("google", err))?;
+
+        if !response.status().is_success() {
+            return Err(LlmError::from_response("google", response).await.into());
+        }
+
+        let body: Value = response.json().await?;
+        Ok(Self::chat_models(&body))
+    }
+
+    async fn count_tokens(&self, model: &str, request: &LlmRequest) -> Result<Option<u64>> {
+        let api_key = self.api_key().await?;
+        let body = self.request_body(request);
+        let response = self
+            .client
+            .post(self.count_tokens_url(model, &api_key))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .json(&serde_json::json!({ "contents": body.contents }))
+            .send()
+            .await
+            .map_err(|err| LlmError::network("google", err))?;
+
+        if !response.status().is_success() {
+            return Err(LlmError::from_response("google", response).await.into());
+        }
+
+        let body: Value = response.json().await?;
+        Ok(body["totalTokens"].as_u64())
+    }
+
     async fn generate(&self, model: &str, request: &LlmRequest) -> Result<LlmResponse> {
-        let api_key = self.api_key()?;
-        let client = reqwest::Client::new();
-        let response = client
+        let api_key = self.api_key().await?;
+        let response = self
+            .client
             .post(self.generate_url(model, false, &api_key))
             .header(reqwest::header::CONTENT_TYPE, "application/json")
             .json(&self.request_body(request))
             .send()
-            .await?;
+            .await
+            .map_err(|err| LlmError::network("google", err))?;
 
         if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!("google error: {} {}", status, body.trim()));
+            return Err(LlmError::from_response("google", response).await.into());
         }
 
         let body: GenerateContentResponse = response.json().await?;
-        let content = body
-            .candidates
-            .unwrap_or_default()
-            .into_iter()
-            .find_map(|candidate| candidate.content)
-            .and_then(|content| content.parts)
-            .unwrap_or_default()
-            .into_iter()
-            .filter_map(|part| part.text)
-            .collect::<Vec<_>>()
-            .join("");
+        let (content, thinking) = Self::split_parts(Self::candidate_parts(body.candidates));
         Ok(LlmResponse {
             content,
+            thinking: (!thinking.is_empty()).then_some(thinking),
             usage: body
                 .usage_metadata
                 .map(|usage| Self::normalize_usage(usage, None)),
+            ..Default::default()
         })
     }
 
     async fn generate_stream(&self, model: &str, request: &LlmRequest) -> Result<LlmStream> {
-        let api_key = self.api_key()?;
-        let client = reqwest::Client::new();
-        let response = client
+        let api_key = self.api_key().await?;
+        let response = self
+            .client
             .post(self.generate_url(model, true, &api_key))
             .header(reqwest::header::CONTENT_TYPE, "application/json")
             .json(&self.request_body(request))
             .send()
-            .await?;
+            .await
+            .map_err(|err| LlmError::network("google", err))?;
 
         if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!("google error: {} {}", status, body.trim()));
+            return Err(LlmError::from_response("google", response).await.into());
         }
 
         struct StreamState {
             stream: BoxStream<'static, Result<Bytes, reqwest::Error>>,
             buffer: String,
             pending_data: Vec<String>,
+            queued: VecDeque<LlmStreamEvent>,
+            last_usage: Option<LlmUsage>,
             finished: bool,
         }
 
+        fn queue_data(state: &mut StreamState, data: &str) -> Result<()> {
+            for event in GoogleBackend::parse_stream_data(data)? {
+                match event {
+                    LlmStreamEvent::Usage(usage) => state.last_usage = Some(usage),
+                    other => state.queued.push_back(other),
+                }
+            }
+            Ok(())
+        }
+
         fn take_message(state: &mut StreamState) -> Result<Option<LlmStreamEvent>> {
+            if let Some(event) = state.queued.pop_front() {
+                return Ok(Some(event));
+            }
             while let Some(idx) = state.buffer.find('\n') {
                 let mut line = state.buffer[..idx].to_string();
                 state.buffer = state.buffer[idx + 1..].to_string();
@@ -266,7 +478,10 @@ impl LlmBackend for GoogleBackend {
                     if !state.pending_data.is_empty() {
                         let data = state.pending_data.join("\n");
                         state.pending_data.clear();
-                        return GoogleBackend::parse_stream_data(&data);
+                        queue_data(state, &data)?;
+                        if let Some(event) = state.queued.pop_front() {
+                            return Ok(Some(event));
+                        }
                     }
                     continue;
                 }
@@ -283,6 +498,8 @@ impl LlmBackend for GoogleBackend {
             stream: Box::pin(response.bytes_stream()),
             buffer: String::new(),
             pending_data: Vec::new(),
+            queued: VecDeque::new(),
+            last_usage: None,
             finished: false,
         };
 
@@ -307,19 +524,25 @@ impl LlmBackend for GoogleBackend {
                     }
                     Some(Err(err)) => {
                         state.finished = true;
-                        return Some((Err(anyhow::Error::new(err)), state));
+                        let err = LlmError::new(
+                            LlmErrorKind::StreamInterrupted,
+                            "google",
+                            err.to_string(),
+                        );
+                        return Some((Err(err.into()), state));
                     }
                     None => {
-                        state.finished = true;
                         if !state.pending_data.is_empty() {
-                            let data = state.pending_data.join("\n");
-                            match GoogleBackend::parse_stream_data(&data) {
-                                Ok(Some(text)) => return Some((Ok(text), state)),
-                                Ok(None) => return None,
-                                Err(err) => return Some((Err(err), state)),
+                            let data = std::mem::take(&mut state.pending_data).join("\n");
+                            if let Err(err) = queue_data(&mut state, &data) {
+                                state.finished = true;
+                                return Some((Err(err), state));
                             }
+                            continue;
                         }
-                        return None;
+                        state.finished = true;
+                        let usage = state.last_usage.take()?;
+                        return Some((Ok(LlmStreamEvent::Usage(usage)), state));
                     }
                 }
             }
@@ -332,10 +555,11 @@ impl LlmBackend for GoogleBackend {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::llm::ReasoningEffort;
 
     #[test]
     fn builds_generate_url_for_streaming() {
-        let backend = GoogleBackend::new(None);
+        let backend = GoogleBackend::new(ProviderSettings::default()).unwrap();
         let url = backend.generate_url("gemini-2.0-flash", true, "test-key");
         assert_eq!(
             url,
@@ -346,14 +570,20 @@ mod tests {
     #[test]
     fn parses_text_from_stream_payload() {
         let payload = r#"{"candidates":[{"content":{"parts":[{"text":"hello"}]}}]}"#;
-        let parsed = GoogleBackend::parse_stream_data(payload).unwrap();
+        let parsed = GoogleBackend::parse_stream_data(payload)
+            .unwrap()
+            .into_iter()
+            .next();
         assert!(matches!(parsed, Some(LlmStreamEvent::Text(text)) if text == "hello"));
     }
 
     #[test]
     fn parses_usage_from_stream_payload() {
         let payload = r#"{"usageMetadata":{"promptTokenCount":21,"candidatesTokenCount":8,"totalTokenCount":29,"cachedContentTokenCount":5,"thoughtsTokenCount":3}}"#;
-        let parsed = GoogleBackend::parse_stream_data(payload).unwrap();
+        let parsed = GoogleBackend::parse_stream_data(payload)
+            .unwrap()
+            .into_iter()
+            .next();
         assert!(matches!(
             parsed,
             Some(LlmStreamEvent::Usage(usage))
@@ -365,4 +595,74 @@ mod tests {
                     && usage.reasoning_tokens == Some(3)
         ));
     }
+
+    #[test]
+    fn requests_thinking_and_separates_thought_parts() {
+        let backend = GoogleBackend::new(ProviderSettings::default()).unwrap();
+        let mut request = LlmRequest::text("hi");
+        request.thinking.effort = Some(ReasoningEffort::Low);
+        let body = serde_json::to_value(backend.request_body(&request)).unwrap();
+        assert_eq!(
+            body["generationConfig"]["thinkingConfig"],
+            serde_json::json!({ "thinkingBudget": 2048, "includeThoughts": true })
+        );
+
+        let payload = r#"{"candidates":[{"content":{"parts":[{"text":"planning","thought":true},{"text":"answer","thoughtSignature":"c2ln"}]}}],"usageMetadata":{"promptTokenCount":3}}"#;
+        let events = GoogleBackend::parse_stream_data(payload).unwrap();
+        assert!(matches!(
+            &events[..],
+            [
+                LlmStreamEvent::Thinking { text, signature: Some(sig) },
+                LlmStreamEvent::Text(answer),
+                LlmStreamEvent::Usage(_),
+            ] if text == "planning" && sig == "c2ln" && answer == "answer"
+        ));
+    }
+
+    #[test]
+    fn sends_json_schema_as_an_openapi_response_schema() {
+        let backend = GoogleBackend::new(ProviderSettings::default()).unwrap();
+        let request = LlmRequest {
+            json_schema: Some(serde_json::json!({
+                "$schema": "https://json-schema.org/draft/2020-12/schema",
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "summary": { "type": ["string", "null"] },
+                    "files": { "type": "array", "items": { "type": "string", "const": "x" } },
+                },
+                "required": ["files"],
+            })),
+            ..LlmRequest::text("hi")
+        };
+        let body = serde_json::to_value(backend.request_body(&request)).unwrap();
+        let config = &body["generationConfig"];
+        assert_eq!(config["responseMimeType"], "application/json");
+        assert_eq!(
+            config["responseSchema"],
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "summary": { "type": "string", "nullable": true },
+                    "files": { "type": "array", "items": { "type": "string" } },
+                },
+                "required": ["files"],
+            })
+        );
+    }
+
+    #[test]
+    fn lists_only_models_that_generate_content() {
+        let body = serde_json::json!({
+            "models": [
+                {"name": "models/gemini-2.5-flash", "supportedGenerationMethods": ["generateContent", "countTokens"]},
+                {"name": "models/text-embedding-004", "supportedGenerationMethods": ["embedContent"]},
+                {"name": "models/gemini-2.5-pro"}
+            ]
+        });
+        assert_eq!(
+            GoogleBackend::chat_models(&body),
+            vec!["gemini-2.5-flash".to_string(), "gemini-2.5-pro".to_string()]
+        );
+    }
 }
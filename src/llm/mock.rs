@@ -0,0 +1,296 @@
+use anyhow::{anyhow, Result};
+use futures_util::stream;
+use once_cell::sync::Lazy;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::llm::{
+    LlmBackend, LlmError, LlmProvider, LlmRequest, LlmResponse, LlmStream, LlmStreamEvent,
+    LlmThinking, LlmToolCall, LlmUsage,
+};
+
+pub const DEFAULT_MOCK_SCRIPT: &str = ".tengu/mock.json";
+
+/// Next turn of each script. Shared so that every client built from one script (routes,
+/// fallbacks, a `/model` switch) walks the same sequence.
+static CURSORS: Lazy<Mutex<HashMap<PathBuf, usize>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A mock script: the assistant turns served in order, one per LLM call.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MockScript {
+    /// Answer tool selection with native `tool_calls` instead of JSON text.
+    pub supports_tools: bool,
+    pub turns: Vec<MockTurn>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MockTurn {
+    /// Fails the call unless the request (system, context and prompt) contains this text.
+    pub expect: Option<String>,
+    pub text: Option<String>,
+    /// Streamed one by one; joined for non-streaming calls. Defaults to `text` as one chunk.
+    pub chunks: Vec<String>,
+    pub thinking: Option<String>,
+    pub tool_calls: Vec<LlmToolCall>,
+    pub usage: Option<LlmUsage>,
+    pub delay_ms: Option<u64>,
+    /// Returned after the chunks, if any, so a stream can also fail midway.
+    pub error: Option<MockError>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MockError {
+    /// HTTP status, classified like a real response (429, 529, 401, ...).
+    pub status: u16,
+    pub message: String,
+    pub retry_after_ms: Option<u64>,
+}
+
+impl MockTurn {
+    fn chunks(&self) -> Vec<String> {
+        if self.chunks.is_empty() {
+            self.text.iter().cloned().collect()
+        } else {
+            self.chunks.clone()
+        }
+    }
+
+    fn usage(&self) -> Option<LlmUsage> {
+        self.usage.clone().map(|mut usage| {
+            if usage.provider.is_empty() {
+                usage.provider = "mock".to_string();
+            }
+            usage
+        })
+    }
+
+    fn error(&self) -> Option<anyhow::Error> {
+        let error = self.error.as_ref()?;
+        let status = StatusCode::from_u16(error.status).unwrap_or(StatusCode::BAD_REQUEST);
+        let mut err = LlmError::from_status("mock", status, &HeaderMap::new(), &error.message);
+        err.retry_after = error.retry_after_ms.map(Duration::from_millis);
+        Some(err.into())
+    }
+}
+
+/// Reads a `.json` or `.toml` script.
+pub fn load_mock_script(path: &Path) -> Result<MockScript> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|err| anyhow!("cannot read mock script {}: {}", path.display(), err))?;
+    let parsed = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&content).map_err(anyhow::Error::from),
+        _ => serde_json::from_str(&content).map_err(anyhow::Error::from),
+    };
+    parsed.map_err(|err| anyhow!("{}: {}", path.display(), err))
+}
+
+/// Plays a `MockScript` back, so agent flows (tool calls, approvals, retries) can be tested
+/// end to end without a model.
+pub struct MockBackend {
+    path: PathBuf,
+    script: MockScript,
+}
+
+impl MockBackend {
+    pub fn new(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            script: load_mock_script(path)?,
+        })
+    }
+
+    async fn next_turn(&self, request: &LlmRequest) -> Result<MockTurn> {
+        let index = CURSORS
+            .lock()
+            .map(|mut cursors| {
+                let cursor = cursors.entry(self.path.clone()).or_insert(0);
+                *cursor += 1;
+                *cursor - 1
+            })
+            .map_err(|_| anyhow!("mock script lock poisoned"))?;
+        let turn = self.script.turns.get(index).cloned().ok_or_else(|| {
+            anyhow!(
+                "mock script {} has no turn {} ({} turns)",
+                self.path.display(),
+                index + 1,
+                self.script.turns.len()
+            )
+        })?;
+        if let Some(expected) = turn.expect.as_deref() {
+            let seen = [
+                request.system.as_deref(),
+                request.context.as_deref(),
+                Some(request.prompt.as_str()),
+            ];
+            if !seen.iter().flatten().any(|text| text.contains(expected)) {
+                return Err(anyhow!(
+                    "mock turn {}: request does not contain {:?}",
+                    index + 1,
+                    expected
+                ));
+            }
+        }
+        if let Some(delay) = turn.delay_ms {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+        Ok(turn)
+    }
+}
+
+#[async_trait::async_trait]
+impl LlmBackend for MockBackend {
+    fn provider(&self) -> LlmProvider {
+        LlmProvider::Mock
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.script.supports_tools
+    }
+
+    async fn generate(&self, _model: &str, request: &LlmRequest) -> Result<LlmResponse> {
+        let turn = self.next_turn(request).await?;
+        if let Some(err) = turn.error() {
+            return Err(err);
+        }
+        Ok(LlmResponse {
+            content: turn.chunks().concat(),
+            usage: turn.usage(),
+            thinking: turn.thinking.clone().map(|text| LlmThinking {
+                text,
+                signature: None,
+            }),
+            tool_calls: turn.tool_calls.clone(),
+        })
+    }
+
+    async fn generate_stream(&self, _model: &str, request: &LlmRequest) -> Result<LlmStream> {
+        let turn = self.next_turn(request).await?;
+        let mut events: Vec<Result<LlmStreamEvent>> = Vec::new();
+        if let Some(text) = turn.thinking.clone() {
+            events.push(Ok(LlmStreamEvent::Thinking {
+                text,
+                signature: None,
+            }));
+        }
+        events.extend(
+            turn.chunks()
+                .into_iter()
+                .map(|c| Ok(LlmStreamEvent::Text(c))),
+        );
+        match turn.error() {
+            Some(err) => events.push(Err(err)),
+            None => events.extend(turn.usage().map(|u| Ok(LlmStreamEvent::Usage(u)))),
+        }
+        Ok(Box::pin(stream::iter(events)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{LlmClient, RetryPolicy};
+    use futures_util::StreamExt;
+    use serde_json::json;
+
+    fn script_file(extension: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("tengu-mock-{}.{}", uuid::Uuid::new_v4(), extension));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn plays_turns_in_order_with_retries_and_tool_calls() {
+        let path = script_file(
+            "json",
+            &json!({
+                "supports_tools": true,
+                "turns": [
+                    {"error": {"status": 529, "message": "overloaded", "retry_after_ms": 0}},
+                    {"expect": "fix", "chunks": ["Hel", "lo"], "usage": {"input_tokens": 5, "output_tokens": 2}},
+                    {"tool_calls": [{"name": "read_file", "arguments": {"path": "Cargo.toml"}}]},
+                    {"chunks": ["par"], "error": {"status": 500, "message": "reset"}},
+                    {"expect": "never sent", "text": "unused"},
+                ]
+            })
+            .to_string(),
+        );
+        let client = LlmClient::new(Box::new(MockBackend::new(&path).unwrap())).with_retry_policy(
+            RetryPolicy {
+                max_retries: 1,
+                ..Default::default()
+            },
+        );
+        assert!(client.supports_tools());
+
+        let events: Vec<_> = client
+            .generate_stream("m", &LlmRequest::text("fix it"))
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        let text: String = events
+            .iter()
+            .filter_map(|event| match event {
+                Ok(LlmStreamEvent::Text(text)) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(text, "Hello");
+        assert!(matches!(
+            events.last(),
+            Some(Ok(LlmStreamEvent::Usage(usage))) if usage.provider == "mock" && usage.output_tokens == Some(2)
+        ));
+
+        let response = client
+            .generate("m", &LlmRequest::text("next"))
+            .await
+            .unwrap();
+        assert_eq!(response.tool_calls[0].arguments["path"], "Cargo.toml");
+
+        let mut stream = client
+            .generate_stream("m", &LlmRequest::text("again"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            stream.next().await,
+            Some(Ok(LlmStreamEvent::Text(_)))
+        ));
+        assert!(stream.next().await.unwrap().is_err());
+
+        let err = client
+            .generate("m", &LlmRequest::text("other"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "mock turn 5: request does not contain \"never sent\""
+        );
+        let err = client
+            .generate("m", &LlmRequest::text("other"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("has no turn 6 (5 turns)"));
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn reads_toml_scripts() {
+        let path = script_file(
+            "toml",
+            "[[turns]]\ntext = \"done\"\ndelay_ms = 10\n\n[turns.usage]\noutput_tokens = 1\n",
+        );
+        let script = load_mock_script(&path).unwrap();
+        assert_eq!(script.turns[0].chunks(), vec!["done".to_string()]);
+        assert_eq!(script.turns[0].usage().unwrap().output_tokens, Some(1));
+        std::fs::remove_file(&path).ok();
+    }
+}
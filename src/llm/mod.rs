@@ -1,11 +1,33 @@
 mod anthropic;
+mod bedrock;
+mod catalog;
 mod core;
+mod credentials;
+mod error;
 mod google;
+mod mock;
 mod ollama;
 mod openai;
+mod replay;
+mod settings;
+mod sigv4;
+mod structured;
+#[cfg(test)]
+mod stub;
+mod tokens;
 
 pub use anthropic::*;
+pub use bedrock::*;
+pub use catalog::*;
 pub use core::*;
+pub use credentials::*;
+pub use error::*;
 pub use google::*;
+pub use mock::*;
 pub use ollama::*;
 pub use openai::*;
+pub use replay::*;
+pub use settings::*;
+pub use sigv4::*;
+pub use structured::*;
+pub use tokens::*;
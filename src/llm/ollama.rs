@@ -2,28 +2,58 @@ use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use futures_util::stream::{self, BoxStream, StreamExt};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
 
 use crate::llm::{
-    LlmBackend, LlmProvider, LlmRequest, LlmResponse, LlmStream, LlmStreamEvent, LlmUsage,
+    model_ids, LlmBackend, LlmError, LlmErrorKind, LlmProvider, LlmRequest, LlmResponse, LlmStream,
+    LlmStreamEvent, LlmThinking, LlmToolCall, LlmUsage, ProviderSettings,
 };
 
+const DEFAULT_OLLAMA_BASE_URL: &str = "http://localhost:11434";
+
 #[derive(Debug, Clone)]
 pub struct OllamaBackend {
     pub base_url: String,
+    keep_alive: Option<String>,
+    options: Map<String, Value>,
+    /// Models that answered "does not support tools"; later requests skip native tools.
+    no_tool_models: Arc<Mutex<HashSet<String>>>,
+    client: reqwest::Client,
 }
 
 #[derive(Debug, Serialize)]
-struct GenerateRequest {
+struct ChatRequest {
     model: String,
-    prompt: String,
+    messages: Vec<ChatMessage>,
     stream: bool,
     #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Value>,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    options: Map<String, Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    think: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatMessage {
+    role: String,
+    content: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     images: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
-struct GenerateResponse {
-    response: String,
+struct ChatResponse {
+    #[serde(default)]
+    message: Option<ChatResponseMessage>,
+    #[serde(default)]
+    done: bool,
     #[serde(default)]
     prompt_eval_count: Option<u64>,
     #[serde(default)]
@@ -31,29 +61,264 @@ struct GenerateResponse {
 }
 
 #[derive(Debug, Deserialize)]
-struct GenerateStreamResponse {
-    response: String,
-    done: bool,
+struct ChatResponseMessage {
     #[serde(default)]
-    prompt_eval_count: Option<u64>,
+    content: String,
     #[serde(default)]
-    eval_count: Option<u64>,
+    thinking: String,
+    #[serde(default)]
+    tool_calls: Vec<ChatToolCall>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatToolCall {
+    function: ChatFunctionCall,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatFunctionCall {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
 }
 
 impl OllamaBackend {
-    pub fn new(base_url: String) -> Self {
-        Self { base_url }
+    pub fn new(settings: ProviderSettings) -> Result<Self> {
+        Ok(Self {
+            base_url: settings
+                .base_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_OLLAMA_BASE_URL.to_string()),
+            keep_alive: settings.keep_alive.clone(),
+            options: settings.options.clone(),
+            no_tool_models: Arc::new(Mutex::new(HashSet::new())),
+            client: settings.http_client()?,
+        })
     }
 
-    fn generate_url(&self) -> String {
+    fn api_url(&self, path: &str) -> String {
         let base = self.base_url.trim_end_matches('/');
         if base.ends_with("/api") {
-            format!("{}/generate", base)
+            format!("{}/{}", base, path)
         } else {
-            format!("{}/api/generate", base)
+            format!("{}/api/{}", base, path)
         }
     }
 
+    fn request_body(&self, model: &str, request: &LlmRequest, stream: bool) -> ChatRequest {
+        let tools_disabled = self
+            .no_tool_models
+            .lock()
+            .map(|models| models.contains(model))
+            .unwrap_or(false);
+        // Streamed chunks are read for text only, so tools go with whole answers.
+        let tools: Vec<Value> = if tools_disabled || stream {
+            Vec::new()
+        } else {
+            request
+                .tools
+                .iter()
+                .map(|tool| {
+                    serde_json::json!({
+                        "type": "function",
+                        "function": {
+                            "name": tool.name,
+                            "description": tool.description,
+                            "parameters": tool.parameters,
+                        }
+                    })
+                })
+                .collect()
+        };
+        // `format` constrains the whole answer, which would rule out tool calls.
+        let format = match &request.json_schema {
+            Some(schema) if tools.is_empty() => Some(schema.clone()),
+            _ => (request.json_output && tools.is_empty()).then(|| Value::from("json")),
+        };
+        let mut options = self.options.clone();
+        if let Ok(Value::Object(sampling)) = serde_json::to_value(&request.sampling) {
+            options.extend(sampling);
+        }
+        if let Some(max_tokens) = request.max_tokens {
+            options.insert("num_predict".to_string(), Value::from(max_tokens));
+        }
+        let text = |role: &str, content: &str| ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+            images: Vec::new(),
+        };
+        let mut messages = Vec::new();
+        if let Some(system) = request.system.as_deref().filter(|s| !s.trim().is_empty()) {
+            messages.push(text("system", system));
+        }
+        if let Some(context) = request.context.as_deref().filter(|c| !c.trim().is_empty()) {
+            messages.push(text("user", context));
+        }
+        messages.push(ChatMessage {
+            images: request
+                .images
+                .iter()
+                .map(|image| image.data_base64.clone())
+                .collect(),
+            ..text("user", &request.prompt)
+        });
+        ChatRequest {
+            model: model.to_string(),
+            messages,
+            stream,
+            tools,
+            options,
+            keep_alive: self.keep_alive.clone(),
+            format,
+            think: request.thinking.is_enabled().then_some(true),
+        }
+    }
+
+    /// Fails early instead of letting a text-only model silently ignore `--image`.
+    /// Servers too old to report capabilities are given the benefit of the doubt.
+    async fn ensure_vision(&self, model: &str, request: &LlmRequest) -> Result<()> {
+        if request.images.is_empty() {
+            return Ok(());
+        }
+        let Ok(response) = self
+            .client
+            .post(self.api_url("show"))
+            .json(&serde_json::json!({ "model": model }))
+            .send()
+            .await
+        else {
+            return Ok(());
+        };
+        let Ok(body) = response.json::<Value>().await else {
+            return Ok(());
+        };
+        let Some(capabilities) = body.get("capabilities").and_then(Value::as_array) else {
+            return Ok(());
+        };
+        if capabilities.iter().any(|c| c.as_str() == Some("vision")) {
+            return Ok(());
+        }
+        Err(LlmError::new(
+            LlmErrorKind::InvalidRequest,
+            "ollama",
+            format!("model {} does not support images", model),
+        )
+        .into())
+    }
+
+    async fn send(
+        &self,
+        model: &str,
+        request: &LlmRequest,
+        stream: bool,
+    ) -> Result<reqwest::Response> {
+        self.ensure_vision(model, request).await?;
+        let response = self
+            .post(&self.request_body(model, request, stream))
+            .await?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let err = LlmError::from_response("ollama", response).await;
+        if request.tools.is_empty() || !err.message.contains("does not support tools") {
+            return Err(err.into());
+        }
+        if let Ok(mut models) = self.no_tool_models.lock() {
+            models.insert(model.to_string());
+        }
+        let response = self
+            .post(&self.request_body(model, request, stream))
+            .await?;
+        if !response.status().is_success() {
+            return Err(LlmError::from_response("ollama", response).await.into());
+        }
+        Ok(response)
+    }
+
+    async fn post(&self, body: &ChatRequest) -> Result<reqwest::Response> {
+        Ok(self
+            .client
+            .post(self.api_url("chat"))
+            .json(body)
+            .send()
+            .await
+            .map_err(|err| LlmError::network("ollama", err))?)
+    }
+
+    /// Downloads a model, reporting each status line of the NDJSON progress stream.
+    pub async fn pull(&self, model: &str, mut on_progress: impl FnMut(&str)) -> Result<()> {
+        let response = self
+            .client
+            .post(self.api_url("pull"))
+            .json(&serde_json::json!({ "model": model, "stream": true }))
+            .send()
+            .await
+            .map_err(|err| LlmError::network("ollama", err))?;
+        if !response.status().is_success() {
+            return Err(LlmError::from_response("ollama", response).await.into());
+        }
+        let mut stream = response.bytes_stream();
+        let mut buffer = String::new();
+        let mut last = String::new();
+        loop {
+            let chunk = stream.next().await;
+            if let Some(chunk) = &chunk {
+                let chunk = chunk
+                    .as_ref()
+                    .map_err(|err| anyhow!("ollama pull interrupted: {}", err))?;
+                buffer.push_str(&String::from_utf8_lossy(chunk));
+            } else {
+                buffer.push('\n');
+            }
+            while let Some(idx) = buffer.find('\n') {
+                let line = buffer[..idx].trim().to_string();
+                buffer = buffer[idx + 1..].to_string();
+                if let Some(progress) = Self::pull_progress(&line)? {
+                    if progress != last {
+                        on_progress(&progress);
+                        last = progress;
+                    }
+                }
+            }
+            if chunk.is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn pull_progress(line: &str) -> Result<Option<String>> {
+        if line.is_empty() {
+            return Ok(None);
+        }
+        let value: Value = serde_json::from_str(line)?;
+        if let Some(error) = value.get("error").and_then(Value::as_str) {
+            return Err(anyhow!("ollama pull failed: {}", error));
+        }
+        let Some(status) = value.get("status").and_then(Value::as_str) else {
+            return Ok(None);
+        };
+        let total = value.get("total").and_then(Value::as_u64).unwrap_or(0);
+        let completed = value.get("completed").and_then(Value::as_u64).unwrap_or(0);
+        if total == 0 {
+            return Ok(Some(status.to_string()));
+        }
+        Ok(Some(format!("{} {}%", status, completed * 100 / total)))
+    }
+
+    pub async fn delete(&self, model: &str) -> Result<()> {
+        let response = self
+            .client
+            .delete(self.api_url("delete"))
+            .json(&serde_json::json!({ "model": model }))
+            .send()
+            .await
+            .map_err(|err| LlmError::network("ollama", err))?;
+        if !response.status().is_success() {
+            return Err(LlmError::from_response("ollama", response).await.into());
+        }
+        Ok(())
+    }
+
     fn normalize_usage(
         prompt_eval_count: Option<u64>,
         eval_count: Option<u64>,
@@ -63,6 +328,7 @@ impl OllamaBackend {
         }
         Some(LlmUsage {
             provider: "local".to_string(),
+            model: None,
             input_tokens: prompt_eval_count,
             output_tokens: eval_count,
             total_tokens: match (prompt_eval_count, eval_count) {
@@ -72,9 +338,34 @@ impl OllamaBackend {
             cache_creation_input_tokens: None,
             cache_read_input_tokens: None,
             reasoning_tokens: None,
+            cost_usd: None,
             raw: None,
         })
     }
+
+    fn parse_response(body: ChatResponse) -> LlmResponse {
+        let message = body.message.unwrap_or(ChatResponseMessage {
+            content: String::new(),
+            thinking: String::new(),
+            tool_calls: Vec::new(),
+        });
+        LlmResponse {
+            content: message.content,
+            thinking: (!message.thinking.is_empty()).then_some(LlmThinking {
+                text: message.thinking,
+                signature: None,
+            }),
+            usage: Self::normalize_usage(body.prompt_eval_count, body.eval_count),
+            tool_calls: message
+                .tool_calls
+                .into_iter()
+                .map(|call| LlmToolCall {
+                    name: call.function.name,
+                    arguments: call.function.arguments,
+                })
+                .collect(),
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -83,57 +374,40 @@ impl LlmBackend for OllamaBackend {
         LlmProvider::Local
     }
 
-    async fn generate(&self, model: &str, request: &LlmRequest) -> Result<LlmResponse> {
-        let client = reqwest::Client::new();
-        let payload = GenerateRequest {
-            model: model.to_string(),
-            prompt: request.prompt.clone(),
-            stream: false,
-            images: request
-                .images
-                .iter()
-                .map(|image| image.data_base64.clone())
-                .collect(),
-        };
-        let response = client
-            .post(self.generate_url())
-            .json(&payload)
+    fn supports_json_schema(&self) -> bool {
+        true
+    }
+
+    fn supports_roles(&self) -> bool {
+        true
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let response = self
+            .client
+            .get(self.api_url("tags"))
             .send()
-            .await?;
+            .await
+            .map_err(|err| LlmError::network("ollama", err))?;
         if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!("ollama error: {} {}", status, body.trim()));
+            return Err(LlmError::from_response("ollama", response).await.into());
         }
-        let body: GenerateResponse = response.json().await?;
-        Ok(LlmResponse {
-            content: body.response,
-            usage: Self::normalize_usage(body.prompt_eval_count, body.eval_count),
-        })
+        let body: Value = response.json().await?;
+        Ok(model_ids(&body["models"], "name"))
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
+    async fn generate(&self, model: &str, request: &LlmRequest) -> Result<LlmResponse> {
+        let response = self.send(model, request, false).await?;
+        let body: ChatResponse = response.json().await?;
+        Ok(Self::parse_response(body))
     }
 
     async fn generate_stream(&self, model: &str, request: &LlmRequest) -> Result<LlmStream> {
-        let client = reqwest::Client::new();
-        let payload = GenerateRequest {
-            model: model.to_string(),
-            prompt: request.prompt.clone(),
-            stream: true,
-            images: request
-                .images
-                .iter()
-                .map(|image| image.data_base64.clone())
-                .collect(),
-        };
-        let response = client
-            .post(self.generate_url())
-            .json(&payload)
-            .send()
-            .await?;
-        if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!("ollama error: {} {}", status, body.trim()));
-        }
+        let response = self.send(model, request, true).await?;
 
         struct StreamState {
             stream: BoxStream<'static, Result<Bytes, reqwest::Error>>,
@@ -142,6 +416,37 @@ impl LlmBackend for OllamaBackend {
             done: bool,
         }
 
+        fn take_line(state: &mut StreamState, line: &str) -> Option<Result<LlmStreamEvent>> {
+            let msg = match serde_json::from_str::<ChatResponse>(line) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    state.done = true;
+                    return Some(Err(anyhow!("ollama stream parse error: {}", err)));
+                }
+            };
+            if msg.done {
+                state.pending_usage =
+                    OllamaBackend::normalize_usage(msg.prompt_eval_count, msg.eval_count);
+            }
+            let (text, thinking) = msg
+                .message
+                .map(|m| (m.content, m.thinking))
+                .unwrap_or_default();
+            if !thinking.is_empty() {
+                // Ollama sends thinking and answer text in separate chunks.
+                return Some(Ok(LlmStreamEvent::Thinking {
+                    text: thinking,
+                    signature: None,
+                }));
+            }
+            if text.is_empty() {
+                let usage = state.pending_usage.take()?;
+                state.done = true;
+                return Some(Ok(LlmStreamEvent::Usage(usage)));
+            }
+            Some(Ok(LlmStreamEvent::Text(text)))
+        }
+
         let state = StreamState {
             stream: Box::pin(response.bytes_stream()),
             buffer: String::new(),
@@ -160,36 +465,14 @@ impl LlmBackend for OllamaBackend {
                 }
 
                 if let Some(idx) = state.buffer.find('\n') {
-                    let line = state.buffer[..idx].to_string();
+                    let line = state.buffer[..idx].trim().to_string();
                     state.buffer = state.buffer[idx + 1..].to_string();
-                    let line = line.trim();
                     if line.is_empty() {
                         continue;
                     }
-                    match serde_json::from_str::<GenerateStreamResponse>(line) {
-                        Ok(msg) => {
-                            if msg.done {
-                                state.pending_usage = OllamaBackend::normalize_usage(
-                                    msg.prompt_eval_count,
-                                    msg.eval_count,
-                                );
-                                if msg.response.is_empty() {
-                                    if let Some(usage) = state.pending_usage.take() {
-                                        state.done = true;
-                                        return Some((Ok(LlmStreamEvent::Usage(usage)), state));
-                                    }
-                                    return None;
-                                }
-                            }
-                            return Some((Ok(LlmStreamEvent::Text(msg.response)), state));
-                        }
-                        Err(err) => {
-                            state.done = true;
-                            return Some((
-                                Err(anyhow!("ollama stream parse error: {}", err)),
-                                state,
-                            ));
-                        }
+                    match take_line(&mut state, &line) {
+                        Some(event) => return Some((event, state)),
+                        None => continue,
                     }
                 }
 
@@ -199,35 +482,21 @@ impl LlmBackend for OllamaBackend {
                     }
                     Some(Err(err)) => {
                         state.done = true;
-                        return Some((Err(anyhow::Error::new(err)), state));
+                        let err = LlmError::new(
+                            LlmErrorKind::StreamInterrupted,
+                            "ollama",
+                            err.to_string(),
+                        );
+                        return Some((Err(err.into()), state));
                     }
                     None => {
-                        if state.buffer.trim().is_empty() {
-                            return None;
-                        }
                         let line = state.buffer.trim().to_string();
                         state.buffer.clear();
-                        match serde_json::from_str::<GenerateStreamResponse>(line.trim()) {
-                            Ok(msg) => {
-                                state.pending_usage = OllamaBackend::normalize_usage(
-                                    msg.prompt_eval_count,
-                                    msg.eval_count,
-                                );
-                                if msg.response.is_empty() {
-                                    if let Some(usage) = state.pending_usage.take() {
-                                        state.done = true;
-                                        return Some((Ok(LlmStreamEvent::Usage(usage)), state));
-                                    }
-                                }
-                                return Some((Ok(LlmStreamEvent::Text(msg.response)), state));
-                            }
-                            Err(err) => {
-                                state.done = true;
-                                return Some((
-                                    Err(anyhow!("ollama stream parse error: {}", err)),
-                                    state,
-                                ));
-                            }
+                        if line.is_empty() {
+                            return None;
+                        }
+                        if let Some(event) = take_line(&mut state, &line) {
+                            return Some((event, state));
                         }
                     }
                 }
@@ -237,3 +506,136 @@ impl LlmBackend for OllamaBackend {
         Ok(Box::pin(output) as BoxStream<'static, Result<LlmStreamEvent>>)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{LlmImage, LlmTool};
+
+    #[tokio::test]
+    async fn sends_system_prompt_and_context_as_role_messages() {
+        let answer = serde_json::json!({
+            "message": { "role": "assistant", "content": "ok" },
+            "done": true,
+        });
+        let (base_url, server) =
+            crate::llm::stub::serve_once("application/json", answer.to_string().into_bytes()).await;
+        let mut settings = ProviderSettings::default();
+        settings.base_url = Some(base_url);
+        let client = crate::llm::LlmClient::new(Box::new(OllamaBackend::new(settings).unwrap()));
+        let request = LlmRequest {
+            system: Some("Project rules".to_string()),
+            context: Some("過去の会話:\nユーザー: hi".to_string()),
+            ..LlmRequest::text("next")
+        };
+        client.generate("qwen3", &request).await.unwrap();
+        let raw = server.await.unwrap();
+        let body: Value = serde_json::from_str(raw.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        let messages: Vec<(&str, &str)> = body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| (m["role"].as_str().unwrap(), m["content"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                ("system", "Project rules"),
+                ("user", "過去の会話:\nユーザー: hi"),
+                ("user", "next"),
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_chat_request_with_images_tools_and_options() {
+        let mut settings = ProviderSettings::default();
+        settings.base_url = Some("http://gpu-box:11434/api".to_string());
+        settings.keep_alive = Some("30m".to_string());
+        settings
+            .options
+            .insert("num_ctx".to_string(), Value::from(32768));
+        let backend = OllamaBackend::new(settings).unwrap();
+        assert_eq!(backend.api_url("chat"), "http://gpu-box:11434/api/chat");
+
+        let mut request = LlmRequest::text("what is this?");
+        request.images.push(LlmImage {
+            media_type: "image/png".to_string(),
+            data_base64: "AAECAw==".to_string(),
+        });
+        request.json_output = true;
+        let body = serde_json::to_value(backend.request_body("llava", &request, true)).unwrap();
+        assert_eq!(body["messages"][0]["role"], "user");
+        assert_eq!(body["messages"][0]["images"][0], "AAECAw==");
+        assert_eq!(body["options"]["num_ctx"], 32768);
+        assert_eq!(body["keep_alive"], "30m");
+        assert_eq!(body["format"], "json");
+
+        request.tools.push(LlmTool {
+            name: "read".to_string(),
+            description: "Read a file".to_string(),
+            parameters: serde_json::json!({"type": "object"}),
+        });
+        let body = serde_json::to_value(backend.request_body("qwen3", &request, false)).unwrap();
+        assert_eq!(body["tools"][0]["function"]["name"], "read");
+        assert!(body.get("format").is_none());
+        let body = serde_json::to_value(backend.request_body("qwen3", &request, true)).unwrap();
+        assert!(body.get("tools").is_none());
+
+        backend
+            .no_tool_models
+            .lock()
+            .unwrap()
+            .insert("qwen3".to_string());
+        let body = serde_json::to_value(backend.request_body("qwen3", &request, false)).unwrap();
+        assert!(body.get("tools").is_none());
+        assert_eq!(body["format"], "json");
+    }
+
+    #[test]
+    fn parses_native_tool_calls() {
+        let body: ChatResponse = serde_json::from_str(
+            r#"{"message":{"role":"assistant","content":"","tool_calls":[{"function":{"name":"read","arguments":{"path":"src/main.rs"}}}]},"done":true,"prompt_eval_count":20,"eval_count":4}"#,
+        )
+        .unwrap();
+        let response = OllamaBackend::parse_response(body);
+        assert_eq!(
+            response.tool_calls,
+            vec![LlmToolCall {
+                name: "read".to_string(),
+                arguments: serde_json::json!({"path": "src/main.rs"}),
+            }]
+        );
+        assert_eq!(response.usage.unwrap().total_tokens, Some(24));
+    }
+
+    #[test]
+    fn reads_pull_progress_and_errors() {
+        let progress = |line: &str| OllamaBackend::pull_progress(line).unwrap();
+        assert_eq!(
+            progress(r#"{"status":"pulling manifest"}"#),
+            Some("pulling manifest".to_string())
+        );
+        assert_eq!(
+            progress(
+                r#"{"status":"pulling 8eeb52dfb3bb","digest":"sha256:8eeb","total":2000,"completed":500}"#
+            ),
+            Some("pulling 8eeb52dfb3bb 25%".to_string())
+        );
+        assert_eq!(progress(""), None);
+        let err =
+            OllamaBackend::pull_progress(r#"{"error":"pull model manifest: file does not exist"}"#)
+                .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ollama pull failed: pull model manifest: file does not exist"
+        );
+        assert_eq!(
+            model_ids(
+                &serde_json::json!([{"name": "llama3.2:latest"}, {"model": "x"}]),
+                "name"
+            ),
+            vec!["llama3.2:latest".to_string()]
+        );
+    }
+}
@@ -5,15 +5,24 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::llm::{
-    LlmBackend, LlmProvider, LlmRequest, LlmResponse, LlmStream, LlmStreamEvent, LlmUsage,
+    model_ids, LlmBackend, LlmError, LlmErrorKind, LlmProvider, LlmRequest, LlmResponse, LlmStream,
+    LlmStreamEvent, LlmThinking, LlmUsage, ProviderSettings,
 };
 
 const DEFAULT_OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_LM_STUDIO_BASE_URL: &str = "http://localhost:1234/v1";
+const DEFAULT_AZURE_API_VERSION: &str = "2024-10-21";
 
 #[derive(Debug, Clone)]
 pub struct OpenAiBackend {
     pub base_url: String,
     pub max_tokens: Option<u32>,
+    /// Label used in errors and usage; the `[providers.<name>]` table name for compatible servers.
+    name: String,
+    provider: LlmProvider,
+    stream_usage: bool,
+    settings: ProviderSettings,
+    client: reqwest::Client,
 }
 
 #[derive(Debug, Serialize)]
@@ -23,8 +32,26 @@ struct ChatCompletionRequest {
     stream: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     max_tokens: Option<u32>,
+    /// Replaces `max_tokens` for OpenAI reasoning models, which reject it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_completion_tokens: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     stream_options: Option<StreamOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    /// Not part of the OpenAI API; vLLM and llama.cpp accept it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_effort: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<Value>,
 }
 
 #[derive(Debug, Serialize)]
@@ -79,27 +106,140 @@ struct ChatChoice {
 #[derive(Debug, Deserialize)]
 struct ChatMessageResponse {
     content: Value,
+    #[serde(default, alias = "reasoning")]
+    reasoning_content: Option<String>,
 }
 
 impl OpenAiBackend {
-    pub fn new(base_url: Option<String>, max_tokens: Option<u32>) -> Self {
-        Self {
-            base_url: base_url.unwrap_or_else(|| DEFAULT_OPENAI_BASE_URL.to_string()),
-            max_tokens,
-        }
+    pub fn new(settings: ProviderSettings) -> Result<Self> {
+        let base_url = settings
+            .base_url
+            .clone()
+            .unwrap_or_else(|| DEFAULT_OPENAI_BASE_URL.to_string());
+        Self::build("openai", LlmProvider::OpenAI, base_url, settings)
     }
 
-    fn chat_completions_url(&self) -> String {
+    /// An OpenAI-compatible server (vLLM, llama.cpp, LM Studio, gateways). Auth is optional.
+    pub fn compatible(name: &str, settings: ProviderSettings) -> Result<Self> {
+        let base_url = match settings.base_url.clone() {
+            Some(url) => url,
+            None if matches!(name, "lm-studio" | "lmstudio") => {
+                DEFAULT_LM_STUDIO_BASE_URL.to_string()
+            }
+            None => return Err(anyhow!("provider {} needs a base_url", name)),
+        };
+        Self::build(name, LlmProvider::OpenAiCompatible, base_url, settings)
+    }
+
+    /// Azure OpenAI: `base_url` is the resource endpoint and the model is a deployment name.
+    pub fn azure(name: &str, settings: ProviderSettings) -> Result<Self> {
+        let base_url = settings
+            .base_url
+            .clone()
+            .filter(|url| !url.trim().is_empty())
+            .ok_or_else(|| {
+                anyhow!(
+                    "provider {} needs a base_url or AZURE_OPENAI_ENDPOINT",
+                    name
+                )
+            })?;
+        Self::build(name, LlmProvider::Azure, base_url, settings)
+    }
+
+    fn build(
+        name: &str,
+        provider: LlmProvider,
+        base_url: String,
+        settings: ProviderSettings,
+    ) -> Result<Self> {
+        Ok(Self {
+            base_url,
+            max_tokens: settings.max_tokens,
+            name: name.to_string(),
+            provider,
+            stream_usage: settings.stream_options.unwrap_or(true),
+            client: settings.http_client()?,
+            settings,
+        })
+    }
+
+    fn chat_completions_url(&self, model: &str) -> String {
         let base = self.base_url.trim_end_matches('/');
-        if base.ends_with("/v1") {
+        if self.provider == LlmProvider::Azure {
+            format!(
+                "{}/openai/deployments/{}/chat/completions?api-version={}",
+                base.trim_end_matches("/openai"),
+                model,
+                self.azure_api_version()
+            )
+        } else if base.ends_with("/v1") {
             format!("{}/chat/completions", base)
         } else {
             format!("{}/v1/chat/completions", base)
         }
     }
 
-    fn api_key(&self) -> Result<String> {
-        std::env::var("OPENAI_API_KEY").map_err(|_| anyhow!("OPENAI_API_KEY is not set"))
+    fn models_url(&self) -> String {
+        let base = self.base_url.trim_end_matches('/');
+        if self.provider == LlmProvider::Azure {
+            format!(
+                "{}/openai/models?api-version={}",
+                base.trim_end_matches("/openai"),
+                self.azure_api_version()
+            )
+        } else if base.ends_with("/v1") {
+            format!("{}/models", base)
+        } else {
+            format!("{}/v1/models", base)
+        }
+    }
+
+    async fn api_key(&self) -> Result<Option<String>> {
+        if self.provider == LlmProvider::OpenAiCompatible && !self.settings.has_api_key_source() {
+            return Ok(None);
+        }
+        self.settings.api_key("OPENAI_API_KEY").await.map(Some)
+    }
+
+    /// OpenAI and Azure reasoning models take `reasoning_effort` but reject `max_tokens`,
+    /// `temperature` and `top_p`. Compatible servers accept them alongside reasoning.
+    fn is_reasoning(&self, request: &LlmRequest) -> bool {
+        request.thinking.is_enabled()
+            && matches!(self.provider, LlmProvider::OpenAI | LlmProvider::Azure)
+    }
+
+    fn azure_api_version(&self) -> &str {
+        self.settings
+            .api_version
+            .as_deref()
+            .unwrap_or(DEFAULT_AZURE_API_VERSION)
+    }
+
+    /// Azure takes an Entra ID bearer token or its own `api-key` header.
+    async fn authorize(&self, builder: reqwest::RequestBuilder) -> Result<reqwest::RequestBuilder> {
+        if self.provider == LlmProvider::Azure {
+            return Ok(match self.settings.bearer_token().await? {
+                Some(token) => builder.bearer_auth(token),
+                None => builder.header(
+                    "api-key",
+                    self.settings.api_key("AZURE_OPENAI_API_KEY").await?,
+                ),
+            });
+        }
+        Ok(match self.api_key().await? {
+            Some(api_key) => builder.bearer_auth(api_key),
+            None => builder,
+        })
+    }
+
+    async fn post(&self, body: &ChatCompletionRequest) -> Result<reqwest::RequestBuilder> {
+        self.authorize(
+            self.client
+                .post(self.chat_completions_url(&body.model))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .json(body),
+        )
+        .await
     }
 
     fn request_body(
@@ -125,6 +265,7 @@ impl OpenAiBackend {
             }
             Value::Array(items)
         };
+        let reasoning = self.is_reasoning(request);
         ChatCompletionRequest {
             model: model.to_string(),
             messages: vec![ChatMessage {
@@ -132,16 +273,39 @@ impl OpenAiBackend {
                 content,
             }],
             stream,
-            max_tokens: self.max_tokens,
-            stream_options: stream.then_some(StreamOptions {
+            max_tokens: request
+                .max_tokens
+                .or(self.max_tokens)
+                .filter(|_| !reasoning),
+            max_completion_tokens: request.max_tokens.or(self.max_tokens).filter(|_| reasoning),
+            stream_options: (stream && self.stream_usage).then_some(StreamOptions {
                 include_usage: true,
             }),
+            temperature: request.sampling.temperature.filter(|_| !reasoning),
+            top_p: request.sampling.top_p.filter(|_| !reasoning),
+            top_k: request
+                .sampling
+                .top_k
+                .filter(|_| self.provider == LlmProvider::OpenAiCompatible),
+            stop: request.sampling.stop.clone(),
+            seed: request.sampling.seed,
+            reasoning_effort: request
+                .thinking
+                .is_enabled()
+                .then(|| request.thinking.effort().as_str()),
+            response_format: request.json_schema.as_ref().map(|schema| {
+                serde_json::json!({
+                    "type": "json_schema",
+                    "json_schema": { "name": "response", "schema": schema },
+                })
+            }),
         }
     }
 
-    fn normalize_usage(usage: OpenAiUsage, raw: Option<Value>) -> LlmUsage {
+    fn normalize_usage(provider: &str, usage: OpenAiUsage, raw: Option<Value>) -> LlmUsage {
         LlmUsage {
-            provider: "openai".to_string(),
+            provider: provider.to_string(),
+            model: None,
             input_tokens: usage.prompt_tokens,
             output_tokens: usage.completion_tokens,
             total_tokens: usage.total_tokens.or_else(|| {
@@ -157,28 +321,26 @@ impl OpenAiBackend {
             reasoning_tokens: usage
                 .completion_tokens_details
                 .and_then(|details| details.reasoning_tokens),
+            cost_usd: None,
             raw,
         }
     }
 
-    fn parse_stream_data(data: &str) -> Result<Option<LlmStreamEvent>> {
+    fn parse_stream_data(provider: &str, data: &str) -> Result<Option<LlmStreamEvent>> {
         let payload = data.trim();
         if payload.is_empty() || payload == "[DONE]" {
             return Ok(None);
         }
 
         let value: Value = serde_json::from_str(payload)?;
-        if let Some(error) = value
-            .get("error")
-            .and_then(|err| err.get("message"))
-            .and_then(Value::as_str)
-        {
-            return Err(anyhow!("openai stream error: {}", error));
+        if let Some(error) = value.get("error").filter(|err| !err.is_null()) {
+            return Err(LlmError::from_stream_error(provider, error).into());
         }
 
         if let Some(usage_value) = value.get("usage").cloned() {
             if let Ok(usage) = serde_json::from_value::<OpenAiUsage>(usage_value.clone()) {
                 return Ok(Some(LlmStreamEvent::Usage(Self::normalize_usage(
+                    provider,
                     usage,
                     Some(usage_value),
                 ))));
@@ -197,6 +359,18 @@ impl OpenAiBackend {
             return Ok(None);
         };
 
+        // OpenAI keeps reasoning hidden; vLLM, llama.cpp and DeepSeek-style servers stream it.
+        if let Some(text) = ["reasoning_content", "reasoning"]
+            .iter()
+            .find_map(|key| delta.get(*key).and_then(Value::as_str))
+            .filter(|text| !text.is_empty())
+        {
+            return Ok(Some(LlmStreamEvent::Thinking {
+                text: text.to_string(),
+                signature: None,
+            }));
+        }
+
         if let Some(text) = delta.get("content").and_then(Value::as_str) {
             if !text.is_empty() {
                 return Ok(Some(LlmStreamEvent::Text(text.to_string())));
@@ -210,31 +384,75 @@ impl OpenAiBackend {
 #[async_trait::async_trait]
 impl LlmBackend for OpenAiBackend {
     fn provider(&self) -> LlmProvider {
-        LlmProvider::OpenAI
+        self.provider
+    }
+
+    fn supports_json_schema(&self) -> bool {
+        true
+    }
+
+    /// Azure lists base models, while requests name deployments.
+    fn lists_served_models(&self) -> bool {
+        self.provider != LlmProvider::Azure
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        let response = self
+            .authorize(self.client.get(self.models_url()))
+            .await?
+            .send()
+            .await
+            .map_err(|err| LlmError::network(&self.name, err))?;
+        if !response.status().is_success() {
+            return Err(LlmError::from_response(&self.name, response).await.into());
+        }
+        let body: Value = response.json().await?;
+        Ok(model_ids(&body["data"], "id"))
+    }
+
+    fn unsupported_sampling(&self, request: &LlmRequest) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.provider == LlmProvider::OpenAiCompatible {
+            return names;
+        }
+        if request.sampling.top_k.is_some() {
+            names.push("top_k");
+        }
+        if self.is_reasoning(request) {
+            if request.sampling.temperature.is_some() {
+                names.push("temperature with reasoning");
+            }
+            if request.sampling.top_p.is_some() {
+                names.push("top_p with reasoning");
+            }
+        }
+        names
     }
 
     async fn generate(&self, model: &str, request: &LlmRequest) -> Result<LlmResponse> {
-        let api_key = self.api_key()?;
-        let client = reqwest::Client::new();
-        let response = client
-            .post(self.chat_completions_url())
-            .bearer_auth(api_key)
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .json(&self.request_body(model, request, false))
+        let response = self
+            .post(&self.request_body(model, request, false))
+            .await?
             .send()
-            .await?;
+            .await
+            .map_err(|err| LlmError::network(&self.name, err))?;
 
         if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!("openai error: {} {}", status, body.trim()));
+            return Err(LlmError::from_response(&self.name, response).await.into());
         }
 
         let body: ChatCompletionResponse = response.json().await?;
-        let content = body
-            .choices
-            .into_iter()
-            .find_map(|choice| choice.message.map(|message| message.content))
+        let message = body.choices.into_iter().find_map(|choice| choice.message);
+        let thinking = message
+            .as_ref()
+            .and_then(|message| message.reasoning_content.clone())
+            .filter(|text| !text.is_empty())
+            .map(|text| LlmThinking {
+                text,
+                signature: None,
+            });
+        let content = message
+            .map(|message| message.content)
             .map(|content| match content {
                 Value::String(text) => text,
                 Value::Array(items) => items
@@ -247,28 +465,28 @@ impl LlmBackend for OpenAiBackend {
             .unwrap_or_default();
         Ok(LlmResponse {
             content,
-            usage: body.usage.map(|usage| Self::normalize_usage(usage, None)),
+            thinking,
+            usage: body
+                .usage
+                .map(|usage| Self::normalize_usage(&self.name, usage, None)),
+            ..Default::default()
         })
     }
 
     async fn generate_stream(&self, model: &str, request: &LlmRequest) -> Result<LlmStream> {
-        let api_key = self.api_key()?;
-        let client = reqwest::Client::new();
-        let response = client
-            .post(self.chat_completions_url())
-            .bearer_auth(api_key)
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .json(&self.request_body(model, request, true))
+        let response = self
+            .post(&self.request_body(model, request, true))
+            .await?
             .send()
-            .await?;
+            .await
+            .map_err(|err| LlmError::network(&self.name, err))?;
 
         if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!("openai error: {} {}", status, body.trim()));
+            return Err(LlmError::from_response(&self.name, response).await.into());
         }
 
         struct StreamState {
+            provider: String,
             stream: BoxStream<'static, Result<Bytes, reqwest::Error>>,
             buffer: String,
             pending_data: Vec<String>,
@@ -287,7 +505,7 @@ impl LlmBackend for OpenAiBackend {
                     if !state.pending_data.is_empty() {
                         let data = state.pending_data.join("\n");
                         state.pending_data.clear();
-                        return OpenAiBackend::parse_stream_data(&data);
+                        return OpenAiBackend::parse_stream_data(&state.provider, &data);
                     }
                     continue;
                 }
@@ -301,6 +519,7 @@ impl LlmBackend for OpenAiBackend {
         }
 
         let state = StreamState {
+            provider: self.name.clone(),
             stream: Box::pin(response.bytes_stream()),
             buffer: String::new(),
             pending_data: Vec::new(),
@@ -328,13 +547,18 @@ impl LlmBackend for OpenAiBackend {
                     }
                     Some(Err(err)) => {
                         state.finished = true;
-                        return Some((Err(anyhow::Error::new(err)), state));
+                        let err = LlmError::new(
+                            LlmErrorKind::StreamInterrupted,
+                            &state.provider,
+                            err.to_string(),
+                        );
+                        return Some((Err(err.into()), state));
                     }
                     None => {
                         state.finished = true;
                         if !state.pending_data.is_empty() {
                             let data = state.pending_data.join("\n");
-                            match OpenAiBackend::parse_stream_data(&data) {
+                            match OpenAiBackend::parse_stream_data(&state.provider, &data) {
                                 Ok(Some(text)) => return Some((Ok(text), state)),
                                 Ok(None) => return None,
                                 Err(err) => return Some((Err(err), state)),
@@ -356,24 +580,25 @@ mod tests {
 
     #[test]
     fn builds_chat_completions_url_from_default_base() {
-        let backend = OpenAiBackend::new(None, None);
+        let backend = OpenAiBackend::new(ProviderSettings::default()).unwrap();
         assert_eq!(
-            backend.chat_completions_url(),
+            backend.chat_completions_url("gpt-4o"),
             "https://api.openai.com/v1/chat/completions"
         );
+        assert_eq!(backend.models_url(), "https://api.openai.com/v1/models");
     }
 
     #[test]
     fn parses_content_delta_from_stream_payload() {
         let payload = r#"{"choices":[{"delta":{"content":"hello"}}]}"#;
-        let parsed = OpenAiBackend::parse_stream_data(payload).unwrap();
+        let parsed = OpenAiBackend::parse_stream_data("openai", payload).unwrap();
         assert!(matches!(parsed, Some(LlmStreamEvent::Text(text)) if text == "hello"));
     }
 
     #[test]
     fn parses_usage_from_stream_payload() {
         let payload = r#"{"choices":[],"usage":{"prompt_tokens":12,"completion_tokens":5,"total_tokens":17,"prompt_tokens_details":{"cached_tokens":3},"completion_tokens_details":{"reasoning_tokens":2}}}"#;
-        let parsed = OpenAiBackend::parse_stream_data(payload).unwrap();
+        let parsed = OpenAiBackend::parse_stream_data("openai", payload).unwrap();
         assert!(matches!(
             parsed,
             Some(LlmStreamEvent::Usage(usage))
@@ -385,4 +610,134 @@ mod tests {
                     && usage.reasoning_tokens == Some(2)
         ));
     }
+
+    #[tokio::test]
+    async fn compatible_server_needs_no_key_and_can_skip_stream_options() {
+        let mut settings = ProviderSettings::default();
+        settings.base_url = Some("http://127.0.0.1:8000/v1".to_string());
+        settings.stream_options = Some(false);
+        let backend = OpenAiBackend::compatible("vllm", settings).unwrap();
+        assert_eq!(backend.provider(), LlmProvider::OpenAiCompatible);
+        assert_eq!(backend.api_key().await.unwrap(), None);
+        assert_eq!(
+            backend.chat_completions_url("qwen"),
+            "http://127.0.0.1:8000/v1/chat/completions"
+        );
+        let request = LlmRequest::text("hi");
+        let body = serde_json::to_value(backend.request_body("qwen", &request, true)).unwrap();
+        assert!(body.get("stream_options").is_none());
+
+        let payload = r#"{"choices":[{"delta":{"reasoning_content":"step 1"}}]}"#;
+        let parsed = OpenAiBackend::parse_stream_data("vllm", payload).unwrap();
+        assert!(matches!(parsed, Some(LlmStreamEvent::Thinking { text, .. }) if text == "step 1"));
+
+        let err = OpenAiBackend::compatible("llamacpp", ProviderSettings::default()).unwrap_err();
+        assert_eq!(err.to_string(), "provider llamacpp needs a base_url");
+        let lm_studio = OpenAiBackend::compatible("lm-studio", ProviderSettings::default());
+        assert_eq!(lm_studio.unwrap().base_url, "http://localhost:1234/v1");
+    }
+
+    #[test]
+    fn sends_max_completion_tokens_without_sampling_for_reasoning() {
+        let mut settings = ProviderSettings::default();
+        settings.max_tokens = Some(8192);
+        let backend = OpenAiBackend::new(settings.clone()).unwrap();
+        let mut request = LlmRequest::text("hi");
+        request.sampling.temperature = Some(0.2);
+        request.sampling.top_p = Some(0.9);
+        let body = serde_json::to_value(backend.request_body("gpt-4o", &request, false)).unwrap();
+        assert_eq!(body["max_tokens"], 8192);
+        assert_eq!(body["top_p"], serde_json::json!(0.9_f32));
+        assert!(backend.unsupported_sampling(&request).is_empty());
+
+        request.thinking.effort = Some(crate::llm::ReasoningEffort::High);
+        let body = serde_json::to_value(backend.request_body("o3", &request, false)).unwrap();
+        assert_eq!(body["reasoning_effort"], "high");
+        assert_eq!(body["max_completion_tokens"], 8192);
+        for field in ["max_tokens", "temperature", "top_p"] {
+            assert!(body.get(field).is_none(), "{} was sent", field);
+        }
+        assert_eq!(
+            backend.unsupported_sampling(&request),
+            vec!["temperature with reasoning", "top_p with reasoning"]
+        );
+
+        settings.base_url = Some("http://127.0.0.1:8000/v1".to_string());
+        let vllm = OpenAiBackend::compatible("vllm", settings).unwrap();
+        let body = serde_json::to_value(vllm.request_body("qwen", &request, false)).unwrap();
+        assert_eq!(body["max_tokens"], 8192);
+        assert!(body.get("temperature").is_some());
+
+        request.max_tokens = Some(200);
+        let body = serde_json::to_value(vllm.request_body("qwen", &request, false)).unwrap();
+        assert_eq!(body["max_tokens"], 200);
+    }
+
+    #[tokio::test]
+    async fn switches_to_azure_deployment_names_without_listing() {
+        let mut settings = ProviderSettings::default();
+        // Nothing listens here: listing models would fail the check.
+        settings.base_url = Some("http://127.0.0.1:9".to_string());
+        let client =
+            crate::llm::LlmClient::new(Box::new(OpenAiBackend::azure("azure", settings).unwrap()));
+        client.check_model("gpt-4o-prod").await.unwrap();
+
+        let mut settings = ProviderSettings::default();
+        settings.base_url = Some("http://127.0.0.1:9".to_string());
+        let client = crate::llm::LlmClient::new(Box::new(
+            crate::llm::BedrockBackend::new(settings).unwrap(),
+        ));
+        client
+            .check_model("us.anthropic.claude-3-7-sonnet-20250219-v1:0")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn calls_azure_deployments_with_api_key_or_entra_token() {
+        let answer = serde_json::json!({
+            "choices": [{ "message": { "role": "assistant", "content": "hi" } }],
+            "usage": { "prompt_tokens": 3, "completion_tokens": 1 },
+        });
+        let (base_url, server) =
+            crate::llm::stub::serve_once("application/json", answer.to_string().into_bytes()).await;
+        let mut settings = ProviderSettings::default();
+        settings.base_url = Some(format!("{}/openai/", base_url));
+        settings.api_key_env = Some("TENGU_TEST_UNSET_KEY".to_string());
+        settings.api_key_command = Some("printf azure-key".to_string());
+        let backend = OpenAiBackend::azure("azure", settings.clone()).unwrap();
+        let response = backend
+            .generate("gpt-4o-prod", &LlmRequest::text("hello"))
+            .await
+            .unwrap();
+        assert_eq!(response.content, "hi");
+        assert_eq!(response.usage.unwrap().provider, "azure");
+        let raw = server.await.unwrap();
+        assert!(raw.starts_with(
+            "POST /openai/deployments/gpt-4o-prod/chat/completions?api-version=2024-10-21 HTTP/1.1"
+        ));
+        assert!(raw.contains("api-key: azure-key\r\n"));
+        assert!(!raw.to_ascii_lowercase().contains("authorization:"));
+
+        let (base_url, server) =
+            crate::llm::stub::serve_once("application/json", answer.to_string().into_bytes()).await;
+        settings.base_url = Some(base_url);
+        settings.api_version = Some("2025-01-01-preview".to_string());
+        settings.token_command = Some("printf entra-token".to_string());
+        let backend = OpenAiBackend::azure("work-azure", settings).unwrap();
+        backend
+            .generate("gpt-4o-prod", &LlmRequest::text("hello"))
+            .await
+            .unwrap();
+        let raw = server.await.unwrap();
+        assert!(raw.contains("?api-version=2025-01-01-preview HTTP/1.1"));
+        assert!(raw.contains("authorization: Bearer entra-token\r\n"));
+        assert!(!raw.contains("api-key:"));
+
+        let err = OpenAiBackend::azure("azure", ProviderSettings::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "provider azure needs a base_url or AZURE_OPENAI_ENDPOINT"
+        );
+    }
 }
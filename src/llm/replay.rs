@@ -0,0 +1,597 @@
+use anyhow::{anyhow, Result};
+use futures_util::stream::{self, StreamExt};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::llm::{
+    LlmBackend, LlmProvider, LlmRequest, LlmResponse, LlmStream, LlmStreamEvent, LlmThinking,
+    LlmToolCall, LlmUsage,
+};
+
+pub const DEFAULT_CASSETTE_DIR: &str = ".tengu/cassettes";
+const MANIFEST: &str = "manifest.json";
+const REDACTED: &str = "[REDACTED]";
+
+/// Cassettes started by this process; the first recording of a request replaces the file,
+/// later ones append, so a re-recorded session does not keep stale answers.
+static STARTED: Lazy<Mutex<HashSet<PathBuf>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// What the recorded backends advertised; replay must answer the same way or the agent
+/// would build different requests.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct Capabilities {
+    supports_tools: bool,
+    supports_prompt_cache: bool,
+    #[serde(default)]
+    supports_json_schema: bool,
+    #[serde(default)]
+    supports_roles: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    providers: BTreeMap<String, Capabilities>,
+}
+
+/// One `<hash>.json` file: the (redacted) request for reading, and its answers in order.
+#[derive(Debug, Serialize, Deserialize)]
+struct Cassette {
+    request: Value,
+    responses: Vec<Recorded>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Recorded {
+    Response {
+        content: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        thinking: Option<LlmThinking>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tool_calls: Vec<LlmToolCall>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        usage: Option<Box<LlmUsage>>,
+    },
+    Stream {
+        events: Vec<RecordedEvent>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RecordedEvent {
+    Text {
+        text: String,
+    },
+    Thinking {
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
+    },
+    Usage(LlmUsage),
+}
+
+impl From<&LlmStreamEvent> for RecordedEvent {
+    fn from(event: &LlmStreamEvent) -> Self {
+        match event {
+            LlmStreamEvent::Text(text) => Self::Text { text: text.clone() },
+            LlmStreamEvent::Thinking { text, signature } => Self::Thinking {
+                text: text.clone(),
+                signature: signature.clone(),
+            },
+            LlmStreamEvent::Usage(usage) => Self::Usage(usage.clone()),
+        }
+    }
+}
+
+impl From<RecordedEvent> for LlmStreamEvent {
+    fn from(event: RecordedEvent) -> Self {
+        match event {
+            RecordedEvent::Text { text } => Self::Text(text),
+            RecordedEvent::Thinking { text, signature } => Self::Thinking { text, signature },
+            RecordedEvent::Usage(usage) => Self::Usage(usage),
+        }
+    }
+}
+
+impl Recorded {
+    fn from_response(response: &LlmResponse) -> Self {
+        Self::Response {
+            content: response.content.clone(),
+            thinking: response.thinking.clone(),
+            tool_calls: response.tool_calls.clone(),
+            usage: response.usage.clone().map(Box::new),
+        }
+    }
+
+    /// A streamed answer can serve `generate`, and a plain one can be streamed back.
+    fn into_response(self) -> LlmResponse {
+        match self {
+            Self::Response {
+                content,
+                thinking,
+                tool_calls,
+                usage,
+            } => LlmResponse {
+                content,
+                usage: usage.map(|usage| *usage),
+                thinking,
+                tool_calls,
+            },
+            Self::Stream { events } => {
+                let mut response = LlmResponse::default();
+                let mut thinking = LlmThinking::default();
+                for event in events {
+                    match event {
+                        RecordedEvent::Text { text } => response.content.push_str(&text),
+                        RecordedEvent::Thinking { text, signature } => {
+                            thinking.push(&text, signature.as_deref())
+                        }
+                        RecordedEvent::Usage(usage) => response.usage = Some(usage),
+                    }
+                }
+                response.thinking = (!thinking.is_empty()).then_some(thinking);
+                response
+            }
+        }
+    }
+
+    fn into_events(self) -> Vec<RecordedEvent> {
+        match self {
+            Self::Stream { events } => events,
+            Self::Response {
+                content,
+                thinking,
+                usage,
+                ..
+            } => {
+                let mut events = Vec::new();
+                if let Some(thinking) = thinking.filter(|t| !t.is_empty()) {
+                    events.push(RecordedEvent::Thinking {
+                        text: thinking.text,
+                        signature: thinking.signature,
+                    });
+                }
+                if !content.is_empty() {
+                    events.push(RecordedEvent::Text { text: content });
+                }
+                events.extend(usage.map(|usage| RecordedEvent::Usage(*usage)));
+                events
+            }
+        }
+    }
+}
+
+/// The request as the backend sees it. The model is left out so a cassette recorded with
+/// one model replays under any name; image data is reduced to its hash.
+fn request_json(request: &LlmRequest) -> Value {
+    let mut value = json!({
+        "system": request.system,
+        "context": request.context,
+        "prompt": request.prompt,
+        "images": request
+            .images
+            .iter()
+            .map(|image| json!({
+                "media_type": image.media_type,
+                "data_hash": fnv1a(image.data_base64.as_bytes()),
+            }))
+            .collect::<Vec<_>>(),
+        "sampling": request.sampling,
+        "thinking": {
+            "budget_tokens": request.thinking.budget_tokens,
+            "effort": request.thinking.effort,
+        },
+        "tools": request
+            .tools
+            .iter()
+            .map(|tool| json!({
+                "name": tool.name,
+                "description": tool.description,
+                "parameters": tool.parameters,
+            }))
+            .collect::<Vec<_>>(),
+        "json_output": request.json_output,
+    });
+    // Only when set, so cassettes recorded before the field existed still match.
+    if let Some(schema) = &request.json_schema {
+        value["json_schema"] = schema.clone();
+    }
+    value
+}
+
+/// FNV-1a: stable across builds and Rust versions, unlike `DefaultHasher`.
+fn fnv1a(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+pub fn request_hash(request: &LlmRequest) -> String {
+    fnv1a(request_json(request).to_string().as_bytes())
+}
+
+/// Values of environment variables that look like credentials (`*_KEY`, `*_TOKEN`,
+/// `*SECRET*`, `*PASSWORD*`), longest first.
+fn env_secrets() -> Vec<String> {
+    let mut secrets: Vec<String> = std::env::vars()
+        .filter(|(name, value)| {
+            let name = name.to_ascii_uppercase();
+            value.len() >= 8
+                && (name.ends_with("KEY")
+                    || name.ends_with("TOKEN")
+                    || name.contains("SECRET")
+                    || name.contains("PASSWORD"))
+        })
+        .map(|(_, value)| value)
+        .collect();
+    secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+    secrets
+}
+
+fn redact(text: &str, secrets: &[String]) -> String {
+    secrets.iter().fold(text.to_string(), |text, secret| {
+        text.replace(secret, REDACTED)
+    })
+}
+
+#[derive(Debug, Clone)]
+struct CassetteDir {
+    dir: PathBuf,
+}
+
+impl CassetteDir {
+    fn path(&self, hash: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", hash))
+    }
+
+    fn read_manifest(&self) -> Result<Option<Manifest>> {
+        let path = self.dir.join(MANIFEST);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(&path)?;
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|err| anyhow!("{}: {}", path.display(), err))
+    }
+
+    fn register(&self, provider: &str, capabilities: Capabilities) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let mut manifest = self.read_manifest()?.unwrap_or_default();
+        manifest
+            .providers
+            .insert(provider.to_string(), capabilities);
+        std::fs::write(
+            self.dir.join(MANIFEST),
+            serde_json::to_string_pretty(&manifest)?,
+        )?;
+        Ok(())
+    }
+
+    fn load(&self, hash: &str) -> Result<Cassette> {
+        let path = self.path(hash);
+        let content = std::fs::read_to_string(&path).map_err(|_| {
+            anyhow!(
+                "no recorded response for this request: {} (record it with --record)",
+                path.display()
+            )
+        })?;
+        serde_json::from_str(&content).map_err(|err| anyhow!("{}: {}", path.display(), err))
+    }
+
+    fn save(&self, request: &LlmRequest, recorded: Recorded) -> Result<()> {
+        let hash = request_hash(request);
+        let path = self.path(&hash);
+        let mut started = STARTED
+            .lock()
+            .map_err(|_| anyhow!("cassette lock poisoned"))?;
+        let mut cassette = match started.contains(&path) {
+            true => self.load(&hash)?,
+            false => Cassette {
+                request: request_json(request),
+                responses: Vec::new(),
+            },
+        };
+        cassette.responses.push(recorded);
+        let content = redact(&serde_json::to_string_pretty(&cassette)?, &env_secrets());
+        std::fs::write(&path, content)?;
+        started.insert(path);
+        Ok(())
+    }
+}
+
+/// Passes calls through to `inner` and writes each successful answer to a cassette.
+/// Recording sits above HTTP, so API keys and headers never reach the files.
+pub struct RecordingBackend {
+    inner: Box<dyn LlmBackend + Send + Sync>,
+    cassettes: CassetteDir,
+}
+
+impl RecordingBackend {
+    /// `provider` is the configured name, which `--replay` looks up in the manifest.
+    pub fn new(
+        inner: Box<dyn LlmBackend + Send + Sync>,
+        provider: &str,
+        dir: &Path,
+    ) -> Result<Self> {
+        let cassettes = CassetteDir {
+            dir: dir.to_path_buf(),
+        };
+        cassettes
+            .register(
+                provider,
+                Capabilities {
+                    supports_tools: inner.supports_tools(),
+                    supports_prompt_cache: inner.supports_prompt_cache(),
+                    supports_json_schema: inner.supports_json_schema(),
+                    supports_roles: inner.supports_roles(),
+                },
+            )
+            .map_err(|err| anyhow!("cannot record into {}: {}", dir.display(), err))?;
+        Ok(Self { inner, cassettes })
+    }
+}
+
+#[async_trait::async_trait]
+impl LlmBackend for RecordingBackend {
+    fn provider(&self) -> LlmProvider {
+        self.inner.provider()
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.inner.supports_tools()
+    }
+
+    fn supports_prompt_cache(&self) -> bool {
+        self.inner.supports_prompt_cache()
+    }
+
+    fn supports_roles(&self) -> bool {
+        self.inner.supports_roles()
+    }
+
+    fn supports_json_schema(&self) -> bool {
+        self.inner.supports_json_schema()
+    }
+
+    fn lists_served_models(&self) -> bool {
+        self.inner.lists_served_models()
+    }
+
+    fn unsupported_sampling(&self, request: &LlmRequest) -> Vec<&'static str> {
+        self.inner.unsupported_sampling(request)
+    }
+
+    async fn count_tokens(&self, model: &str, request: &LlmRequest) -> Result<Option<u64>> {
+        self.inner.count_tokens(model, request).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>> {
+        self.inner.list_models().await
+    }
+
+    async fn generate(&self, model: &str, request: &LlmRequest) -> Result<LlmResponse> {
+        let response = self.inner.generate(model, request).await?;
+        self.cassettes
+            .save(request, Recorded::from_response(&response))?;
+        Ok(response)
+    }
+
+    /// Saves once the stream ends; a stream that fails midway is not recorded.
+    async fn generate_stream(&self, model: &str, request: &LlmRequest) -> Result<LlmStream> {
+        let stream = self.inner.generate_stream(model, request).await?;
+        let events = Arc::new(Mutex::new(Some(Vec::new())));
+        let seen = Arc::clone(&events);
+        let tapped = stream.inspect(move |event| {
+            if let Ok(mut guard) = seen.lock() {
+                match event {
+                    Ok(event) => {
+                        if let Some(events) = guard.as_mut() {
+                            events.push(RecordedEvent::from(event));
+                        }
+                    }
+                    Err(_) => *guard = None,
+                }
+            }
+        });
+        let cassettes = self.cassettes.clone();
+        let request = request.clone();
+        let finish = stream::once(async move {
+            let events = events.lock().ok().and_then(|mut guard| guard.take())?;
+            cassettes
+                .save(&request, Recorded::Stream { events })
+                .err()
+                .map(Err)
+        })
+        .filter_map(|result| async move { result });
+        Ok(Box::pin(tapped.chain(finish)))
+    }
+}
+
+/// Serves answers from cassettes, without a network. Identical requests get the recorded
+/// answers in order, then the last one again.
+pub struct ReplayBackend {
+    cassettes: CassetteDir,
+    capabilities: Capabilities,
+    served: Mutex<HashMap<String, usize>>,
+}
+
+impl ReplayBackend {
+    /// Stands in for `provider`; a manifest without that name uses its first entry.
+    pub fn new(dir: &Path, provider: &str) -> Result<Self> {
+        let cassettes = CassetteDir {
+            dir: dir.to_path_buf(),
+        };
+        let manifest = cassettes
+            .read_manifest()?
+            .ok_or_else(|| anyhow!("no cassettes in {}", dir.display()))?;
+        let capabilities = manifest
+            .providers
+            .get(provider)
+            .or_else(|| manifest.providers.values().next())
+            .copied()
+            .unwrap_or_default();
+        Ok(Self {
+            cassettes,
+            capabilities,
+            served: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn next(&self, request: &LlmRequest) -> Result<Recorded> {
+        let hash = request_hash(request);
+        let mut responses = self.cassettes.load(&hash)?.responses;
+        let index = self
+            .served
+            .lock()
+            .map(|mut served| {
+                let count = served.entry(hash.clone()).or_insert(0);
+                *count += 1;
+                *count - 1
+            })
+            .unwrap_or(0);
+        if responses.is_empty() {
+            return Err(anyhow!("cassette {} has no responses", hash));
+        }
+        Ok(responses.swap_remove(index.min(responses.len() - 1)))
+    }
+}
+
+#[async_trait::async_trait]
+impl LlmBackend for ReplayBackend {
+    fn provider(&self) -> LlmProvider {
+        LlmProvider::Replay
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.capabilities.supports_tools
+    }
+
+    fn supports_prompt_cache(&self) -> bool {
+        self.capabilities.supports_prompt_cache
+    }
+
+    /// Cassettes recorded before roles were a capability only had prompt-cache backends
+    /// sending them.
+    fn supports_roles(&self) -> bool {
+        self.capabilities.supports_roles || self.capabilities.supports_prompt_cache
+    }
+
+    fn supports_json_schema(&self) -> bool {
+        self.capabilities.supports_json_schema
+    }
+
+    async fn generate(&self, _model: &str, request: &LlmRequest) -> Result<LlmResponse> {
+        Ok(self.next(request)?.into_response())
+    }
+
+    async fn generate_stream(&self, _model: &str, request: &LlmRequest) -> Result<LlmStream> {
+        let events = self.next(request)?.into_events();
+        Ok(Box::pin(stream::iter(
+            events.into_iter().map(|event| Ok(event.into())),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::LlmClient;
+
+    struct ScriptBackend;
+
+    #[async_trait::async_trait]
+    impl LlmBackend for ScriptBackend {
+        fn provider(&self) -> LlmProvider {
+            LlmProvider::Anthropic
+        }
+
+        fn supports_tools(&self) -> bool {
+            true
+        }
+
+        async fn generate(&self, _model: &str, request: &LlmRequest) -> Result<LlmResponse> {
+            Ok(LlmResponse {
+                content: format!("plan for {}", request.prompt),
+                tool_calls: vec![LlmToolCall {
+                    name: "read_file".to_string(),
+                    arguments: json!({"path": "src/main.rs"}),
+                }],
+                ..Default::default()
+            })
+        }
+
+        async fn generate_stream(&self, _model: &str, request: &LlmRequest) -> Result<LlmStream> {
+            let usage = LlmUsage {
+                provider: "anthropic".to_string(),
+                input_tokens: Some(12),
+                output_tokens: Some(3),
+                ..Default::default()
+            };
+            Ok(Box::pin(stream::iter(vec![
+                Ok(LlmStreamEvent::Text("echo: ".to_string())),
+                Ok(LlmStreamEvent::Text(request.prompt.clone())),
+                Ok(LlmStreamEvent::Usage(usage)),
+            ])))
+        }
+    }
+
+    async fn collect(stream: LlmStream) -> (String, Option<LlmUsage>) {
+        let events: Vec<_> = stream.collect().await;
+        let mut text = String::new();
+        let mut usage = None;
+        for event in events {
+            match event.unwrap() {
+                LlmStreamEvent::Text(delta) => text.push_str(&delta),
+                LlmStreamEvent::Usage(u) => usage = Some(u),
+                LlmStreamEvent::Thinking { .. } => {}
+            }
+        }
+        (text, usage)
+    }
+
+    #[tokio::test]
+    async fn replays_recorded_answers_and_streams_without_secrets() {
+        let dir = std::env::temp_dir().join(format!("tengu-cassettes-{}", uuid::Uuid::new_v4()));
+        std::env::set_var("TENGU_TEST_REPLAY_TOKEN", "sk-test-1234567890");
+        let secret = LlmRequest::text("my key is sk-test-1234567890");
+        let plan = LlmRequest::text("fix the bug");
+
+        let backend = RecordingBackend::new(Box::new(ScriptBackend), "anthropic", &dir).unwrap();
+        let recorder = LlmClient::new(Box::new(backend));
+        recorder.generate("claude", &plan).await.unwrap();
+        let (text, _) = collect(recorder.generate_stream("claude", &secret).await.unwrap()).await;
+        assert_eq!(text, "echo: my key is sk-test-1234567890");
+        let cassette =
+            std::fs::read_to_string(dir.join(format!("{}.json", request_hash(&secret)))).unwrap();
+        assert!(!cassette.contains("sk-test-1234567890"));
+        assert!(cassette.contains("[REDACTED]"));
+
+        let replay = LlmClient::new(Box::new(ReplayBackend::new(&dir, "anthropic").unwrap()));
+        assert!(replay.supports_tools());
+        let response = replay.generate("other-model", &plan).await.unwrap();
+        assert_eq!(response.content, "plan for fix the bug");
+        assert_eq!(response.tool_calls[0].arguments["path"], "src/main.rs");
+        let (text, usage) = collect(replay.generate_stream("claude", &secret).await.unwrap()).await;
+        assert_eq!(text, "echo: my key is [REDACTED]");
+        let usage = usage.unwrap();
+        assert_eq!(usage.provider, "anthropic");
+        assert_eq!(usage.input_tokens, Some(12));
+        assert_eq!(usage.model.as_deref(), Some("claude"));
+
+        let (text, _) = collect(replay.generate_stream("claude", &plan).await.unwrap()).await;
+        assert_eq!(text, "plan for fix the bug");
+        let err = replay
+            .generate("claude", &LlmRequest::text("unrecorded"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("no recorded response"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
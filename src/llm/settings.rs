@@ -0,0 +1,326 @@
+use anyhow::{anyhow, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::llm::{AwsCredentials, CredentialRef, CredentialStore};
+
+/// Connection settings for one backend, resolved from `[providers.<name>]`.
+#[derive(Debug, Clone, Default)]
+pub struct ProviderSettings {
+    pub base_url: Option<String>,
+    pub api_key_env: Option<String>,
+    pub api_key_command: Option<String>,
+    /// How long an `api_key_command` result is reused; `None` keeps it for the session.
+    pub api_key_command_ttl: Option<Duration>,
+    /// Key saved by `tengu auth login`, used when no env var or command supplies one.
+    pub credentials: Option<CredentialRef>,
+    pub headers: Vec<(String, String)>,
+    pub read_timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    pub proxy: Option<String>,
+    pub max_tokens: Option<u32>,
+    /// `false` for OpenAI-compatible servers that reject `stream_options`.
+    pub stream_options: Option<bool>,
+    /// Ollama only: how long the model stays loaded, and native `options` (num_ctx, seed, ...).
+    pub keep_alive: Option<String>,
+    pub options: serde_json::Map<String, serde_json::Value>,
+    /// Anthropic only: cache breakpoints on or off, and their lifetime ("5m" or "1h").
+    pub prompt_cache: Option<bool>,
+    pub prompt_cache_ttl: Option<String>,
+    /// Replay only: the cassette directory.
+    pub cassettes: Option<std::path::PathBuf>,
+    /// Mock only: the script file.
+    pub script: Option<std::path::PathBuf>,
+    /// Azure only: the `api-version` query parameter, and a command printing an Entra ID
+    /// token that is sent instead of the `api-key` header.
+    pub api_version: Option<String>,
+    pub token_command: Option<String>,
+    /// Bedrock only: the AWS region, and keys that replace the AWS env credentials.
+    pub region: Option<String>,
+    pub aws_credentials: Option<AwsCredentials>,
+    api_key_cache: Arc<Mutex<Option<(String, Instant)>>>,
+    token_cache: Arc<Mutex<Option<(String, Instant)>>>,
+}
+
+/// Secret helpers that prompt or hang (an expired SSO session, say) must not stall requests.
+const SECRET_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Entra ID tokens live for an hour or more; refresh well before that.
+const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(45 * 60);
+
+/// Which setting supplied an API key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiKeySource {
+    Env(String),
+    Command,
+    Credentials { profile: String },
+}
+
+impl std::fmt::Display for ApiKeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Env(name) => write!(f, "env {}", name),
+            Self::Command => write!(f, "api_key_command"),
+            Self::Credentials { profile } => write!(f, "credentials (profile {})", profile),
+        }
+    }
+}
+
+impl ProviderSettings {
+    /// Builds the client shared by every request of a backend.
+    pub fn http_client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(timeout) = self.read_timeout {
+            // A read timeout, not a total one: streamed answers may take minutes.
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(proxy) = self.proxy.as_deref().filter(|p| !p.trim().is_empty()) {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        if !self.headers.is_empty() {
+            let mut headers = HeaderMap::new();
+            for (key, value) in &self.headers {
+                headers.insert(
+                    HeaderName::from_bytes(key.as_bytes())?,
+                    HeaderValue::from_str(value)?,
+                );
+            }
+            builder = builder.default_headers(headers);
+        }
+        Ok(builder.build()?)
+    }
+
+    pub async fn api_key(&self, default_env: &str) -> Result<String> {
+        self.resolve_api_key(default_env).await.map(|(key, _)| key)
+    }
+
+    /// The env var (`api_key_env`, else `default_env`, the provider's usual variable) wins
+    /// over `api_key_command`, which wins over a key saved by `tengu auth login`.
+    pub async fn resolve_api_key(&self, default_env: &str) -> Result<(String, ApiKeySource)> {
+        let env = self.api_key_env.as_deref().unwrap_or(default_env);
+        if let Some(key) = std::env::var(env).ok().filter(|v| !v.trim().is_empty()) {
+            return Ok((key, ApiKeySource::Env(env.to_string())));
+        }
+        if let Some(command) = self.api_key_command.as_deref() {
+            return Ok((self.command_api_key(command).await?, ApiKeySource::Command));
+        }
+        if let Some(key) = self.stored_api_key()? {
+            let profile = self
+                .credentials
+                .as_ref()
+                .map(|credentials| credentials.profile.clone())
+                .unwrap_or_default();
+            return Ok((key, ApiKeySource::Credentials { profile }));
+        }
+        Err(anyhow!("{} is not set (or run `tengu auth login`)", env))
+    }
+
+    /// Whether a key was configured at all, for servers that work without one.
+    pub fn has_api_key_source(&self) -> bool {
+        self.api_key_env
+            .as_deref()
+            .and_then(|env| std::env::var(env).ok())
+            .is_some_and(|key| !key.trim().is_empty())
+            || self.api_key_command.is_some()
+            || self.stored_api_key().ok().flatten().is_some()
+    }
+
+    /// The output of `token_command`, reused for `api_key_command_ttl` (45 minutes by default).
+    pub async fn bearer_token(&self) -> Result<Option<String>> {
+        let Some(command) = self.token_command.as_deref() else {
+            return Ok(None);
+        };
+        let ttl = self.api_key_command_ttl.unwrap_or(DEFAULT_TOKEN_TTL);
+        cached_command(&self.token_cache, "token_command", command, Some(ttl))
+            .await
+            .map(Some)
+    }
+
+    async fn command_api_key(&self, command: &str) -> Result<String> {
+        cached_command(
+            &self.api_key_cache,
+            "api_key_command",
+            command,
+            self.api_key_command_ttl,
+        )
+        .await
+    }
+
+    fn stored_api_key(&self) -> Result<Option<String>> {
+        let Some(credentials) = self.credentials.as_ref() else {
+            return Ok(None);
+        };
+        let store = CredentialStore::new(credentials.path.clone());
+        Ok(store
+            .get(&credentials.provider, &credentials.profile)?
+            .map(|stored| stored.api_key))
+    }
+}
+
+async fn cached_command(
+    cache: &Mutex<Option<(String, Instant)>>,
+    label: &str,
+    command: &str,
+    ttl: Option<Duration>,
+) -> Result<String> {
+    let cached = cache.lock().ok().and_then(|cache| {
+        cache
+            .clone()
+            .filter(|(_, at)| ttl.is_none_or(|ttl| at.elapsed() < ttl))
+    });
+    if let Some((value, _)) = cached {
+        return Ok(value);
+    }
+    let value = run_secret_command(label, command, SECRET_COMMAND_TIMEOUT).await?;
+    if let Ok(mut cache) = cache.lock() {
+        *cache = Some((value.clone(), Instant::now()));
+    }
+    Ok(value)
+}
+
+async fn run_secret_command(label: &str, command: &str, timeout: Duration) -> Result<String> {
+    let output = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(timeout, output)
+        .await
+        .map_err(|_| anyhow!("{} timed out after {:?}", label, timeout))??;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("{} failed: {}", label, stderr.trim()));
+    }
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if value.is_empty() {
+        return Err(anyhow!("{} printed nothing", label));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolves_api_key_from_env_then_command_then_store() {
+        let settings = ProviderSettings {
+            api_key_env: Some("TENGU_TEST_UNSET_KEY".to_string()),
+            api_key_command: Some("printf ' sk-from-command\\n'".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(settings.api_key("UNUSED").await.unwrap(), "sk-from-command");
+        assert_eq!(
+            settings.resolve_api_key("UNUSED").await.unwrap().1,
+            ApiKeySource::Command
+        );
+
+        let settings = ProviderSettings {
+            api_key_env: Some("TENGU_TEST_UNSET_KEY".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.api_key("UNUSED").await.unwrap_err().to_string(),
+            "TENGU_TEST_UNSET_KEY is not set (or run `tengu auth login`)"
+        );
+
+        assert!(!settings.has_api_key_source());
+        std::env::set_var("TENGU_TEST_EMPTY_KEY", " ");
+        let empty = ProviderSettings {
+            api_key_env: Some("TENGU_TEST_EMPTY_KEY".to_string()),
+            ..Default::default()
+        };
+        assert!(!empty.has_api_key_source());
+
+        let root = std::env::temp_dir().join(format!("tengu-keys-{}", uuid::Uuid::new_v4()));
+        let path = root.join("credentials.toml");
+        CredentialStore::new(path.clone())
+            .set("gateway", "work", "sk-stored")
+            .unwrap();
+        let settings = ProviderSettings {
+            api_key_env: Some("TENGU_TEST_UNSET_KEY".to_string()),
+            credentials: Some(CredentialRef {
+                path,
+                provider: "gateway".to_string(),
+                profile: "work".to_string(),
+            }),
+            ..Default::default()
+        };
+        assert!(settings.has_api_key_source());
+        assert_eq!(
+            settings.resolve_api_key("UNUSED").await.unwrap(),
+            (
+                "sk-stored".to_string(),
+                ApiKeySource::Credentials {
+                    profile: "work".to_string()
+                }
+            )
+        );
+        std::env::set_var("TENGU_TEST_SET_KEY", "sk-from-env");
+        let settings = ProviderSettings {
+            api_key_env: Some("TENGU_TEST_SET_KEY".to_string()),
+            ..settings
+        };
+        assert_eq!(
+            settings.resolve_api_key("UNUSED").await.unwrap().1,
+            ApiKeySource::Env("TENGU_TEST_SET_KEY".to_string())
+        );
+        assert!(ProviderSettings {
+            api_key_env: Some("TENGU_TEST_SET_KEY".to_string()),
+            ..Default::default()
+        }
+        .has_api_key_source());
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn reruns_api_key_command_after_its_ttl() {
+        let counter = std::env::temp_dir().join(format!("tengu-ttl-{}", uuid::Uuid::new_v4()));
+        let command = format!(
+            "echo x >> {0}; printf 'key-%s' $(wc -l < {0})",
+            counter.display()
+        );
+        let cached = ProviderSettings {
+            api_key_command: Some(command.clone()),
+            ..Default::default()
+        };
+        assert_eq!(cached.api_key("UNUSED_TTL_KEY").await.unwrap(), "key-1");
+        assert_eq!(cached.api_key("UNUSED_TTL_KEY").await.unwrap(), "key-1");
+        let expiring = ProviderSettings {
+            api_key_command: Some(command),
+            api_key_command_ttl: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        assert_eq!(expiring.api_key("UNUSED_TTL_KEY").await.unwrap(), "key-2");
+        assert_eq!(expiring.api_key("UNUSED_TTL_KEY").await.unwrap(), "key-3");
+        std::fs::remove_file(&counter).ok();
+    }
+
+    #[tokio::test]
+    async fn times_out_hanging_secret_commands() {
+        let err = run_secret_command("token_command", "sleep 5", Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "token_command timed out after 100ms");
+    }
+
+    #[test]
+    fn rejects_invalid_proxy_and_headers() {
+        let settings = ProviderSettings {
+            headers: vec![("bad header".to_string(), "x".to_string())],
+            ..Default::default()
+        };
+        assert!(settings.http_client().is_err());
+        let settings = ProviderSettings {
+            proxy: Some("http://proxy.internal:3128".to_string()),
+            headers: vec![("x-team".to_string(), "platform".to_string())],
+            ..Default::default()
+        };
+        assert!(settings.http_client().is_ok());
+    }
+}
@@ -0,0 +1,191 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use ring::{digest, hmac};
+
+/// Keys from the standard AWS env vars.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl AwsCredentials {
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let access_key_id =
+            var("AWS_ACCESS_KEY_ID").ok_or_else(|| anyhow!("AWS_ACCESS_KEY_ID is not set"))?;
+        let secret_access_key = var("AWS_SECRET_ACCESS_KEY")
+            .ok_or_else(|| anyhow!("AWS_SECRET_ACCESS_KEY is not set"))?;
+        Ok(Self {
+            access_key_id,
+            secret_access_key,
+            session_token: var("AWS_SESSION_TOKEN"),
+        })
+    }
+}
+
+/// `AWS_REGION`, then `AWS_DEFAULT_REGION`.
+pub fn aws_region_from_env() -> Option<String> {
+    ["AWS_REGION", "AWS_DEFAULT_REGION"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok())
+        .filter(|region| !region.trim().is_empty())
+}
+
+/// Percent-encodes everything but RFC 3986 unreserved characters, as SigV4 expects.
+pub fn aws_uri_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// The headers that sign a request with AWS Signature Version 4: `x-amz-date`,
+/// `x-amz-content-sha256`, `x-amz-security-token` for session credentials, and
+/// `authorization`.
+pub fn sign_request(
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    method: &str,
+    url: &reqwest::Url,
+    body: &[u8],
+    now: DateTime<Utc>,
+) -> Vec<(String, String)> {
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let payload_hash = hex(digest::digest(&digest::SHA256, body).as_ref());
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+
+    let mut headers = vec![
+        ("host".to_string(), host),
+        ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+        ("x-amz-date".to_string(), amz_date.clone()),
+    ];
+    if let Some(token) = credentials.session_token.as_deref() {
+        headers.push(("x-amz-security-token".to_string(), token.to_string()));
+    }
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    // Paths are sent encoded once and signed encoded twice (every service but S3).
+    let canonical_uri = url
+        .path()
+        .split('/')
+        .map(aws_uri_encode)
+        .collect::<Vec<_>>()
+        .join("/");
+    let mut query = url
+        .query_pairs()
+        .map(|(key, value)| (aws_uri_encode(&key), aws_uri_encode(&value)))
+        .collect::<Vec<_>>();
+    query.sort();
+    let canonical_query = query
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method, canonical_uri, canonical_query, canonical_headers, signed_headers, payload_hash
+    );
+
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+    );
+    let mut key = format!("AWS4{}", credentials.secret_access_key).into_bytes();
+    for part in [date.as_str(), region, service, "aws4_request"] {
+        key = hmac_sha256(&key, part.as_bytes());
+    }
+    let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+    headers.remove(0);
+    headers.push((
+        "authorization".to_string(),
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key_id, scope, signed_headers, signature
+        ),
+    ));
+    headers
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, data).as_ref().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn signs_requests_with_double_encoded_paths_and_session_tokens() {
+        let mut credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        let now = Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).unwrap();
+        let url = reqwest::Url::parse("https://example.amazonaws.com/?Param2=value2&Param1=value1")
+            .unwrap();
+        let headers = sign_request(&credentials, "us-east-1", "service", "GET", &url, b"", now);
+        assert_eq!(headers[1].1, "20150830T123600Z");
+        assert_eq!(
+            headers.last().unwrap().1,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-content-sha256;x-amz-date, \
+             Signature=311c7f58b10b06de8540bb5a27f441ee0609f1d5ad7b191e68d7ea87d90e3d6b"
+        );
+
+        credentials.session_token = Some("session-token".to_string());
+        let model = aws_uri_encode("anthropic.claude-3-5-sonnet-20240620-v1:0");
+        assert_eq!(model, "anthropic.claude-3-5-sonnet-20240620-v1%3A0");
+        let url = reqwest::Url::parse(&format!("http://127.0.0.1:8080/model/{}/converse", model))
+            .unwrap();
+        let headers = sign_request(
+            &credentials,
+            "us-west-2",
+            "bedrock",
+            "POST",
+            &url,
+            br#"{"messages":[]}"#,
+            now,
+        );
+        assert_eq!(
+            headers[2],
+            (
+                "x-amz-security-token".to_string(),
+                "session-token".to_string()
+            )
+        );
+        assert!(headers.last().unwrap().1.ends_with(
+            "SignedHeaders=host;x-amz-content-sha256;x-amz-date;x-amz-security-token, \
+             Signature=d3e427ecaac00d7506540abf3f114bcbc8817eec6e706908877f1ad301593776"
+        ));
+    }
+}
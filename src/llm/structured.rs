@@ -0,0 +1,147 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::path::Path;
+
+use crate::mcp::validate_json_schema;
+
+/// Reads a JSON Schema file for `--json-schema`.
+pub fn load_json_schema(path: &Path) -> Result<Value> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|err| anyhow!("cannot read json schema {}: {}", path.display(), err))?;
+    let schema: Value = serde_json::from_str(&content)
+        .map_err(|err| anyhow!("invalid json schema {}: {}", path.display(), err))?;
+    if !schema.is_object() {
+        return Err(anyhow!("json schema {} must be an object", path.display()));
+    }
+    Ok(schema)
+}
+
+/// The JSON value of an answer, tolerating code fences and text around it, checked against
+/// `schema`. The error is the reason sent back to the model.
+pub fn parse_structured(content: &str, schema: &Value) -> Result<Value, String> {
+    let value = extract_json(content)?;
+    validate_json_schema(schema, &value)?;
+    Ok(value)
+}
+
+fn extract_json(content: &str) -> Result<Value, String> {
+    let trimmed = content.trim();
+    let body = match trimmed.strip_prefix("```") {
+        Some(fenced) => {
+            let body = fenced.split_once('\n').map_or(fenced, |(_, rest)| rest);
+            body.rsplit_once("```")
+                .map_or(body, |(inner, _)| inner)
+                .trim()
+        }
+        None => trimmed,
+    };
+    if let Ok(value) = serde_json::from_str(body) {
+        return Ok(value);
+    }
+    let start = body.find(['{', '[']).ok_or("the answer contains no JSON")?;
+    let end = body.rfind(['}', ']']).filter(|end| *end > start);
+    end.and_then(|end| serde_json::from_str(&body[start..=end]).ok())
+        .ok_or_else(|| "the answer is not valid JSON".to_string())
+}
+
+/// The answer still did not match `LlmRequest::json_schema` after the retries.
+#[derive(Debug, Clone)]
+pub struct SchemaMismatch {
+    pub attempts: usize,
+    pub reason: String,
+}
+
+impl std::fmt::Display for SchemaMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "answer does not match the json schema after {} attempts: {}",
+            self.attempts, self.reason
+        )
+    }
+}
+
+impl std::error::Error for SchemaMismatch {}
+
+/// Appended to the prompt for backends that cannot constrain their output.
+pub fn schema_instruction(schema: &Value) -> String {
+    format!(
+        "次のJSONスキーマに合うJSONだけを出力してください。説明やコードブロックは不要です。\n{}",
+        schema
+    )
+}
+
+pub fn schema_retry_prompt(prompt: &str, reason: &str) -> String {
+    format!(
+        "{}\n\n前回の回答はJSONスキーマに合いませんでした ({})。スキーマに合うJSONだけを出力してください。",
+        prompt, reason
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{LlmClient, LlmRequest, MockBackend};
+    use serde_json::json;
+
+    #[test]
+    fn extracts_and_validates_json_answers() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"ok": {"type": "boolean"}},
+            "required": ["ok"]
+        });
+        assert_eq!(
+            parse_structured("```json\n{\"ok\": true}\n```", &schema),
+            Ok(json!({"ok": true}))
+        );
+        assert_eq!(
+            parse_structured("Here you go: {\"ok\": false}.", &schema),
+            Ok(json!({"ok": false}))
+        );
+        assert_eq!(
+            parse_structured("{\"ok\": \"yes\"}", &schema),
+            Err("$.ok: expected boolean, got string".to_string())
+        );
+        assert_eq!(
+            parse_structured("no idea", &schema),
+            Err("the answer contains no JSON".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn asks_again_until_the_answer_matches_the_schema() {
+        let path = std::env::temp_dir().join(format!("tengu-schema-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            json!({
+                "turns": [
+                    {"expect": "JSONスキーマ", "text": "{\"ok\": \"yes\"}"},
+                    {"expect": "expected boolean", "text": "```json\n{\"ok\": true}\n```"},
+                    {"text": "sure"},
+                    {"text": "still no"},
+                    {"text": "no"},
+                ]
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let client = LlmClient::new(Box::new(MockBackend::new(&path).unwrap()));
+        let request = LlmRequest {
+            json_schema: Some(json!({
+                "type": "object",
+                "properties": {"ok": {"type": "boolean"}},
+                "required": ["ok"]
+            })),
+            ..LlmRequest::text("is it ok?")
+        };
+        let response = client.generate("m", &request).await.unwrap();
+        assert_eq!(response.content, r#"{"ok":true}"#);
+        let err = client.generate("m", &request).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "answer does not match the json schema after 3 attempts: the answer contains no JSON"
+        );
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,130 @@
+use std::borrow::Cow;
+
+use crate::llm::LlmRequest;
+
+/// Roughly what one image costs across providers (Anthropic bills ~1.6k for a 1092px square).
+const IMAGE_TOKENS: u64 = 1600;
+/// Role markers and message framing.
+const REQUEST_OVERHEAD: u64 = 16;
+
+/// Token count without a tokenizer: about four bytes per token for ASCII text, one per
+/// character for other scripts (Japanese and Chinese are close to that).
+pub fn estimate_tokens(text: &str) -> u64 {
+    let (ascii, other) = text.chars().fold((0u64, 0u64), |(ascii, other), c| {
+        if c.is_ascii() {
+            (ascii + 1, other)
+        } else {
+            (ascii, other + 1)
+        }
+    });
+    ascii.div_ceil(4) + other
+}
+
+/// Everything a backend sends as input: system prompt, context, prompt, tool definitions
+/// and images.
+pub fn estimate_request_tokens(request: &LlmRequest) -> u64 {
+    let text: u64 = [
+        request.system.as_deref(),
+        request.context.as_deref(),
+        Some(request.prompt.as_str()),
+    ]
+    .into_iter()
+    .flatten()
+    .map(estimate_tokens)
+    .sum();
+    let tools: u64 = request
+        .tools
+        .iter()
+        .map(|tool| {
+            estimate_tokens(&tool.name)
+                + estimate_tokens(&tool.description)
+                + estimate_tokens(&tool.parameters.to_string())
+        })
+        .sum();
+    text + tools + request.images.len() as u64 * IMAGE_TOKENS + REQUEST_OVERHEAD
+}
+
+/// Cuts `text` to about `max_tokens`, keeping the first two thirds and the last third of
+/// the budget and marking what was left out.
+pub fn truncate_to_tokens(text: &str, max_tokens: u64) -> Cow<'_, str> {
+    let total = estimate_tokens(text);
+    if total <= max_tokens {
+        return Cow::Borrowed(text);
+    }
+    let chars: Vec<char> = text.chars().collect();
+    let keep = (chars.len() as u64 * max_tokens / total) as usize;
+    let head = keep * 2 / 3;
+    let tail = keep - head;
+    let head_text: String = chars[..head].iter().collect();
+    let tail_text: String = chars[chars.len() - tail..].iter().collect();
+    let omitted = total.saturating_sub(estimate_tokens(&head_text) + estimate_tokens(&tail_text));
+    Cow::Owned(format!(
+        "{}\n[... about {} tokens omitted ...]\n{}",
+        head_text, omitted, tail_text
+    ))
+}
+
+/// Drops whole lines from the start of `text` until it fits in about `max_tokens`.
+pub fn keep_last_tokens(text: &str, max_tokens: u64) -> Cow<'_, str> {
+    if estimate_tokens(text) <= max_tokens {
+        return Cow::Borrowed(text);
+    }
+    let mut used = 0;
+    let mut start = text.len();
+    for (index, line) in text.rsplit('\n').enumerate() {
+        used += estimate_tokens(line) + 1;
+        if used > max_tokens {
+            break;
+        }
+        start -= line.len() + usize::from(index > 0);
+    }
+    Cow::Owned(text[start..].trim_start_matches('\n').to_string())
+}
+
+/// "950", "12k", "1M".
+pub fn format_tokens(count: u64) -> String {
+    if count >= 1_000_000 {
+        format!("{}M", count / 1_000_000)
+    } else if count >= 1_000 {
+        format!("{}k", count / 1_000)
+    } else {
+        count.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_ascii_and_cjk_text() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("fn main() {}"), 3);
+        assert_eq!(estimate_tokens("過去の会話"), 5);
+        let request = LlmRequest {
+            system: Some("x".repeat(40)),
+            ..LlmRequest::text("y".repeat(40))
+        };
+        assert_eq!(estimate_request_tokens(&request), 20 + REQUEST_OVERHEAD);
+    }
+
+    #[test]
+    fn truncates_the_middle_and_drops_old_lines() {
+        let text = (0..200)
+            .map(|i| format!("line {:03}", i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let cut = truncate_to_tokens(&text, 100);
+        assert!(cut.starts_with("line 000"));
+        assert!(cut.ends_with("line 199"));
+        assert!(cut.contains("tokens omitted"));
+        assert!(estimate_tokens(&cut) <= 115);
+        assert_eq!(truncate_to_tokens("short", 100), "short");
+
+        let kept = keep_last_tokens(&text, 30);
+        assert!(kept.starts_with("line 1"));
+        assert!(kept.ends_with("line 199"));
+        assert!(estimate_tokens(&kept) <= 30);
+        assert_eq!(keep_last_tokens("a\nb", 100), "a\nb");
+    }
+}
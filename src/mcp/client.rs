@@ -0,0 +1,985 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::llm::LlmImage;
+use crate::mcp::{
+    validate_json_schema, HttpTransport, McpContent, McpHttpError, McpRequestHandler,
+    McpServerConfig, McpServerRequest, McpTool, McpTransportKind, SseTransport, StdioTransport,
+    ToolsListResult,
+};
+
+pub const PROTOCOL_VERSION: &str = "2025-11-25";
+
+/// Versions Tengu can speak, newest first. Servers answer `initialize` with the version
+/// they picked; anything outside this list aborts the handshake.
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[&str] =
+    &["2025-11-25", "2025-06-18", "2025-03-26", "2024-11-05"];
+
+/// Retried once when a server rejects the latest version outright instead of
+/// answering with its own.
+const FALLBACK_PROTOCOL_VERSION: &str = "2025-03-26";
+
+const DEFAULT_INIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sink for messages a transport receives from the server.
+pub type McpInbound = mpsc::UnboundedSender<Value>;
+
+pub type McpNotificationHandler = Arc<dyn Fn(&str, &McpNotification) + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub struct McpHandlers {
+    pub notification: Option<McpNotificationHandler>,
+    pub request: Option<McpRequestHandler>,
+}
+
+#[async_trait]
+pub trait McpTransport: Send + Sync {
+    async fn send(&self, message: Value) -> Result<()>;
+
+    /// Opens a channel for server-initiated messages once the session is initialized.
+    async fn listen(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_protocol_version(&self, _version: &str) {}
+}
+
+/// A JSON-RPC error object returned by the server.
+#[derive(Debug)]
+pub struct McpRpcError(pub Value);
+
+impl std::fmt::Display for McpRpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mcp error: {}", self.0)
+    }
+}
+
+impl std::error::Error for McpRpcError {}
+
+/// What a server reported during `initialize`.
+#[derive(Debug, Clone)]
+pub struct McpServerInfo {
+    pub protocol_version: String,
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub capabilities: Value,
+    pub instructions: Option<String>,
+    pub handshake: Duration,
+}
+
+impl McpServerInfo {
+    pub fn from_init_result(result: &Value, handshake: Duration) -> Result<Self> {
+        let protocol_version = result
+            .get("protocolVersion")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("mcp initialize result has no protocolVersion"))?
+            .to_string();
+        if !SUPPORTED_PROTOCOL_VERSIONS.contains(&protocol_version.as_str()) {
+            return Err(anyhow!(
+                "unsupported mcp protocol version: {} (supported: {})",
+                protocol_version,
+                SUPPORTED_PROTOCOL_VERSIONS.join(", ")
+            ));
+        }
+        let server_info = result.get("serverInfo");
+        let field = |key: &str| {
+            server_info
+                .and_then(|info| info.get(key))
+                .and_then(Value::as_str)
+                .map(|s| s.to_string())
+        };
+        Ok(Self {
+            protocol_version,
+            name: field("name"),
+            version: field("version"),
+            capabilities: result.get("capabilities").cloned().unwrap_or(json!({})),
+            instructions: result
+                .get("instructions")
+                .and_then(Value::as_str)
+                .map(|s| s.to_string()),
+            handshake,
+        })
+    }
+
+    pub fn capability_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .capabilities
+            .as_object()
+            .map(|caps| caps.keys().cloned().collect())
+            .unwrap_or_default();
+        names.sort();
+        names
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum McpNotification {
+    ToolsListChanged,
+    Progress {
+        token: Value,
+        progress: f64,
+        total: Option<f64>,
+        message: Option<String>,
+    },
+    Message {
+        level: String,
+        logger: Option<String>,
+        data: Value,
+    },
+    Other {
+        method: String,
+        params: Value,
+    },
+}
+
+impl McpNotification {
+    pub fn parse(method: &str, params: Value) -> Self {
+        match method {
+            "notifications/tools/list_changed" => Self::ToolsListChanged,
+            "notifications/progress" => Self::Progress {
+                token: params.get("progressToken").cloned().unwrap_or(Value::Null),
+                progress: params
+                    .get("progress")
+                    .and_then(Value::as_f64)
+                    .unwrap_or(0.0),
+                total: params.get("total").and_then(Value::as_f64),
+                message: params
+                    .get("message")
+                    .and_then(Value::as_str)
+                    .map(|s| s.to_string()),
+            },
+            "notifications/message" => Self::Message {
+                level: params
+                    .get("level")
+                    .and_then(Value::as_str)
+                    .unwrap_or("info")
+                    .to_string(),
+                logger: params
+                    .get("logger")
+                    .and_then(Value::as_str)
+                    .map(|s| s.to_string()),
+                data: params.get("data").cloned().unwrap_or(Value::Null),
+            },
+            _ => Self::Other {
+                method: method.to_string(),
+                params,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CallToolResult {
+    pub content: Vec<McpContent>,
+    pub structured_content: Option<Value>,
+    pub is_error: bool,
+}
+
+impl CallToolResult {
+    pub fn from_value(value: Value) -> Self {
+        let content = value
+            .get("content")
+            .and_then(Value::as_array)
+            .map(|items| {
+                items
+                    .iter()
+                    .map(|item| serde_json::from_value(item.clone()).unwrap_or(McpContent::Unknown))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            content,
+            structured_content: value.get("structuredContent").cloned(),
+            is_error: value
+                .get("isError")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+        }
+    }
+
+    /// Checks `structuredContent` against the tool's `outputSchema`, if it declares one.
+    pub fn validate(&self, output_schema: Option<&Value>) -> Result<()> {
+        let Some(schema) = output_schema else {
+            return Ok(());
+        };
+        if self.is_error {
+            return Ok(());
+        }
+        let structured = self.structured_content.as_ref().ok_or_else(|| {
+            anyhow!("tool declares outputSchema but returned no structuredContent")
+        })?;
+        validate_json_schema(schema, structured)
+            .map_err(|reason| anyhow!("structuredContent does not match outputSchema: {}", reason))
+    }
+
+    /// Renders every content block as text for the follow-up prompt; images are
+    /// returned separately by [`CallToolResult::images`].
+    pub fn text(&self) -> String {
+        let mut parts = Vec::new();
+        for item in &self.content {
+            match item {
+                McpContent::Text { text } => parts.push(text.clone()),
+                McpContent::Image { mime_type, .. } => {
+                    parts.push(format!("[image: {}]", mime_type));
+                }
+                McpContent::Audio { mime_type } => parts.push(format!("[audio: {}]", mime_type)),
+                McpContent::Resource { resource } => match (&resource.text, &resource.blob) {
+                    (Some(text), _) => parts.push(format!("resource {}:\n{}", resource.uri, text)),
+                    (None, Some(blob)) => parts.push(format!(
+                        "[resource {}: {} ({} bytes base64)]",
+                        resource.uri,
+                        resource.mime_type.as_deref().unwrap_or("binary"),
+                        blob.len()
+                    )),
+                    (None, None) => parts.push(format!("[resource {}]", resource.uri)),
+                },
+                McpContent::ResourceLink {
+                    uri,
+                    name,
+                    description,
+                    ..
+                } => {
+                    let mut line = format!("resource link: {}", uri);
+                    if let Some(name) = name {
+                        line.push_str(&format!(" ({})", name));
+                    }
+                    if let Some(description) = description {
+                        line.push_str(&format!(" — {}", description));
+                    }
+                    parts.push(line);
+                }
+                McpContent::Unknown => {}
+            }
+        }
+        if let Some(structured) = &self.structured_content {
+            let rendered =
+                serde_json::to_string_pretty(structured).unwrap_or_else(|_| structured.to_string());
+            // Servers usually mirror structured output in a text block; avoid repeating it.
+            if !parts
+                .iter()
+                .any(|part| serde_json::from_str::<Value>(part).ok().as_ref() == Some(structured))
+            {
+                parts.push(format!("structured content:\n{}", rendered));
+            }
+        }
+        parts.join("\n")
+    }
+
+    pub fn images(&self) -> Vec<LlmImage> {
+        self.content
+            .iter()
+            .filter_map(|item| match item {
+                McpContent::Image { data, mime_type } => Some(LlmImage {
+                    media_type: mime_type.clone(),
+                    data_base64: data.clone(),
+                }),
+                McpContent::Resource { resource } => {
+                    let mime = resource.mime_type.as_deref()?;
+                    let blob = resource.blob.as_ref()?;
+                    mime.starts_with("image/").then(|| LlmImage {
+                        media_type: mime.to_string(),
+                        data_base64: blob.clone(),
+                    })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+type PendingMap = Mutex<HashMap<u64, oneshot::Sender<Result<Value>>>>;
+
+struct ClientShared {
+    server: String,
+    pending: PendingMap,
+    handlers: McpHandlers,
+}
+
+impl ClientShared {
+    fn dispatch(&self, message: Value, transport: &Arc<dyn McpTransport>) {
+        if let Value::Array(items) = message {
+            for item in items {
+                self.dispatch(item, transport);
+            }
+            return;
+        }
+        let method = message.get("method").and_then(Value::as_str);
+        let id = message.get("id").cloned();
+        match (method, id) {
+            (Some(method), Some(id)) => {
+                let params = message.get("params").cloned().unwrap_or(Value::Null);
+                let server = self.server.clone();
+                let handler = self.handlers.request.clone();
+                let request = McpServerRequest::parse(method, &params);
+                let method = method.to_string();
+                let transport = Arc::clone(transport);
+                tokio::spawn(async move {
+                    let response = match (method.as_str(), request, handler) {
+                        ("ping", _, _) => json!({ "jsonrpc": "2.0", "id": id, "result": {} }),
+                        (_, Ok(Some(request)), Some(handler)) => match handler(server, request)
+                            .await
+                        {
+                            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                            Err(err) => json_rpc_error(id, -1, &err.to_string()),
+                        },
+                        (_, Err(err), Some(_)) => json_rpc_error(id, -32602, &err.to_string()),
+                        _ => json_rpc_error(id, -32601, &format!("method not found: {}", method)),
+                    };
+                    let _ = transport.send(response).await;
+                });
+            }
+            (Some(method), None) => {
+                let params = message.get("params").cloned().unwrap_or(Value::Null);
+                let notification = McpNotification::parse(method, params);
+                if let Some(handler) = &self.handlers.notification {
+                    handler(&self.server, &notification);
+                }
+            }
+            (None, Some(id)) => {
+                let Some(id) = id.as_u64() else {
+                    return;
+                };
+                let sender = self.pending.lock().ok().and_then(|mut p| p.remove(&id));
+                let Some(sender) = sender else {
+                    return;
+                };
+                let result = if let Some(error) = message.get("error") {
+                    Err(McpRpcError(error.clone()).into())
+                } else {
+                    Ok(message.get("result").cloned().unwrap_or(Value::Null))
+                };
+                let _ = sender.send(result);
+            }
+            (None, None) => {}
+        }
+    }
+
+    fn capabilities(&self) -> Value {
+        if self.handlers.request.is_some() {
+            json!({ "sampling": {}, "elicitation": {} })
+        } else {
+            json!({})
+        }
+    }
+
+    fn fail_all(&self, reason: &str) {
+        if let Ok(mut pending) = self.pending.lock() {
+            for (_, sender) in pending.drain() {
+                let _ = sender.send(Err(anyhow!("{}", reason)));
+            }
+        }
+    }
+}
+
+pub struct McpClient {
+    shared: Arc<ClientShared>,
+    transport: Arc<dyn McpTransport>,
+    next_id: AtomicU64,
+    timeout: Option<Duration>,
+    dispatcher: JoinHandle<()>,
+    info: OnceLock<McpServerInfo>,
+    kind: McpTransportKind,
+}
+
+impl McpClient {
+    pub async fn connect(
+        name: &str,
+        server: &McpServerConfig,
+        handlers: McpHandlers,
+    ) -> Result<Self> {
+        let timeout = server.timeout_sec.map(Duration::from_secs);
+        tokio::time::timeout(
+            timeout.unwrap_or(DEFAULT_INIT_TIMEOUT),
+            Self::open(name, server, handlers, timeout),
+        )
+        .await
+        .map_err(|_| anyhow!("mcp initialize timed out: {}", name))?
+    }
+
+    async fn open(
+        name: &str,
+        server: &McpServerConfig,
+        handlers: McpHandlers,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let kind = server.transport_kind()?;
+        if kind == McpTransportKind::Sse {
+            return Self::open_legacy_sse(name, server, handlers, timeout).await;
+        }
+        let (inbound, inbound_rx) = mpsc::unbounded_channel();
+        let transport: Arc<dyn McpTransport> = match kind {
+            McpTransportKind::Stdio => Arc::new(StdioTransport::spawn(name, server, inbound)?),
+            _ => Arc::new(HttpTransport::new(server, inbound)?),
+        };
+        let mut client =
+            Self::with_transport(name, transport, inbound_rx, handlers.clone(), timeout);
+        client.kind = kind;
+        match client.handshake().await {
+            Ok(()) => Ok(client),
+            Err(err) if kind == McpTransportKind::Http && is_legacy_sse_server(&err) => {
+                append_mcp_log(
+                    name,
+                    &format!("streamable http failed ({}); trying legacy http+sse", err),
+                );
+                Self::open_legacy_sse(name, server, handlers, timeout).await
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn open_legacy_sse(
+        name: &str,
+        server: &McpServerConfig,
+        handlers: McpHandlers,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let (inbound, inbound_rx) = mpsc::unbounded_channel();
+        let transport = Arc::new(SseTransport::connect(server, inbound).await?);
+        let mut client = Self::with_transport(name, transport, inbound_rx, handlers, timeout);
+        client.kind = McpTransportKind::Sse;
+        client.handshake().await?;
+        Ok(client)
+    }
+
+    fn with_transport(
+        name: &str,
+        transport: Arc<dyn McpTransport>,
+        mut inbound_rx: mpsc::UnboundedReceiver<Value>,
+        handlers: McpHandlers,
+        timeout: Option<Duration>,
+    ) -> Self {
+        let shared = Arc::new(ClientShared {
+            server: name.to_string(),
+            pending: Mutex::new(HashMap::new()),
+            handlers,
+        });
+        let dispatcher = tokio::spawn({
+            let shared = Arc::clone(&shared);
+            let transport = Arc::clone(&transport);
+            async move {
+                while let Some(message) = inbound_rx.recv().await {
+                    shared.dispatch(message, &transport);
+                }
+                shared.fail_all("mcp server closed the connection");
+            }
+        });
+        Self {
+            shared,
+            transport,
+            next_id: AtomicU64::new(1),
+            timeout,
+            dispatcher,
+            info: OnceLock::new(),
+            kind: McpTransportKind::Stdio,
+        }
+    }
+
+    async fn handshake(&self) -> Result<()> {
+        let started = Instant::now();
+        let result = match self.initialize(PROTOCOL_VERSION).await {
+            Err(err) if err.downcast_ref::<McpRpcError>().is_some() => {
+                self.initialize(FALLBACK_PROTOCOL_VERSION).await?
+            }
+            other => other?,
+        };
+        let info = McpServerInfo::from_init_result(&result, started.elapsed())?;
+        self.transport.set_protocol_version(&info.protocol_version);
+        self.notify("notifications/initialized", None).await?;
+        let _ = self.transport.listen().await;
+        let _ = self.info.set(info);
+        Ok(())
+    }
+
+    async fn initialize(&self, protocol_version: &str) -> Result<Value> {
+        self.request(
+            "initialize",
+            json!({
+                "protocolVersion": protocol_version,
+                "capabilities": self.shared.capabilities(),
+                "clientInfo": {
+                    "name": "tengu",
+                    "version": env!("CARGO_PKG_VERSION")
+                }
+            }),
+        )
+        .await
+    }
+
+    pub fn info(&self) -> Option<&McpServerInfo> {
+        self.info.get()
+    }
+
+    pub fn transport_kind(&self) -> McpTransportKind {
+        self.kind
+    }
+
+    /// Whether the server closed its end (the process exited or the stream ended).
+    pub fn is_closed(&self) -> bool {
+        self.dispatcher.is_finished()
+    }
+
+    pub async fn list_tools(&self) -> Result<Vec<McpTool>> {
+        let mut tools = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match cursor.as_deref() {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let result = self.request("tools/list", params).await?;
+            let list: ToolsListResult = serde_json::from_value(result)?;
+            tools.extend(list.tools);
+            cursor = list.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        Ok(tools)
+    }
+
+    /// Calls a tool; dropping the returned future sends `notifications/cancelled`.
+    pub async fn call_tool(&self, name: &str, arguments: Value) -> Result<CallToolResult> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let params = json!({
+            "name": name,
+            "arguments": arguments,
+            "_meta": { "progressToken": id }
+        });
+        let result = self.request_with_id(id, "tools/call", params).await?;
+        Ok(CallToolResult::from_value(result))
+    }
+
+    pub async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.request_with_id(id, method, params).await
+    }
+
+    async fn request_with_id(&self, id: u64, method: &str, params: Value) -> Result<Value> {
+        let (tx, rx) = oneshot::channel();
+        if let Ok(mut pending) = self.shared.pending.lock() {
+            pending.insert(id, tx);
+        }
+        let mut guard = CancelGuard {
+            id,
+            shared: Arc::clone(&self.shared),
+            transport: Arc::clone(&self.transport),
+            armed: method != "initialize",
+        };
+        self.transport
+            .send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+            .await?;
+        let response = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, rx)
+                .await
+                .map_err(|_| anyhow!("mcp request timed out: {}", method))?,
+            None => rx.await,
+        };
+        guard.armed = false;
+        response.map_err(|_| anyhow!("mcp request dropped: {}", method))?
+    }
+
+    pub async fn notify(&self, method: &str, params: Option<Value>) -> Result<()> {
+        let mut message = json!({ "jsonrpc": "2.0", "method": method });
+        if let Some(params) = params {
+            message["params"] = params;
+        }
+        self.transport.send(message).await
+    }
+}
+
+impl Drop for McpClient {
+    fn drop(&mut self) {
+        self.dispatcher.abort();
+    }
+}
+
+/// Removes the pending entry and tells the server to stop work if the request never completed.
+struct CancelGuard {
+    id: u64,
+    shared: Arc<ClientShared>,
+    transport: Arc<dyn McpTransport>,
+    armed: bool,
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if let Ok(mut pending) = self.shared.pending.lock() {
+            pending.remove(&self.id);
+        }
+        if !self.armed {
+            return;
+        }
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let transport = Arc::clone(&self.transport);
+        let message = json!({
+            "jsonrpc": "2.0",
+            "method": "notifications/cancelled",
+            "params": { "requestId": self.id, "reason": "cancelled by user" }
+        });
+        handle.spawn(async move {
+            let _ = transport.send(message).await;
+        });
+    }
+}
+
+/// Servers that predate streamable HTTP reject the `initialize` POST with 400/404/405.
+/// Auth failures are left alone so they are reported as-is.
+fn is_legacy_sse_server(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<McpHttpError>()
+        .map(|err| matches!(err.status.as_u16(), 400 | 404 | 405))
+        .unwrap_or(false)
+}
+
+fn json_rpc_error(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+pub fn mcp_log_path(server: &str) -> PathBuf {
+    let name: String = server
+        .chars()
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() || ch == '-' || ch == '_' {
+                ch
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    PathBuf::from(".")
+        .join(".tengu")
+        .join("logs")
+        .join(format!("mcp-{}.log", name))
+}
+
+pub fn open_mcp_log(server: &str) -> Result<std::fs::File> {
+    let path = mcp_log_path(server);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?)
+}
+
+pub fn append_mcp_log(server: &str, line: &str) {
+    if let Ok(mut file) = open_mcp_log(server) {
+        let _ = writeln!(file, "{} {}", chrono::Utc::now().to_rfc3339(), line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct RecordingTransport {
+        sent: Mutex<Vec<Value>>,
+        inbound: McpInbound,
+        respond: bool,
+    }
+
+    #[async_trait]
+    impl McpTransport for RecordingTransport {
+        async fn send(&self, message: Value) -> Result<()> {
+            if self.respond {
+                if let (Some(id), Some(_)) = (message.get("id"), message.get("method")) {
+                    let _ = self.inbound.send(json!({
+                        "jsonrpc": "2.0",
+                        "method": "notifications/progress",
+                        "params": { "progressToken": id, "progress": 1, "total": 2 }
+                    }));
+                    let _ = self.inbound.send(json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "result": { "content": [{ "type": "text", "text": "ok" }] }
+                    }));
+                }
+            }
+            self.sent.lock().unwrap().push(message);
+            Ok(())
+        }
+    }
+
+    fn test_client(
+        respond: bool,
+    ) -> (
+        McpClient,
+        Arc<RecordingTransport>,
+        Arc<Mutex<Vec<McpNotification>>>,
+    ) {
+        let (inbound, inbound_rx) = mpsc::unbounded_channel();
+        let transport = Arc::new(RecordingTransport {
+            sent: Mutex::new(Vec::new()),
+            inbound,
+            respond,
+        });
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handler: McpNotificationHandler = {
+            let seen = Arc::clone(&seen);
+            Arc::new(move |_server: &str, notification: &McpNotification| {
+                seen.lock().unwrap().push(notification.clone());
+            })
+        };
+        let client = McpClient::with_transport(
+            "test",
+            transport.clone() as Arc<dyn McpTransport>,
+            inbound_rx,
+            McpHandlers {
+                notification: Some(handler),
+                request: None,
+            },
+            None,
+        );
+        (client, transport, seen)
+    }
+
+    #[test]
+    fn parses_known_notifications() {
+        assert_eq!(
+            McpNotification::parse("notifications/tools/list_changed", Value::Null),
+            McpNotification::ToolsListChanged
+        );
+        let progress = McpNotification::parse(
+            "notifications/progress",
+            json!({ "progressToken": 3, "progress": 5, "total": 10, "message": "indexing" }),
+        );
+        assert_eq!(
+            progress,
+            McpNotification::Progress {
+                token: json!(3),
+                progress: 5.0,
+                total: Some(10.0),
+                message: Some("indexing".to_string()),
+            }
+        );
+        let message = McpNotification::parse(
+            "notifications/message",
+            json!({ "level": "warning", "data": "disk almost full" }),
+        );
+        assert!(
+            matches!(message, McpNotification::Message { ref level, .. } if level == "warning")
+        );
+    }
+
+    #[tokio::test]
+    async fn routes_progress_and_response_for_tool_call() {
+        let (client, _transport, seen) = test_client(true);
+        let result = client.call_tool("echo", json!({})).await.unwrap();
+        assert_eq!(result.text(), "ok");
+        tokio::task::yield_now().await;
+        let seen = seen.lock().unwrap();
+        assert!(matches!(
+            seen.first(),
+            Some(McpNotification::Progress { total: Some(total), .. }) if *total == 2.0
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_closed_once_the_server_hangs_up() {
+        let (inbound, inbound_rx) = mpsc::unbounded_channel();
+        let (unused, _) = mpsc::unbounded_channel();
+        let transport = Arc::new(RecordingTransport {
+            sent: Mutex::new(Vec::new()),
+            inbound: unused,
+            respond: false,
+        });
+        let client = McpClient::with_transport(
+            "test",
+            transport as Arc<dyn McpTransport>,
+            inbound_rx,
+            McpHandlers::default(),
+            None,
+        );
+        assert!(!client.is_closed());
+        drop(inbound);
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(client.is_closed());
+    }
+
+    #[tokio::test]
+    async fn sends_cancelled_when_call_is_dropped() {
+        let (client, transport, _seen) = test_client(false);
+        let call = client.call_tool("slow", json!({}));
+        let timed_out = tokio::time::timeout(Duration::from_millis(20), call).await;
+        assert!(timed_out.is_err());
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        let sent = transport.sent.lock().unwrap();
+        let cancelled = sent
+            .iter()
+            .find(|m| m["method"] == "notifications/cancelled")
+            .expect("cancel notification");
+        assert_eq!(cancelled["params"]["requestId"], sent[0]["id"]);
+    }
+
+    #[tokio::test]
+    async fn answers_server_requests_through_request_handler() {
+        let (inbound, inbound_rx) = mpsc::unbounded_channel();
+        let transport = Arc::new(RecordingTransport {
+            sent: Mutex::new(Vec::new()),
+            inbound: inbound.clone(),
+            respond: false,
+        });
+        let client = McpClient::with_transport(
+            "test",
+            transport.clone() as Arc<dyn McpTransport>,
+            inbound_rx,
+            McpHandlers {
+                notification: None,
+                request: Some(crate::mcp::headless_request_handler()),
+            },
+            None,
+        );
+        assert_eq!(client.shared.capabilities()["elicitation"], json!({}));
+        inbound
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": "e1",
+                "method": "elicitation/create",
+                "params": { "message": "name?", "requestedSchema": { "type": "object", "properties": {} } }
+            }))
+            .unwrap();
+        inbound
+            .send(json!({ "jsonrpc": "2.0", "id": 9, "method": "roots/list" }))
+            .unwrap();
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+        let sent = transport.sent.lock().unwrap();
+        let reply = |id: Value| sent.iter().find(|m| m["id"] == id).cloned().unwrap();
+        assert_eq!(reply(json!("e1"))["result"]["action"], "decline");
+        assert_eq!(reply(json!(9))["error"]["code"], -32601);
+    }
+
+    /// Answers `initialize` like a server that only knows `accepts`.
+    struct VersionedServer {
+        inbound: McpInbound,
+        accepts: &'static str,
+        sent: Mutex<Vec<Value>>,
+    }
+
+    #[async_trait]
+    impl McpTransport for VersionedServer {
+        async fn send(&self, message: Value) -> Result<()> {
+            if message["method"] == "initialize" {
+                let requested = message["params"]["protocolVersion"].clone();
+                let reply = if requested == self.accepts {
+                    json!({
+                        "jsonrpc": "2.0",
+                        "id": message["id"],
+                        "result": {
+                            "protocolVersion": self.accepts,
+                            "capabilities": { "tools": {}, "logging": {} },
+                            "serverInfo": { "name": "legacy", "version": "0.9" },
+                            "instructions": "Use search before fetch."
+                        }
+                    })
+                } else {
+                    json_rpc_error(message["id"].clone(), -32602, "unsupported version")
+                };
+                let _ = self.inbound.send(reply);
+            }
+            self.sent.lock().unwrap().push(message);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_older_protocol_version() {
+        let (inbound, inbound_rx) = mpsc::unbounded_channel();
+        let transport = Arc::new(VersionedServer {
+            inbound,
+            accepts: "2025-03-26",
+            sent: Mutex::new(Vec::new()),
+        });
+        let client = McpClient::with_transport(
+            "legacy",
+            transport.clone() as Arc<dyn McpTransport>,
+            inbound_rx,
+            McpHandlers::default(),
+            None,
+        );
+        client.handshake().await.unwrap();
+        let info = client.info().unwrap();
+        assert_eq!(info.protocol_version, "2025-03-26");
+        assert_eq!(info.name.as_deref(), Some("legacy"));
+        assert_eq!(info.capability_names(), vec!["logging", "tools"]);
+        assert_eq!(
+            info.instructions.as_deref(),
+            Some("Use search before fetch.")
+        );
+        let sent = transport.sent.lock().unwrap();
+        let versions: Vec<&Value> = sent
+            .iter()
+            .filter(|m| m["method"] == "initialize")
+            .map(|m| &m["params"]["protocolVersion"])
+            .collect();
+        assert_eq!(
+            versions,
+            vec![&json!(PROTOCOL_VERSION), &json!("2025-03-26")]
+        );
+        assert!(sent
+            .iter()
+            .any(|m| m["method"] == "notifications/initialized"));
+    }
+
+    #[test]
+    fn rejects_unknown_negotiated_version() {
+        let err = McpServerInfo::from_init_result(
+            &json!({ "protocolVersion": "1999-01-01" }),
+            Duration::ZERO,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("unsupported mcp protocol version: 1999-01-01"));
+    }
+
+    #[test]
+    fn renders_every_content_type() {
+        let result = CallToolResult::from_value(json!({
+            "content": [
+                { "type": "text", "text": "found 2 files" },
+                { "type": "image", "data": "aGk=", "mimeType": "image/png" },
+                { "type": "resource", "resource": { "uri": "file:///a.txt", "text": "hello" } },
+                { "type": "resource_link", "uri": "file:///b.txt", "name": "b.txt" },
+                { "type": "mystery" }
+            ],
+            "structuredContent": { "count": 2 }
+        }));
+        let text = result.text();
+        assert!(text.contains("found 2 files"));
+        assert!(text.contains("[image: image/png]"));
+        assert!(text.contains("resource file:///a.txt:\nhello"));
+        assert!(text.contains("resource link: file:///b.txt (b.txt)"));
+        assert!(text.contains("\"count\": 2"));
+        assert_eq!(result.images().len(), 1);
+        assert_eq!(result.content[4], McpContent::Unknown);
+    }
+
+    #[test]
+    fn validates_structured_content_against_output_schema() {
+        let schema = json!({ "type": "object", "required": ["count"] });
+        let ok = CallToolResult::from_value(json!({ "structuredContent": { "count": 1 } }));
+        assert!(ok.validate(Some(&schema)).is_ok());
+        let missing = CallToolResult::from_value(json!({ "content": [] }));
+        assert!(missing.validate(Some(&schema)).is_err());
+        let wrong = CallToolResult::from_value(json!({ "structuredContent": { "total": 1 } }));
+        assert!(wrong.validate(Some(&schema)).is_err());
+        assert!(wrong.validate(None).is_ok());
+    }
+}
@@ -1,113 +1,132 @@
 use anyhow::{anyhow, Result};
+use async_trait::async_trait;
 use futures_util::StreamExt;
-use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
-use reqwest::{Client, Response};
-use serde::Serialize;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE};
+use reqwest::{Client, Response, StatusCode};
 use serde_json::Value;
+use std::sync::Mutex;
 
-use crate::mcp::{McpServerConfig, McpTool, ToolsListResult};
+use crate::mcp::{McpInbound, McpServerConfig, McpTransport, PROTOCOL_VERSION};
 
-const PROTOCOL_VERSION: &str = "2025-11-25";
+#[derive(Debug)]
+pub struct McpHttpError {
+    pub status: StatusCode,
+    pub body: String,
+}
 
-#[derive(Debug, Clone, Serialize)]
-struct JsonRpcRequest<'a> {
-    jsonrpc: &'static str,
-    id: u64,
-    method: &'a str,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    params: Option<Value>,
+impl std::fmt::Display for McpHttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mcp http error: {} {}", self.status, self.body.trim())
+    }
 }
 
-#[derive(Debug, Clone, Serialize)]
-struct JsonRpcNotification<'a> {
-    jsonrpc: &'static str,
-    method: &'a str,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    params: Option<Value>,
+impl std::error::Error for McpHttpError {}
+
+pub struct HttpTransport {
+    client: Client,
+    url: String,
+    headers: HeaderMap,
+    session_id: Mutex<Option<String>>,
+    protocol_version: Mutex<String>,
+    inbound: McpInbound,
 }
 
-pub async fn list_tools_http(server: &McpServerConfig) -> Result<Vec<McpTool>> {
-    let url = server
-        .url
-        .as_ref()
-        .ok_or_else(|| anyhow!("mcp server url is required for http"))?;
-    let client = build_client(server)?;
-
-    let mut headers = build_headers(server)?;
-    let mut next_id = 1u64;
-
-    let init_request = JsonRpcRequest {
-        jsonrpc: "2.0",
-        id: next_id,
-        method: "initialize",
-        params: Some(serde_json::json!({
-            "protocolVersion": PROTOCOL_VERSION,
-            "capabilities": {},
-            "clientInfo": {
-                "name": "tengu",
-                "version": env!("CARGO_PKG_VERSION")
-            }
-        })),
-    };
-    let (init_result, session_id) =
-        send_request(&client, url, &headers, &init_request, next_id).await?;
-    let _ = init_result;
-    if let Some(session_id) = session_id {
-        headers.insert(
-            HeaderName::from_static("mcp-session-id"),
-            HeaderValue::from_str(&session_id)?,
-        );
+impl HttpTransport {
+    pub fn new(server: &McpServerConfig, inbound: McpInbound) -> Result<Self> {
+        let url = server
+            .url
+            .clone()
+            .ok_or_else(|| anyhow!("mcp server url is required for http"))?;
+        Ok(Self {
+            // Per-request timeouts are enforced by the client; a client-wide timeout would
+            // also cut off the long-lived event stream.
+            client: Client::new(),
+            url,
+            headers: build_headers(server)?,
+            session_id: Mutex::new(None),
+            protocol_version: Mutex::new(PROTOCOL_VERSION.to_string()),
+            inbound,
+        })
     }
-    next_id += 1;
 
-    let init_notification = JsonRpcNotification {
-        jsonrpc: "2.0",
-        method: "notifications/initialized",
-        params: None,
-    };
-    let _ = send_notification(&client, url, &headers, &init_notification).await;
+    fn request_headers(&self) -> Result<HeaderMap> {
+        let mut headers = self.headers.clone();
+        if let Some(version) = self.protocol_version.lock().ok().map(|v| v.clone()) {
+            headers.insert(
+                HeaderName::from_static("mcp-protocol-version"),
+                HeaderValue::from_str(&version)?,
+            );
+        }
+        if let Some(session_id) = self.session_id.lock().ok().and_then(|s| s.clone()) {
+            headers.insert(
+                HeaderName::from_static("mcp-session-id"),
+                HeaderValue::from_str(&session_id)?,
+            );
+        }
+        Ok(headers)
+    }
+}
 
-    let mut tools = Vec::new();
-    let mut cursor: Option<String> = None;
-    loop {
-        let id = next_id;
-        next_id += 1;
-        let params = match cursor.as_deref() {
-            Some(cursor) => serde_json::json!({ "cursor": cursor }),
-            None => serde_json::json!({}),
-        };
-        let request = JsonRpcRequest {
-            jsonrpc: "2.0",
-            id,
-            method: "tools/list",
-            params: Some(params),
-        };
-        let (value, _) = send_request(&client, url, &headers, &request, id).await?;
-        let list: ToolsListResult = serde_json::from_value(value)?;
-        tools.extend(list.tools);
-        cursor = list.next_cursor;
-        if cursor.is_none() {
-            break;
+#[async_trait]
+impl McpTransport for HttpTransport {
+    async fn send(&self, message: Value) -> Result<()> {
+        let resp = self
+            .client
+            .post(&self.url)
+            .headers(self.request_headers()?)
+            .header(ACCEPT, "application/json, text/event-stream")
+            .json(&message)
+            .send()
+            .await?;
+        if let Some(session_id) = extract_session_id(&resp) {
+            if let Ok(mut guard) = self.session_id.lock() {
+                *guard = Some(session_id);
+            }
+        }
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(McpHttpError { status, body }.into());
+        }
+        if status == StatusCode::ACCEPTED || status == StatusCode::NO_CONTENT {
+            return Ok(());
+        }
+        if is_event_stream(&resp) {
+            tokio::spawn(forward_sse(resp, self.inbound.clone()));
+        } else {
+            let bytes = resp.bytes().await?;
+            if !bytes.is_empty() {
+                let value: Value = serde_json::from_slice(&bytes)?;
+                let _ = self.inbound.send(value);
+            }
         }
+        Ok(())
     }
 
-    Ok(tools)
-}
+    async fn listen(&self) -> Result<()> {
+        let resp = self
+            .client
+            .get(&self.url)
+            .headers(self.request_headers()?)
+            .header(ACCEPT, "text/event-stream")
+            .send()
+            .await?;
+        // Servers without a standalone stream answer 405; that is not an error.
+        if resp.status().is_success() && is_event_stream(&resp) {
+            tokio::spawn(forward_sse(resp, self.inbound.clone()));
+        }
+        Ok(())
+    }
 
-fn build_client(server: &McpServerConfig) -> Result<Client> {
-    let mut builder = Client::builder();
-    if let Some(timeout_sec) = server.timeout_sec {
-        builder = builder.timeout(std::time::Duration::from_secs(timeout_sec));
+    fn set_protocol_version(&self, version: &str) {
+        if let Ok(mut guard) = self.protocol_version.lock() {
+            *guard = version.to_string();
+        }
     }
-    Ok(builder.build()?)
 }
 
-fn build_headers(server: &McpServerConfig) -> Result<HeaderMap> {
+pub(super) fn build_headers(server: &McpServerConfig) -> Result<HeaderMap> {
     let mut headers = HeaderMap::new();
-    headers.insert(
-        HeaderName::from_static("mcp-protocol-version"),
-        HeaderValue::from_static(PROTOCOL_VERSION),
-    );
     if let Some(env_var) = &server.bearer_token_env_var {
         if let Ok(token) = std::env::var(env_var) {
             let value = format!("Bearer {}", token);
@@ -127,122 +146,94 @@ fn build_headers(server: &McpServerConfig) -> Result<HeaderMap> {
     Ok(headers)
 }
 
-async fn send_notification(
-    client: &Client,
-    url: &str,
-    headers: &HeaderMap,
-    notification: &JsonRpcNotification<'_>,
-) -> Result<()> {
-    let resp = client
-        .post(url)
-        .headers(headers.clone())
-        .json(notification)
-        .send()
-        .await?;
-    if !resp.status().is_success() {
-        return Err(anyhow!("mcp notification failed: {}", resp.status()));
-    }
-    Ok(())
-}
-
-async fn send_request(
-    client: &Client,
-    url: &str,
-    headers: &HeaderMap,
-    request: &JsonRpcRequest<'_>,
-    id: u64,
-) -> Result<(Value, Option<String>)> {
-    let resp = client
-        .post(url)
-        .headers(headers.clone())
-        .json(request)
-        .send()
-        .await?;
-    let session_id = extract_session_id(&resp);
-    let value = parse_response(resp, id).await?;
-    Ok((value, session_id))
-}
-
 fn extract_session_id(resp: &Response) -> Option<String> {
     resp.headers()
-        .get("MCP-Session-Id")
-        .or_else(|| resp.headers().get("Mcp-Session-Id"))
+        .get("mcp-session-id")
         .and_then(|v| v.to_str().ok())
         .map(|v| v.to_string())
 }
 
-async fn parse_response(resp: Response, id: u64) -> Result<Value> {
-    let is_sse = resp
-        .headers()
+pub(super) fn is_event_stream(resp: &Response) -> bool {
+    resp.headers()
         .get(CONTENT_TYPE)
         .and_then(|v| v.to_str().ok())
         .map(|v| v.starts_with("text/event-stream"))
-        .unwrap_or(false);
-    if is_sse {
-        parse_sse_response(resp, id).await
-    } else {
-        let value: Value = resp.json().await?;
-        extract_result_by_id(&value, id).ok_or_else(|| anyhow!("missing result for id {}", id))?
-    }
+        .unwrap_or(false)
 }
 
-async fn parse_sse_response(resp: Response, id: u64) -> Result<Value> {
+async fn forward_sse(resp: Response, inbound: McpInbound) {
     let mut buffer = String::new();
-    let mut data_lines: Vec<String> = Vec::new();
     let mut stream = resp.bytes_stream();
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk?;
+    while let Some(Ok(chunk)) = stream.next().await {
         buffer.push_str(&String::from_utf8_lossy(&chunk));
-        while let Some(pos) = buffer.find('\n') {
-            let mut line = buffer[..pos].to_string();
-            if line.ends_with('\r') {
-                line.pop();
-            }
-            buffer = buffer[pos + 1..].to_string();
-            if line.is_empty() {
-                if !data_lines.is_empty() {
-                    let data = data_lines.join("\n");
-                    data_lines.clear();
-                    if data == "[DONE]" {
-                        continue;
-                    }
-                    if let Ok(value) = serde_json::from_str::<Value>(&data) {
-                        if let Some(result) = extract_result_by_id(&value, id) {
-                            return result;
-                        }
-                    }
-                }
+        while let Some(data) = take_sse_data(&mut buffer) {
+            if data == "[DONE]" {
                 continue;
             }
-            if let Some(rest) = line.strip_prefix("data:") {
-                data_lines.push(rest.trim_start().to_string());
+            if let Ok(value) = serde_json::from_str::<Value>(&data) {
+                if inbound.send(value).is_err() {
+                    return;
+                }
             }
         }
     }
-    Err(anyhow!("missing sse response for id {}", id))
 }
 
-fn extract_result_by_id(value: &Value, id: u64) -> Option<Result<Value>> {
-    match value {
-        Value::Array(items) => {
-            for item in items {
-                if let Some(result) = extract_result_by_id(item, id) {
-                    return Some(result);
-                }
-            }
-            None
+pub(super) struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Pops the next complete SSE event from `buffer` and returns its joined `data:` lines.
+fn take_sse_data(buffer: &mut String) -> Option<String> {
+    take_sse_event(buffer).map(|event| event.data)
+}
+
+pub(super) fn take_sse_event(buffer: &mut String) -> Option<SseEvent> {
+    loop {
+        let normalized = buffer.replace("\r\n", "\n");
+        if normalized.len() != buffer.len() {
+            *buffer = normalized;
         }
-        Value::Object(map) => {
-            let message_id = map.get("id")?.as_u64()?;
-            if message_id != id {
-                return None;
-            }
-            if let Some(error) = map.get("error") {
-                return Some(Err(anyhow!("mcp error: {}", error)));
+        let end = buffer.find("\n\n")?;
+        let block = buffer[..end].to_string();
+        buffer.drain(..end + 2);
+        let mut event = None;
+        let mut data = Vec::new();
+        for line in block.lines() {
+            if let Some(rest) = line.strip_prefix("data:") {
+                data.push(rest.strip_prefix(' ').unwrap_or(rest));
+            } else if let Some(rest) = line.strip_prefix("event:") {
+                event = Some(rest.trim().to_string());
             }
-            let result = map.get("result")?.clone();
-            Some(Ok(result))
         }
-        _ => None,
+        if !data.is_empty() {
+            return Some(SseEvent {
+                event,
+                data: data.join("\n"),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_sse_events_and_skips_comments() {
+        let mut buffer = ": keepalive\n\nevent: message\r\ndata: {\"id\":1}\r\n\r\ndata: {\"a\":\ndata: 2}\n\ndata: partial".to_string();
+        assert_eq!(take_sse_data(&mut buffer).as_deref(), Some("{\"id\":1}"));
+        assert_eq!(take_sse_data(&mut buffer).as_deref(), Some("{\"a\":\n2}"));
+        assert_eq!(take_sse_data(&mut buffer), None);
+        assert_eq!(buffer, "data: partial");
+    }
+
+    #[test]
+    fn keeps_sse_event_names() {
+        let mut buffer = "event: endpoint\ndata: /messages?sessionId=1\n\n".to_string();
+        let event = take_sse_event(&mut buffer).unwrap();
+        assert_eq!(event.event.as_deref(), Some("endpoint"));
+        assert_eq!(event.data, "/messages?sessionId=1");
     }
 }
@@ -0,0 +1,319 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+use crate::mcp::{
+    append_mcp_log, CallToolResult, McpClient, McpHandlers, McpNotification,
+    McpNotificationHandler, McpRequestHandler, McpServerConfig, McpServerInfo, McpStore, McpTool,
+};
+
+/// Snapshot of one server for `tengu mcp doctor` and `/mcp status`.
+#[derive(Debug, Clone)]
+pub struct McpServerStatus {
+    pub name: String,
+    pub transport: String,
+    pub info: Option<McpServerInfo>,
+    pub tool_count: Option<usize>,
+    pub last_error: Option<String>,
+}
+
+impl McpServerStatus {
+    pub fn render(&self) -> String {
+        let mut lines = Vec::new();
+        match &self.info {
+            Some(info) => {
+                lines.push(format!(
+                    "{}: ok ({}, protocol {}, handshake {}ms)",
+                    self.name,
+                    self.transport,
+                    info.protocol_version,
+                    info.handshake.as_millis()
+                ));
+                if let Some(name) = &info.name {
+                    let version = info.version.as_deref().unwrap_or("-");
+                    lines.push(format!("  server: {} {}", name, version));
+                }
+                let capabilities = info.capability_names();
+                lines.push(format!(
+                    "  capabilities: {}",
+                    if capabilities.is_empty() {
+                        "none".to_string()
+                    } else {
+                        capabilities.join(", ")
+                    }
+                ));
+            }
+            None => lines.push(format!("{}: failed ({})", self.name, self.transport)),
+        }
+        if let Some(count) = self.tool_count {
+            lines.push(format!("  tools: {}", count));
+        }
+        if let Some(instructions) = self
+            .info
+            .as_ref()
+            .and_then(|info| info.instructions.as_deref())
+        {
+            let first = instructions.lines().next().unwrap_or_default();
+            let first: String = first.chars().take(120).collect();
+            lines.push(format!("  instructions: {}", first));
+        }
+        if let Some(error) = &self.last_error {
+            lines.push(format!("  last error: {}", error));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Keeps one live client per enabled server and a tool catalog that is refreshed
+/// when a server sends `notifications/tools/list_changed`.
+pub struct McpManager {
+    servers: BTreeMap<String, McpServerConfig>,
+    clients: Mutex<HashMap<String, Arc<OnceCell<Arc<McpClient>>>>>,
+    catalog: Mutex<BTreeMap<String, Vec<McpTool>>>,
+    stale: Arc<Mutex<HashSet<String>>>,
+    failed: Mutex<HashSet<String>>,
+    last_error: Mutex<HashMap<String, String>>,
+    handler: Arc<Mutex<Option<McpNotificationHandler>>>,
+    request_handler: Mutex<Option<McpRequestHandler>>,
+}
+
+impl McpManager {
+    pub fn new(servers: BTreeMap<String, McpServerConfig>) -> Self {
+        let servers = servers
+            .into_iter()
+            .filter(|(_, server)| server.is_enabled())
+            .collect();
+        Self {
+            servers,
+            clients: Mutex::new(HashMap::new()),
+            catalog: Mutex::new(BTreeMap::new()),
+            stale: Arc::new(Mutex::new(HashSet::new())),
+            failed: Mutex::new(HashSet::new()),
+            last_error: Mutex::new(HashMap::new()),
+            handler: Arc::new(Mutex::new(None)),
+            request_handler: Mutex::new(None),
+        }
+    }
+
+    pub fn from_store() -> Result<Self> {
+        Ok(Self::new(McpStore::load_merged()?.mcp_servers))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+
+    pub fn set_notification_handler(&self, handler: McpNotificationHandler) {
+        if let Ok(mut guard) = self.handler.lock() {
+            *guard = Some(handler);
+        }
+    }
+
+    /// Must be set before the first connection; servers only learn about sampling and
+    /// elicitation support during `initialize`.
+    pub fn set_request_handler(&self, handler: McpRequestHandler) {
+        if let Ok(mut guard) = self.request_handler.lock() {
+            *guard = Some(handler);
+        }
+    }
+
+    /// Connects on first use without holding the map lock, so a slow server does not
+    /// block calls to the others. A client whose server hung up is replaced.
+    async fn client(&self, name: &str) -> Result<Arc<McpClient>> {
+        let cell = {
+            let mut clients = self
+                .clients
+                .lock()
+                .map_err(|_| anyhow!("mcp client map poisoned"))?;
+            let cell = clients.entry(name.to_string()).or_default();
+            if cell.get().is_some_and(|client| client.is_closed()) {
+                *cell = Arc::new(OnceCell::new());
+            }
+            Arc::clone(cell)
+        };
+        let client = cell.get_or_try_init(|| self.connect(name)).await?;
+        Ok(Arc::clone(client))
+    }
+
+    async fn connect(&self, name: &str) -> Result<Arc<McpClient>> {
+        let server = self
+            .servers
+            .get(name)
+            .ok_or_else(|| anyhow!("mcp server not found: {}", name))?;
+        let stale = Arc::clone(&self.stale);
+        let forward = Arc::clone(&self.handler);
+        let handler: McpNotificationHandler =
+            Arc::new(move |server: &str, notification: &McpNotification| {
+                match notification {
+                    McpNotification::ToolsListChanged => {
+                        if let Ok(mut stale) = stale.lock() {
+                            stale.insert(server.to_string());
+                        }
+                    }
+                    McpNotification::Message {
+                        level,
+                        logger,
+                        data,
+                    } => {
+                        let text = data
+                            .as_str()
+                            .map(|s| s.to_string())
+                            .unwrap_or_else(|| data.to_string());
+                        let logger = logger.as_deref().unwrap_or("-");
+                        append_mcp_log(server, &format!("[{}] {}: {}", level, logger, text));
+                    }
+                    _ => {}
+                }
+                let forward = forward.lock().ok().and_then(|guard| guard.clone());
+                if let Some(forward) = forward {
+                    forward(server, notification);
+                }
+            });
+        let handlers = McpHandlers {
+            notification: Some(handler),
+            request: self.request_handler.lock().ok().and_then(|g| g.clone()),
+        };
+        Ok(Arc::new(McpClient::connect(name, server, handlers).await?))
+    }
+
+    /// Returns `(server, tool)` pairs for every reachable server, listing only servers
+    /// whose catalog is missing or stale. Servers that failed once are skipped.
+    pub async fn tool_catalog(&self) -> Vec<(String, McpTool)> {
+        let mut out = Vec::new();
+        for name in self.servers.keys() {
+            let cached = self
+                .catalog
+                .lock()
+                .ok()
+                .and_then(|catalog| catalog.get(name).cloned());
+            let is_stale = self
+                .stale
+                .lock()
+                .map(|mut stale| stale.remove(name))
+                .unwrap_or(false);
+            let tools = match cached {
+                Some(tools) if !is_stale => tools,
+                _ if self
+                    .failed
+                    .lock()
+                    .map(|f| f.contains(name))
+                    .unwrap_or(false) =>
+                {
+                    continue;
+                }
+                _ => match self.refresh_tools(name).await {
+                    Ok(tools) => tools,
+                    Err(err) => {
+                        // Do not retry a broken server on every prompt.
+                        append_mcp_log(name, &format!("tools/list failed: {}", err));
+                        self.record_error(name, &err);
+                        if let Ok(mut failed) = self.failed.lock() {
+                            failed.insert(name.clone());
+                        }
+                        continue;
+                    }
+                },
+            };
+            out.extend(tools.into_iter().map(|tool| (name.clone(), tool)));
+        }
+        out
+    }
+
+    async fn refresh_tools(&self, name: &str) -> Result<Vec<McpTool>> {
+        let client = self.client(name).await?;
+        let tools = client.list_tools().await?;
+        if let Ok(mut catalog) = self.catalog.lock() {
+            catalog.insert(name.to_string(), tools.clone());
+        }
+        Ok(tools)
+    }
+
+    pub async fn call_tool(
+        &self,
+        server: &str,
+        tool: &str,
+        arguments: Value,
+    ) -> Result<CallToolResult> {
+        let result = self.call_tool_inner(server, tool, arguments).await;
+        if let Err(err) = &result {
+            self.record_error(server, err);
+        }
+        result
+    }
+
+    async fn call_tool_inner(
+        &self,
+        server: &str,
+        tool: &str,
+        arguments: Value,
+    ) -> Result<CallToolResult> {
+        let client = self.client(server).await?;
+        let result = client.call_tool(tool, arguments).await?;
+        let output_schema = self.catalog.lock().ok().and_then(|catalog| {
+            catalog
+                .get(server)?
+                .iter()
+                .find(|t| t.name == tool)?
+                .output_schema
+                .clone()
+        });
+        result.validate(output_schema.as_ref())?;
+        Ok(result)
+    }
+
+    /// Connects to every server (retrying ones that failed before) and re-lists tools.
+    pub async fn status(&self) -> Vec<McpServerStatus> {
+        let mut out = Vec::new();
+        for (name, server) in &self.servers {
+            let configured = server
+                .transport_kind()
+                .map(|kind| kind.as_str().to_string())
+                .unwrap_or_else(|_| "unknown".to_string());
+            let (transport, info, tool_count) = match self.client(name).await {
+                Ok(client) => {
+                    let tool_count = match self.refresh_tools(name).await {
+                        Ok(tools) => {
+                            if let Ok(mut failed) = self.failed.lock() {
+                                failed.remove(name);
+                            }
+                            Some(tools.len())
+                        }
+                        Err(err) => {
+                            self.record_error(name, &err);
+                            None
+                        }
+                    };
+                    (
+                        client.transport_kind().as_str().to_string(),
+                        client.info().cloned(),
+                        tool_count,
+                    )
+                }
+                Err(err) => {
+                    self.record_error(name, &err);
+                    (configured, None, None)
+                }
+            };
+            out.push(McpServerStatus {
+                name: name.clone(),
+                transport,
+                info,
+                tool_count,
+                last_error: self
+                    .last_error
+                    .lock()
+                    .ok()
+                    .and_then(|errors| errors.get(name).cloned()),
+            });
+        }
+        out
+    }
+
+    fn record_error(&self, server: &str, err: &anyhow::Error) {
+        if let Ok(mut errors) = self.last_error.lock() {
+            errors.insert(server.to_string(), err.to_string());
+        }
+    }
+}
@@ -1,9 +1,19 @@
+mod client;
 mod http;
+mod manager;
+mod requests;
+mod schema;
+mod sse;
 mod stdio;
 mod store;
 mod types;
 
+pub use client::*;
 pub use http::*;
+pub use manager::*;
+pub use requests::*;
+pub use schema::*;
+pub use sse::*;
 pub use stdio::*;
 pub use store::*;
 pub use types::*;
@@ -0,0 +1,462 @@
+use anyhow::{anyhow, Result};
+use futures_util::future::BoxFuture;
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+
+use crate::llm::{LlmImage, LlmRequest, SamplingParams};
+
+/// Serves requests the server sends to the client (`sampling/createMessage`,
+/// `elicitation/create`). The returned value becomes the JSON-RPC `result`.
+pub type McpRequestHandler =
+    Arc<dyn Fn(String, McpServerRequest) -> BoxFuture<'static, Result<Value>> + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub enum McpServerRequest {
+    Sampling(SamplingRequest),
+    Elicitation(ElicitationRequest),
+}
+
+impl McpServerRequest {
+    pub fn parse(method: &str, params: &Value) -> Result<Option<Self>> {
+        match method {
+            "sampling/createMessage" => Ok(Some(Self::Sampling(SamplingRequest::parse(params)?))),
+            "elicitation/create" => Ok(Some(Self::Elicitation(ElicitationRequest::parse(params)?))),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SamplingMessage {
+    pub role: String,
+    pub text: String,
+    pub images: Vec<LlmImage>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SamplingRequest {
+    pub messages: Vec<SamplingMessage>,
+    pub system_prompt: Option<String>,
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+    pub stop_sequences: Vec<String>,
+    /// `modelPreferences.hints` names, most preferred first.
+    pub model_hints: Vec<String>,
+}
+
+impl SamplingRequest {
+    pub fn parse(params: &Value) -> Result<Self> {
+        let raw_messages = params
+            .get("messages")
+            .and_then(Value::as_array)
+            .ok_or_else(|| anyhow!("sampling request has no messages"))?;
+        let mut messages = Vec::new();
+        for raw in raw_messages {
+            let role = raw
+                .get("role")
+                .and_then(Value::as_str)
+                .unwrap_or("user")
+                .to_string();
+            let contents = match raw.get("content") {
+                Some(Value::Array(items)) => items.clone(),
+                Some(item) => vec![item.clone()],
+                None => Vec::new(),
+            };
+            let mut text = Vec::new();
+            let mut images = Vec::new();
+            for content in contents {
+                match content.get("type").and_then(Value::as_str) {
+                    Some("text") => {
+                        if let Some(t) = content.get("text").and_then(Value::as_str) {
+                            text.push(t.to_string());
+                        }
+                    }
+                    Some("image") => {
+                        let data = content.get("data").and_then(Value::as_str);
+                        let mime = content.get("mimeType").and_then(Value::as_str);
+                        if let (Some(data), Some(mime)) = (data, mime) {
+                            images.push(LlmImage {
+                                media_type: mime.to_string(),
+                                data_base64: data.to_string(),
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            messages.push(SamplingMessage {
+                role,
+                text: text.join("\n"),
+                images,
+            });
+        }
+        Ok(Self {
+            messages,
+            system_prompt: params
+                .get("systemPrompt")
+                .and_then(Value::as_str)
+                .map(|s| s.to_string()),
+            max_tokens: params
+                .get("maxTokens")
+                .and_then(Value::as_u64)
+                .map(|n| n.min(u32::MAX as u64) as u32),
+            temperature: params
+                .get("temperature")
+                .and_then(Value::as_f64)
+                .map(|t| t as f32),
+            stop_sequences: string_list(params.get("stopSequences")),
+            model_hints: params
+                .pointer("/modelPreferences/hints")
+                .and_then(Value::as_array)
+                .map(|hints| {
+                    hints
+                        .iter()
+                        .filter_map(|hint| hint.get("name").and_then(Value::as_str))
+                        .map(|name| name.to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Flattens the conversation into a single prompt for `LlmClient`; the system prompt,
+    /// output limit and sampling settings are passed through as they are.
+    pub fn to_llm_request(&self) -> LlmRequest {
+        let mut prompt = String::new();
+        let single_user = self.messages.len() == 1 && self.messages[0].role == "user";
+        let mut images = Vec::new();
+        for message in &self.messages {
+            if single_user {
+                prompt.push_str(&message.text);
+            } else {
+                prompt.push_str(&format!("{}: {}\n", message.role, message.text));
+            }
+            images.extend(message.images.iter().cloned());
+        }
+        LlmRequest {
+            prompt: prompt.trim_end().to_string(),
+            system: self
+                .system_prompt
+                .as_deref()
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string),
+            images,
+            max_tokens: self.max_tokens,
+            sampling: SamplingParams {
+                temperature: self.temperature,
+                stop: self.stop_sequences.clone(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    pub fn preview(&self) -> String {
+        let text = self
+            .messages
+            .last()
+            .map(|m| m.text.as_str())
+            .unwrap_or_default();
+        let first_line = text.lines().next().unwrap_or_default();
+        let mut preview: String = first_line.chars().take(80).collect();
+        if first_line.chars().count() > 80 || text.lines().count() > 1 {
+            preview.push('…');
+        }
+        preview
+    }
+
+    pub fn result(content: &str, model: &str) -> Value {
+        json!({
+            "role": "assistant",
+            "content": { "type": "text", "text": content },
+            "model": model,
+            "stopReason": "endTurn"
+        })
+    }
+}
+
+fn string_list(value: Option<&Value>) -> Vec<String> {
+    value
+        .and_then(Value::as_array)
+        .map(|items| {
+            items
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|s| s.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElicitationFieldKind {
+    String,
+    Number,
+    Integer,
+    Boolean,
+}
+
+#[derive(Debug, Clone)]
+pub struct ElicitationField {
+    pub name: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub kind: ElicitationFieldKind,
+    pub options: Vec<String>,
+    pub default: Option<Value>,
+    pub required: bool,
+}
+
+impl ElicitationField {
+    pub fn label(&self) -> &str {
+        self.title.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Converts raw user input into a JSON value; `Ok(None)` means the field is left empty.
+    pub fn parse_input(&self, input: &str) -> Result<Option<Value>> {
+        let input = input.trim();
+        if input.is_empty() {
+            if let Some(default) = &self.default {
+                return Ok(Some(default.clone()));
+            }
+            if self.required {
+                return Err(anyhow!("{} is required", self.label()));
+            }
+            return Ok(None);
+        }
+        if !self.options.is_empty() {
+            if let Some(option) = self.options.iter().find(|o| o.as_str() == input) {
+                return Ok(Some(Value::String(option.clone())));
+            }
+            if let Some(option) = input
+                .parse::<usize>()
+                .ok()
+                .and_then(|idx| idx.checked_sub(1))
+                .and_then(|idx| self.options.get(idx))
+            {
+                return Ok(Some(Value::String(option.clone())));
+            }
+            return Err(anyhow!("choose one of: {}", self.options.join(", ")));
+        }
+        match self.kind {
+            ElicitationFieldKind::String => Ok(Some(Value::String(input.to_string()))),
+            ElicitationFieldKind::Integer => input
+                .parse::<i64>()
+                .map(|n| Some(json!(n)))
+                .map_err(|_| anyhow!("{} must be an integer", self.label())),
+            ElicitationFieldKind::Number => input
+                .parse::<f64>()
+                .map(|n| Some(json!(n)))
+                .map_err(|_| anyhow!("{} must be a number", self.label())),
+            ElicitationFieldKind::Boolean => match input.to_ascii_lowercase().as_str() {
+                "y" | "yes" | "true" | "1" => Ok(Some(Value::Bool(true))),
+                "n" | "no" | "false" | "0" => Ok(Some(Value::Bool(false))),
+                _ => Err(anyhow!("{} must be yes or no", self.label())),
+            },
+        }
+    }
+
+    pub fn prompt(&self) -> String {
+        let mut out = self.label().to_string();
+        if !self.options.is_empty() {
+            let options: Vec<String> = self
+                .options
+                .iter()
+                .enumerate()
+                .map(|(idx, o)| format!("{}) {}", idx + 1, o))
+                .collect();
+            out.push_str(&format!(" [{}]", options.join(" ")));
+        } else if self.kind == ElicitationFieldKind::Boolean {
+            out.push_str(" [y/n]");
+        }
+        if let Some(default) = &self.default {
+            out.push_str(&format!(" (default: {})", default));
+        }
+        if self.required {
+            out.push_str(" *");
+        }
+        if let Some(description) = &self.description {
+            out.push_str(&format!(" — {}", description));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ElicitationRequest {
+    pub message: String,
+    pub fields: Vec<ElicitationField>,
+}
+
+impl ElicitationRequest {
+    pub fn parse(params: &Value) -> Result<Self> {
+        let message = params
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let schema = params
+            .get("requestedSchema")
+            .cloned()
+            .unwrap_or(Value::Null);
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|items| items.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let mut fields = Vec::new();
+        if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+            for (name, property) in properties {
+                let kind = match property.get("type").and_then(Value::as_str) {
+                    Some("string") | None => ElicitationFieldKind::String,
+                    Some("number") => ElicitationFieldKind::Number,
+                    Some("integer") => ElicitationFieldKind::Integer,
+                    Some("boolean") => ElicitationFieldKind::Boolean,
+                    Some(other) => {
+                        return Err(anyhow!("unsupported elicitation field type: {}", other));
+                    }
+                };
+                fields.push(ElicitationField {
+                    name: name.clone(),
+                    title: property
+                        .get("title")
+                        .and_then(Value::as_str)
+                        .map(|s| s.to_string()),
+                    description: property
+                        .get("description")
+                        .and_then(Value::as_str)
+                        .map(|s| s.to_string()),
+                    kind,
+                    options: property
+                        .get("enum")
+                        .and_then(Value::as_array)
+                        .map(|items| {
+                            items
+                                .iter()
+                                .filter_map(Value::as_str)
+                                .map(|s| s.to_string())
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                    default: property.get("default").cloned(),
+                    required: required.contains(&name.as_str()),
+                });
+            }
+        }
+        Ok(Self { message, fields })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ElicitationResponse {
+    Accept(Map<String, Value>),
+    Decline,
+    Cancel,
+}
+
+impl ElicitationResponse {
+    pub fn to_value(&self) -> Value {
+        match self {
+            Self::Accept(content) => json!({ "action": "accept", "content": content }),
+            Self::Decline => json!({ "action": "decline" }),
+            Self::Cancel => json!({ "action": "cancel" }),
+        }
+    }
+}
+
+/// Policy for non-interactive runs: nobody can approve sampling or fill a form.
+pub fn headless_request_handler() -> McpRequestHandler {
+    Arc::new(|_server: String, request: McpServerRequest| {
+        Box::pin(async move {
+            match request {
+                McpServerRequest::Sampling(_) => Err(anyhow!(
+                    "sampling requires interactive approval and was declined"
+                )),
+                McpServerRequest::Elicitation(_) => Ok(ElicitationResponse::Decline.to_value()),
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_sampling_request_to_llm_request() {
+        let params = json!({
+            "messages": [
+                { "role": "user", "content": { "type": "text", "text": "Summarize the diff" } },
+                { "role": "assistant", "content": { "type": "text", "text": "Which diff?" } },
+                { "role": "user", "content": [
+                    { "type": "text", "text": "This one" },
+                    { "type": "image", "data": "aGk=", "mimeType": "image/png" }
+                ] }
+            ],
+            "systemPrompt": "Be terse.",
+            "maxTokens": 200,
+            "temperature": 0.2,
+            "stopSequences": ["END"],
+            "modelPreferences": { "hints": [{ "name": "haiku" }], "speedPriority": 0.9 }
+        });
+        let request = SamplingRequest::parse(&params).unwrap();
+        assert_eq!(request.model_hints, vec!["haiku".to_string()]);
+        let llm = request.to_llm_request();
+        assert_eq!(llm.system.as_deref(), Some("Be terse."));
+        assert_eq!(llm.max_tokens, Some(200));
+        assert_eq!(llm.sampling.temperature, Some(0.2));
+        assert_eq!(llm.sampling.stop, vec!["END".to_string()]);
+        assert!(llm.prompt.starts_with("user: Summarize the diff"));
+        assert!(llm.prompt.contains("assistant: Which diff?"));
+        assert_eq!(llm.images.len(), 1);
+        assert_eq!(request.preview(), "This one");
+    }
+
+    #[test]
+    fn parses_elicitation_schema_and_coerces_input() {
+        let params = json!({
+            "message": "Configure deployment",
+            "requestedSchema": {
+                "type": "object",
+                "properties": {
+                    "env": { "type": "string", "enum": ["staging", "production"] },
+                    "replicas": { "type": "integer", "default": 2 },
+                    "confirm": { "type": "boolean", "title": "Confirm" }
+                },
+                "required": ["env", "confirm"]
+            }
+        });
+        let request = ElicitationRequest::parse(&params).unwrap();
+        assert_eq!(request.fields.len(), 3);
+        let field = |name: &str| request.fields.iter().find(|f| f.name == name).unwrap();
+
+        assert_eq!(
+            field("env").parse_input("2").unwrap(),
+            Some(json!("production"))
+        );
+        assert!(field("env").parse_input("dev").is_err());
+        assert!(field("env").parse_input("").is_err());
+        assert_eq!(field("replicas").parse_input("").unwrap(), Some(json!(2)));
+        assert!(field("replicas").parse_input("two").is_err());
+        assert_eq!(
+            field("confirm").parse_input("y").unwrap(),
+            Some(json!(true))
+        );
+        assert_eq!(field("confirm").prompt(), "Confirm [y/n] *");
+    }
+
+    #[test]
+    fn serializes_elicitation_responses() {
+        let mut content = Map::new();
+        content.insert("env".to_string(), json!("staging"));
+        assert_eq!(
+            ElicitationResponse::Accept(content).to_value(),
+            json!({ "action": "accept", "content": { "env": "staging" } })
+        );
+        assert_eq!(
+            ElicitationResponse::Decline.to_value(),
+            json!({ "action": "decline" })
+        );
+    }
+}
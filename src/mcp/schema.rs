@@ -0,0 +1,157 @@
+use serde_json::Value;
+
+/// Validates `value` against the subset of JSON Schema used by MCP tool schemas:
+/// `type`, `enum`, `const`, `properties`, `required`, `additionalProperties`,
+/// `items` and `anyOf`/`oneOf`. Returns the first mismatch as `path: reason`.
+pub fn validate_json_schema(schema: &Value, value: &Value) -> Result<(), String> {
+    validate_at(schema, value, "$")
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let Some(schema) = schema.as_object() else {
+        // `true`/missing schemas accept anything; `false` rejects everything.
+        return match schema {
+            Value::Bool(false) => Err(format!("{}: not allowed", path)),
+            _ => Ok(()),
+        };
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let allowed: Vec<&str> = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|name| type_matches(name, value)) {
+            return Err(format!(
+                "{}: expected {}, got {}",
+                path,
+                allowed.join(" or "),
+                type_name(value)
+            ));
+        }
+    }
+
+    if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+        if !options.contains(value) {
+            return Err(format!("{}: value is not one of the allowed values", path));
+        }
+    }
+    if let Some(constant) = schema.get("const") {
+        if constant != value {
+            return Err(format!("{}: expected {}", path, constant));
+        }
+    }
+
+    for key in ["anyOf", "oneOf"] {
+        if let Some(variants) = schema.get(key).and_then(Value::as_array) {
+            if !variants
+                .iter()
+                .any(|variant| validate_at(variant, value, path).is_ok())
+            {
+                return Err(format!("{}: does not match any allowed schema", path));
+            }
+        }
+    }
+
+    if let Value::Object(map) = value {
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !map.contains_key(name) {
+                    return Err(format!("{}: missing required property {}", path, name));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (name, item) in map {
+            let child = format!("{}.{}", path, name);
+            match properties.and_then(|p| p.get(name)) {
+                Some(property) => validate_at(property, item, &child)?,
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        return Err(format!("{}: unexpected property", child));
+                    }
+                    Some(extra @ Value::Object(_)) => validate_at(extra, item, &child)?,
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (idx, item) in items.iter().enumerate() {
+            validate_at(item_schema, item, &format!("{}[{}]", path, idx))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn type_matches(name: &str, value: &Value) -> bool {
+    match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn accepts_matching_structured_content() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "temperature": { "type": "number" },
+                "conditions": { "type": "string", "enum": ["sunny", "rainy"] },
+                "tags": { "type": "array", "items": { "type": "string" } }
+            },
+            "required": ["temperature"]
+        });
+        let value = json!({ "temperature": 21.5, "conditions": "sunny", "tags": ["a"] });
+        assert!(validate_json_schema(&schema, &value).is_ok());
+    }
+
+    #[test]
+    fn reports_path_of_first_mismatch() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "rows": { "type": "array", "items": { "type": "integer" } }
+            },
+            "required": ["rows"],
+            "additionalProperties": false
+        });
+        assert_eq!(
+            validate_json_schema(&schema, &json!({ "rows": [1, "2"] })).unwrap_err(),
+            "$.rows[1]: expected integer, got string"
+        );
+        assert_eq!(
+            validate_json_schema(&schema, &json!({})).unwrap_err(),
+            "$: missing required property rows"
+        );
+        assert_eq!(
+            validate_json_schema(&schema, &json!({ "rows": [], "extra": 1 })).unwrap_err(),
+            "$.extra: unexpected property"
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use reqwest::header::{HeaderMap, ACCEPT};
+use reqwest::{Client, Response, Url};
+use serde_json::Value;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use super::http::{build_headers, is_event_stream, take_sse_event};
+use crate::mcp::{McpHttpError, McpInbound, McpServerConfig, McpTransport};
+
+/// The 2024-11-05 HTTP+SSE transport: a long-lived GET stream whose first `endpoint`
+/// event names the URL that client messages are POSTed to. Responses arrive on the stream.
+pub struct SseTransport {
+    client: Client,
+    endpoint: Url,
+    headers: HeaderMap,
+    reader: JoinHandle<()>,
+}
+
+impl SseTransport {
+    pub async fn connect(server: &McpServerConfig, inbound: McpInbound) -> Result<Self> {
+        let url = server
+            .url
+            .as_deref()
+            .ok_or_else(|| anyhow!("mcp server url is required for sse"))?;
+        let base = Url::parse(url)?;
+        let client = Client::new();
+        let headers = build_headers(server)?;
+        let resp = client
+            .get(base.clone())
+            .headers(headers.clone())
+            .header(ACCEPT, "text/event-stream")
+            .send()
+            .await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(McpHttpError { status, body }.into());
+        }
+        if !is_event_stream(&resp) {
+            return Err(anyhow!("mcp sse endpoint did not return an event stream"));
+        }
+        let (endpoint_tx, endpoint_rx) = oneshot::channel();
+        let reader = tokio::spawn(read_events(resp, inbound, endpoint_tx));
+        let endpoint = endpoint_rx
+            .await
+            .map_err(|_| anyhow!("mcp sse stream closed before sending an endpoint"))?;
+        Ok(Self {
+            client,
+            endpoint: base.join(&endpoint)?,
+            headers,
+            reader,
+        })
+    }
+}
+
+#[async_trait]
+impl McpTransport for SseTransport {
+    async fn send(&self, message: Value) -> Result<()> {
+        let resp = self
+            .client
+            .post(self.endpoint.clone())
+            .headers(self.headers.clone())
+            .json(&message)
+            .send()
+            .await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(McpHttpError { status, body }.into());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SseTransport {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn read_events(resp: Response, inbound: McpInbound, endpoint_tx: oneshot::Sender<String>) {
+    let mut endpoint_tx = Some(endpoint_tx);
+    let mut buffer = String::new();
+    let mut stream = resp.bytes_stream();
+    while let Some(Ok(chunk)) = stream.next().await {
+        buffer.push_str(&String::from_utf8_lossy(&chunk));
+        while let Some(event) = take_sse_event(&mut buffer) {
+            if event.event.as_deref() == Some("endpoint") {
+                if let Some(tx) = endpoint_tx.take() {
+                    let _ = tx.send(event.data.trim().to_string());
+                }
+                continue;
+            }
+            if let Ok(value) = serde_json::from_str::<Value>(&event.data) {
+                if inbound.send(value).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -1,4 +1,6 @@
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 
@@ -10,6 +12,8 @@ pub struct McpConfig {
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct McpServerConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transport: Option<String>,
     pub command: Option<String>,
     #[serde(default)]
     pub args: Option<Vec<String>>,
@@ -20,6 +24,106 @@ pub struct McpServerConfig {
     #[serde(default)]
     pub http_headers: Option<BTreeMap<String, String>>,
     pub timeout_sec: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum McpTransportKind {
+    Stdio,
+    Http,
+    Sse,
+}
+
+impl McpTransportKind {
+    pub fn from_str(input: &str) -> Result<Self> {
+        match input.trim().to_ascii_lowercase().as_str() {
+            "stdio" => Ok(Self::Stdio),
+            "http" | "streamable-http" | "streamable_http" => Ok(Self::Http),
+            "sse" => Ok(Self::Sse),
+            other => Err(anyhow!("unsupported mcp transport: {}", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Stdio => "stdio",
+            Self::Http => "http",
+            Self::Sse => "sse",
+        }
+    }
+}
+
+impl McpServerConfig {
+    pub fn transport_kind(&self) -> Result<McpTransportKind> {
+        if let Some(transport) = self.transport.as_deref() {
+            return McpTransportKind::from_str(transport);
+        }
+        if self.url.is_some() {
+            Ok(McpTransportKind::Http)
+        } else if self.command.is_some() {
+            Ok(McpTransportKind::Stdio)
+        } else {
+            Err(anyhow!("mcp server has neither command nor url"))
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    pub fn summary(&self) -> String {
+        let summary = match (self.transport_kind(), &self.url, &self.command) {
+            (Ok(kind @ (McpTransportKind::Http | McpTransportKind::Sse)), Some(url), _) => {
+                format!("{} {}", kind.as_str(), url)
+            }
+            (Ok(McpTransportKind::Stdio), _, Some(cmd)) => {
+                let args = self.args.as_ref().map(|a| a.join(" ")).unwrap_or_default();
+                if args.is_empty() {
+                    format!("stdio {}", cmd)
+                } else {
+                    format!("stdio {} {}", cmd, args)
+                }
+            }
+            _ => "unknown".to_string(),
+        };
+        if self.is_enabled() {
+            summary
+        } else {
+            format!("{} (disabled)", summary)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum McpScope {
+    User,
+    Project,
+    Local,
+}
+
+impl McpScope {
+    pub fn from_str(input: &str) -> Result<Self> {
+        match input.trim().to_ascii_lowercase().as_str() {
+            "user" | "global" => Ok(Self::User),
+            "project" => Ok(Self::Project),
+            "local" => Ok(Self::Local),
+            other => Err(anyhow!("unsupported mcp scope: {}", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Project => "project",
+            Self::Local => "local",
+        }
+    }
+
+    /// Lowest precedence first; later scopes override earlier ones by server name.
+    pub fn all() -> [McpScope; 3] {
+        [Self::User, Self::Project, Self::Local]
+    }
 }
 
 pub struct McpStore;
@@ -29,6 +133,17 @@ impl McpStore {
         PathBuf::from(".").join(".tengu").join("mcp.toml")
     }
 
+    pub fn path_for_scope(scope: McpScope) -> Result<PathBuf> {
+        match scope {
+            McpScope::User => {
+                let home = std::env::var_os("HOME").ok_or_else(|| anyhow!("HOME not set"))?;
+                Ok(PathBuf::from(home).join(".tengu").join("mcp.toml"))
+            }
+            McpScope::Project => Ok(Self::default_path()),
+            McpScope::Local => Ok(PathBuf::from(".").join(".tengu").join("mcp.local.toml")),
+        }
+    }
+
     pub fn load(path: &Path) -> anyhow::Result<McpConfig> {
         if !path.exists() {
             return Ok(McpConfig::default());
@@ -38,6 +153,40 @@ impl McpStore {
         Ok(config)
     }
 
+    pub fn load_scope(scope: McpScope) -> Result<McpConfig> {
+        Self::load(&Self::path_for_scope(scope)?)
+    }
+
+    /// Loads every scope and merges servers by name (local > project > user).
+    pub fn load_merged() -> Result<McpConfig> {
+        let mut merged = McpConfig::default();
+        for (name, (_scope, server)) in Self::load_with_scopes()? {
+            merged.mcp_servers.insert(name, server);
+        }
+        Ok(merged)
+    }
+
+    pub fn load_with_scopes() -> Result<BTreeMap<String, (McpScope, McpServerConfig)>> {
+        let mut merged = BTreeMap::new();
+        for scope in McpScope::all() {
+            let Ok(path) = Self::path_for_scope(scope) else {
+                continue;
+            };
+            let config = Self::load(&path)?;
+            for (name, server) in config.mcp_servers {
+                merged.insert(name, (scope, server));
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Returns the highest-precedence scope that defines `name`.
+    pub fn find_scope(name: &str) -> Result<Option<McpScope>> {
+        Ok(Self::load_with_scopes()?
+            .get(name)
+            .map(|(scope, _server)| *scope))
+    }
+
     pub fn save(path: &Path, config: &McpConfig) -> anyhow::Result<()> {
         if let Some(parent) = path.parent() {
             if !parent.exists() {
@@ -48,4 +197,151 @@ impl McpStore {
         std::fs::write(path, content)?;
         Ok(())
     }
+
+    pub fn save_scope(scope: McpScope, config: &McpConfig) -> Result<()> {
+        Self::save(&Self::path_for_scope(scope)?, config)
+    }
+
+    /// Parses the `.mcp.json` layout shared by other MCP clients:
+    /// `{"mcpServers": {"name": {"command": ..., "args": [...], "env": {...}}}}`.
+    pub fn import_json(content: &str) -> Result<BTreeMap<String, McpServerConfig>> {
+        let value: Value = serde_json::from_str(content)?;
+        let servers = value
+            .get("mcpServers")
+            .or_else(|| value.get("mcp_servers"))
+            .or_else(|| value.get("servers"))
+            .and_then(Value::as_object)
+            .ok_or_else(|| anyhow!("mcpServers object not found"))?;
+        let mut imported = BTreeMap::new();
+        for (name, entry) in servers {
+            let server: JsonServerEntry = serde_json::from_value(entry.clone())
+                .map_err(|err| anyhow!("invalid mcp server entry {}: {}", name, err))?;
+            imported.insert(name.clone(), server.into_config(name)?);
+        }
+        Ok(imported)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonServerEntry {
+    #[serde(rename = "type", alias = "transport", default)]
+    kind: Option<String>,
+    #[serde(default)]
+    command: Option<String>,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    timeout: Option<u64>,
+    #[serde(default)]
+    disabled: Option<bool>,
+}
+
+impl JsonServerEntry {
+    fn into_config(self, name: &str) -> Result<McpServerConfig> {
+        let transport = match self.kind.as_deref() {
+            Some(kind) => Some(McpTransportKind::from_str(kind)?),
+            None => None,
+        };
+        if self.command.is_none() && self.url.is_none() {
+            return Err(anyhow!("mcp server {} has neither command nor url", name));
+        }
+        Ok(McpServerConfig {
+            transport: transport.map(|kind| kind.as_str().to_string()),
+            command: self.command,
+            args: (!self.args.is_empty()).then_some(self.args),
+            env: (!self.env.is_empty()).then_some(self.env),
+            url: self.url,
+            bearer_token_env_var: None,
+            http_headers: (!self.headers.is_empty()).then_some(self.headers),
+            // .mcp.json timeouts are expressed in milliseconds.
+            timeout_sec: self.timeout.map(|ms| ms.div_ceil(1000)),
+            enabled: self.disabled.map(|disabled| !disabled),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn infers_transport_from_command_or_url() {
+        let stdio = McpServerConfig {
+            command: Some("npx".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(stdio.transport_kind().unwrap(), McpTransportKind::Stdio);
+
+        let http = McpServerConfig {
+            url: Some("https://example.com/mcp".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(http.transport_kind().unwrap(), McpTransportKind::Http);
+
+        let sse = McpServerConfig {
+            transport: Some("sse".to_string()),
+            url: Some("https://example.com/sse".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(sse.transport_kind().unwrap(), McpTransportKind::Sse);
+        assert_eq!(sse.summary(), "sse https://example.com/sse");
+    }
+
+    #[test]
+    fn marks_disabled_servers_in_summary() {
+        let server = McpServerConfig {
+            command: Some("uvx".to_string()),
+            args: Some(vec!["mcp-server-git".to_string()]),
+            enabled: Some(false),
+            ..Default::default()
+        };
+        assert!(!server.is_enabled());
+        assert_eq!(server.summary(), "stdio uvx mcp-server-git (disabled)");
+    }
+
+    #[test]
+    fn imports_servers_from_mcp_json() {
+        let content = r#"{
+            "mcpServers": {
+                "github": {
+                    "type": "http",
+                    "url": "https://api.githubcopilot.com/mcp/",
+                    "headers": {"Authorization": "Bearer token"}
+                },
+                "fs": {
+                    "command": "npx",
+                    "args": ["-y", "@modelcontextprotocol/server-filesystem", "."],
+                    "env": {"DEBUG": "1"},
+                    "timeout": 1500
+                }
+            }
+        }"#;
+        let servers = McpStore::import_json(content).unwrap();
+        assert_eq!(servers.len(), 2);
+
+        let github = &servers["github"];
+        assert_eq!(github.transport.as_deref(), Some("http"));
+        assert_eq!(
+            github.http_headers.as_ref().unwrap()["Authorization"],
+            "Bearer token"
+        );
+
+        let fs = &servers["fs"];
+        assert_eq!(fs.transport_kind().unwrap(), McpTransportKind::Stdio);
+        assert_eq!(fs.args.as_ref().unwrap().len(), 3);
+        assert_eq!(fs.env.as_ref().unwrap()["DEBUG"], "1");
+        assert_eq!(fs.timeout_sec, Some(2));
+    }
+
+    #[test]
+    fn rejects_import_entries_without_command_or_url() {
+        let content = r#"{"mcpServers": {"broken": {"args": ["x"]}}}"#;
+        assert!(McpStore::import_json(content).is_err());
+    }
 }
@@ -19,6 +19,16 @@ pub enum SessionConversationRole {
 pub struct SessionConversationTurn {
     pub role: SessionConversationRole,
     pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<SessionThinking>,
+}
+
+/// Reasoning kept with an assistant turn; `signature` lets Anthropic accept it on replay.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionThinking {
+    pub text: String,
+    #[serde(default)]
+    pub signature: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -63,6 +73,16 @@ pub struct SessionPendingApproval {
     pub message: Option<String>,
 }
 
+/// One `/compact`: the summary that replaced `turns`, kept so it can be undone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionCompaction {
+    pub summary: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focus: Option<String>,
+    pub turns: Vec<SessionConversationTurn>,
+    pub created_at: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionUsageRecord {
     pub provider: String,
@@ -82,6 +102,10 @@ pub struct SessionUsageRecord {
     pub requests: u64,
     #[serde(default)]
     pub last_raw: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_model: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -101,6 +125,9 @@ pub struct Session {
     pub usage_records: Vec<SessionUsageRecord>,
     #[serde(default)]
     pub pending_approval: Option<SessionPendingApproval>,
+    /// Oldest first; the last summary stands in for the turns before `conversation`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub compactions: Vec<SessionCompaction>,
 }
 
 impl Session {
@@ -120,6 +147,7 @@ impl Session {
             pending_images: Vec::new(),
             usage_records: Vec::new(),
             pending_approval: None,
+            compactions: Vec::new(),
         }
     }
 
@@ -131,6 +159,7 @@ impl Session {
         forked.pending_images = self.pending_images.clone();
         forked.usage_records = self.usage_records.clone();
         forked.pending_approval = self.pending_approval.clone();
+        forked.compactions = self.compactions.clone();
         forked
     }
 }
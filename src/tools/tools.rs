@@ -8,6 +8,7 @@ use std::process::Command;
 use std::sync::{Arc, Mutex};
 
 use crate::config::{Config, PermissionsConfig, SandboxConfig};
+use crate::llm::LlmImage;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -17,6 +18,7 @@ pub enum Tool {
     Shell,
     Grep,
     Glob,
+    Mcp,
 }
 
 #[derive(Debug, Clone)]
@@ -41,6 +43,11 @@ pub enum ToolInput {
         pattern: String,
         root: Option<PathBuf>,
     },
+    Mcp {
+        server: String,
+        tool: String,
+        arguments: serde_json::Value,
+    },
 }
 
 #[derive(Debug)]
@@ -54,6 +61,19 @@ pub enum ToolResult {
         diff: String,
         content: String,
     },
+    Content {
+        text: String,
+        images: Vec<LlmImage>,
+    },
+}
+
+impl ToolResult {
+    pub fn images(&self) -> &[LlmImage] {
+        match self {
+            ToolResult::Content { images, .. } => images,
+            _ => &[],
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -93,7 +113,16 @@ impl ToolPolicy {
         }
     }
 
-    fn check(&self, input: &ToolInput) -> Result<()> {
+    /// Drops a one-shot approval that was granted for a call that never ran.
+    pub fn clear_allow_once(&self) {
+        if let Ok(mut guard) = self.approval_override.lock() {
+            if matches!(*guard, ApprovalOverride::AllowOnce(_)) {
+                *guard = ApprovalOverride::None;
+            }
+        }
+    }
+
+    pub fn check(&self, input: &ToolInput) -> Result<()> {
         self.check_permissions(input)?;
         self.check_sandbox(input)?;
         Ok(())
@@ -114,7 +143,7 @@ impl ToolPolicy {
                             ApprovalOverride::DenyAll => {
                                 return Err(anyhow!(
                                     "permission denied by approval override for tool: {}",
-                                    tool_name(input)
+                                    tool_display_name(input)
                                 ));
                             }
                             ApprovalOverride::AllowOnce(tool) => {
@@ -133,10 +162,13 @@ impl ToolPolicy {
                     }
                 }
                 "read-only" => {
-                    if matches!(input, ToolInput::Write { .. } | ToolInput::Shell { .. }) {
+                    if matches!(
+                        input,
+                        ToolInput::Write { .. } | ToolInput::Shell { .. } | ToolInput::Mcp { .. }
+                    ) {
                         return Err(anyhow!(
                             "permission denied by approval_policy=read-only for tool: {}",
-                            tool_name(input)
+                            tool_display_name(input)
                         ));
                     }
                 }
@@ -157,7 +189,7 @@ impl ToolPolicy {
                 .iter()
                 .any(|rule| rule_matches_tool(rule, input, Some(&self.workspace_root)))
             {
-                return Err(anyhow!("tool not allowed: {}", tool_name(input)));
+                return Err(anyhow!("tool not allowed: {}", tool_display_name(input)));
             }
         }
 
@@ -177,11 +209,14 @@ impl ToolPolicy {
             .to_ascii_lowercase();
 
         if matches!(mode.as_str(), "read-only")
-            && matches!(input, ToolInput::Write { .. } | ToolInput::Shell { .. })
+            && matches!(
+                input,
+                ToolInput::Write { .. } | ToolInput::Shell { .. } | ToolInput::Mcp { .. }
+            )
         {
             return Err(anyhow!(
                 "sandbox denies write in read-only mode: {}",
-                tool_name(input)
+                tool_display_name(input)
             ));
         }
 
@@ -272,6 +307,22 @@ impl ToolExecutor {
         })
     }
 
+    /// Like [`ToolExecutor::execute`], but shell commands run as a child process that is
+    /// killed when the returned future is dropped (e.g. the turn is cancelled).
+    pub async fn execute_async(&self, input: ToolInput) -> Result<ToolResult> {
+        let ToolInput::Shell { command, args } = &input else {
+            return self.execute(input);
+        };
+        self.policy.check(&input)?;
+        let output = tokio::process::Command::new(command)
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+        shell_result(command, output)
+    }
+
     pub fn execute(&self, input: ToolInput) -> Result<ToolResult> {
         self.policy.check(&input)?;
         match input {
@@ -290,14 +341,7 @@ impl ToolExecutor {
             }
             ToolInput::Shell { command, args } => {
                 let output = Command::new(&command).args(args).output()?;
-                if output.status.success() {
-                    Ok(ToolResult::Text(
-                        String::from_utf8_lossy(&output.stdout).to_string(),
-                    ))
-                } else {
-                    let stderr = String::from_utf8_lossy(&output.stderr);
-                    Err(anyhow!("command failed: {} ({})", command, stderr.trim()))
-                }
+                shell_result(&command, output)
             }
             ToolInput::Grep { pattern, paths } => {
                 let mut matches = Vec::new();
@@ -312,6 +356,11 @@ impl ToolExecutor {
                 collect_glob_matches(&root, &pattern, &mut matches)?;
                 Ok(ToolResult::Paths(matches))
             }
+            ToolInput::Mcp { server, tool, .. } => Err(anyhow!(
+                "mcp tool must be called through an mcp client: {}/{}",
+                server,
+                tool
+            )),
         }
     }
 }
@@ -329,9 +378,22 @@ fn tool_name(input: &ToolInput) -> &'static str {
         ToolInput::Shell { .. } => "Shell",
         ToolInput::Grep { .. } => "Grep",
         ToolInput::Glob { .. } => "Glob",
+        ToolInput::Mcp { .. } => "Mcp",
+    }
+}
+
+/// Name used in rules and messages; MCP tools are `mcp__<server>__<tool>`.
+pub fn tool_display_name(input: &ToolInput) -> String {
+    match input {
+        ToolInput::Mcp { server, tool, .. } => mcp_tool_rule_name(server, tool),
+        _ => tool_name(input).to_string(),
     }
 }
 
+pub fn mcp_tool_rule_name(server: &str, tool: &str) -> String {
+    format!("mcp__{}__{}", server, tool)
+}
+
 fn tool_kind(input: &ToolInput) -> Tool {
     match input {
         ToolInput::Read { .. } => Tool::Read,
@@ -339,6 +401,7 @@ fn tool_kind(input: &ToolInput) -> Tool {
         ToolInput::Shell { .. } => Tool::Shell,
         ToolInput::Grep { .. } => Tool::Grep,
         ToolInput::Glob { .. } => Tool::Glob,
+        ToolInput::Mcp { .. } => Tool::Mcp,
     }
 }
 
@@ -348,7 +411,7 @@ fn tool_paths(input: &ToolInput) -> Vec<PathBuf> {
         ToolInput::Write { path, .. } => vec![path.clone()],
         ToolInput::Grep { paths, .. } => paths.clone(),
         ToolInput::Glob { root, .. } => root.clone().map(|p| vec![p]).unwrap_or_default(),
-        ToolInput::Shell { .. } => Vec::new(),
+        ToolInput::Shell { .. } | ToolInput::Mcp { .. } => Vec::new(),
     }
 }
 
@@ -360,30 +423,67 @@ pub enum ToolApprovalDecision {
     DenyAll,
 }
 
+#[derive(Debug, Clone)]
+pub struct McpToolTarget {
+    pub server: String,
+    pub tool: String,
+    pub arguments: serde_json::Value,
+}
+
 #[derive(Debug, Clone)]
 pub struct ToolApprovalRequest {
     pub tool: Tool,
     pub paths: Vec<PathBuf>,
+    pub mcp: Option<McpToolTarget>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ToolApprovalRequired {
     pub tool: Tool,
     pub paths: Vec<PathBuf>,
+    pub mcp: Option<McpToolTarget>,
 }
 
 impl ToolApprovalRequired {
     fn new(input: &ToolInput) -> Self {
+        let mcp = match input {
+            ToolInput::Mcp {
+                server,
+                tool,
+                arguments,
+            } => Some(McpToolTarget {
+                server: server.clone(),
+                tool: tool.clone(),
+                arguments: arguments.clone(),
+            }),
+            _ => None,
+        };
         Self {
             tool: tool_kind(input),
             paths: tool_paths(input),
+            mcp,
+        }
+    }
+
+    pub fn to_request(&self) -> ToolApprovalRequest {
+        ToolApprovalRequest {
+            tool: self.tool,
+            paths: self.paths.clone(),
+            mcp: self.mcp.clone(),
         }
     }
 }
 
 impl std::fmt::Display for ToolApprovalRequired {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "permission approval required for tool: {:?}", self.tool)
+        match &self.mcp {
+            Some(target) => write!(
+                f,
+                "permission approval required for tool: {}",
+                mcp_tool_rule_name(&target.server, &target.tool)
+            ),
+            None => write!(f, "permission approval required for tool: {:?}", self.tool),
+        }
     }
 }
 
@@ -397,6 +497,17 @@ pub enum ApprovalOverride {
     DenyAll,
 }
 
+fn shell_result(command: &str, output: std::process::Output) -> Result<ToolResult> {
+    if output.status.success() {
+        Ok(ToolResult::Text(
+            String::from_utf8_lossy(&output.stdout).to_string(),
+        ))
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(anyhow!("command failed: {} ({})", command, stderr.trim()))
+    }
+}
+
 fn resolve_path(root: &Path, path: &Path) -> PathBuf {
     if path.is_absolute() {
         path.to_path_buf()
@@ -437,6 +548,10 @@ fn rule_matches_tool(rule: &str, input: &ToolInput, root: Option<&Path>) -> bool
         (rule, None)
     };
 
+    if let ToolInput::Mcp { server, tool, .. } = input {
+        return mcp_rule_matches(name, server, tool);
+    }
+
     let tool = tool_name(input);
     let name_lower = name.to_ascii_lowercase();
     let tool_lower = tool.to_ascii_lowercase();
@@ -453,6 +568,21 @@ fn rule_matches_tool(rule: &str, input: &ToolInput, root: Option<&Path>) -> bool
     targets.iter().any(|target| wildcard_match(pattern, target))
 }
 
+/// `mcp` matches every MCP tool, `mcp__server` every tool of one server, and
+/// `mcp__server__tool` (wildcards allowed) a specific tool.
+fn mcp_rule_matches(rule: &str, server: &str, tool: &str) -> bool {
+    if rule.eq_ignore_ascii_case("mcp") {
+        return true;
+    }
+    let Some(rest) = rule.strip_prefix("mcp__") else {
+        return false;
+    };
+    if !rest.contains("__") {
+        return wildcard_match(rest, server);
+    }
+    wildcard_match(rule, &mcp_tool_rule_name(server, tool))
+}
+
 fn tool_match_targets(input: &ToolInput, root: Option<&Path>) -> Vec<String> {
     match input {
         ToolInput::Read { path } | ToolInput::Write { path, .. } => {
@@ -490,6 +620,7 @@ fn tool_match_targets(input: &ToolInput, root: Option<&Path>) -> Vec<String> {
             }
             out
         }
+        ToolInput::Mcp { server, tool, .. } => vec![mcp_tool_rule_name(server, tool)],
     }
 }
 
@@ -609,3 +740,55 @@ fn wildcard_match(pattern: &str, text: &str) -> bool {
 
     p_idx == p.len()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mcp_input(server: &str, tool: &str) -> ToolInput {
+        ToolInput::Mcp {
+            server: server.to_string(),
+            tool: tool.to_string(),
+            arguments: serde_json::json!({}),
+        }
+    }
+
+    #[tokio::test]
+    async fn kills_shell_child_when_call_is_dropped() {
+        let marker = std::env::temp_dir().join(format!("tengu-cancel-{}", uuid::Uuid::new_v4()));
+        let executor = ToolExecutor::new();
+        let call = executor.execute_async(ToolInput::Shell {
+            command: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                format!("sleep 0.3; touch {}", marker.display()),
+            ],
+        });
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(50), call)
+                .await
+                .is_err()
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn matches_mcp_rules_by_server_and_tool() {
+        let input = mcp_input("github", "create_issue");
+        assert!(rule_matches_tool("mcp", &input, None));
+        assert!(rule_matches_tool("mcp__github", &input, None));
+        assert!(rule_matches_tool("mcp__github__*", &input, None));
+        assert!(rule_matches_tool("mcp__github__create_issue", &input, None));
+        assert!(!rule_matches_tool("mcp__github__delete_*", &input, None));
+        assert!(!rule_matches_tool("mcp__gitlab", &input, None));
+        assert!(!rule_matches_tool("bash", &input, None));
+        assert!(!rule_matches_tool(
+            "mcp__github",
+            &ToolInput::Read {
+                path: PathBuf::from("a.txt")
+            },
+            None
+        ));
+    }
+}
@@ -1,8 +1,9 @@
+use std::collections::HashMap;
 use std::fs;
 use std::io::{self, Stdout, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::{mpsc, Arc};
+use std::sync::{mpsc, Arc, Mutex, Weak};
 use std::time::Duration;
 
 use anyhow::{anyhow, Result};
@@ -17,13 +18,19 @@ use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
 
 use crate::agent::{AgentRunner, AgentStore};
-use crate::config::Config;
-use crate::llm::{LlmImage, LlmRequest, LlmStreamEvent};
-use crate::mcp::McpStore;
+use crate::config::{CompactConfig, Config};
+use crate::llm::{
+    CredentialStore, LlmError, LlmImage, LlmRequest, LlmRetryNotice, LlmStreamEvent,
+    DEFAULT_PROFILE,
+};
+use crate::mcp::{
+    ElicitationRequest, ElicitationResponse, McpNotification, McpRequestHandler, McpServerRequest,
+    McpStore,
+};
 use crate::review::{build_review_prompt, parse_review_args};
 use crate::session::SessionPendingApproval;
 use crate::session::{Session, SessionStore};
-use crate::tools::{Tool, ToolApprovalDecision, ToolApprovalRequest};
+use crate::tools::{mcp_tool_rule_name, Tool, ToolApprovalDecision, ToolApprovalRequest};
 use crate::tui::render;
 use crate::tui::state::{AppState, ApprovalPending, PendingMode, TuiEvent};
 
@@ -32,11 +39,29 @@ pub struct App {
     runner: Arc<AgentRunner>,
     handle: Handle,
     current_task: Option<JoinHandle<()>>,
+    /// Bumped whenever a task starts or is aborted; events of older tasks are dropped.
+    task_generation: u64,
     session_store: Option<SessionStore>,
     current_session: Option<Session>,
     pending_local_action: Option<PendingLocalAction>,
     pending_tool_approval: Option<ToolApprovalRequest>,
     restored_tool_approval: Option<RestoredToolApproval>,
+    pending_elicitation: Option<PendingElicitation>,
+    pending_model_pick: Option<Vec<String>>,
+    compact: CompactConfig,
+    compacting: bool,
+    /// Set when an automatic compaction failed, so the queued prompt is sent anyway.
+    skip_auto_compact: bool,
+}
+
+const DEFAULT_COMPACT_THRESHOLD: f64 = 0.8;
+const DEFAULT_COMPACT_KEEP_TURNS: usize = 4;
+
+struct PendingElicitation {
+    request: ElicitationRequest,
+    index: usize,
+    values: serde_json::Map<String, serde_json::Value>,
+    respond_to: oneshot::Sender<ElicitationResponse>,
 }
 
 impl App {
@@ -61,16 +86,45 @@ impl App {
             }));
             Box::pin(async move { rx.await.unwrap_or(ToolApprovalDecision::DenyOnce) })
         }));
+        let retry_sender = state.result_tx.clone();
+        runner.set_retry_handler(Arc::new(move |notice: &LlmRetryNotice| {
+            let _ = retry_sender.send(Ok(TuiEvent::LlmRetry(notice.clone())));
+        }));
+        let warning_sender = state.result_tx.clone();
+        runner.set_warning_handler(Arc::new(move |message: &str| {
+            let _ = warning_sender.send(Ok(TuiEvent::LlmWarning(message.to_string())));
+        }));
+        if let Some(manager) = runner.mcp_manager() {
+            let mcp_sender = state.result_tx.clone();
+            manager.set_notification_handler(Arc::new(
+                move |server: &str, notification: &McpNotification| {
+                    let _ = mcp_sender.send(Ok(TuiEvent::Mcp {
+                        server: server.to_string(),
+                        notification: notification.clone(),
+                    }));
+                },
+            ));
+            manager.set_request_handler(build_mcp_request_handler(
+                Arc::downgrade(&runner),
+                state.result_tx.clone(),
+            ));
+        }
         Self {
             state,
             runner,
             handle,
             current_task: None,
+            task_generation: 0,
             session_store,
             current_session,
             pending_local_action: None,
             pending_tool_approval: None,
             restored_tool_approval: None,
+            pending_elicitation: None,
+            pending_model_pick: None,
+            compact: load_config().unwrap_or_default().compact,
+            compacting: false,
+            skip_auto_compact: false,
         }
     }
 
@@ -102,12 +156,7 @@ impl App {
                         && key.modifiers.contains(KeyModifiers::CONTROL)
                     {
                         if self.state.status_state == "running" {
-                            if let Some(handle) = self.current_task.take() {
-                                handle.abort();
-                            }
-                            self.cancel_pending_approval();
-                            self.state.set_idle();
-                            self.state.append_message("interrupted");
+                            self.cancel_current_task();
                         } else {
                             self.state.should_quit = true;
                         }
@@ -137,7 +186,17 @@ impl App {
                             self.handle_input();
                         }
                         KeyCode::Esc => {
-                            self.state.input.clear();
+                            if self.pending_elicitation.is_some() {
+                                self.state.input.clear();
+                                self.finish_elicitation(ElicitationResponse::Cancel);
+                            } else if self.pending_model_pick.is_some() {
+                                self.state.input.clear();
+                                self.handle_model_pick("");
+                            } else if self.state.status_state == "running" {
+                                self.cancel_current_task();
+                            } else {
+                                self.state.input.clear();
+                            }
                             self.refresh_suggestions();
                         }
                         _ => {}
@@ -203,6 +262,14 @@ impl App {
         let input = self.state.input.trim().to_string();
         self.state.input.clear();
         self.refresh_suggestions();
+        if self.pending_elicitation.is_some() {
+            self.handle_elicitation_input(&input);
+            return;
+        }
+        if self.pending_model_pick.is_some() {
+            self.handle_model_pick(&input);
+            return;
+        }
         if input.is_empty() {
             return;
         }
@@ -247,8 +314,14 @@ impl App {
                     self.state.append_blank_line();
                     return;
                 }
-                SlashCommandOutcome::Compact(note) => {
-                    let response = self.compact_session(note.as_deref());
+                SlashCommandOutcome::Compact(focus) => {
+                    let response = self.start_compaction(focus);
+                    self.state.append_message(&response);
+                    self.state.append_blank_line();
+                    return;
+                }
+                SlashCommandOutcome::UndoCompact => {
+                    let response = self.undo_compaction();
                     self.state.append_message(&response);
                     self.state.append_blank_line();
                     return;
@@ -349,6 +422,10 @@ impl App {
                     self.state.append_blank_line();
                     return;
                 }
+                SlashCommandOutcome::McpStatus => {
+                    self.show_mcp_status();
+                    return;
+                }
                 SlashCommandOutcome::OpenEditor(path) => {
                     let response = self.open_editor(path);
                     self.state.append_message(&response);
@@ -359,9 +436,7 @@ impl App {
                     response,
                     new_session,
                 } => {
-                    if let Some(handle) = self.current_task.take() {
-                        handle.abort();
-                    }
+                    self.abort_current_task();
                     self.cancel_pending_approval();
                     self.state.reset_session_view();
                     if new_session {
@@ -524,6 +599,16 @@ impl App {
         if self.state.status_state == "running" {
             return;
         }
+        if self.state.queue.is_empty() {
+            return;
+        }
+        if self.needs_auto_compaction() {
+            let response = self.start_compaction(None);
+            self.state
+                .append_message(&format!("auto-compact: {}", response));
+            return;
+        }
+        self.skip_auto_compact = false;
         let Some(pending) = self.state.queue.pop_front() else {
             return;
         };
@@ -548,15 +633,20 @@ impl App {
             self.state.start_assistant_response();
         }
         let runner = Arc::clone(&self.runner);
+        let info = self.runner.model_info();
+        let budget = info.as_ref().and_then(|info| info.context_budget());
+        self.state.context_window = info.and_then(|info| info.context_window);
+        let context = self.state.build_context(10, budget);
         let request = LlmRequest {
             prompt: pending.text.clone(),
             images: pending.images.clone(),
+            history: self.state.build_history(10, budget),
+            ..Default::default()
         };
-        let context = self.state.build_context(10);
         let pending_text = pending.text.clone();
         let pending_mode = pending.mode;
         self.state.push_user_conversation(&pending_text);
-        let result_tx = self.state.result_tx.clone();
+        let result_tx = self.task_sender();
         let handle = self.handle.spawn(async move {
             if pending_mode == PendingMode::Plan {
                 match runner
@@ -587,6 +677,12 @@ impl App {
                                     return;
                                 }
                             }
+                            Ok(LlmStreamEvent::Thinking { text, signature }) => {
+                                let event = TuiEvent::Thinking { text, signature };
+                                if result_tx.send(Ok(event)).is_err() {
+                                    return;
+                                }
+                            }
                             Ok(LlmStreamEvent::Usage(usage)) => {
                                 if result_tx.send(Ok(TuiEvent::Usage(usage))).is_err() {
                                     return;
@@ -600,6 +696,11 @@ impl App {
                     }
                     let _ = result_tx.send(Ok(TuiEvent::Done));
                 }
+                // Backend errors were already retried by the client; a blocking call would fail
+                // the same way.
+                Err(err) if err.downcast_ref::<LlmError>().is_some() => {
+                    let _ = result_tx.send(Err(err));
+                }
                 Err(_) => match runner.handle_request_with_context(request, &context).await {
                     Ok(output) => {
                         if let Some(usage) = output.response.usage {
@@ -619,6 +720,9 @@ impl App {
 
     fn drain_results(&mut self) {
         while let Ok(result) = self.state.result_rx.try_recv() {
+            let Some(result) = current_task_event(result, self.task_generation) else {
+                continue;
+            };
             match result {
                 Ok(event) => match event {
                     TuiEvent::Chunk(text) => {
@@ -626,6 +730,11 @@ impl App {
                         self.state.append_stream_chunk(&text);
                         self.state.append_assistant_chunk(&text);
                     }
+                    TuiEvent::Thinking { text, signature } => {
+                        self.state.status_detail = "thinking".to_string();
+                        self.state
+                            .append_thinking_chunk(&text, signature.as_deref());
+                    }
                     TuiEvent::Usage(usage) => {
                         self.state.record_provider_usage(&usage);
                     }
@@ -638,6 +747,24 @@ impl App {
                         self.state.set_idle();
                         self.current_task = None;
                     }
+                    TuiEvent::Compacted {
+                        summary,
+                        focus,
+                        replaced,
+                    } => {
+                        self.compacting = false;
+                        self.state
+                            .apply_compaction(summary.clone(), focus, replaced);
+                        self.state.append_message(&format!(
+                            "conversation compacted: {} turns summarized (/compact undo restores them)",
+                            replaced
+                        ));
+                        self.state.append_message(&summary);
+                        self.state.append_blank_line();
+                        self.touch_current_session();
+                        self.state.set_idle();
+                        self.current_task = None;
+                    }
                     TuiEvent::Done => {
                         self.state.finalize_assistant_response();
                         self.touch_current_session();
@@ -657,7 +784,73 @@ impl App {
                         self.restored_tool_approval = None;
                         self.state.approval_pending = Some(ApprovalPending { respond_to });
                     }
+                    TuiEvent::Mcp {
+                        server,
+                        notification,
+                    } => self.handle_mcp_notification(&server, notification),
+                    TuiEvent::LlmRetry(notice) => {
+                        if self.state.status_state == "running" {
+                            self.state.status_detail = format_llm_retry(&notice);
+                        }
+                    }
+                    TuiEvent::LlmWarning(message) => {
+                        self.state.append_message(&format!("warning: {}", message));
+                    }
+                    TuiEvent::McpStatus(report) => {
+                        self.state.append_message(&report);
+                        self.state.append_blank_line();
+                    }
+                    TuiEvent::ModelList { models, error } => self.show_model_list(models, error),
+                    TuiEvent::ModelSwitched {
+                        provider,
+                        model,
+                        save,
+                        error,
+                    } => {
+                        let response = match error {
+                            Some(err) => format!("model update failed: {}", err),
+                            None => self.finish_model_switch(&provider, &model, save),
+                        };
+                        self.state.append_message(&response);
+                        self.state.append_blank_line();
+                    }
+                    TuiEvent::McpSampling {
+                        server,
+                        preview,
+                        respond_to,
+                    } => {
+                        if self.state.approval_pending.is_some() {
+                            let _ = respond_to.send(ToolApprovalDecision::DenyOnce);
+                            self.state.append_message(&format!(
+                                "mcp {} sampling request declined (busy): another approval is pending",
+                                server
+                            ));
+                            continue;
+                        }
+                        self.state.record_approval_request();
+                        self.state.append_message(&format!(
+                            "mcp {} requests an LLM completion: {}\n[y] Yes  [n] No  [a] Always allow  [d] Don't ask again",
+                            server, preview
+                        ));
+                        self.state.status_state = "running".to_string();
+                        self.state.status_detail = "approval required".to_string();
+                        self.state.approval_pending = Some(ApprovalPending { respond_to });
+                    }
+                    TuiEvent::McpElicitation {
+                        server,
+                        request,
+                        respond_to,
+                    } => self.start_elicitation(&server, request, respond_to),
+                    TuiEvent::Task { .. } => {}
                 },
+                Err(err) if self.compacting => {
+                    self.compacting = false;
+                    self.skip_auto_compact = true;
+                    self.state
+                        .append_message(&format!("compaction failed: {}", err));
+                    self.state.set_idle();
+                    self.current_task = None;
+                }
                 Err(err) => {
                     self.state.append_message(&format!("error: {}", err));
                     self.state.set_idle();
@@ -667,6 +860,118 @@ impl App {
         }
     }
 
+    fn handle_mcp_notification(&mut self, server: &str, notification: McpNotification) {
+        match notification {
+            McpNotification::ToolsListChanged => {
+                self.state
+                    .append_message(&format!("mcp {}: tools updated", server));
+            }
+            McpNotification::Progress {
+                progress,
+                total,
+                message,
+                ..
+            } => {
+                if self.state.status_state == "running" {
+                    self.state.status_detail =
+                        format_mcp_progress(server, progress, total, message.as_deref());
+                }
+            }
+            McpNotification::Message { level, data, .. } => {
+                // Everything is already written to the server log file; only surface problems.
+                if matches!(
+                    level.as_str(),
+                    "warning" | "error" | "critical" | "alert" | "emergency"
+                ) {
+                    let text = data
+                        .as_str()
+                        .map(|s| s.to_string())
+                        .unwrap_or_else(|| data.to_string());
+                    self.state
+                        .append_message(&format!("mcp {} [{}]: {}", server, level, text));
+                }
+            }
+            McpNotification::Other { .. } => {}
+        }
+    }
+
+    fn start_elicitation(
+        &mut self,
+        server: &str,
+        request: ElicitationRequest,
+        respond_to: oneshot::Sender<ElicitationResponse>,
+    ) {
+        // Only one form at a time; a second request is cancelled rather than queued.
+        if self.pending_elicitation.is_some() {
+            let _ = respond_to.send(ElicitationResponse::Cancel);
+            return;
+        }
+        self.state
+            .append_message(&format!("mcp {} asks: {}", server, request.message));
+        self.state
+            .append_message("enter each value and press Enter (Esc cancels, /decline declines)");
+        if let Some(field) = request.fields.first() {
+            self.state.append_message(&format!("  {}", field.prompt()));
+        } else {
+            self.state.append_message("  press Enter to accept");
+        }
+        self.state.status_state = "running".to_string();
+        self.state.status_detail = "input requested".to_string();
+        self.pending_elicitation = Some(PendingElicitation {
+            request,
+            index: 0,
+            values: serde_json::Map::new(),
+            respond_to,
+        });
+    }
+
+    fn handle_elicitation_input(&mut self, input: &str) {
+        if input == "/decline" {
+            self.finish_elicitation(ElicitationResponse::Decline);
+            return;
+        }
+        let Some(pending) = self.pending_elicitation.as_mut() else {
+            return;
+        };
+        if let Some(field) = pending.request.fields.get(pending.index) {
+            match field.parse_input(input) {
+                Ok(value) => {
+                    if let Some(value) = value {
+                        pending.values.insert(field.name.clone(), value);
+                    }
+                    pending.index += 1;
+                }
+                Err(err) => {
+                    let prompt = field.prompt();
+                    self.state.append_message(&format!("  {}", err));
+                    self.state.append_message(&format!("  {}", prompt));
+                    return;
+                }
+            }
+        }
+        if let Some(next) = pending.request.fields.get(pending.index) {
+            let prompt = next.prompt();
+            self.state.append_message(&format!("  {}", prompt));
+            return;
+        }
+        let values = std::mem::take(&mut pending.values);
+        self.finish_elicitation(ElicitationResponse::Accept(values));
+    }
+
+    fn finish_elicitation(&mut self, response: ElicitationResponse) {
+        let Some(pending) = self.pending_elicitation.take() else {
+            return;
+        };
+        let label = match &response {
+            ElicitationResponse::Accept(_) => "submitted",
+            ElicitationResponse::Decline => "declined",
+            ElicitationResponse::Cancel => "cancelled",
+        };
+        let _ = pending.respond_to.send(response);
+        self.state.append_message(&format!("mcp input {}", label));
+        self.state.status_detail = "waiting LLM".to_string();
+    }
+
     fn handle_approval_key(&mut self, key: &KeyCode) -> bool {
         let decision = match key {
             KeyCode::Char('y') => Some(ToolApprovalDecision::AllowOnce),
@@ -720,10 +1025,43 @@ impl App {
         true
     }
 
+    /// Aborting the task drops the LLM stream (closing the HTTP request), kills a running
+    /// shell child and lets in-flight MCP calls send `notifications/cancelled`.
+    fn cancel_current_task(&mut self) {
+        self.abort_current_task();
+        self.compacting = false;
+        self.cancel_pending_approval();
+        self.runner.reset_interrupted_turn();
+        self.state.interrupt_assistant_response();
+        self.state.set_idle();
+        self.state.append_message("interrupted");
+        self.state.append_blank_line();
+        self.touch_current_session();
+    }
+
+    fn abort_current_task(&mut self) {
+        if let Some(handle) = self.current_task.take() {
+            handle.abort();
+        }
+        self.task_generation += 1;
+    }
+
+    /// A sender for a new task; anything still queued from earlier tasks is dropped.
+    fn task_sender(&mut self) -> TaskSender {
+        self.task_generation += 1;
+        TaskSender {
+            generation: self.task_generation,
+            tx: self.state.result_tx.clone(),
+        }
+    }
+
     fn cancel_pending_approval(&mut self) {
         if let Some(pending) = self.state.approval_pending.take() {
             let _ = pending.respond_to.send(ToolApprovalDecision::DenyOnce);
         }
+        if let Some(pending) = self.pending_elicitation.take() {
+            let _ = pending.respond_to.send(ElicitationResponse::Cancel);
+        }
         self.pending_local_action = None;
         self.pending_tool_approval = None;
         self.restored_tool_approval = None;
@@ -741,6 +1079,7 @@ impl App {
         session.pending_images = self.state.export_pending_images();
         session.usage_records = self.state.export_usage_records();
         session.pending_approval = pending_approval;
+        session.compactions = self.state.export_compactions();
         session.updated_at = chrono::Utc::now().to_rfc3339();
         if let Some(store) = &self.session_store {
             let _ = store.save(session);
@@ -822,6 +1161,7 @@ impl App {
                         &session.queue,
                         &session.pending_images,
                         &session.usage_records,
+                        &session.compactions,
                     );
                     self.restore_pending_approval(session.pending_approval.clone());
                     format!("resumed session: {}", session.id)
@@ -838,6 +1178,7 @@ impl App {
                         &session.queue,
                         &session.pending_images,
                         &session.usage_records,
+                        &session.compactions,
                     );
                     self.restore_pending_approval(session.pending_approval.clone());
                     format!("resumed session: {}", session.id)
@@ -857,6 +1198,7 @@ impl App {
                     &session.queue,
                     &session.pending_images,
                     &session.usage_records,
+                    &session.compactions,
                 );
                 self.restore_pending_approval(session.pending_approval.clone());
                 format!("loaded session: {}", path.display())
@@ -951,45 +1293,73 @@ impl App {
         "queued last planned request for execution".to_string()
     }
 
-    fn compact_session(&mut self, note: Option<&str>) -> String {
-        let summary = self
-            .state
-            .conversation
-            .iter()
-            .rev()
-            .take(6)
-            .collect::<Vec<_>>()
-            .into_iter()
-            .rev()
-            .map(|turn| {
-                let role = match turn.role {
-                    crate::tui::state::ConversationRole::User => "user",
-                    crate::tui::state::ConversationRole::Assistant => "assistant",
-                };
-                format!("{role}: {}", turn.content)
-            })
-            .collect::<Vec<_>>()
-            .join("\n");
-
-        self.state.reset_session_view();
-        let compacted = if let Some(note) = note.filter(|value| !value.trim().is_empty()) {
-            format!("conversation compacted ({})", note.trim())
-        } else {
-            "conversation compacted".to_string()
+    /// Asks the `compact` route to summarize all but the newest turns; the result arrives as
+    /// `TuiEvent::Compacted`.
+    fn start_compaction(&mut self, focus: Option<String>) -> String {
+        if self.state.status_state == "running" {
+            return "busy: wait for the current task or press Esc".to_string();
+        }
+        let keep_turns = self
+            .compact
+            .keep_turns
+            .unwrap_or(DEFAULT_COMPACT_KEEP_TURNS);
+        let Some((replaced, history)) = self.state.compaction_source(keep_turns) else {
+            return format!(
+                "nothing to compact (the last {} turns are kept)",
+                keep_turns
+            );
         };
-        self.state.append_message(&compacted);
-        if !summary.is_empty() {
-            self.state.append_message("summary:");
-            self.state.append_message(&summary);
-            self.state
-                .conversation
-                .push(crate::tui::state::ConversationTurn {
-                    role: crate::tui::state::ConversationRole::Assistant,
-                    content: format!("Compacted summary:\n{}", summary),
+        let focus = focus.filter(|focus| !focus.trim().is_empty());
+        self.state.set_running("compacting");
+        self.compacting = true;
+        let runner = Arc::clone(&self.runner);
+        let result_tx = self.task_sender();
+        let handle = self.handle.spawn(async move {
+            let event = runner
+                .summarize_history(&history, focus.as_deref())
+                .await
+                .map(|summary| TuiEvent::Compacted {
+                    summary,
+                    focus,
+                    replaced,
                 });
+            let _ = result_tx.send(event);
+        });
+        self.current_task = Some(handle);
+        format!("summarizing {} older turns", replaced)
+    }
+
+    fn undo_compaction(&mut self) -> String {
+        if self.state.status_state == "running" {
+            return "busy: wait for the current task or press Esc".to_string();
+        }
+        match self.state.undo_compaction() {
+            Some(restored) => {
+                self.touch_current_session();
+                format!("compaction undone: {} turns restored", restored)
+            }
+            None => "nothing to undo".to_string(),
         }
-        self.touch_current_session();
-        "session compacted".to_string()
+    }
+
+    fn needs_auto_compaction(&self) -> bool {
+        if self.skip_auto_compact || self.compact.auto == Some(false) {
+            return false;
+        }
+        let Some(budget) = self
+            .runner
+            .model_info()
+            .and_then(|info| info.context_budget())
+        else {
+            return false;
+        };
+        let threshold = self.compact.threshold.unwrap_or(DEFAULT_COMPACT_THRESHOLD);
+        let keep_turns = self
+            .compact
+            .keep_turns
+            .unwrap_or(DEFAULT_COMPACT_KEEP_TURNS);
+        should_compact(self.state.conversation_tokens(), budget, threshold)
+            && self.state.compaction_source(keep_turns).is_some()
     }
 
     fn init_memory_file(&mut self) -> String {
@@ -1104,10 +1474,8 @@ impl App {
 
         match key {
             "model.default" => {
-                let mut config = load_config().unwrap_or_default();
-                config.model.default = value.to_string();
-                match write_local_config(&config) {
-                    Ok(()) => {
+                match update_local_config(|table| set_model_key(table, "default", value)) {
+                    Ok(_) => {
                         self.state.status_model = value.to_string();
                         format!("model.default set: {}", value)
                     }
@@ -1122,10 +1490,8 @@ impl App {
                 ) {
                     return format!("unsupported provider: {}", value);
                 }
-                let mut config = load_config().unwrap_or_default();
-                config.model.provider = normalized.clone();
-                match write_local_config(&config) {
-                    Ok(()) => format!("model.provider set: {}", normalized),
+                match update_local_config(|table| set_model_key(table, "provider", &normalized)) {
+                    Ok(_) => format!("model.provider set: {}", normalized),
                     Err(err) => format!("config update failed: {}", err),
                 }
             }
@@ -1194,28 +1560,45 @@ impl App {
         }
     }
 
+    /// Keys are entered with `tengu auth login`; here we only report which source is in use.
     fn login_auth(&self) -> String {
         let config = load_config().unwrap_or_default();
-        let provider = if !config.model.provider.trim().is_empty() {
-            config.model.provider
-        } else {
-            "anthropic".to_string()
-        };
-        let Some(env_name) = auth_env_var_for_provider(&provider) else {
-            return format!("login unsupported for provider: {}", provider);
+        let (provider, profile) = auth_provider_and_profile(&config);
+        let table = config.providers.get(&provider);
+        let env = table
+            .and_then(|table| table.api_key_env.as_deref())
+            .or(auth_env_var_for_provider(&provider));
+        if let Some(env) = env.filter(|env| std::env::var(env).is_ok()) {
+            return format!("auth ready: provider={} via {}", provider, env);
+        }
+        if table.is_some_and(|table| table.api_key_command.is_some()) {
+            return format!("auth ready: provider={} via api_key_command", provider);
+        }
+        let store = match CredentialStore::default_path() {
+            Ok(path) => CredentialStore::new(path),
+            Err(err) => return format!("login failed: {}", err),
         };
-        if std::env::var(env_name).is_err() {
-            return format!("{} is not set", env_name);
-        }
-        match save_tui_auth_session(&provider, env_name) {
-            Ok(()) => format!("auth ready: provider={} via {}", provider, env_name),
+        match store.get(&provider, &profile) {
+            Ok(Some(_)) => format!(
+                "auth ready: provider={} via credentials (profile {})",
+                provider, profile
+            ),
+            Ok(None) => format!(
+                "no API key for {}: run `tengu auth login --provider {} --profile {}`",
+                provider, provider, profile
+            ),
             Err(err) => format!("login failed: {}", err),
         }
     }
 
     fn logout_auth(&self) -> String {
-        match clear_tui_auth_session() {
-            Ok(()) => "auth session cleared".to_string(),
+        let config = load_config().unwrap_or_default();
+        let (provider, profile) = auth_provider_and_profile(&config);
+        let removed = CredentialStore::default_path()
+            .and_then(|path| CredentialStore::new(path).remove(&provider, &profile));
+        match removed {
+            Ok(true) => format!("removed {} key (profile {})", provider, profile),
+            Ok(false) => format!("no stored key for {} (profile {})", provider, profile),
             Err(err) => format!("logout failed: {}", err),
         }
     }
@@ -1329,6 +1712,9 @@ impl App {
         let mut lines = vec!["provider usage:".to_string()];
         for record in &self.state.provider_usage {
             lines.push(format!("[{}]", record.provider));
+            if let Some(model) = &record.last_model {
+                lines.push(format!("model: {}", model));
+            }
             lines.push(format!("requests: {}", record.requests));
             lines.push(format!("input_tokens: {}", record.input_tokens));
             lines.push(format!("output_tokens: {}", record.output_tokens));
@@ -1345,6 +1731,12 @@ impl App {
                     record.cache_read_input_tokens
                 ));
             }
+            if let Some(rate) = record.cache_hit_rate() {
+                lines.push(format!("cache_hit_rate: {:.1}%", rate * 100.0));
+            }
+            if let Some(cost) = record.cost_usd {
+                lines.push(format!("cost_usd: ${:.4} (estimate)", cost));
+            }
             if record.reasoning_tokens > 0 {
                 lines.push(format!("reasoning_tokens: {}", record.reasoning_tokens));
             }
@@ -1385,12 +1777,15 @@ impl App {
                 .iter()
                 .map(|record| serde_json::json!({
                     "provider": record.provider,
+                    "model": record.last_model,
                     "requests": record.requests,
                     "input_tokens": record.input_tokens,
                     "output_tokens": record.output_tokens,
                     "total_tokens": record.total_tokens,
                     "cache_creation_input_tokens": record.cache_creation_input_tokens,
                     "cache_read_input_tokens": record.cache_read_input_tokens,
+                    "cache_hit_rate": record.cache_hit_rate(),
+                    "cost_usd": record.cost_usd,
                     "reasoning_tokens": record.reasoning_tokens,
                     "last_raw_usage": record.last_raw,
                 }))
@@ -1412,28 +1807,121 @@ impl App {
         }
     }
 
-    fn set_model(&mut self, model: Option<&str>) -> String {
-        let Some(model) = model.map(str::trim).filter(|value| !value.is_empty()) else {
-            return format!("model: {}", self.state.status_model);
+    /// Without a name, lists the provider's models to pick from. With `[provider:]model`,
+    /// builds a client for it, asks the provider whether it serves the model and switches
+    /// the running session over; config files are only touched with `--save`.
+    fn set_model(&mut self, args: Option<&str>) -> String {
+        let args = args.unwrap_or_default();
+        let save = args.split_whitespace().any(|arg| arg == "--save");
+        let spec = args.split_whitespace().find(|arg| *arg != "--save");
+        let runner = Arc::clone(&self.runner);
+        let result_tx = self.state.result_tx.clone();
+        let Some(spec) = spec else {
+            if save {
+                return "usage: /model [provider:]model [--save]".to_string();
+            }
+            self.handle.spawn(async move {
+                let event = match runner.list_models().await {
+                    Ok(models) => TuiEvent::ModelList {
+                        models,
+                        error: None,
+                    },
+                    Err(err) => TuiEvent::ModelList {
+                        models: runner.catalog_models(),
+                        error: Some(err.to_string()),
+                    },
+                };
+                let _ = result_tx.send(Ok(event));
+            });
+            let provider = self.runner.provider_name();
+            return format!(
+                "model: {}:{}\nlisting {} models...",
+                provider,
+                self.runner.model_name(),
+                provider
+            );
         };
+        let config = load_config().unwrap_or_default();
+        let (provider, model) = config.split_model_spec(spec);
+        let (provider, client) = match self.runner.build_client(provider, model) {
+            Ok(built) => built,
+            Err(err) => return format!("model update failed: {}", err),
+        };
+        let model = model.to_string();
+        let checking = format!("checking model {}:{}...", provider, model);
+        self.handle.spawn(async move {
+            let error = client
+                .check_model(&model)
+                .await
+                .err()
+                .map(|e| e.to_string());
+            if error.is_none() {
+                runner.switch_client(provider.clone(), client, model.clone());
+            }
+            let _ = result_tx.send(Ok(TuiEvent::ModelSwitched {
+                provider,
+                model,
+                save,
+                error,
+            }));
+        });
+        checking
+    }
 
-        let mut config = load_config().unwrap_or_default();
-        config.model.default = model.to_string();
-        let path = local_config_path();
-        if let Some(parent) = path.parent() {
-            if let Err(err) = fs::create_dir_all(parent) {
-                return format!("model update failed: {}", err);
+    fn show_model_list(&mut self, models: Vec<String>, error: Option<String>) {
+        if let Some(err) = error {
+            self.state
+                .append_message(&format!("model listing failed: {}", err));
+        }
+        if models.is_empty() {
+            self.state.append_message("no models available");
+            self.state.append_blank_line();
+            return;
+        }
+        let lines = format_model_list(&models, &self.runner.model_name(), |id| {
+            self.runner.model_summary(id)
+        });
+        self.state.append_message(&lines);
+        self.state
+            .append_message("pick a number or name (Enter cancels)");
+        self.pending_model_pick = Some(models);
+    }
+
+    fn handle_model_pick(&mut self, input: &str) {
+        let Some(models) = self.pending_model_pick.take() else {
+            return;
+        };
+        if input.is_empty() {
+            self.state.append_message("model pick cancelled");
+            self.state.append_blank_line();
+            return;
+        }
+        match pick_model(&models, input) {
+            Some(model) => {
+                let response = self.set_model(Some(&model));
+                self.state.append_message(&response);
+            }
+            None => {
+                self.state
+                    .append_message(&format!("no such model: {} (Enter cancels)", input));
+                self.pending_model_pick = Some(models);
             }
         }
-        match toml::to_string_pretty(&config) {
-            Ok(content) => match fs::write(&path, content) {
-                Ok(()) => {
-                    self.state.status_model = model.to_string();
-                    format!("default model set: {}", model)
-                }
-                Err(err) => format!("model update failed: {}", err),
-            },
-            Err(err) => format!("model update failed: {}", err),
+    }
+
+    fn finish_model_switch(&mut self, provider: &str, model: &str, save: bool) -> String {
+        self.state.status_model = model.to_string();
+        let switched = format!("model switched: {}:{}", provider, model);
+        if !save {
+            return switched;
+        }
+        let saved = update_local_config(|table| {
+            set_model_key(table, "provider", provider);
+            set_model_key(table, "name", model);
+        });
+        match saved {
+            Ok(path) => format!("{}\nsaved to {}", switched, path.display()),
+            Err(err) => format!("{}\nmodel save failed: {}", switched, err),
         }
     }
 
@@ -1460,6 +1948,26 @@ impl App {
         )
     }
 
+    fn show_mcp_status(&mut self) {
+        let Some(manager) = self.runner.mcp_manager().cloned() else {
+            self.state.append_message("no mcp servers");
+            self.state.append_blank_line();
+            return;
+        };
+        self.state.append_message("checking mcp servers...");
+        let result_tx = self.state.result_tx.clone();
+        self.handle.spawn(async move {
+            let report = manager
+                .status()
+                .await
+                .iter()
+                .map(|status| status.render())
+                .collect::<Vec<_>>()
+                .join("\n");
+            let _ = result_tx.send(Ok(TuiEvent::McpStatus(report)));
+        });
+    }
+
     fn run_doctor(&self) -> String {
         let checks = [
             ("git", command_exists("git")),
@@ -1657,6 +2165,7 @@ enum SlashCommandOutcome {
     TaskWriter(Option<String>),
     ApplyPlan,
     Compact(Option<String>),
+    UndoCompact,
     OpenMemory,
     InitMemory,
     ConfigCommand(Vec<String>),
@@ -1673,6 +2182,7 @@ enum SlashCommandOutcome {
     TerminalSetup,
     ToggleVim,
     Doctor,
+    McpStatus,
     OpenEditor(Option<PathBuf>),
     Reset {
         response: String,
@@ -1772,16 +2282,25 @@ fn handle_slash_command(input: &str) -> Option<SlashCommandOutcome> {
         "/strategy" => Some(SlashCommandOutcome::SetStrategy(
             (!args.is_empty()).then(|| args.join(" ")),
         )),
-        "/compact" => Some(SlashCommandOutcome::Compact(
-            (!args.is_empty()).then(|| args.join(" ")),
-        )),
+        "/compact" => match args.as_slice() {
+            ["undo"] => Some(SlashCommandOutcome::UndoCompact),
+            _ => Some(SlashCommandOutcome::Compact(
+                (!args.is_empty()).then(|| args.join(" ")),
+            )),
+        },
         "/memory" => Some(SlashCommandOutcome::OpenMemory),
         "/init" => Some(SlashCommandOutcome::InitMemory),
         "/config" => Some(SlashCommandOutcome::ConfigCommand(
             args.iter().map(|arg| (*arg).to_string()).collect(),
         )),
         "/doctor" => Some(SlashCommandOutcome::Doctor),
-        "/mcp" => list_mcp_servers().ok().map(SlashCommandOutcome::Display),
+        "/mcp" => match args.as_slice() {
+            [] => list_mcp_servers().ok().map(SlashCommandOutcome::Display),
+            ["status"] => Some(SlashCommandOutcome::McpStatus),
+            _ => Some(SlashCommandOutcome::Display(
+                "usage: /mcp [status]".to_string(),
+            )),
+        },
         "/tools" => Some(SlashCommandOutcome::Display(list_builtin_tools())),
         "/status" => show_status().ok().map(SlashCommandOutcome::Display),
         "/approvals" | "/permissions" => {
@@ -1997,7 +2516,180 @@ fn expand_custom_command_template(template: &str, args: &[&str]) -> String {
     expanded
 }
 
+/// Sampling is approved per request (or remembered per server via always/never);
+/// elicitation forms are filled in through the input line.
+fn build_mcp_request_handler(
+    runner: Weak<AgentRunner>,
+    result_tx: mpsc::Sender<anyhow::Result<TuiEvent>>,
+) -> McpRequestHandler {
+    let remembered: Arc<Mutex<HashMap<String, bool>>> = Arc::new(Mutex::new(HashMap::new()));
+    Arc::new(move |server: String, request: McpServerRequest| {
+        let runner = runner.clone();
+        let result_tx = result_tx.clone();
+        let remembered = Arc::clone(&remembered);
+        Box::pin(async move {
+            match request {
+                McpServerRequest::Sampling(sampling) => {
+                    let known = remembered.lock().ok().and_then(|m| m.get(&server).copied());
+                    let approved = match known {
+                        Some(approved) => approved,
+                        None => {
+                            let (tx, rx) = oneshot::channel();
+                            let _ = result_tx.send(Ok(TuiEvent::McpSampling {
+                                server: server.clone(),
+                                preview: sampling.preview(),
+                                respond_to: tx,
+                            }));
+                            let decision = rx.await.unwrap_or(ToolApprovalDecision::DenyOnce);
+                            let remember = match decision {
+                                ToolApprovalDecision::AllowAll => Some(true),
+                                ToolApprovalDecision::DenyAll => Some(false),
+                                _ => None,
+                            };
+                            if let (Some(remember), Ok(mut map)) = (remember, remembered.lock()) {
+                                map.insert(server.clone(), remember);
+                            }
+                            matches!(
+                                decision,
+                                ToolApprovalDecision::AllowOnce | ToolApprovalDecision::AllowAll
+                            )
+                        }
+                    };
+                    if !approved {
+                        return Err(anyhow!("user rejected sampling request"));
+                    }
+                    let runner = runner
+                        .upgrade()
+                        .ok_or_else(|| anyhow!("agent is no longer running"))?;
+                    runner.sample(&sampling).await
+                }
+                McpServerRequest::Elicitation(elicitation) => {
+                    let (tx, rx) = oneshot::channel();
+                    let _ = result_tx.send(Ok(TuiEvent::McpElicitation {
+                        server,
+                        request: elicitation,
+                        respond_to: tx,
+                    }));
+                    Ok(rx.await.unwrap_or(ElicitationResponse::Cancel).to_value())
+                }
+            }
+        })
+    })
+}
+
+fn format_model_list(
+    models: &[String],
+    current: &str,
+    summary: impl Fn(&str) -> Option<String>,
+) -> String {
+    models
+        .iter()
+        .enumerate()
+        .map(|(index, id)| {
+            let marker = if id == current { "*" } else { " " };
+            match summary(id) {
+                Some(summary) => format!("{}{:>3}. {}  {}", marker, index + 1, id, summary),
+                None => format!("{}{:>3}. {}", marker, index + 1, id),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A 1-based number from the list, or an id typed out in full.
+fn pick_model(models: &[String], input: &str) -> Option<String> {
+    if let Ok(index) = input.parse::<usize>() {
+        return index
+            .checked_sub(1)
+            .and_then(|index| models.get(index))
+            .cloned();
+    }
+    models.iter().find(|id| id.as_str() == input).cloned()
+}
+
+struct TaskSender {
+    generation: u64,
+    tx: mpsc::Sender<anyhow::Result<TuiEvent>>,
+}
+
+impl TaskSender {
+    fn send(&self, result: anyhow::Result<TuiEvent>) -> Result<()> {
+        self.tx
+            .send(Ok(TuiEvent::Task {
+                generation: self.generation,
+                result: Box::new(result),
+            }))
+            .map_err(|_| anyhow!("the TUI has closed"))
+    }
+}
+
+/// Unwraps task events. Output an aborted task queued before it stopped is dropped, but
+/// its usage is kept: those tokens were spent.
+fn current_task_event(
+    result: anyhow::Result<TuiEvent>,
+    generation: u64,
+) -> Option<anyhow::Result<TuiEvent>> {
+    match result {
+        Ok(TuiEvent::Task {
+            generation: task,
+            result,
+        }) => match *result {
+            Ok(TuiEvent::Usage(usage)) => Some(Ok(TuiEvent::Usage(usage))),
+            result if task == generation => Some(result),
+            _ => None,
+        },
+        other => Some(other),
+    }
+}
+
+/// True once the conversation fills `threshold` of the model's context budget.
+fn should_compact(conversation_tokens: u64, budget: u64, threshold: f64) -> bool {
+    budget > 0 && conversation_tokens as f64 > budget as f64 * threshold
+}
+
+fn format_llm_retry(notice: &LlmRetryNotice) -> String {
+    format!(
+        "{} {}, retrying in {:.1}s ({}/{})",
+        notice.error.provider,
+        notice.error.kind.as_str().replace('_', " "),
+        notice.delay.as_secs_f64(),
+        notice.attempt,
+        notice.max_retries
+    )
+}
+
+fn format_mcp_progress(
+    server: &str,
+    progress: f64,
+    total: Option<f64>,
+    message: Option<&str>,
+) -> String {
+    let mut out = match total {
+        Some(total) if total > 0.0 => format!(
+            "mcp {}: {:.0}%",
+            server,
+            (progress / total * 100.0).clamp(0.0, 100.0)
+        ),
+        _ => format!("mcp {}: {}", server, progress),
+    };
+    if let Some(message) = message.filter(|m| !m.is_empty()) {
+        out.push_str(" • ");
+        out.push_str(message);
+    }
+    out
+}
+
 fn format_approval_prompt(request: &ToolApprovalRequest) -> String {
+    if let Some(target) = &request.mcp {
+        let mut arguments = target.arguments.to_string();
+        if arguments.chars().count() > 200 {
+            arguments = arguments.chars().take(200).collect::<String>() + "…";
+        }
+        return format!(
+            "Allow MCP tool {} on server {}?\narguments: {}\n[y] Yes  [n] No  [a] Always allow  [d] Don't ask again",
+            target.tool, target.server, arguments
+        );
+    }
     let tool_name = tool_name_label(request.tool);
     let target = if request.paths.is_empty() {
         "target".to_string()
@@ -2023,10 +2715,14 @@ fn tool_name_label(tool: Tool) -> &'static str {
         Tool::Shell => "Shell",
         Tool::Grep => "Grep",
         Tool::Glob => "Glob",
+        Tool::Mcp => "Mcp",
     }
 }
 
 fn format_tool_summary(request: &ToolApprovalRequest) -> String {
+    if let Some(target) = &request.mcp {
+        return mcp_tool_rule_name(&target.server, &target.tool);
+    }
     if request.paths.is_empty() {
         tool_name_label(request.tool).to_string()
     } else {
@@ -2087,8 +2783,8 @@ fn slash_help_items() -> Vec<SlashCommandHelp> {
             desc_en: "Show provider usage",
         },
         SlashCommandHelp {
-            cmd: "/compact [note]",
-            desc_en: "Compact the current conversation",
+            cmd: "/compact [focus|undo]",
+            desc_en: "Summarize older turns with the model",
         },
         SlashCommandHelp {
             cmd: "/memory",
@@ -2100,11 +2796,11 @@ fn slash_help_items() -> Vec<SlashCommandHelp> {
         },
         SlashCommandHelp {
             cmd: "/login",
-            desc_en: "Persist auth state from env vars",
+            desc_en: "Show where the API key comes from",
         },
         SlashCommandHelp {
             cmd: "/logout",
-            desc_en: "Clear persisted auth state",
+            desc_en: "Remove the stored API key",
         },
         SlashCommandHelp {
             cmd: "/pr_comments",
@@ -2151,8 +2847,8 @@ fn slash_help_items() -> Vec<SlashCommandHelp> {
             desc_en: "Attach image(s) to the next prompt",
         },
         SlashCommandHelp {
-            cmd: "/model",
-            desc_en: "Show model name",
+            cmd: "/model [provider:]model [--save]",
+            desc_en: "Pick or switch the model",
         },
         SlashCommandHelp {
             cmd: "/approvals",
@@ -2180,7 +2876,7 @@ fn slash_help_items() -> Vec<SlashCommandHelp> {
         },
         SlashCommandHelp {
             cmd: "/mcp",
-            desc_en: "List MCP servers",
+            desc_en: "List MCP servers (/mcp status to diagnose)",
         },
         SlashCommandHelp {
             cmd: "/diff",
@@ -2246,31 +2942,16 @@ fn build_slash_help_from_items(items: &[SlashCommandHelp]) -> String {
 }
 
 fn list_mcp_servers() -> anyhow::Result<String> {
-    let path = McpStore::default_path();
-    let config = McpStore::load(&path)?;
-    if config.mcp_servers.is_empty() {
+    let servers = McpStore::load_with_scopes()?;
+    if servers.is_empty() {
         return Ok("no mcp servers".to_string());
     }
-    let mut lines = Vec::new();
-    for (name, server) in config.mcp_servers.iter() {
-        let summary = if let Some(url) = &server.url {
-            format!("http {}", url)
-        } else if let Some(cmd) = &server.command {
-            let args = server
-                .args
-                .as_ref()
-                .map(|a| a.join(" "))
-                .unwrap_or_default();
-            if args.is_empty() {
-                format!("stdio {}", cmd)
-            } else {
-                format!("stdio {} {}", cmd, args)
-            }
-        } else {
-            "unknown".to_string()
-        };
-        lines.push(format!("{} {}", name, summary.trim()));
-    }
+    let lines: Vec<String> = servers
+        .iter()
+        .map(|(name, (scope, server))| {
+            format!("{} [{}] {}", name, scope.as_str(), server.summary())
+        })
+        .collect();
     Ok(lines.join("\n"))
 }
 
@@ -2546,14 +3227,35 @@ fn local_config_path() -> PathBuf {
     PathBuf::from(".").join(".tengu").join("config.toml")
 }
 
-fn write_local_config(config: &Config) -> Result<()> {
+/// Edits only `./.tengu/config.toml`; the user config is never copied into it.
+fn update_local_config(edit: impl FnOnce(&mut toml::Table)) -> Result<PathBuf> {
     let path = local_config_path();
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let updated = edit_config_table(&content, edit)?;
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent)?;
     }
-    let content = toml::to_string_pretty(config)?;
-    fs::write(path, content)?;
-    Ok(())
+    fs::write(&path, updated)?;
+    Ok(path)
+}
+
+fn edit_config_table(content: &str, edit: impl FnOnce(&mut toml::Table)) -> Result<String> {
+    let mut table: toml::Table = toml::from_str(content)?;
+    edit(&mut table);
+    Ok(toml::to_string_pretty(&table)?)
+}
+
+fn set_model_key(table: &mut toml::Table, key: &str, value: &str) {
+    let section = table
+        .entry("model")
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    if let toml::Value::Table(section) = section {
+        section.insert(key.to_string(), value.into());
+    }
 }
 
 fn command_exists(command: &str) -> bool {
@@ -2565,39 +3267,28 @@ fn auth_env_var_for_provider(provider: &str) -> Option<&'static str> {
         "anthropic" => Some("ANTHROPIC_API_KEY"),
         "openai" => Some("OPENAI_API_KEY"),
         "google" | "gemini" => Some("GOOGLE_API_KEY"),
+        "azure" | "azure-openai" => Some("AZURE_OPENAI_API_KEY"),
         _ => None,
     }
 }
 
-fn tui_auth_session_path() -> PathBuf {
-    std::env::var_os("HOME")
-        .map(PathBuf::from)
-        .unwrap_or_else(|| PathBuf::from("."))
-        .join(".tengu")
-        .join("auth")
-        .join("session.json")
-}
-
-fn save_tui_auth_session(provider: &str, env_var: &str) -> Result<()> {
-    let path = tui_auth_session_path();
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?;
-    }
-    let payload = serde_json::json!({
-        "provider": provider,
-        "env_var": env_var,
-        "updated_at": chrono::Utc::now().to_rfc3339(),
-    });
-    fs::write(path, serde_json::to_string_pretty(&payload)?)?;
-    Ok(())
-}
-
-fn clear_tui_auth_session() -> Result<()> {
-    let path = tui_auth_session_path();
-    if path.exists() {
-        fs::remove_file(path)?;
-    }
-    Ok(())
+fn auth_provider_and_profile(config: &Config) -> (String, String) {
+    let provider = if !config.model.provider.trim().is_empty() {
+        config.model.provider.clone()
+    } else {
+        "anthropic".to_string()
+    };
+    let profile = std::env::var("TENGU_PROFILE")
+        .ok()
+        .or_else(|| {
+            config
+                .providers
+                .get(&provider)
+                .and_then(|table| table.profile.clone())
+        })
+        .filter(|profile| !profile.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+    (provider, profile)
 }
 
 #[cfg(test)]
@@ -2661,6 +3352,169 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn keeps_partial_answer_marked_as_interrupted() {
+        let (tx, rx) = mpsc::channel();
+        let mut state = AppState::new(String::new(), String::new(), String::new(), rx, tx);
+        state.start_assistant_response();
+        state.append_assistant_chunk("half an ans");
+        state.interrupt_assistant_response();
+        let last = state.conversation.last().unwrap();
+        assert_eq!(last.content, "half an ans\n[interrupted]");
+    }
+
+    #[test]
+    fn drops_events_of_aborted_tasks() {
+        let (tx, rx) = mpsc::channel();
+        let stale = TaskSender { generation: 1, tx };
+        stale.send(Ok(TuiEvent::Chunk("late".to_string()))).unwrap();
+        stale.send(Ok(TuiEvent::Done)).unwrap();
+        stale
+            .send(Ok(TuiEvent::Usage(crate::llm::LlmUsage::default())))
+            .unwrap();
+        let events: Vec<_> = rx
+            .try_iter()
+            .filter_map(|result| current_task_event(result, 2))
+            .collect();
+        assert!(matches!(events.as_slice(), [Ok(TuiEvent::Usage(_))]));
+
+        let current = TaskSender {
+            generation: 2,
+            tx: stale.tx.clone(),
+        };
+        current.send(Ok(TuiEvent::Done)).unwrap();
+        let event = current_task_event(rx.try_recv().unwrap(), 2);
+        assert!(matches!(event, Some(Ok(TuiEvent::Done))));
+        let status = current_task_event(Ok(TuiEvent::McpStatus(String::new())), 2);
+        assert!(matches!(status, Some(Ok(TuiEvent::McpStatus(_)))));
+    }
+
+    #[test]
+    fn keeps_thinking_with_the_answer_in_the_session() {
+        let (tx, rx) = mpsc::channel();
+        let mut state = AppState::new(String::new(), String::new(), String::new(), rx, tx);
+        state.start_assistant_response();
+        state.append_thinking_chunk("check the ", None);
+        state.append_thinking_chunk("tests", Some("sig-1"));
+        state.append_assistant_chunk("done");
+        state.finalize_assistant_response();
+        let exported = state.export_conversation();
+        let thinking = exported.last().unwrap().thinking.as_ref().unwrap();
+        assert_eq!(thinking.text, "check the tests");
+        assert_eq!(thinking.signature.as_deref(), Some("sig-1"));
+        let thought_lines = state
+            .log_lines
+            .iter()
+            .filter(|line| line.text.starts_with("思考:"))
+            .count();
+        assert_eq!(thought_lines, 1);
+        assert!(state.current_thinking.is_empty());
+    }
+
+    #[test]
+    fn shows_cache_hit_rate_in_usage() {
+        let (tx, rx) = mpsc::channel();
+        let mut state = AppState::new(String::new(), String::new(), String::new(), rx, tx);
+        state.record_provider_usage(&crate::llm::LlmUsage {
+            provider: "anthropic".to_string(),
+            input_tokens: Some(100),
+            output_tokens: Some(20),
+            cache_creation_input_tokens: Some(100),
+            cache_read_input_tokens: Some(800),
+            ..Default::default()
+        });
+        let record = &state.provider_usage[0];
+        assert_eq!(record.cache_hit_rate(), Some(0.8));
+        state.context_window = Some(200_000);
+        assert_eq!(state.context_meter().as_deref(), Some("ctx 1k/200k (0%)"));
+    }
+
+    #[test]
+    fn formats_the_context_meter() {
+        assert_eq!(
+            crate::tui::state::format_context_meter(150_000, Some(200_000)),
+            "ctx 150k/200k (75%)"
+        );
+        assert_eq!(
+            crate::tui::state::format_context_meter(950, None),
+            "ctx 950"
+        );
+    }
+
+    #[test]
+    fn drops_oldest_turns_beyond_the_context_budget() {
+        let (tx, rx) = mpsc::channel();
+        let mut state = AppState::new(String::new(), String::new(), String::new(), rx, tx);
+        state.push_user_conversation(&"x".repeat(400));
+        state.push_user_conversation("short");
+        assert_eq!(state.build_context(10, Some(50)), "ユーザー: short");
+        assert!(state.build_context(10, None).starts_with("ユーザー: xxx"));
+    }
+
+    #[test]
+    fn compacts_older_turns_behind_a_summary_and_undoes_it() {
+        let (tx, rx) = mpsc::channel();
+        let mut state = AppState::new(String::new(), String::new(), String::new(), rx, tx);
+        for turn in ["one", "two", "three"] {
+            state.push_user_conversation(turn);
+        }
+        assert!(state.compaction_source(3).is_none());
+        let (replaced, history) = state.compaction_source(1).unwrap();
+        assert_eq!(replaced, 2);
+        assert_eq!(history, "ユーザー: one\nユーザー: two");
+
+        state.apply_compaction(
+            "目標: count".to_string(),
+            Some("numbers".to_string()),
+            replaced,
+        );
+        assert_eq!(state.conversation.len(), 1);
+        assert_eq!(
+            state.build_context(10, None),
+            "これまでの会話の要約:\n目標: count\nユーザー: three"
+        );
+        assert_eq!(state.export_compactions()[0].turns.len(), 2);
+        state.push_user_conversation("four");
+        assert!(state
+            .compaction_source(1)
+            .unwrap()
+            .1
+            .starts_with("これまでの会話の要約:\n目標: count\nユーザー: three"));
+
+        assert_eq!(state.undo_compaction(), Some(2));
+        let turns: Vec<_> = state
+            .conversation
+            .iter()
+            .map(|turn| turn.content.as_str())
+            .collect();
+        assert_eq!(turns, ["one", "two", "three", "four"]);
+        assert_eq!(state.undo_compaction(), None);
+
+        assert!(should_compact(900, 1000, 0.8));
+        assert!(!should_compact(800, 1000, 0.8));
+        assert!(!should_compact(10, 0, 0.8));
+        assert!(matches!(
+            handle_slash_command("/compact undo"),
+            Some(SlashCommandOutcome::UndoCompact)
+        ));
+        assert!(matches!(
+            handle_slash_command("/compact keep the API design"),
+            Some(SlashCommandOutcome::Compact(Some(focus))) if focus == "keep the API design"
+        ));
+    }
+
+    #[test]
+    fn parses_mcp_status_command() {
+        assert!(matches!(
+            handle_slash_command("/mcp status"),
+            Some(SlashCommandOutcome::McpStatus)
+        ));
+        assert!(matches!(
+            handle_slash_command("/mcp bogus"),
+            Some(SlashCommandOutcome::Display(message)) if message == "usage: /mcp [status]"
+        ));
+    }
+
     #[test]
     fn parses_save_command() {
         let outcome = handle_slash_command("/save");
@@ -2718,6 +3572,63 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn splits_provider_from_model_spec() {
+        let mut config = Config::default();
+        config
+            .providers
+            .insert("work".to_string(), Default::default());
+        assert_eq!(
+            config.split_model_spec("openai:gpt-4o"),
+            (Some("openai"), "gpt-4o")
+        );
+        assert_eq!(
+            config.split_model_spec("work:claude-sonnet-4"),
+            (Some("work"), "claude-sonnet-4")
+        );
+        assert_eq!(
+            config.split_model_spec("llama3.2:3b"),
+            (None, "llama3.2:3b")
+        );
+        assert_eq!(config.split_model_spec("gpt-4o"), (None, "gpt-4o"));
+    }
+
+    #[test]
+    fn saves_only_the_model_keys_into_the_local_config() {
+        let updated = edit_config_table(
+            "[permissions]\napproval_policy = \"on-request\"\n",
+            |table| {
+                set_model_key(table, "provider", "openai");
+                set_model_key(table, "name", "gpt-4o");
+            },
+        )
+        .unwrap();
+        let table: toml::Table = toml::from_str(&updated).unwrap();
+        assert_eq!(table["model"]["provider"].as_str(), Some("openai"));
+        assert_eq!(table["model"]["name"].as_str(), Some("gpt-4o"));
+        assert_eq!(table["model"].as_table().unwrap().len(), 2);
+        assert_eq!(
+            table["permissions"]["approval_policy"].as_str(),
+            Some("on-request")
+        );
+        assert!(table.get("providers").is_none() && table.get("sandbox").is_none());
+        assert!(edit_config_table("not = [valid", |_| {}).is_err());
+    }
+
+    #[test]
+    fn picks_models_by_number_or_name() {
+        let models = vec!["gpt-4o".to_string(), "gpt-4o-mini".to_string()];
+        assert_eq!(pick_model(&models, "2"), Some("gpt-4o-mini".to_string()));
+        assert_eq!(pick_model(&models, "gpt-4o"), Some("gpt-4o".to_string()));
+        assert_eq!(pick_model(&models, "0"), None);
+        assert_eq!(pick_model(&models, "3"), None);
+        assert_eq!(pick_model(&models, "gpt"), None);
+        let listed = format_model_list(&models, "gpt-4o-mini", |id| {
+            (id == "gpt-4o").then(|| "128k ctx".to_string())
+        });
+        assert_eq!(listed, "   1. gpt-4o  128k ctx\n*  2. gpt-4o-mini");
+    }
+
     #[test]
     fn parses_image_command() {
         let outcome = handle_slash_command("/image a.png b.png");
@@ -2840,4 +3751,30 @@ mod tests {
                 if args == vec!["set".to_string(), "plan_mode".to_string(), "on".to_string()]
         ));
     }
+
+    #[test]
+    fn formats_mcp_progress_for_status_line() {
+        assert_eq!(
+            format_mcp_progress("db", 3.0, Some(4.0), Some("scanning")),
+            "mcp db: 75% • scanning"
+        );
+        assert_eq!(format_mcp_progress("db", 7.0, None, None), "mcp db: 7");
+    }
+
+    #[test]
+    fn shows_mcp_server_tool_and_arguments_in_approval_prompt() {
+        let request = ToolApprovalRequest {
+            tool: Tool::Mcp,
+            paths: Vec::new(),
+            mcp: Some(crate::tools::McpToolTarget {
+                server: "github".to_string(),
+                tool: "create_issue".to_string(),
+                arguments: serde_json::json!({ "title": "bug" }),
+            }),
+        };
+        let prompt = format_approval_prompt(&request);
+        assert!(prompt.starts_with("Allow MCP tool create_issue on server github?"));
+        assert!(prompt.contains(r#"arguments: {"title":"bug"}"#));
+        assert_eq!(format_tool_summary(&request), "mcp__github__create_issue");
+    }
 }
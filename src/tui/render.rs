@@ -104,10 +104,16 @@ pub fn draw(stdout: &mut Stdout, state: &mut AppState) -> io::Result<()> {
         )?;
     }
 
-    let app_left = format!(
-        "model: {} • build {}",
-        state.status_model, state.status_build
-    );
+    let app_left = match state.context_meter() {
+        Some(meter) => format!(
+            "model: {} • {} • build {}",
+            state.status_model, meter, state.status_build
+        ),
+        None => format!(
+            "model: {} • build {}",
+            state.status_model, state.status_build
+        ),
+    };
     let app_right = if state.suggestions.is_empty() {
         "Ctrl+C to quit • ? for shortcuts"
     } else {
@@ -292,7 +298,10 @@ fn render_log_lines(lines: &[crate::tui::state::LogLine], width: usize) -> Vec<S
             LogRole::Assistant | LogRole::System => {
                 if line.text.is_empty() {
                     if !buffer.is_empty() {
-                        output.extend(render_markdown_lines(&buffer, width));
+                        output.extend(render_markdown_lines(
+                            &collapse_thought_blocks(&buffer),
+                            width,
+                        ));
                         buffer.clear();
                     }
                     output.push(String::new());
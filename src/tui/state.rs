@@ -2,10 +2,14 @@ use std::collections::VecDeque;
 use std::path::PathBuf;
 use std::sync::mpsc;
 
-use crate::llm::{LlmImage, LlmUsage};
+use crate::llm::{
+    estimate_tokens, format_tokens, LlmImage, LlmRetryNotice, LlmRole, LlmThinking, LlmTurn,
+    LlmUsage,
+};
+use crate::mcp::{ElicitationRequest, ElicitationResponse, McpNotification};
 use crate::session::{
-    SessionConversationRole, SessionConversationTurn, SessionImage, SessionLogLine, SessionLogRole,
-    SessionPendingInput, SessionUsageRecord,
+    SessionCompaction, SessionConversationRole, SessionConversationTurn, SessionImage,
+    SessionLogLine, SessionLogRole, SessionPendingInput, SessionThinking, SessionUsageRecord,
 };
 use crate::tools::{ToolApprovalDecision, ToolApprovalRequest};
 use crate::tui::InlineRenderState;
@@ -19,10 +23,51 @@ pub enum TuiEvent {
         request: String,
         plan: String,
     },
+    Compacted {
+        summary: String,
+        focus: Option<String>,
+        replaced: usize,
+    },
     ApprovalRequest {
         request: ToolApprovalRequest,
         respond_to: oneshot::Sender<ToolApprovalDecision>,
     },
+    Mcp {
+        server: String,
+        notification: McpNotification,
+    },
+    McpStatus(String),
+    ModelList {
+        models: Vec<String>,
+        error: Option<String>,
+    },
+    ModelSwitched {
+        provider: String,
+        model: String,
+        save: bool,
+        error: Option<String>,
+    },
+    Thinking {
+        text: String,
+        signature: Option<String>,
+    },
+    LlmRetry(LlmRetryNotice),
+    LlmWarning(String),
+    McpSampling {
+        server: String,
+        preview: String,
+        respond_to: oneshot::Sender<ToolApprovalDecision>,
+    },
+    McpElicitation {
+        server: String,
+        request: ElicitationRequest,
+        respond_to: oneshot::Sender<ElicitationResponse>,
+    },
+    /// An event of a prompt or compaction task, tagged with the task's generation.
+    Task {
+        generation: u64,
+        result: Box<anyhow::Result<TuiEvent>>,
+    },
 }
 
 #[derive(Debug, Clone, Default)]
@@ -46,6 +91,27 @@ pub struct ProviderUsageRecord {
     pub reasoning_tokens: u64,
     pub requests: u64,
     pub last_raw: Option<String>,
+    /// Sum of the estimates; `None` until a request with a known price is recorded.
+    pub cost_usd: Option<f64>,
+    /// The model of the latest request served by this provider.
+    pub last_model: Option<String>,
+}
+
+impl ProviderUsageRecord {
+    /// Share of prompt tokens read from the provider's cache. Anthropic counts cached tokens
+    /// apart from `input_tokens`; the other providers include them in it.
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        let prompt_tokens = if self.provider == "anthropic" {
+            self.input_tokens
+                .saturating_add(self.cache_creation_input_tokens)
+                .saturating_add(self.cache_read_input_tokens)
+        } else {
+            self.input_tokens
+        };
+        let cached = self.cache_read_input_tokens > 0 || self.cache_creation_input_tokens > 0;
+        (cached && prompt_tokens > 0)
+            .then(|| self.cache_read_input_tokens as f64 / prompt_tokens as f64)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -64,6 +130,16 @@ pub enum ConversationRole {
 pub struct ConversationTurn {
     pub role: ConversationRole,
     pub content: String,
+    pub thinking: Option<LlmThinking>,
+}
+
+/// Turns replaced by a model-written summary; `summary` covers earlier compactions too.
+#[derive(Debug, Clone)]
+pub struct Compaction {
+    pub summary: String,
+    pub focus: Option<String>,
+    pub turns: Vec<ConversationTurn>,
+    pub created_at: String,
 }
 
 #[derive(Debug, Clone)]
@@ -113,6 +189,7 @@ pub struct AppState {
     pub approval_pending: Option<ApprovalPending>,
     pub conversation: Vec<ConversationTurn>,
     pub current_assistant: String,
+    pub current_thinking: LlmThinking,
     pub pending_images: Vec<LlmImage>,
     pub plan_mode: bool,
     pub last_plan_request: Option<String>,
@@ -122,6 +199,10 @@ pub struct AppState {
     pub vim_mode: bool,
     pub usage: UsageStats,
     pub provider_usage: Vec<ProviderUsageRecord>,
+    /// Input tokens of the last answer, for the context meter.
+    pub context_tokens: Option<u64>,
+    pub context_window: Option<u64>,
+    pub compactions: Vec<Compaction>,
 }
 
 impl AppState {
@@ -169,6 +250,7 @@ impl AppState {
             approval_pending: None,
             conversation: Vec::new(),
             current_assistant: String::new(),
+            current_thinking: LlmThinking::default(),
             pending_images: Vec::new(),
             plan_mode: false,
             last_plan_request: None,
@@ -178,6 +260,9 @@ impl AppState {
             vim_mode: false,
             usage: UsageStats::default(),
             provider_usage: Vec::new(),
+            context_tokens: None,
+            context_window: None,
+            compactions: Vec::new(),
         }
     }
 
@@ -212,39 +297,181 @@ impl AppState {
         self.current_assistant.push_str(text);
     }
 
+    /// The log gets a single `思考:` block, which the renderer shows collapsed; the text is
+    /// kept for the conversation only.
+    pub fn append_thinking_chunk(&mut self, text: &str, signature: Option<&str>) {
+        if self.current_thinking.text.is_empty() && !text.is_empty() {
+            for line in ["思考:", "", ""] {
+                self.log_lines.push_back(LogLine {
+                    role: LogRole::Assistant,
+                    text: line.to_string(),
+                });
+            }
+        }
+        self.current_thinking.push(text, signature);
+    }
+
     pub fn start_assistant_response(&mut self) {
         self.current_assistant.clear();
+        self.current_thinking = LlmThinking::default();
+    }
+
+    fn take_thinking(&mut self) -> Option<LlmThinking> {
+        let thinking = std::mem::take(&mut self.current_thinking);
+        (!thinking.is_empty()).then_some(thinking)
     }
 
     pub fn finalize_assistant_response(&mut self) {
+        let thinking = self.take_thinking();
         if !self.current_assistant.trim().is_empty() {
             self.conversation.push(ConversationTurn {
                 role: ConversationRole::Assistant,
                 content: self.current_assistant.trim().to_string(),
+                thinking,
             });
         }
         self.current_assistant.clear();
         self.append_blank_line();
     }
 
+    /// Keeps a partial answer in the conversation, marked so the model knows it was cut off.
+    pub fn interrupt_assistant_response(&mut self) {
+        let thinking = self.take_thinking();
+        let partial = self.current_assistant.trim();
+        if !partial.is_empty() {
+            self.conversation.push(ConversationTurn {
+                role: ConversationRole::Assistant,
+                content: format!("{}\n[interrupted]", partial),
+                thinking,
+            });
+        }
+        self.current_assistant.clear();
+    }
+
     pub fn push_user_conversation(&mut self, text: &str) {
         self.conversation.push(ConversationTurn {
             role: ConversationRole::User,
             content: text.to_string(),
+            thinking: None,
+        });
+    }
+
+    /// The compacted summary, then the newest `max_turns` turns, fewer when they exceed
+    /// `max_tokens`.
+    pub fn build_context(&self, max_turns: usize, max_tokens: Option<u64>) -> String {
+        let (summary, turns) = self.context_turns(max_turns, max_tokens);
+        summary
+            .into_iter()
+            .chain(turns.iter().map(|turn| context_line(turn)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The turns of `build_context`, one message each; the summary opens as a user turn.
+    pub fn build_history(&self, max_turns: usize, max_tokens: Option<u64>) -> Vec<LlmTurn> {
+        let (summary, turns) = self.context_turns(max_turns, max_tokens);
+        let summary = summary.map(|content| LlmTurn {
+            role: LlmRole::User,
+            content,
+            thinking: None,
         });
+        summary
+            .into_iter()
+            .chain(turns.iter().map(|turn| LlmTurn {
+                role: match turn.role {
+                    ConversationRole::User => LlmRole::User,
+                    ConversationRole::Assistant => LlmRole::Assistant,
+                },
+                content: turn.content.clone(),
+                thinking: turn.thinking.clone(),
+            }))
+            .collect()
+    }
+
+    fn context_turns(
+        &self,
+        max_turns: usize,
+        max_tokens: Option<u64>,
+    ) -> (Option<String>, Vec<&ConversationTurn>) {
+        let summary = self
+            .compact_summary()
+            .map(|summary| format!("これまでの会話の要約:\n{}", summary));
+        let mut budget =
+            max_tokens.map(|max| max.saturating_sub(summary.as_deref().map_or(0, estimate_tokens)));
+        let mut turns = Vec::new();
+        for turn in self.conversation.iter().rev().take(max_turns) {
+            if let Some(remaining) = budget.as_mut() {
+                let tokens = estimate_tokens(&context_line(turn));
+                if tokens > *remaining {
+                    break;
+                }
+                *remaining -= tokens;
+            }
+            turns.push(turn);
+        }
+        turns.reverse();
+        (summary, turns)
     }
 
-    pub fn build_context(&self, max_turns: usize) -> String {
-        let start = self.conversation.len().saturating_sub(max_turns);
+    pub fn compact_summary(&self) -> Option<&str> {
+        self.compactions
+            .last()
+            .map(|compaction| compaction.summary.as_str())
+    }
+
+    /// The summary and every turn, i.e. what compaction would shrink.
+    pub fn conversation_tokens(&self) -> u64 {
+        self.compact_summary().map_or(0, estimate_tokens)
+            + self
+                .conversation
+                .iter()
+                .map(|turn| estimate_tokens(&turn.content))
+                .sum::<u64>()
+    }
+
+    /// Number of turns a compaction keeping the newest `keep_turns` would replace, and the
+    /// text to summarize (the previous summary and those turns).
+    pub fn compaction_source(&self, keep_turns: usize) -> Option<(usize, String)> {
+        let replaced = self.conversation.len().checked_sub(keep_turns)?;
+        if replaced == 0 {
+            return None;
+        }
         let mut parts = Vec::new();
-        for turn in self.conversation.iter().skip(start) {
+        if let Some(summary) = self.compact_summary() {
+            parts.push(format!("これまでの会話の要約:\n{}", summary));
+        }
+        for turn in &self.conversation[..replaced] {
             let role = match turn.role {
                 ConversationRole::User => "ユーザー",
                 ConversationRole::Assistant => "アシスタント",
             };
             parts.push(format!("{}: {}", role, turn.content));
         }
-        parts.join("\n")
+        Some((replaced, parts.join("\n")))
+    }
+
+    pub fn apply_compaction(&mut self, summary: String, focus: Option<String>, replaced: usize) {
+        let replaced = replaced.min(self.conversation.len());
+        let turns = self.conversation.drain(..replaced).collect();
+        self.compactions.push(Compaction {
+            summary,
+            focus,
+            turns,
+            created_at: chrono::Utc::now().to_rfc3339(),
+        });
+    }
+
+    /// Puts the turns of the last compaction back; returns how many.
+    pub fn undo_compaction(&mut self) -> Option<usize> {
+        let compaction = self.compactions.pop()?;
+        let restored = compaction.turns.len();
+        self.conversation.splice(0..0, compaction.turns);
+        Some(restored)
+    }
+
+    pub fn context_meter(&self) -> Option<String> {
+        self.context_tokens
+            .map(|tokens| format_context_meter(tokens, self.context_window))
     }
 
     pub fn input_row_count(&self) -> u16 {
@@ -309,6 +536,16 @@ impl AppState {
     }
 
     pub fn record_provider_usage(&mut self, usage: &LlmUsage) {
+        if let Some(input) = usage.input_tokens {
+            // Anthropic counts cached tokens apart from `input_tokens`.
+            self.context_tokens = Some(if usage.provider == "anthropic" {
+                input
+                    + usage.cache_read_input_tokens.unwrap_or(0)
+                    + usage.cache_creation_input_tokens.unwrap_or(0)
+            } else {
+                input
+            });
+        }
         let input_tokens = usage.input_tokens.unwrap_or(0);
         let output_tokens = usage.output_tokens.unwrap_or(0);
         let total_tokens = usage
@@ -338,9 +575,15 @@ impl AppState {
                 .saturating_add(cache_read_input_tokens);
             record.reasoning_tokens = record.reasoning_tokens.saturating_add(reasoning_tokens);
             record.requests = record.requests.saturating_add(1);
+            if let Some(cost) = usage.cost_usd {
+                record.cost_usd = Some(record.cost_usd.unwrap_or(0.0) + cost);
+            }
             if last_raw.is_some() {
                 record.last_raw = last_raw;
             }
+            if usage.model.is_some() {
+                record.last_model = usage.model.clone();
+            }
             return;
         }
 
@@ -354,6 +597,8 @@ impl AppState {
             reasoning_tokens,
             requests: 1,
             last_raw,
+            cost_usd: usage.cost_usd,
+            last_model: usage.model.clone(),
         });
     }
 
@@ -382,14 +627,17 @@ impl AppState {
     }
 
     pub fn export_conversation(&self) -> Vec<SessionConversationTurn> {
-        self.conversation
+        self.conversation.iter().map(export_turn).collect()
+    }
+
+    pub fn export_compactions(&self) -> Vec<SessionCompaction> {
+        self.compactions
             .iter()
-            .map(|turn| SessionConversationTurn {
-                role: match turn.role {
-                    ConversationRole::User => SessionConversationRole::User,
-                    ConversationRole::Assistant => SessionConversationRole::Assistant,
-                },
-                content: turn.content.clone(),
+            .map(|compaction| SessionCompaction {
+                summary: compaction.summary.clone(),
+                focus: compaction.focus.clone(),
+                turns: compaction.turns.iter().map(export_turn).collect(),
+                created_at: compaction.created_at.clone(),
             })
             .collect()
     }
@@ -449,6 +697,8 @@ impl AppState {
                 reasoning_tokens: record.reasoning_tokens,
                 requests: record.requests,
                 last_raw: record.last_raw.clone(),
+                cost_usd: record.cost_usd,
+                last_model: record.last_model.clone(),
             })
             .collect()
     }
@@ -460,6 +710,7 @@ impl AppState {
         queue: &[SessionPendingInput],
         pending_images: &[SessionImage],
         usage_records: &[SessionUsageRecord],
+        compactions: &[SessionCompaction],
     ) {
         self.reset_session_view();
         if !log_lines.is_empty() {
@@ -475,14 +726,14 @@ impl AppState {
                 })
                 .collect();
         }
-        self.conversation = conversation
+        self.conversation = conversation.iter().map(restore_turn).collect();
+        self.compactions = compactions
             .iter()
-            .map(|turn| ConversationTurn {
-                role: match turn.role {
-                    SessionConversationRole::User => ConversationRole::User,
-                    SessionConversationRole::Assistant => ConversationRole::Assistant,
-                },
-                content: turn.content.clone(),
+            .map(|compaction| Compaction {
+                summary: compaction.summary.clone(),
+                focus: compaction.focus.clone(),
+                turns: compaction.turns.iter().map(restore_turn).collect(),
+                created_at: compaction.created_at.clone(),
             })
             .collect();
         self.queue = queue
@@ -520,6 +771,8 @@ impl AppState {
                 reasoning_tokens: record.reasoning_tokens,
                 requests: record.requests,
                 last_raw: record.last_raw.clone(),
+                cost_usd: record.cost_usd,
+                last_model: record.last_model.clone(),
             })
             .collect();
     }
@@ -544,6 +797,7 @@ impl AppState {
         });
         self.queue.clear();
         self.conversation.clear();
+        self.compactions.clear();
         self.current_assistant.clear();
         self.input.clear();
         self.suggestions.clear();
@@ -554,10 +808,39 @@ impl AppState {
         self.added_dirs.clear();
         self.vim_mode = false;
         self.provider_usage.clear();
+        self.context_tokens = None;
         self.set_idle();
     }
 }
 
+fn export_turn(turn: &ConversationTurn) -> SessionConversationTurn {
+    SessionConversationTurn {
+        role: match turn.role {
+            ConversationRole::User => SessionConversationRole::User,
+            ConversationRole::Assistant => SessionConversationRole::Assistant,
+        },
+        content: turn.content.clone(),
+        thinking: turn.thinking.as_ref().map(|thinking| SessionThinking {
+            text: thinking.text.clone(),
+            signature: thinking.signature.clone(),
+        }),
+    }
+}
+
+fn restore_turn(turn: &SessionConversationTurn) -> ConversationTurn {
+    ConversationTurn {
+        role: match turn.role {
+            SessionConversationRole::User => ConversationRole::User,
+            SessionConversationRole::Assistant => ConversationRole::Assistant,
+        },
+        content: turn.content.clone(),
+        thinking: turn.thinking.as_ref().map(|thinking| LlmThinking {
+            text: thinking.text.clone(),
+            signature: thinking.signature.clone(),
+        }),
+    }
+}
+
 fn extract_plan_items(plan: &str) -> Vec<String> {
     let mut items = Vec::new();
     for line in plan.lines() {
@@ -593,3 +876,23 @@ fn extract_plan_items(plan: &str) -> Vec<String> {
     }
     items
 }
+
+/// "ctx 12k/200k (6%)", or "ctx 12k" when the window is unknown.
+pub fn format_context_meter(tokens: u64, window: Option<u64>) -> String {
+    match window.filter(|window| *window > 0) {
+        Some(window) => format!(
+            "ctx {}/{} ({}%)",
+            format_tokens(tokens),
+            format_tokens(window),
+            tokens * 100 / window
+        ),
+        None => format!("ctx {}", format_tokens(tokens)),
+    }
+}
+fn context_line(turn: &ConversationTurn) -> String {
+    let role = match turn.role {
+        ConversationRole::User => "ユーザー",
+        ConversationRole::Assistant => "アシスタント",
+    };
+    format!("{}: {}", role, turn.content)
+}